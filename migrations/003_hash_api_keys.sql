@@ -0,0 +1,21 @@
+-- Store API keys as prefix + SHA-256 hash instead of plaintext.
+-- The full key is only returned once, from create/regenerate.
+-- Run with: psql $DATABASE_URL -f migrations/003_hash_api_keys.sql
+
+ALTER TABLE domains ADD COLUMN key_prefix VARCHAR(16);
+ALTER TABLE domains ADD COLUMN key_hash VARCHAR(64);
+
+UPDATE domains
+SET key_prefix = substring(key FROM 1 FOR 15),
+    key_hash = encode(sha256(convert_to(key, 'UTF8')), 'hex');
+
+ALTER TABLE domains ALTER COLUMN key_prefix SET NOT NULL;
+ALTER TABLE domains ALTER COLUMN key_hash SET NOT NULL;
+ALTER TABLE domains ADD CONSTRAINT domains_key_hash_key UNIQUE (key_hash);
+
+DROP INDEX IF EXISTS idx_domains_key;
+ALTER TABLE domains DROP COLUMN key;
+CREATE INDEX idx_domains_key_prefix ON domains(key_prefix);
+
+COMMENT ON COLUMN domains.key_prefix IS 'First 15 chars of the API key (nk_ + 12 hex), used for lookup and masked display';
+COMMENT ON COLUMN domains.key_hash IS 'SHA-256 hex of the full API key';
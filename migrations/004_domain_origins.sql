@@ -0,0 +1,20 @@
+-- Origin policy per domain: extra allowed origins and a dev mode that allows localhost.
+-- domains.domain_name stays allowed (any scheme/port); rows here add more entries.
+-- Run with: psql $DATABASE_URL -f migrations/004_domain_origins.sql
+
+ALTER TABLE domains ADD COLUMN dev_mode BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE domain_origins (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    domain_id UUID NOT NULL REFERENCES domains(id) ON DELETE CASCADE,
+    origin VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE(domain_id, origin)
+);
+CREATE INDEX idx_domain_origins_domain_id ON domain_origins(domain_id);
+
+ALTER TABLE domain_origins ENABLE ROW LEVEL SECURITY;
+CREATE POLICY domain_origins_own ON domain_origins FOR ALL USING (true);
+
+COMMENT ON TABLE domain_origins IS 'Allowed origins per domain: [scheme://]host[:port], host may be *.example.com';
+COMMENT ON COLUMN domains.dev_mode IS 'Allow localhost / 127.0.0.1 / [::1] origins on any port';
@@ -0,0 +1,10 @@
+-- Per-domain signing secret for connection tokens (and other server-signed credentials).
+-- Unlike the API key it must be readable by the server, so it is stored as-is.
+-- Run with: psql $DATABASE_URL -f migrations/005_domain_secret.sql
+
+ALTER TABLE domains ADD COLUMN secret VARCHAR(80);
+UPDATE domains
+SET secret = 'ns_' || replace(uuid_generate_v4()::text, '-', '') || replace(uuid_generate_v4()::text, '-', '');
+ALTER TABLE domains ALTER COLUMN secret SET NOT NULL;
+
+COMMENT ON COLUMN domains.secret IS 'Domain secret (ns_ + 64 hex) for signing connection tokens; shown once on create/regenerate';
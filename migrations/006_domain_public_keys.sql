@@ -0,0 +1,19 @@
+-- Public keys for verifying channel-auth JWTs (RS256 / EdDSA), selected by the JWT `kid`.
+-- HS256 channel tokens use domains.secret instead.
+-- Run with: psql $DATABASE_URL -f migrations/006_domain_public_keys.sql
+
+CREATE TABLE domain_public_keys (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    domain_id UUID NOT NULL REFERENCES domains(id) ON DELETE CASCADE,
+    kid VARCHAR(128) NOT NULL,
+    algorithm VARCHAR(16) NOT NULL CHECK (algorithm IN ('RS256', 'EdDSA')),
+    public_key_pem TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE(domain_id, kid)
+);
+CREATE INDEX idx_domain_public_keys_domain_id ON domain_public_keys(domain_id);
+
+ALTER TABLE domain_public_keys ENABLE ROW LEVEL SECURITY;
+CREATE POLICY domain_public_keys_own ON domain_public_keys FOR ALL USING (true);
+
+COMMENT ON TABLE domain_public_keys IS 'Public keys (PEM) for RS256/EdDSA channel-auth JWTs, matched by kid';
@@ -0,0 +1,7 @@
+-- Authorizer webhook per domain: notif POSTs {socket_id, channel, user_token} to this URL
+-- on private/presence subscribes that carry no `auth`; 200 = allowed.
+-- Run with: psql $DATABASE_URL -f migrations/007_domain_authorizer.sql
+
+ALTER TABLE domains ADD COLUMN authorizer_url VARCHAR(2048);
+
+COMMENT ON COLUMN domains.authorizer_url IS 'Optional authorizer webhook URL for private/presence subscribes';
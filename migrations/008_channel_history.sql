@@ -0,0 +1,11 @@
+-- Opt-in channel history per domain: broadcasts to channels matching history_channels
+-- (names or `*` globs) are kept in Redis Streams for GET /api/channels/:name/history and rewind.
+-- Run with: psql $DATABASE_URL -f migrations/008_channel_history.sql
+
+ALTER TABLE domains ADD COLUMN history_channels TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE domains ADD COLUMN history_max_events INTEGER NOT NULL DEFAULT 100;
+ALTER TABLE domains ADD COLUMN history_max_age_secs BIGINT;
+
+COMMENT ON COLUMN domains.history_channels IS 'Channel names or glob patterns whose events are kept in history';
+COMMENT ON COLUMN domains.history_max_events IS 'History retention: max events per channel';
+COMMENT ON COLUMN domains.history_max_age_secs IS 'History retention: max event age in seconds (NULL = no age limit)';
@@ -0,0 +1,7 @@
+-- Presence last seen per domain: when a user's last socket leaves a presence channel the time
+-- is kept in Redis for GET /api/users/:id/presence and GET /api/channels/:name/users.
+-- Run with: psql $DATABASE_URL -f migrations/009_presence_last_seen.sql
+
+ALTER TABLE domains ADD COLUMN presence_last_seen_secs BIGINT NOT NULL DEFAULT 604800;
+
+COMMENT ON COLUMN domains.presence_last_seen_secs IS 'Last-seen retention in seconds (0 = not tracked)';
@@ -0,0 +1,9 @@
+-- Presence limits per domain: members per presence channel (further subscribes get
+-- pusher:error 4100) and the size of channel_data / user_info.
+-- Run with: psql $DATABASE_URL -f migrations/010_presence_limits.sql
+
+ALTER TABLE domains ADD COLUMN presence_max_members INTEGER NOT NULL DEFAULT 1000;
+ALTER TABLE domains ADD COLUMN presence_max_channel_data_bytes INTEGER NOT NULL DEFAULT 4096;
+
+COMMENT ON COLUMN domains.presence_max_members IS 'Max distinct users per presence channel';
+COMMENT ON COLUMN domains.presence_max_channel_data_bytes IS 'Max serialized channel_data / user_info size in bytes';
@@ -19,6 +19,26 @@ pub struct Config {
     pub jwt_secret: String,
     /// Log level: `error`, `warn`, `info`, `debug`, `trace`.
     pub log_level: String,
+    /// Timeout for authorizer webhook calls, in milliseconds.
+    pub authorizer_timeout_ms: u64,
+    /// How long positive authorizer decisions are cached, in seconds.
+    pub authorizer_cache_secs: u64,
+    /// Allow authorizer URLs on loopback, link-local and private addresses.
+    pub authorizer_allow_private: bool,
+    /// How long the last event of a cache channel is kept, in seconds.
+    pub cache_channel_ttl_secs: u64,
+    /// How long a dropped connection can be resumed, in seconds.
+    pub resume_grace_secs: u64,
+    /// Events kept per channel for replay on resume.
+    pub recovery_buffer_size: u64,
+    /// How long a client has to ack a reliable event before it is redelivered, in seconds.
+    pub reliable_ack_timeout_secs: u64,
+    /// How long unacked reliable events and their delivery status are kept, in seconds.
+    pub reliable_retention_secs: u64,
+    /// How long idempotency keys of the publish API are remembered, in seconds.
+    pub idempotency_window_secs: u64,
+    /// MQTT listener bind address; no MQTT listener when unset.
+    pub mqtt_addr: Option<SocketAddr>,
 }
 
 impl Config {
@@ -40,6 +60,21 @@ impl Config {
         let jwt_secret = std::env::var("JWT_SECRET")
             .unwrap_or_else(|_| "notif_jwt_secret_change_in_production_32chars".to_string());
         let log_level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
+        let authorizer_timeout_ms = env_u64("AUTHORIZER_TIMEOUT_MS", 3000)?;
+        let authorizer_cache_secs = env_u64("AUTHORIZER_CACHE_SECS", 60)?;
+        let authorizer_allow_private = env_bool("AUTHORIZER_ALLOW_PRIVATE", false)?;
+        let cache_channel_ttl_secs = env_u64("CACHE_CHANNEL_TTL_SECS", 1800)?;
+        let resume_grace_secs = env_u64("RESUME_GRACE_SECS", 120)?;
+        let recovery_buffer_size = env_u64("RECOVERY_BUFFER_SIZE", 100)?;
+        let reliable_ack_timeout_secs = env_u64("RELIABLE_ACK_TIMEOUT_SECS", 30)?;
+        let reliable_retention_secs = env_u64("RELIABLE_RETENTION_SECS", 86400)?;
+        let idempotency_window_secs = env_u64("IDEMPOTENCY_WINDOW_SECS", 86400)?;
+        let mqtt_addr = match std::env::var("MQTT_ADDR") {
+            Ok(v) if !v.trim().is_empty() => {
+                Some(v.trim().parse().map_err(|_| ConfigLoadError::InvalidMqttAddr)?)
+            }
+            _ => None,
+        };
 
         Ok(Self {
             server_addr,
@@ -49,12 +84,48 @@ impl Config {
             app_key,
             jwt_secret,
             log_level,
+            authorizer_timeout_ms,
+            authorizer_cache_secs,
+            authorizer_allow_private,
+            cache_channel_ttl_secs,
+            resume_grace_secs,
+            recovery_buffer_size,
+            reliable_ack_timeout_secs,
+            reliable_retention_secs,
+            idempotency_window_secs,
+            mqtt_addr,
         })
     }
 }
 
+/// Read an unsigned integer variable, falling back to `default` when unset.
+fn env_u64(name: &'static str, default: u64) -> Result<u64, ConfigLoadError> {
+    match std::env::var(name) {
+        Ok(v) => v.trim().parse().map_err(|_| ConfigLoadError::InvalidNumber(name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Read a boolean variable (`true`/`false`/`1`/`0`), falling back to `default` when unset.
+fn env_bool(name: &'static str, default: bool) -> Result<bool, ConfigLoadError> {
+    match std::env::var(name) {
+        Ok(v) => match v.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err(ConfigLoadError::InvalidBool(name)),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigLoadError {
     #[error("Invalid SERVER_ADDR")]
     InvalidServerAddr,
+    #[error("Invalid MQTT_ADDR")]
+    InvalidMqttAddr,
+    #[error("Invalid number in {0}")]
+    InvalidNumber(&'static str),
+    #[error("Invalid boolean in {0} (use true or false)")]
+    InvalidBool(&'static str),
 }
@@ -5,12 +5,22 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::db::{
-    channels_list_by_user, domain_create, domain_delete, domain_set_active, domains_list_by_user,
-    user_get_by_id, ws_connections_active_by_user, ws_status_aggregate_by_user,
+    channels_list_by_user, domain_create, domain_delete, domain_find_by_id, domain_origin_add,
+    domain_origin_delete, domain_origins_list, domain_public_key_add, domain_public_key_delete,
+    domain_public_keys_list, domain_regenerate_key, domain_regenerate_secret, domain_set_active,
+    domain_set_authorizer_url, domain_set_dev_mode, domain_set_history, domain_set_last_seen_retention, domain_set_presence_limits, domains_list_by_user, user_get_by_id, ws_connections_active_by_user,
+    ws_status_aggregate_by_user, DomainOriginRow, DomainPublicKeyRow, DomainRow,
 };
 use crate::error::AppError;
 use crate::handlers::http::AppState;
 use crate::middleware::auth::AuthUser;
+use crate::services::api_key::{
+    api_key_prefix, generate_api_key, generate_domain_secret, hash_api_key, mask_api_key,
+};
+use crate::services::auth::{parse_public_key_algorithm, public_key_decoding_key};
+use crate::services::history::MAX_HISTORY_EVENTS;
+use crate::services::origin::AllowedOrigin;
+use crate::services::presence::{MAX_CHANNEL_DATA_BYTES, MAX_LAST_SEEN_RETENTION_SECS, MAX_PRESENCE_MAX_MEMBERS};
 
 // ---- User ----
 
@@ -44,12 +54,48 @@ pub async fn get_user(
 pub struct DomainResponse {
     pub id: String,
     pub domain_name: String,
-    pub key: String,
+    /// Full API key: only present right after create/regenerate, never in listings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub key_masked: String,
+    /// Domain secret for signing connection tokens: only present right after create/regenerate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
     pub is_active: bool,
+    pub dev_mode: bool,
+    pub authorizer_url: Option<String>,
+    pub history_channels: Vec<String>,
+    pub history_max_events: i32,
+    pub history_max_age_secs: Option<i64>,
+    pub presence_last_seen_secs: i64,
+    pub presence_max_members: i32,
+    pub presence_max_channel_data_bytes: i32,
     pub created_at: String,
 }
 
-/// GET /dashboard/domains
+impl DomainResponse {
+    fn from_row(r: DomainRow, key: Option<String>, show_secret: bool) -> Self {
+        Self {
+            id: r.id.to_string(),
+            key_masked: mask_api_key(&r.key_prefix),
+            secret: show_secret.then_some(r.secret),
+            domain_name: r.domain_name,
+            key,
+            is_active: r.is_active,
+            dev_mode: r.dev_mode,
+            authorizer_url: r.authorizer_url,
+            history_channels: r.history_channels,
+            history_max_events: r.history_max_events,
+            history_max_age_secs: r.history_max_age_secs,
+            presence_last_seen_secs: r.presence_last_seen_secs,
+            presence_max_members: r.presence_max_members,
+            presence_max_channel_data_bytes: r.presence_max_channel_data_bytes,
+            created_at: r.created_at.to_rfc3339(),
+        }
+    }
+}
+
+/// GET /dashboard/domains — keys are masked.
 pub async fn list_domains(
     State(state): State<AppState>,
     AuthUser(user_id): AuthUser,
@@ -57,13 +103,7 @@ pub async fn list_domains(
     let rows = domains_list_by_user(state.db(), user_id).await?;
     Ok(Json(
         rows.into_iter()
-            .map(|r| DomainResponse {
-                id: r.id.to_string(),
-                domain_name: r.domain_name,
-                key: r.key,
-                is_active: r.is_active,
-                created_at: r.created_at.to_rfc3339(),
-            })
+            .map(|r| DomainResponse::from_row(r, None, false))
             .collect(),
     ))
 }
@@ -73,7 +113,8 @@ pub struct CreateDomainRequest {
     pub domain_name: String,
 }
 
-/// POST /dashboard/domains — create domain + generate API key (1 domain = 1 key)
+/// POST /dashboard/domains — create domain + generate API key (1 domain = 1 key) and secret.
+/// The full key and the secret are returned only in this response.
 pub async fn create_domain(
     State(state): State<AppState>,
     AuthUser(user_id): AuthUser,
@@ -83,30 +124,159 @@ pub async fn create_domain(
     if domain_name.is_empty() {
         return Err(AppError::Validation("domain_name required".to_string()));
     }
-    let key = format!("nk_{}", Uuid::new_v4().simple());
-    let row = domain_create(state.db(), user_id, &domain_name, &key).await?;
-    Ok(Json(DomainResponse {
-        id: row.id.to_string(),
-        domain_name: row.domain_name,
-        key: row.key,
-        is_active: row.is_active,
-        created_at: row.created_at.to_rfc3339(),
-    }))
+    AllowedOrigin::parse(&domain_name)?;
+    let key = generate_api_key();
+    let prefix = api_key_prefix(&key).unwrap_or_default();
+    let secret = generate_domain_secret();
+    let row = domain_create(
+        state.db(),
+        user_id,
+        &domain_name,
+        prefix,
+        &hash_api_key(&key),
+        &secret,
+    )
+    .await?;
+    Ok(Json(DomainResponse::from_row(row, Some(key), true)))
+}
+
+/// POST /dashboard/domains/:id/regenerate-key — issue a new key; the old one stops working.
+/// The full key is returned only in this response.
+pub async fn regenerate_domain_key(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<Json<DomainResponse>, AppError> {
+    let key = generate_api_key();
+    let prefix = api_key_prefix(&key).unwrap_or_default();
+    let row = domain_regenerate_key(state.db(), id, user_id, prefix, &hash_api_key(&key)).await?;
+    Ok(Json(DomainResponse::from_row(row, Some(key), false)))
+}
+
+/// POST /dashboard/domains/:id/regenerate-secret — issue a new domain secret; tokens signed
+/// with the old one stop verifying. The secret is returned only in this response.
+pub async fn regenerate_domain_secret(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<Json<DomainResponse>, AppError> {
+    let row = domain_regenerate_secret(state.db(), id, user_id, &generate_domain_secret()).await?;
+    Ok(Json(DomainResponse::from_row(row, None, true)))
 }
 
 #[derive(Debug, Deserialize)]
-pub struct SetDomainActiveRequest {
-    pub is_active: bool,
+pub struct UpdateDomainRequest {
+    #[serde(default)]
+    pub is_active: Option<bool>,
+    /// Allow localhost origins (any port) for development.
+    #[serde(default)]
+    pub dev_mode: Option<bool>,
+    /// Authorizer webhook URL; empty string clears it.
+    #[serde(default)]
+    pub authorizer_url: Option<String>,
+    /// Channel names or `*` patterns that keep history; empty list disables history.
+    #[serde(default)]
+    pub history_channels: Option<Vec<String>>,
+    /// History retention: max events per channel.
+    #[serde(default)]
+    pub history_max_events: Option<i32>,
+    /// History retention: max age in seconds; 0 removes the age limit.
+    #[serde(default)]
+    pub history_max_age_secs: Option<i64>,
+    /// Presence last-seen retention in seconds; 0 stops tracking.
+    #[serde(default)]
+    pub presence_last_seen_secs: Option<i64>,
+    /// Max distinct users per presence channel.
+    #[serde(default)]
+    pub presence_max_members: Option<i32>,
+    /// Max serialized size of `channel_data` / presence `user_info`.
+    #[serde(default)]
+    pub presence_max_channel_data_bytes: Option<i32>,
 }
 
-/// PATCH /dashboard/domains/:id
+/// PATCH /dashboard/domains/:id — update `is_active`, `dev_mode`, `authorizer_url`, history
+/// settings, last-seen retention and/or presence limits.
 pub async fn set_domain_active(
     State(state): State<AppState>,
     AuthUser(user_id): AuthUser,
     axum::extract::Path(id): axum::extract::Path<Uuid>,
-    Json(body): Json<SetDomainActiveRequest>,
+    Json(body): Json<UpdateDomainRequest>,
 ) -> Result<Json<serde_json::Value>, AppError> {
-    domain_set_active(state.db(), id, user_id, body.is_active).await?;
+    if let Some(is_active) = body.is_active {
+        domain_set_active(state.db(), id, user_id, is_active).await?;
+    }
+    if let Some(dev_mode) = body.dev_mode {
+        domain_set_dev_mode(state.db(), id, user_id, dev_mode).await?;
+    }
+    if let Some(url) = body.authorizer_url {
+        let url = url.trim();
+        if url.is_empty() {
+            domain_set_authorizer_url(state.db(), id, user_id, None).await?;
+        } else {
+            state.authorizer_service().validate_url(url)?;
+            domain_set_authorizer_url(state.db(), id, user_id, Some(url)).await?;
+        }
+    }
+    if body.history_channels.is_some() || body.history_max_events.is_some() || body.history_max_age_secs.is_some() {
+        let current = domain_find_by_id(state.db(), id, user_id)
+            .await?
+            .ok_or_else(|| AppError::Auth("Domain not found".to_string()))?;
+        let channels: Vec<String> = match body.history_channels {
+            Some(list) => list
+                .into_iter()
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect(),
+            None => current.history_channels,
+        };
+        let max_events = body.history_max_events.unwrap_or(current.history_max_events);
+        if !(1..=MAX_HISTORY_EVENTS).contains(&max_events) {
+            return Err(AppError::Validation(format!(
+                "history_max_events must be between 1 and {}",
+                MAX_HISTORY_EVENTS
+            )));
+        }
+        let max_age = match body.history_max_age_secs {
+            Some(s) if s < 0 => {
+                return Err(AppError::Validation("history_max_age_secs must not be negative".to_string()))
+            }
+            Some(0) => None,
+            Some(s) => Some(s),
+            None => current.history_max_age_secs,
+        };
+        domain_set_history(state.db(), id, user_id, &channels, max_events, max_age).await?;
+    }
+    if let Some(secs) = body.presence_last_seen_secs {
+        if !(0..=MAX_LAST_SEEN_RETENTION_SECS).contains(&secs) {
+            return Err(AppError::Validation(format!(
+                "presence_last_seen_secs must be between 0 and {}",
+                MAX_LAST_SEEN_RETENTION_SECS
+            )));
+        }
+        domain_set_last_seen_retention(state.db(), id, user_id, secs).await?;
+    }
+    if body.presence_max_members.is_some() || body.presence_max_channel_data_bytes.is_some() {
+        let current = domain_find_by_id(state.db(), id, user_id)
+            .await?
+            .ok_or_else(|| AppError::Auth("Domain not found".to_string()))?;
+        let max_members = body.presence_max_members.unwrap_or(current.presence_max_members);
+        if !(1..=MAX_PRESENCE_MAX_MEMBERS).contains(&max_members) {
+            return Err(AppError::Validation(format!(
+                "presence_max_members must be between 1 and {}",
+                MAX_PRESENCE_MAX_MEMBERS
+            )));
+        }
+        let max_bytes = body
+            .presence_max_channel_data_bytes
+            .unwrap_or(current.presence_max_channel_data_bytes);
+        if !(1..=MAX_CHANNEL_DATA_BYTES).contains(&max_bytes) {
+            return Err(AppError::Validation(format!(
+                "presence_max_channel_data_bytes must be between 1 and {}",
+                MAX_CHANNEL_DATA_BYTES
+            )));
+        }
+        domain_set_presence_limits(state.db(), id, user_id, max_members, max_bytes).await?;
+    }
     Ok(Json(serde_json::json!({ "ok": true })))
 }
 
@@ -120,6 +290,147 @@ pub async fn delete_domain(
     Ok(Json(serde_json::json!({ "ok": true })))
 }
 
+// ---- Domain origins (origin policy) ----
+
+#[derive(Debug, Serialize)]
+pub struct DomainOriginResponse {
+    pub id: String,
+    pub origin: String,
+    pub created_at: String,
+}
+
+impl From<DomainOriginRow> for DomainOriginResponse {
+    fn from(r: DomainOriginRow) -> Self {
+        Self {
+            id: r.id.to_string(),
+            origin: r.origin,
+            created_at: r.created_at.to_rfc3339(),
+        }
+    }
+}
+
+/// GET /dashboard/domains/:id/origins — extra allowed origins (domain_name is always allowed).
+pub async fn list_domain_origins(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<Json<Vec<DomainOriginResponse>>, AppError> {
+    domain_find_by_id(state.db(), id, user_id)
+        .await?
+        .ok_or_else(|| AppError::Auth("Domain not found".to_string()))?;
+    let rows = domain_origins_list(state.db(), id).await?;
+    Ok(Json(rows.into_iter().map(DomainOriginResponse::from).collect()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddDomainOriginRequest {
+    /// `[scheme://]host[:port]`, host may be `*.example.com`, port may be `*`.
+    pub origin: String,
+}
+
+/// POST /dashboard/domains/:id/origins
+pub async fn add_domain_origin(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    Json(body): Json<AddDomainOriginRequest>,
+) -> Result<Json<DomainOriginResponse>, AppError> {
+    let origin = body.origin.trim().to_lowercase();
+    AllowedOrigin::parse(&origin)?;
+    let row = domain_origin_add(state.db(), id, user_id, &origin).await?;
+    Ok(Json(row.into()))
+}
+
+/// DELETE /dashboard/domains/:id/origins/:origin_id
+pub async fn delete_domain_origin(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path((id, origin_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    domain_origin_delete(state.db(), origin_id, id, user_id).await?;
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+// ---- Domain public keys (channel-auth JWT) ----
+
+#[derive(Debug, Serialize)]
+pub struct DomainPublicKeyResponse {
+    pub id: String,
+    pub kid: String,
+    pub algorithm: String,
+    pub public_key_pem: String,
+    pub created_at: String,
+}
+
+impl From<DomainPublicKeyRow> for DomainPublicKeyResponse {
+    fn from(r: DomainPublicKeyRow) -> Self {
+        Self {
+            id: r.id.to_string(),
+            kid: r.kid,
+            algorithm: r.algorithm,
+            public_key_pem: r.public_key_pem,
+            created_at: r.created_at.to_rfc3339(),
+        }
+    }
+}
+
+/// GET /dashboard/domains/:id/public-keys
+pub async fn list_domain_public_keys(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<Json<Vec<DomainPublicKeyResponse>>, AppError> {
+    domain_find_by_id(state.db(), id, user_id)
+        .await?
+        .ok_or_else(|| AppError::Auth("Domain not found".to_string()))?;
+    let rows = domain_public_keys_list(state.db(), id).await?;
+    Ok(Json(rows.into_iter().map(DomainPublicKeyResponse::from).collect()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddDomainPublicKeyRequest {
+    /// Key id; channel-auth JWTs select the key with their `kid` header.
+    pub kid: String,
+    /// `RS256` or `EdDSA`.
+    pub algorithm: String,
+    pub public_key_pem: String,
+}
+
+/// POST /dashboard/domains/:id/public-keys
+pub async fn add_domain_public_key(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    Json(body): Json<AddDomainPublicKeyRequest>,
+) -> Result<Json<DomainPublicKeyResponse>, AppError> {
+    let kid = body.kid.trim();
+    if kid.is_empty() {
+        return Err(AppError::Validation("kid required".to_string()));
+    }
+    let algorithm = parse_public_key_algorithm(&body.algorithm)?;
+    public_key_decoding_key(algorithm, &body.public_key_pem)?;
+    let row = domain_public_key_add(
+        state.db(),
+        id,
+        user_id,
+        kid,
+        &body.algorithm,
+        body.public_key_pem.trim(),
+    )
+    .await?;
+    Ok(Json(row.into()))
+}
+
+/// DELETE /dashboard/domains/:id/public-keys/:key_id
+pub async fn delete_domain_public_key(
+    State(state): State<AppState>,
+    AuthUser(user_id): AuthUser,
+    axum::extract::Path((id, key_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    domain_public_key_delete(state.db(), key_id, id, user_id).await?;
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
 // ---- Channels ----
 
 #[derive(Debug, Serialize)]
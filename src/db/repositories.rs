@@ -1,6 +1,7 @@
 //! Repositories: users, domains (1 domain = 1 key), channels, ws_connections.
 
 use crate::error::{AppError, AppResult};
+use crate::services::api_key::{api_key_prefix, hash_api_key};
 use chrono::{DateTime, Utc};
 use sqlx::FromRow;
 use uuid::Uuid;
@@ -61,59 +62,152 @@ pub async fn user_get_by_id(pool: &DbPool, id: Uuid) -> AppResult<Option<UserRow
 
 // ---- Domains (1 domain = 1 API key) ----
 
+/// Domain row. The API key itself is never stored: only its lookup prefix and SHA-256 hash.
+/// `secret` signs connection tokens and must stay server-readable.
 #[derive(Debug, FromRow)]
 pub struct DomainRow {
     pub id: Uuid,
     pub user_id: Uuid,
     pub domain_name: String,
-    pub key: String,
+    pub key_prefix: String,
+    pub key_hash: String,
+    pub secret: String,
     pub created_at: DateTime<Utc>,
     pub is_active: bool,
+    pub dev_mode: bool,
+    pub authorizer_url: Option<String>,
+    pub history_channels: Vec<String>,
+    pub history_max_events: i32,
+    pub history_max_age_secs: Option<i64>,
+    pub presence_last_seen_secs: i64,
+    pub presence_max_members: i32,
+    pub presence_max_channel_data_bytes: i32,
 }
 
+const DOMAIN_COLUMNS: &str = "id, user_id, domain_name, key_prefix, key_hash, secret, created_at, is_active, \
+     dev_mode, authorizer_url, history_channels, history_max_events, history_max_age_secs, \
+     presence_last_seen_secs, presence_max_members, presence_max_channel_data_bytes";
+
 pub async fn domain_create(
     pool: &DbPool,
     user_id: Uuid,
     domain_name: &str,
-    key: &str,
+    key_prefix: &str,
+    key_hash: &str,
+    secret: &str,
 ) -> AppResult<DomainRow> {
     let domain_name = domain_name.trim().to_lowercase();
-    let row = sqlx::query_as::<_, DomainRow>(
+    let row = sqlx::query_as::<_, DomainRow>(&format!(
         r#"
-        INSERT INTO domains (user_id, domain_name, key)
-        VALUES ($1, $2, $3)
+        INSERT INTO domains (user_id, domain_name, key_prefix, key_hash, secret)
+        VALUES ($1, $2, $3, $4, $5)
         ON CONFLICT (user_id, domain_name) DO NOTHING
-        RETURNING id, user_id, domain_name, key, created_at, is_active
+        RETURNING {}
         "#,
-    )
+        DOMAIN_COLUMNS
+    ))
     .bind(user_id)
     .bind(&domain_name)
-    .bind(key)
+    .bind(key_prefix)
+    .bind(key_hash)
+    .bind(secret)
     .fetch_optional(pool)
     .await?;
     row.ok_or_else(|| AppError::Validation("Domain already exists for this user".to_string()))
 }
 
 pub async fn domains_list_by_user(pool: &DbPool, user_id: Uuid) -> AppResult<Vec<DomainRow>> {
-    let rows = sqlx::query_as::<_, DomainRow>(
-        "SELECT id, user_id, domain_name, key, created_at, is_active FROM domains WHERE user_id = $1 ORDER BY created_at DESC",
-    )
+    let rows = sqlx::query_as::<_, DomainRow>(&format!(
+        "SELECT {} FROM domains WHERE user_id = $1 ORDER BY created_at DESC",
+        DOMAIN_COLUMNS
+    ))
     .bind(user_id)
     .fetch_all(pool)
     .await?;
     Ok(rows)
 }
 
-pub async fn domain_find_by_key(pool: &DbPool, key: &str) -> AppResult<Option<DomainRow>> {
-    let row = sqlx::query_as::<_, DomainRow>(
-        "SELECT id, user_id, domain_name, key, created_at, is_active FROM domains WHERE key = $1 AND is_active = true",
-    )
-    .bind(key)
+/// Domain owned by `user_id` (any status).
+pub async fn domain_find_by_id(pool: &DbPool, id: Uuid, user_id: Uuid) -> AppResult<Option<DomainRow>> {
+    let row = sqlx::query_as::<_, DomainRow>(&format!(
+        "SELECT {} FROM domains WHERE id = $1 AND user_id = $2",
+        DOMAIN_COLUMNS
+    ))
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row)
+}
+
+/// Active domain by id (e.g. from a connection token's `kid`).
+pub async fn domain_find_active_by_id(pool: &DbPool, id: Uuid) -> AppResult<Option<DomainRow>> {
+    let row = sqlx::query_as::<_, DomainRow>(&format!(
+        "SELECT {} FROM domains WHERE id = $1 AND is_active = true",
+        DOMAIN_COLUMNS
+    ))
+    .bind(id)
     .fetch_optional(pool)
     .await?;
     Ok(row)
 }
 
+/// Find an active domain by its full API key: look up by prefix, then compare hashes.
+pub async fn domain_find_by_key(pool: &DbPool, key: &str) -> AppResult<Option<DomainRow>> {
+    let Some(prefix) = api_key_prefix(key) else {
+        return Ok(None);
+    };
+    let key_hash = hash_api_key(key);
+    let rows = sqlx::query_as::<_, DomainRow>(&format!(
+        "SELECT {} FROM domains WHERE key_prefix = $1 AND is_active = true",
+        DOMAIN_COLUMNS
+    ))
+    .bind(prefix)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().find(|r| r.key_hash == key_hash))
+}
+
+/// Replace the domain's API key. The old key stops working immediately.
+pub async fn domain_regenerate_key(
+    pool: &DbPool,
+    id: Uuid,
+    user_id: Uuid,
+    key_prefix: &str,
+    key_hash: &str,
+) -> AppResult<DomainRow> {
+    let row = sqlx::query_as::<_, DomainRow>(&format!(
+        "UPDATE domains SET key_prefix = $1, key_hash = $2 WHERE id = $3 AND user_id = $4 RETURNING {}",
+        DOMAIN_COLUMNS
+    ))
+    .bind(key_prefix)
+    .bind(key_hash)
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    row.ok_or_else(|| AppError::Auth("Domain not found".to_string()))
+}
+
+/// Replace the domain secret. Tokens signed with the old secret stop verifying immediately.
+pub async fn domain_regenerate_secret(
+    pool: &DbPool,
+    id: Uuid,
+    user_id: Uuid,
+    secret: &str,
+) -> AppResult<DomainRow> {
+    let row = sqlx::query_as::<_, DomainRow>(&format!(
+        "UPDATE domains SET secret = $1 WHERE id = $2 AND user_id = $3 RETURNING {}",
+        DOMAIN_COLUMNS
+    ))
+    .bind(secret)
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    row.ok_or_else(|| AppError::Auth("Domain not found".to_string()))
+}
+
 pub async fn domain_set_active(
     pool: &DbPool,
     id: Uuid,
@@ -132,6 +226,110 @@ pub async fn domain_set_active(
     Ok(())
 }
 
+pub async fn domain_set_dev_mode(
+    pool: &DbPool,
+    id: Uuid,
+    user_id: Uuid,
+    dev_mode: bool,
+) -> AppResult<()> {
+    let r = sqlx::query("UPDATE domains SET dev_mode = $1 WHERE id = $2 AND user_id = $3")
+        .bind(dev_mode)
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    if r.rows_affected() == 0 {
+        return Err(AppError::Auth("Domain not found".to_string()));
+    }
+    Ok(())
+}
+
+/// Set or clear (`None`) the domain's authorizer webhook URL.
+pub async fn domain_set_authorizer_url(
+    pool: &DbPool,
+    id: Uuid,
+    user_id: Uuid,
+    authorizer_url: Option<&str>,
+) -> AppResult<()> {
+    let r = sqlx::query("UPDATE domains SET authorizer_url = $1 WHERE id = $2 AND user_id = $3")
+        .bind(authorizer_url)
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    if r.rows_affected() == 0 {
+        return Err(AppError::Auth("Domain not found".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn domain_set_history(
+    pool: &DbPool,
+    id: Uuid,
+    user_id: Uuid,
+    channels: &[String],
+    max_events: i32,
+    max_age_secs: Option<i64>,
+) -> AppResult<()> {
+    let r = sqlx::query(
+        r#"
+        UPDATE domains
+        SET history_channels = $1, history_max_events = $2, history_max_age_secs = $3
+        WHERE id = $4 AND user_id = $5
+        "#,
+    )
+    .bind(channels)
+    .bind(max_events)
+    .bind(max_age_secs)
+    .bind(id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(AppError::Auth("Domain not found".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn domain_set_last_seen_retention(pool: &DbPool, id: Uuid, user_id: Uuid, secs: i64) -> AppResult<()> {
+    let r = sqlx::query("UPDATE domains SET presence_last_seen_secs = $1 WHERE id = $2 AND user_id = $3")
+        .bind(secs)
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    if r.rows_affected() == 0 {
+        return Err(AppError::Auth("Domain not found".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn domain_set_presence_limits(
+    pool: &DbPool,
+    id: Uuid,
+    user_id: Uuid,
+    max_members: i32,
+    max_channel_data_bytes: i32,
+) -> AppResult<()> {
+    let r = sqlx::query(
+        r#"
+        UPDATE domains
+        SET presence_max_members = $1, presence_max_channel_data_bytes = $2
+        WHERE id = $3 AND user_id = $4
+        "#,
+    )
+    .bind(max_members)
+    .bind(max_channel_data_bytes)
+    .bind(id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(AppError::Auth("Domain not found".to_string()));
+    }
+    Ok(())
+}
+
 pub async fn domain_delete(pool: &DbPool, id: Uuid, user_id: Uuid) -> AppResult<()> {
     let r = sqlx::query("DELETE FROM domains WHERE id = $1 AND user_id = $2")
         .bind(id)
@@ -144,6 +342,150 @@ pub async fn domain_delete(pool: &DbPool, id: Uuid, user_id: Uuid) -> AppResult<
     Ok(())
 }
 
+// ---- Domain origins (origin policy) ----
+
+#[derive(Debug, FromRow)]
+pub struct DomainOriginRow {
+    pub id: Uuid,
+    pub domain_id: Uuid,
+    pub origin: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Allowed origin patterns of a domain (in addition to `domain_name`).
+pub async fn domain_origins_list(pool: &DbPool, domain_id: Uuid) -> AppResult<Vec<DomainOriginRow>> {
+    let rows = sqlx::query_as::<_, DomainOriginRow>(
+        "SELECT id, domain_id, origin, created_at FROM domain_origins WHERE domain_id = $1 ORDER BY created_at",
+    )
+    .bind(domain_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Add an allowed origin to a domain owned by `user_id`.
+pub async fn domain_origin_add(
+    pool: &DbPool,
+    domain_id: Uuid,
+    user_id: Uuid,
+    origin: &str,
+) -> AppResult<DomainOriginRow> {
+    let row = sqlx::query_as::<_, DomainOriginRow>(
+        r#"
+        INSERT INTO domain_origins (domain_id, origin)
+        SELECT id, $2 FROM domains WHERE id = $1 AND user_id = $3
+        ON CONFLICT (domain_id, origin) DO NOTHING
+        RETURNING id, domain_id, origin, created_at
+        "#,
+    )
+    .bind(domain_id)
+    .bind(origin)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    row.ok_or_else(|| AppError::Validation("Domain not found or origin already exists".to_string()))
+}
+
+pub async fn domain_origin_delete(
+    pool: &DbPool,
+    id: Uuid,
+    domain_id: Uuid,
+    user_id: Uuid,
+) -> AppResult<()> {
+    let r = sqlx::query(
+        r#"
+        DELETE FROM domain_origins o
+        USING domains d
+        WHERE o.id = $1 AND o.domain_id = $2 AND d.id = o.domain_id AND d.user_id = $3
+        "#,
+    )
+    .bind(id)
+    .bind(domain_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(AppError::Auth("Origin not found".to_string()));
+    }
+    Ok(())
+}
+
+// ---- Domain public keys (channel-auth JWT) ----
+
+#[derive(Debug, FromRow)]
+pub struct DomainPublicKeyRow {
+    pub id: Uuid,
+    pub domain_id: Uuid,
+    pub kid: String,
+    pub algorithm: String,
+    pub public_key_pem: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub async fn domain_public_keys_list(
+    pool: &DbPool,
+    domain_id: Uuid,
+) -> AppResult<Vec<DomainPublicKeyRow>> {
+    let rows = sqlx::query_as::<_, DomainPublicKeyRow>(
+        "SELECT id, domain_id, kid, algorithm, public_key_pem, created_at FROM domain_public_keys WHERE domain_id = $1 ORDER BY created_at",
+    )
+    .bind(domain_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Register a public key on a domain owned by `user_id`.
+pub async fn domain_public_key_add(
+    pool: &DbPool,
+    domain_id: Uuid,
+    user_id: Uuid,
+    kid: &str,
+    algorithm: &str,
+    public_key_pem: &str,
+) -> AppResult<DomainPublicKeyRow> {
+    let row = sqlx::query_as::<_, DomainPublicKeyRow>(
+        r#"
+        INSERT INTO domain_public_keys (domain_id, kid, algorithm, public_key_pem)
+        SELECT id, $2, $3, $4 FROM domains WHERE id = $1 AND user_id = $5
+        ON CONFLICT (domain_id, kid) DO NOTHING
+        RETURNING id, domain_id, kid, algorithm, public_key_pem, created_at
+        "#,
+    )
+    .bind(domain_id)
+    .bind(kid)
+    .bind(algorithm)
+    .bind(public_key_pem)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    row.ok_or_else(|| AppError::Validation("Domain not found or kid already exists".to_string()))
+}
+
+pub async fn domain_public_key_delete(
+    pool: &DbPool,
+    id: Uuid,
+    domain_id: Uuid,
+    user_id: Uuid,
+) -> AppResult<()> {
+    let r = sqlx::query(
+        r#"
+        DELETE FROM domain_public_keys k
+        USING domains d
+        WHERE k.id = $1 AND k.domain_id = $2 AND d.id = k.domain_id AND d.user_id = $3
+        "#,
+    )
+    .bind(id)
+    .bind(domain_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    if r.rows_affected() == 0 {
+        return Err(AppError::Auth("Public key not found".to_string()));
+    }
+    Ok(())
+}
+
 // ---- Channels ----
 
 #[derive(Debug, FromRow)]
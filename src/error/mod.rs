@@ -29,6 +29,12 @@ pub enum AppError {
     #[error("Invalid channel name: {0}")]
     InvalidChannel(String),
 
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("Authentication failed: {0}")]
     Auth(String),
 
@@ -57,6 +63,8 @@ impl IntoResponse for AppError {
             ),
             AppError::Validation(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
             AppError::InvalidChannel(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
+            AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
+            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg.clone()),
             AppError::Auth(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
             AppError::Jwt(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
             AppError::Internal(e) => (
@@ -0,0 +1,116 @@
+//! Server-driven control of open connections, routed to whichever node holds them.
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use serde_json::json;
+use tracing::info;
+
+use crate::error::AppError;
+use crate::handlers::http::{authenticate_app_key, AppState};
+use crate::models::channel::is_reserved_channel;
+use crate::models::event::{SocketSubscribeRequest, SocketUnsubscribeRequest};
+use crate::services::control::{ControlCommand, MAX_CONTROL_CHANNELS};
+
+/// POST /api/users/:id/terminate_connections — close every connection signed in as the
+/// user in the key's domain. Requires x-app-key.
+pub async fn terminate_user_connections(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain_id = authenticate_app_key(&state, &headers).await?.map(|d| d.id);
+    let sockets = state.user_service().sockets(domain_id, &user_id).await?;
+    let connections = sockets.len();
+    state
+        .control_service()
+        .send(domain_id, sockets, ControlCommand::Terminate)
+        .await?;
+    info!(user_id = %user_id, connections, "user connections terminated");
+    Ok(Json(json!({ "ok": true, "user_id": user_id, "connections": connections })))
+}
+
+/// POST /api/sockets/:socket_id/terminate — close one connection of the key's domain.
+/// Unknown sockets are ignored. Requires x-app-key.
+pub async fn terminate_socket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(socket_id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain_id = authenticate_app_key(&state, &headers).await?.map(|d| d.id);
+    state
+        .control_service()
+        .send(domain_id, vec![socket_id.clone()], ControlCommand::Terminate)
+        .await?;
+    info!(socket_id = %socket_id, "socket terminated");
+    Ok(Json(json!({ "ok": true, "socket_id": socket_id })))
+}
+
+/// POST /api/sockets/:socket_id/subscribe — subscribe a connected socket to channels without
+/// a client round-trip; the socket gets the usual `subscription_succeeded`. Private and
+/// presence channels need no client auth. Requires x-app-key.
+pub async fn subscribe_socket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(socket_id): Path<String>,
+    Json(body): Json<SocketSubscribeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain_id = authenticate_app_key(&state, &headers).await?.map(|d| d.id);
+    validate_channels(&body.channels)?;
+    let user_id = match body.channel_data.as_ref().and_then(|d| d.get("user_id")) {
+        None => None,
+        Some(serde_json::Value::String(id)) if !id.is_empty() => Some(id.clone()),
+        Some(_) => {
+            return Err(AppError::Validation(
+                "channel_data.user_id must be a non-empty string".to_string(),
+            ))
+        }
+    };
+    let command = ControlCommand::Subscribe {
+        channels: body.channels.clone(),
+        user_id,
+        channel_data: body.channel_data,
+    };
+    state
+        .control_service()
+        .send(domain_id, vec![socket_id.clone()], command)
+        .await?;
+    info!(socket_id = %socket_id, channels = body.channels.len(), "server-driven subscribe");
+    Ok(Json(json!({ "ok": true, "socket_id": socket_id, "channels": body.channels })))
+}
+
+/// POST /api/sockets/:socket_id/unsubscribe — remove a connected socket from channels; the
+/// socket gets `pusher_internal:unsubscribed`. Requires x-app-key.
+pub async fn unsubscribe_socket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(socket_id): Path<String>,
+    Json(body): Json<SocketUnsubscribeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain_id = authenticate_app_key(&state, &headers).await?.map(|d| d.id);
+    validate_channels(&body.channels)?;
+    let command = ControlCommand::Unsubscribe {
+        channels: body.channels.clone(),
+    };
+    state
+        .control_service()
+        .send(domain_id, vec![socket_id.clone()], command)
+        .await?;
+    info!(socket_id = %socket_id, channels = body.channels.len(), "server-driven unsubscribe");
+    Ok(Json(json!({ "ok": true, "socket_id": socket_id, "channels": body.channels })))
+}
+
+fn validate_channels(channels: &[String]) -> Result<(), AppError> {
+    if channels.is_empty() || channels.len() > MAX_CONTROL_CHANNELS {
+        return Err(AppError::Validation(format!(
+            "channels must list 1-{} channels",
+            MAX_CONTROL_CHANNELS
+        )));
+    }
+    if let Some(channel) = channels.iter().find(|c| c.is_empty() || is_reserved_channel(c)) {
+        return Err(AppError::InvalidChannel(format!("Invalid channel name: {:?}", channel)));
+    }
+    Ok(())
+}
@@ -1,17 +1,30 @@
-//! HTTP handlers: broadcast trigger and health.
+//! HTTP handlers: broadcast trigger, channel history, delivery status, metrics and health.
 
 use axum::{
-    extract::State,
+    extract::{Path, Query, State},
     http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
+use serde::Deserialize;
 use serde_json::json;
 
 use crate::auth::JwtSecret;
-use crate::db::DbPool;
+use crate::db::{DbPool, DomainRow};
 use crate::error::AppError;
 use crate::models::event::BroadcastRequest;
-use crate::services::{AuthService, ChannelService, PresenceService};
+use crate::services::channel::{resolve_expiry, PublishOptions};
+use crate::services::history::{HistoryPolicy, DEFAULT_HISTORY_PAGE, MAX_HISTORY_PAGE};
+use crate::models::channel::{is_reserved_channel, ChannelType};
+use crate::services::encryption::validate_encrypted_payload;
+use crate::services::idempotency::{request_fingerprint, validate_idempotency_key, IdempotencyClaim};
+use crate::services::schedule::ScheduledBroadcast;
+use crate::services::{
+    AuthService, AuthorizerService, ChannelService, ControlService, DeliveryService, IdempotencyService,
+    Metrics, ModerationService, PollingService, PresenceService, ScheduleService, UserService,
+};
+use std::sync::Arc;
+use tracing::warn;
 
 /// Shared application state for HTTP/WS and dashboard.
 #[derive(Clone)]
@@ -21,6 +34,15 @@ pub struct AppState {
     pub channel_service: ChannelService,
     pub auth_service: AuthService,
     pub presence_service: PresenceService,
+    pub authorizer_service: AuthorizerService,
+    pub delivery_service: DeliveryService,
+    pub idempotency_service: IdempotencyService,
+    pub schedule_service: ScheduleService,
+    pub user_service: UserService,
+    pub control_service: ControlService,
+    pub moderation_service: ModerationService,
+    pub polling_service: PollingService,
+    pub metrics: Arc<Metrics>,
     pub db: DbPool,
     pub jwt_secret: JwtSecret,
 }
@@ -38,55 +60,263 @@ impl AppState {
     pub fn presence_service(&self) -> &PresenceService {
         &self.presence_service
     }
+    pub fn authorizer_service(&self) -> &AuthorizerService {
+        &self.authorizer_service
+    }
+    pub fn delivery_service(&self) -> &DeliveryService {
+        &self.delivery_service
+    }
+    pub fn idempotency_service(&self) -> &IdempotencyService {
+        &self.idempotency_service
+    }
+    pub fn schedule_service(&self) -> &ScheduleService {
+        &self.schedule_service
+    }
+    pub fn user_service(&self) -> &UserService {
+        &self.user_service
+    }
+    pub fn control_service(&self) -> &ControlService {
+        &self.control_service
+    }
+    pub fn moderation_service(&self) -> &ModerationService {
+        &self.moderation_service
+    }
+    pub fn polling_service(&self) -> &PollingService {
+        &self.polling_service
+    }
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
 }
 
 const HEADER_APP_KEY: &str = "x-app-key";
+const HEADER_IDEMPOTENCY_KEY: &str = "idempotency-key";
+const HEADER_IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
 
 /// POST /api/broadcast — trigger a push notification to a channel.
 /// Requires header: x-app-key: <app_key> (legacy config key or API key from dashboard).
+/// With an `Idempotency-Key` header (or `idempotency_key` field), a repeated request within
+/// the window returns the original response instead of publishing again.
 pub async fn broadcast(
     State(state): State<AppState>,
     headers: HeaderMap,
-    Json(body): Json<BroadcastRequest>,
-) -> Result<Json<serde_json::Value>, AppError> {
-    let key = headers
-        .get(HEADER_APP_KEY)
+    Json(mut body): Json<BroadcastRequest>,
+) -> Result<Response, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+
+    let idempotency_key = headers
+        .get(HEADER_IDEMPOTENCY_KEY)
         .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    validate_api_key(state.db(), &state.app_key, key).await?;
+        .map(String::from)
+        .or(body.idempotency_key.take());
+    body.idempotency_key = None;
+    let Some(idempotency_key) = idempotency_key else {
+        return Ok(Json(publish_broadcast(&state, domain.as_ref(), body).await?).into_response());
+    };
+    validate_idempotency_key(&idempotency_key)?;
+    let domain_id = domain.as_ref().map(|d| d.id);
+    let fingerprint = request_fingerprint(&body)?;
+    let idempotency = state.idempotency_service();
+    let claim = match idempotency.begin(domain_id, &idempotency_key, &fingerprint).await? {
+        IdempotencyClaim::New(claim) => claim,
+        IdempotencyClaim::Replay(response) => {
+            return Ok(([(HEADER_IDEMPOTENT_REPLAYED, "true")], Json(response)).into_response());
+        }
+    };
 
-    let count = state
-        .channel_service
-        .broadcast(&body.channel, &body.event, body.data)
-        .await?;
+    match publish_broadcast(&state, domain.as_ref(), body).await {
+        Ok(response) => {
+            // Published already: a failure to remember it must not make the client retry.
+            if let Err(e) = idempotency
+                .complete(domain_id, &idempotency_key, &fingerprint, &response)
+                .await
+            {
+                warn!(error = %e, "storing idempotent response failed");
+            }
+            Ok(Json(response).into_response())
+        }
+        Err(e) => {
+            let _ = idempotency.release(domain_id, &idempotency_key, &claim).await;
+            Err(e)
+        }
+    }
+}
 
-    Ok(Json(json!({
+/// Publish a broadcast request and build its response. A future `deliver_at` queues it instead.
+pub(crate) async fn publish_broadcast(
+    state: &AppState,
+    domain: Option<&DomainRow>,
+    body: BroadcastRequest,
+) -> Result<serde_json::Value, AppError> {
+    if is_reserved_channel(&body.channel) {
+        return Err(AppError::InvalidChannel(format!("Channel name is reserved: {}", body.channel)));
+    }
+    let now = chrono::Utc::now();
+    let expires_at = resolve_expiry(body.expires_at, body.ttl, body.deliver_at, now)?;
+    if let Some(deliver_at) = body.deliver_at.filter(|at| *at > now) {
+        if ChannelType::from_name(&body.channel).is_encrypted() {
+            validate_encrypted_payload(&body.data)?;
+        }
+        let schedule = ScheduledBroadcast {
+            expires_at,
+            ..ScheduledBroadcast::new(
+                domain.map(|d| d.id),
+                body.channel,
+                body.event,
+                body.data,
+                body.reliable,
+                None,
+                deliver_at,
+            )
+        };
+        state.schedule_service().create(&schedule).await?;
+        return Ok(json!({
+            "ok": true,
+            "scheduled": true,
+            "schedule_id": schedule.id,
+            "channel": schedule.channel,
+            "event": schedule.event,
+            "deliver_at": schedule.next_run_at,
+            "expires_at": schedule.expires_at
+        }));
+    }
+
+    let options = PublishOptions {
+        history: domain.and_then(|d| HistoryPolicy::for_channel(d, &body.channel)),
+        reliable: body.reliable,
+        expires_at: expires_at.map(|at| at.timestamp_millis()),
+    };
+
+    let published = if body.reliable {
+        // Pending deliveries are recorded before anyone can receive the event, so a failure
+        // here leaves nothing published for the client's retry to duplicate.
+        let event = state
+            .channel_service
+            .prepare(&body.channel, &body.event, body.data, &options)
+            .await?;
+        state.delivery_service().open(&event, domain.map(|d| d.id)).await?;
+        match state.channel_service.publish_prepared(event.clone(), &options).await {
+            Ok(published) => published,
+            Err(e) => {
+                if let Err(cancel_error) = state.delivery_service().cancel(&event).await {
+                    warn!(event_id = %event.id, error = %cancel_error, "cancelling reliable delivery failed");
+                }
+                return Err(e);
+            }
+        }
+    } else {
+        state
+            .channel_service
+            .broadcast_with_options(&body.channel, &body.event, body.data, &options)
+            .await?
+    };
+
+    Ok(json!({
         "ok": true,
         "channel": body.channel,
         "event": body.event,
-        "subscriber_count": count
+        "id": published.id,
+        "seq": published.seq,
+        "published_at": published.published_at,
+        "history_id": published.history_id,
+        "subscriber_count": published.subscriber_count
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// GET /api/channels/:name/history — stored events, newest first, paginated by cursor.
+/// Requires a domain's x-app-key and reads that domain's history of the channel.
+pub async fn channel_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    let domain = domain
+        .filter(|d| HistoryPolicy::for_channel(d, &channel).is_some())
+        .ok_or_else(|| AppError::Validation("history is not enabled for this channel".to_string()))?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_PAGE).clamp(1, MAX_HISTORY_PAGE);
+    let events = state
+        .channel_service
+        .history(domain.id, &channel, query.cursor.as_deref(), limit)
+        .await?;
+    let next_cursor = if events.len() >= limit {
+        events.last().and_then(|e| e.history_id.clone())
+    } else {
+        None
+    };
+
+    Ok(Json(json!({
+        "channel": channel,
+        "events": events,
+        "next_cursor": next_cursor
     })))
 }
 
+/// GET /api/events/:id/delivery — who received and acked a reliable event.
+/// Requires x-app-key; domain keys only see their own events.
+pub async fn event_delivery(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(event_id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    let status = state
+        .delivery_service()
+        .status(&event_id)
+        .await?
+        .filter(|s| domain.as_ref().is_none_or(|d| s.meta.domain_id == Some(d.id)))
+        .ok_or_else(|| AppError::NotFound("Unknown reliable event or status expired".to_string()))?;
+    Ok(Json(serde_json::to_value(status)?))
+}
+
+/// Domain of the request's `x-app-key` header (`None` for the legacy key).
+pub(crate) async fn authenticate_app_key(state: &AppState, headers: &HeaderMap) -> Result<Option<DomainRow>, AppError> {
+    let key = headers
+        .get(HEADER_APP_KEY)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    validate_api_key(state.db(), &state.app_key, key).await
+}
+
 /// Validates API key: either legacy app_key or active key from domains table (1 domain = 1 key).
+/// Returns the domain for dashboard keys, `None` for the legacy key.
 async fn validate_api_key(
     pool: &crate::db::DbPool,
     legacy_app_key: &str,
     key: &str,
-) -> Result<(), AppError> {
+) -> Result<Option<DomainRow>, AppError> {
     if key.is_empty() {
         return Err(AppError::Auth("invalid or missing x-app-key".to_string()));
     }
     if key == legacy_app_key {
-        return Ok(());
+        return Ok(None);
     }
     let row = crate::db::domain_find_by_key(pool, key).await?;
     match row {
-        Some(r) if r.is_active => Ok(()),
+        Some(r) if r.is_active => Ok(Some(r)),
         _ => Err(AppError::Auth("invalid or inactive x-app-key".to_string())),
     }
 }
 
+/// GET /metrics — process counters in the Prometheus text format.
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics().render(),
+    )
+}
+
 /// GET /health — liveness probe.
 pub async fn health() -> (StatusCode, Json<serde_json::Value>) {
     (
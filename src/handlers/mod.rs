@@ -1,7 +1,21 @@
 //! HTTP and WebSocket request handlers.
 
+pub mod connection;
 pub mod http;
+pub mod moderation;
+pub mod polling;
+pub mod presence;
+pub mod schedule;
+pub mod sse;
+pub mod user;
 pub mod ws;
 
+pub use connection::*;
 pub use http::*;
+pub use moderation::*;
+pub use polling::*;
+pub use presence::*;
+pub use schedule::*;
+pub use sse::*;
+pub use user::*;
 pub use ws::*;
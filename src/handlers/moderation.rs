@@ -0,0 +1,119 @@
+//! Channel moderation: kick users from private/presence channels, bans per domain.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use crate::error::AppError;
+use crate::handlers::http::{authenticate_app_key, AppState};
+use crate::models::channel::{is_reserved_channel, ChannelType};
+use crate::models::event::KickRequest;
+use crate::services::control::ControlCommand;
+
+/// POST /api/channels/:name/kick — remove a user's sockets of the key's domain from a private
+/// or presence channel (on any node) and tell remaining members with
+/// `pusher_internal:member_removed` once no socket of that user id is left.
+/// With `ban`, the user cannot subscribe again until it ends. Requires x-app-key.
+pub async fn kick_member(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel): Path<String>,
+    Json(body): Json<KickRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain_id = authenticate_app_key(&state, &headers).await?.map(|d| d.id);
+    if is_reserved_channel(&channel) || !ChannelType::from_name(&channel).is_private() {
+        return Err(AppError::InvalidChannel(
+            "Only private and presence channels support kick".to_string(),
+        ));
+    }
+    if body.user_id.is_empty() {
+        return Err(AppError::Validation("user_id is required".to_string()));
+    }
+
+    // Ban first so a kicked socket cannot race back in.
+    let ban = match &body.ban {
+        Some(request) => Some(
+            state
+                .moderation_service()
+                .ban(domain_id, &channel, &body.user_id, request)
+                .await?,
+        ),
+        None => None,
+    };
+    state
+        .control_service()
+        .send_to_domain(
+            domain_id,
+            ControlCommand::Kick {
+                channel: channel.clone(),
+                user_id: body.user_id.clone(),
+            },
+        )
+        .await?;
+
+    let mut removed = 0;
+    if ChannelType::from_name(&channel) == ChannelType::Presence {
+        let (sockets, user_left) = state
+            .presence_service()
+            .remove_user(&channel, domain_id, &body.user_id)
+            .await?;
+        removed = sockets.len();
+        if removed > 0 && user_left {
+            state
+                .channel_service
+                .publish_internal(
+                    &channel,
+                    "pusher_internal:member_removed",
+                    json!({ "user_id": body.user_id }),
+                )
+                .await?;
+        }
+    }
+    info!(channel = %channel, user_id = %body.user_id, banned = ban.is_some(), "member kicked");
+    Ok(Json(json!({
+        "ok": true,
+        "channel": channel,
+        "user_id": body.user_id,
+        "presence_sockets_removed": removed,
+        "ban": ban
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BansQuery {
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+/// GET /api/bans?channel= — active bans of the key's domain, newest first.
+pub async fn list_bans(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<BansQuery>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain_id = authenticate_app_key(&state, &headers).await?.map(|d| d.id);
+    let bans = state
+        .moderation_service()
+        .list(domain_id, query.channel.as_deref())
+        .await?;
+    Ok(Json(json!({ "bans": bans })))
+}
+
+/// DELETE /api/channels/:name/bans/:user_id — lift a ban; 404 if there is none.
+pub async fn unban_member(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((channel, user_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain_id = authenticate_app_key(&state, &headers).await?.map(|d| d.id);
+    if !state.moderation_service().unban(domain_id, &channel, &user_id).await? {
+        return Err(AppError::NotFound("Ban not found".to_string()));
+    }
+    info!(channel = %channel, user_id = %user_id, "ban lifted");
+    Ok(Json(json!({ "ok": true })))
+}
@@ -0,0 +1,204 @@
+//! HTTP long-polling transport: the WebSocket protocol (subscribe, unsubscribe, ping, signin,
+//! client events, ...) over plain requests, for clients that can use neither WebSockets nor
+//! Server-Sent Events. Frames are the same JSON as on `/ws`.
+//!
+//! `POST /poll` opens a session, `GET /poll/:session_id` waits for frames, `POST` on it sends
+//! frames and `DELETE` closes it. The session id is the credential of the last three.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::error::AppError;
+use crate::handlers::http::AppState;
+use crate::handlers::ws::{authenticate_connection, run_session, ConnectionContext, SessionEnd};
+use crate::models::presence::generate_socket_id;
+use crate::services::control::CLOSE_TERMINATED;
+use crate::services::polling::{
+    PollInbound, DEFAULT_POLL_WAIT_SECS, MAX_POLL_SEND_FRAMES, MAX_POLL_WAIT_SECS, POLL_SESSION_TIMEOUT_SECS,
+};
+
+/// How often the owner of a session checks that it is still polled and refreshes its
+/// heartbeat (well within `POLL_OWNER_TIMEOUT_SECS`).
+const POLL_LIVENESS_CHECK: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+pub struct PollQuery {
+    /// Seconds to wait for frames; 0 returns right away.
+    #[serde(default)]
+    pub wait: Option<u64>,
+    /// `ack` of the previous response: its frames arrived and can be dropped.
+    #[serde(default)]
+    pub ack: Option<u64>,
+}
+
+/// POST /poll — open a long-polling session. Authenticates like `/ws` (connection token, or
+/// API key + Origin). The first poll returns `connection_established`.
+pub async fn create_poll_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let (domain, grant) = authenticate_connection(&state, &headers, &params).await?;
+    let ctx = ConnectionContext::new(&state, domain, grant).await?;
+    let (session_id, inbox) = state.polling_service().create().await?;
+    let socket_id = generate_socket_id();
+    info!(socket_id = %socket_id, "poll session opened");
+    tokio::spawn(run_poll_session(state, ctx, session_id.clone(), socket_id.clone(), inbox));
+    Ok(Json(json!({
+        "session_id": session_id,
+        "socket_id": socket_id,
+        "timeout_secs": POLL_SESSION_TIMEOUT_SECS
+    })))
+}
+
+/// GET /poll/:session_id?wait=&ack= — frames for the client, waiting up to `wait` seconds
+/// (default 25) for the first. Frames stay queued until a poll sends the `ack` of the
+/// response that returned them. Keeps the session alive; 404 once it is closed or timed out.
+pub async fn poll_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<PollQuery>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let wait = query.wait.unwrap_or(DEFAULT_POLL_WAIT_SECS).min(MAX_POLL_WAIT_SECS);
+    let polled = state
+        .polling_service()
+        .poll(&session_id, query.ack, Duration::from_secs(wait))
+        .await?
+        .ok_or_else(session_not_found)?;
+    let messages: Vec<serde_json::Value> = polled
+        .frames
+        .into_iter()
+        .map(|frame| serde_json::from_str(&frame).unwrap_or(serde_json::Value::String(frame)))
+        .collect();
+    Ok(Json(json!({ "messages": messages, "ack": polled.ack })))
+}
+
+/// POST /poll/:session_id — send one frame or an array of frames, handled in order as on
+/// `/ws`. Replies come with the next poll.
+pub async fn send_poll_frames(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let frames = match body {
+        serde_json::Value::Array(frames) => frames,
+        frame => vec![frame],
+    };
+    if frames.is_empty() || frames.len() > MAX_POLL_SEND_FRAMES {
+        return Err(AppError::Validation(format!(
+            "send 1-{} frames at a time",
+            MAX_POLL_SEND_FRAMES
+        )));
+    }
+    if frames.iter().any(|f| !f.is_object()) {
+        return Err(AppError::Validation("frames must be JSON objects".to_string()));
+    }
+    for frame in frames {
+        let inbound = PollInbound::Frame {
+            frame: frame.to_string(),
+        };
+        if !state.polling_service().send(&session_id, inbound).await? {
+            return Err(session_not_found());
+        }
+    }
+    Ok(Json(json!({ "ok": true })))
+}
+
+/// DELETE /poll/:session_id — close the session, like closing a WebSocket: it stays
+/// resumable for the grace window.
+pub async fn close_poll_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    if !state.polling_service().send(&session_id, PollInbound::Close).await? {
+        return Err(session_not_found());
+    }
+    Ok(Json(json!({ "ok": true })))
+}
+
+fn session_not_found() -> AppError {
+    AppError::NotFound("Unknown or expired poll session".to_string())
+}
+
+/// Drive a session on this node until the client closes it, stops polling or the server
+/// terminates it. Outgoing frames are queued for polls on any node.
+async fn run_poll_session(
+    state: AppState,
+    ctx: ConnectionContext,
+    session_id: String,
+    socket_id: String,
+    inbox: mpsc::UnboundedReceiver<PollInbound>,
+) {
+    let polling = state.polling_service().clone();
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let mut outbox = {
+        let polling = polling.clone();
+        let session_id = session_id.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                let mut batch = vec![frame];
+                while let Ok(frame) = rx.try_recv() {
+                    batch.push(frame);
+                }
+                if let Err(e) = polling.push(&session_id, &batch).await {
+                    warn!(error = %e, "queueing poll frames failed");
+                }
+            }
+        })
+    };
+
+    let liveness = tokio::time::interval(POLL_LIVENESS_CHECK);
+    let frames = futures::stream::unfold(
+        (inbox, liveness, polling.clone(), session_id.clone()),
+        |(mut inbox, mut liveness, polling, session_id)| async move {
+            loop {
+                tokio::select! {
+                    inbound = inbox.recv() => match inbound {
+                        Some(PollInbound::Frame { frame }) => {
+                            return Some((frame, (inbox, liveness, polling, session_id)))
+                        }
+                        Some(PollInbound::Close) | None => return None,
+                    },
+                    _ = liveness.tick() => {
+                        if !polling.keep_alive(&session_id).await.unwrap_or(true) {
+                            info!("poll session timed out");
+                            return None;
+                        }
+                    }
+                }
+            }
+        },
+    );
+    let (socket_id, end) = run_session(state, ctx, socket_id, tx.clone(), frames).await;
+    if end == SessionEnd::Terminated {
+        let _ = tx.send(
+            json!({
+                "event": "pusher:error",
+                "data": { "message": "Connection terminated by server", "code": CLOSE_TERMINATED }
+            })
+            .to_string(),
+        );
+    }
+    drop(tx);
+    // Give queued frames a moment to reach Redis; tasks of the ended session may still hold
+    // a sender until they are cancelled.
+    let _ = tokio::time::timeout(Duration::from_secs(1), &mut outbox).await;
+    outbox.abort();
+    let released = match end {
+        SessionEnd::Terminated => polling.release_after_frames(&session_id).await,
+        SessionEnd::Closed => polling.release(&session_id).await,
+    };
+    if let Err(e) = released {
+        warn!(error = %e, "releasing poll session failed");
+    }
+    info!(socket_id = %socket_id, "poll session closed");
+}
@@ -0,0 +1,102 @@
+//! Presence queries: members of a channel and when users were last seen.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::error::AppError;
+use crate::handlers::http::{authenticate_app_key, AppState};
+use crate::models::channel::{is_reserved_channel, ChannelType};
+use crate::services::presence::{last_seen_policy, DEFAULT_MEMBERS_PAGE, MAX_MEMBERS_PAGE};
+use crate::services::user::MAX_USER_ID_LEN;
+
+#[derive(Debug, Deserialize)]
+pub struct UsersQuery {
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page.
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// GET /api/channels/:name/users — members of a presence channel by user id, paginated by
+/// cursor; the first page also lists users who left within the domain's last-seen retention
+/// (most recent first). Requires x-app-key.
+pub async fn channel_users(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(channel): Path<String>,
+    Query(query): Query<UsersQuery>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    if is_reserved_channel(&channel) || ChannelType::from_name(&channel) != ChannelType::Presence {
+        return Err(AppError::InvalidChannel("Only presence channels have users".to_string()));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_MEMBERS_PAGE).clamp(1, MAX_MEMBERS_PAGE);
+    let (page, next_cursor) = state
+        .presence_service()
+        .list_members_page(&channel, query.cursor.as_deref(), limit)
+        .await?;
+    let users: Vec<serde_json::Value> = page
+        .into_iter()
+        .map(|u| json!({ "id": u.user_id, "user_info": u.user_info }))
+        .collect();
+    let policy = last_seen_policy(domain.as_ref()).filter(|_| query.cursor.is_none());
+    let recently_left: Vec<serde_json::Value> = match policy {
+        Some(policy) => {
+            let seen = state
+                .presence_service()
+                .last_seen_of_channel(&policy.scope, &channel, policy.retention_secs)
+                .await?;
+            let ids: Vec<String> = seen.iter().map(|s| s.id.clone()).collect();
+            let online = state.presence_service().online_users(&channel, &ids).await?;
+            seen.into_iter()
+                .filter(|s| !online.contains(&s.id))
+                .map(|s| json!({ "id": s.id, "last_seen_at": s.at_ms }))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    Ok(Json(json!({
+        "channel": channel,
+        "users": users,
+        "next_cursor": next_cursor,
+        "recently_left": recently_left
+    })))
+}
+
+/// GET /api/users/:id/presence — when the user's last socket left each presence channel
+/// (within the retention) and whether they are back on it. Requires x-app-key.
+pub async fn user_presence(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    if user_id.is_empty() || user_id.len() > MAX_USER_ID_LEN {
+        return Err(AppError::Validation(format!("user id must be 1-{} characters", MAX_USER_ID_LEN)));
+    }
+    let Some(policy) = last_seen_policy(domain.as_ref()) else {
+        return Err(AppError::Validation("last-seen tracking is disabled for this domain".to_string()));
+    };
+    let seen = state
+        .presence_service()
+        .last_seen_of_user(&policy.scope, &user_id, policy.retention_secs)
+        .await?;
+    let ids: Vec<String> = seen.iter().map(|s| s.id.clone()).collect();
+    let online = state.presence_service().user_online_in(&ids, &user_id).await?;
+    let channels: Vec<serde_json::Value> = seen
+        .iter()
+        .zip(online)
+        .map(|(s, online)| json!({ "channel": s.id, "last_seen_at": s.at_ms, "online": online }))
+        .collect();
+    Ok(Json(json!({
+        "user_id": user_id,
+        "last_seen_at": seen.first().map(|s| s.at_ms),
+        "channels": channels
+    })))
+}
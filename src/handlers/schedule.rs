@@ -0,0 +1,146 @@
+//! Scheduled broadcasts: recurring schedule CRUD and the dispatcher that publishes due runs.
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use chrono::Utc;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::db::domain_find_active_by_id;
+use crate::error::AppError;
+use crate::handlers::http::{authenticate_app_key, publish_broadcast, AppState};
+use crate::models::channel::{is_reserved_channel, ChannelType};
+use crate::models::event::{BroadcastRequest, CreateScheduleRequest};
+use crate::services::encryption::validate_encrypted_payload;
+use crate::services::metrics::DeliveryPath;
+use crate::services::schedule::{next_cron_run, ScheduledBroadcast, DISPATCH_INTERVAL};
+
+/// POST /api/schedules — create a recurring broadcast. Requires x-app-key.
+pub async fn create_schedule(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<CreateScheduleRequest>,
+) -> Result<Json<ScheduledBroadcast>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    if is_reserved_channel(&body.channel) {
+        return Err(AppError::InvalidChannel(format!("Channel name is reserved: {}", body.channel)));
+    }
+    if ChannelType::from_name(&body.channel).is_encrypted() {
+        validate_encrypted_payload(&body.data)?;
+    }
+    let cron = body.cron.trim().to_string();
+    let first_run = next_cron_run(&cron, Utc::now())?;
+    if body.ttl == Some(0) {
+        return Err(AppError::Validation("ttl must be at least 1 second".to_string()));
+    }
+    let schedule = ScheduledBroadcast {
+        ttl: body.ttl,
+        ..ScheduledBroadcast::new(
+            domain.map(|d| d.id),
+            body.channel,
+            body.event,
+            body.data,
+            body.reliable,
+            Some(cron),
+            first_run,
+        )
+    };
+    state.schedule_service().create(&schedule).await?;
+    info!(schedule_id = %schedule.id, channel = %schedule.channel, "schedule created");
+    Ok(Json(schedule))
+}
+
+/// GET /api/schedules — pending one-off and recurring broadcasts of the key's domain.
+pub async fn list_schedules(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    let schedules = state.schedule_service().list(domain.map(|d| d.id)).await?;
+    Ok(Json(json!({ "schedules": schedules })))
+}
+
+/// DELETE /api/schedules/:id — cancel a pending broadcast.
+pub async fn cancel_schedule(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    if !state.schedule_service().cancel(domain.map(|d| d.id), &id).await? {
+        return Err(AppError::NotFound("Schedule not found".to_string()));
+    }
+    info!(schedule_id = %id, "schedule cancelled");
+    Ok(Json(json!({ "ok": true })))
+}
+
+/// Publish due schedules, forever. Runs on every node; each run is claimed by one of them.
+/// A run that could not be published stays leased and is retried once the lease ends.
+pub async fn run_schedule_dispatcher(state: AppState) {
+    let mut interval = tokio::time::interval(DISPATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let due = match state.schedule_service().claim_due(now).await {
+            Ok(due) => due,
+            Err(e) => {
+                warn!(error = %e, "claiming due schedules failed");
+                continue;
+            }
+        };
+        for schedule in due {
+            if !dispatch(&state, &schedule).await {
+                continue;
+            }
+            if let Err(e) = state.schedule_service().complete_run(&schedule, now).await {
+                warn!(schedule_id = %schedule.id, error = %e, "rescheduling failed");
+            }
+        }
+    }
+}
+
+/// Publish one run. `false` if it failed in a way worth retrying (Redis unavailable).
+async fn dispatch(state: &AppState, schedule: &ScheduledBroadcast) -> bool {
+    if schedule.expires_at.is_some_and(|at| at <= Utc::now()) {
+        state.metrics().expired_dropped(DeliveryPath::Scheduled);
+        info!(schedule_id = %schedule.id, "scheduled broadcast expired before dispatch; dropped");
+        return true;
+    }
+    let domain = match schedule.domain_id {
+        Some(id) => match domain_find_active_by_id(state.db(), id).await {
+            Ok(Some(domain)) => Some(domain),
+            Ok(None) => {
+                warn!(schedule_id = %schedule.id, "domain gone or inactive; skipping scheduled broadcast");
+                return true;
+            }
+            Err(e) => {
+                warn!(schedule_id = %schedule.id, error = %e, "domain lookup failed");
+                return false;
+            }
+        },
+        None => None,
+    };
+    let request = BroadcastRequest {
+        channel: schedule.channel.clone(),
+        event: schedule.event.clone(),
+        data: schedule.data.clone(),
+        reliable: schedule.reliable,
+        idempotency_key: None,
+        deliver_at: None,
+        expires_at: schedule.expires_at,
+        ttl: schedule.ttl,
+    };
+    match publish_broadcast(state, domain.as_ref(), request).await {
+        Ok(_) => {
+            info!(schedule_id = %schedule.id, channel = %schedule.channel, "scheduled broadcast sent");
+            true
+        }
+        Err(e) => {
+            warn!(schedule_id = %schedule.id, error = %e, "scheduled broadcast failed");
+            !matches!(e, AppError::Redis(_))
+        }
+    }
+}
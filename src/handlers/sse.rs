@@ -0,0 +1,338 @@
+//! Server-Sent Events transport: receive-only subscriptions over `text/event-stream`, for
+//! clients that cannot keep a WebSocket open. Events come from the same `ChannelService`
+//! fan-out as WebSocket subscriptions.
+//!
+//! The SSE `id` of each sequenced event is the stream position: a JSON object of channel ->
+//! last sequence number. A reconnect sends it back as `Last-Event-ID` and missed events are
+//! replayed from the recovery buffer.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::db::DomainRow;
+use crate::error::AppError;
+use crate::handlers::http::AppState;
+use crate::handlers::ws::{authenticate_connection, origin_policy, EventMeta};
+use crate::models::channel::{is_cache_channel, is_reserved_channel, ChannelType};
+use crate::services::connection_token::ConnectionClaims;
+use crate::services::metrics::{DeliveryPath, Metrics};
+
+/// Interval of `: keepalive` comments, so proxies keep an idle stream open.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Most channels one stream may subscribe to.
+const MAX_SSE_CHANNELS: usize = 100;
+
+const HEADER_LAST_EVENT_ID: &str = "last-event-id";
+
+/// Channel -> highest sequence number sent; the SSE event id.
+type Positions = BTreeMap<String, u64>;
+
+/// GET /sse?channels=a,b — stream the channels' events. Authenticates like `/ws` (connection
+/// token, or API key + Origin); private channels need a connection token that lists them.
+/// Presence channels need a WebSocket. `Last-Event-ID` (or `?last_event_id=`) resumes.
+pub async fn sse_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, AppError> {
+    let (domain, grant) = authenticate_connection(&state, &headers, &params).await?;
+    let domain_id = domain.as_ref().map(|d| d.id);
+    let cors_origin = cors_origin(&state, domain.as_ref(), &headers).await?;
+    let channels = parse_channels(params.get("channels").map(String::as_str).unwrap_or_default())?;
+    for channel in &channels {
+        authorize_channel(&state, domain_id, grant.as_ref(), channel).await?;
+    }
+    let resume: Positions = headers
+        .get(HEADER_LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .or(params.get("last_event_id").map(String::as_str))
+        .and_then(|id| serde_json::from_str(id).ok())
+        .unwrap_or_default();
+
+    // Live receivers first, so nothing published during the backfill is lost.
+    let mut receivers = Vec::with_capacity(channels.len());
+    for channel in &channels {
+        receivers.push((channel.clone(), state.channel_service.subscribe(channel).await?));
+    }
+
+    let mut stream = SseStream::new(state.metrics().clone());
+    for channel in &channels {
+        let position = match resume.get(channel) {
+            Some(&last_seq) => {
+                stream.skip_through.insert(channel.clone(), last_seq);
+                last_seq
+            }
+            None => state.channel_service.current_seq(channel).await.unwrap_or(0),
+        };
+        stream.positions.insert(channel.clone(), position);
+    }
+    let mut initial = Vec::new();
+    for channel in &channels {
+        initial.push(envelope(json!({
+            "event": "pusher_internal:subscription_succeeded",
+            "channel": channel
+        })));
+        match resume.get(channel) {
+            Some(&last_seq) => {
+                let recovery = state.channel_service.recover(channel, last_seq).await?;
+                for payload in recovery.events {
+                    initial.extend(stream.event(channel, &payload, DeliveryPath::Replay));
+                }
+                if !recovery.complete {
+                    initial.push(envelope(json!({
+                        "event": "pusher:recovery_failed",
+                        "channel": channel,
+                        "data": {
+                            "message": "Some events published while disconnected are no longer available",
+                            "last_seq": last_seq
+                        }
+                    })));
+                }
+            }
+            None if is_cache_channel(channel) => match state.channel_service.cached_event(channel).await? {
+                Some(payload) => initial.extend(stream.event(channel, &payload, DeliveryPath::Replay)),
+                None => initial.push(envelope(json!({
+                    "event": "pusher:cache_miss",
+                    "channel": channel,
+                    "data": {}
+                }))),
+            },
+            None => {}
+        }
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel::<(String, String)>();
+    let forwarders = Forwarders(
+        receivers
+            .into_iter()
+            .map(|(channel, mut channel_rx)| {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while let Ok(payload) = channel_rx.recv().await {
+                        if tx.send((channel.clone(), payload)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect(),
+    );
+    let live = futures::stream::unfold((rx, stream, forwarders), |(mut rx, mut stream, forwarders)| async move {
+        loop {
+            let (channel, payload) = rx.recv().await?;
+            if let Some(event) = stream.event(&channel, &payload, DeliveryPath::Live) {
+                return Some((event, (rx, stream, forwarders)));
+            }
+        }
+    });
+    let events = futures::stream::iter(initial)
+        .chain(live)
+        .map(Ok::<_, Infallible>);
+    info!(channels = channels.len(), resumed = !resume.is_empty(), "sse stream opened");
+
+    let mut response = Sse::new(events)
+        .keep_alive(KeepAlive::new().interval(SSE_KEEPALIVE).text("keepalive"))
+        .into_response();
+    let response_headers = response.headers_mut();
+    // Proxies (nginx) must not buffer the stream.
+    response_headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    if let Some(origin) = cors_origin {
+        response_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        response_headers.insert(header::VARY, HeaderValue::from_static("origin"));
+    }
+    Ok(response)
+}
+
+/// The request's `Origin` if the domain allows it (CORS for `EventSource` on another
+/// origin). Streams without a domain get no CORS header.
+async fn cors_origin(state: &AppState, domain: Option<&DomainRow>, headers: &HeaderMap) -> Result<Option<HeaderValue>, AppError> {
+    let (Some(domain), Some(origin)) = (domain, headers.get(header::ORIGIN)) else {
+        return Ok(None);
+    };
+    let Ok(value) = origin.to_str() else {
+        return Ok(None);
+    };
+    Ok(origin_policy(state, domain).await?.allows(value).then(|| origin.clone()))
+}
+
+/// Comma-separated channel names, deduplicated in order.
+fn parse_channels(list: &str) -> Result<Vec<String>, AppError> {
+    let mut channels: Vec<String> = Vec::new();
+    for name in list.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        if is_reserved_channel(name) {
+            return Err(AppError::InvalidChannel(format!("Invalid channel name: {:?}", name)));
+        }
+        if !channels.iter().any(|c| c == name) {
+            channels.push(name.to_string());
+        }
+    }
+    if channels.is_empty() || channels.len() > MAX_SSE_CHANNELS {
+        return Err(AppError::Validation(format!(
+            "channels must list 1-{} channels",
+            MAX_SSE_CHANNELS
+        )));
+    }
+    Ok(channels)
+}
+
+/// A token limits the stream to its channels; private channels need one. Banned token users
+/// are refused as on `/ws`.
+pub(crate) async fn authorize_channel(
+    state: &AppState,
+    domain_id: Option<Uuid>,
+    grant: Option<&ConnectionClaims>,
+    channel: &str,
+) -> Result<(), AppError> {
+    let channel_type = ChannelType::from_name(channel);
+    if channel_type == ChannelType::Presence {
+        return Err(AppError::InvalidChannel(format!(
+            "Presence channel {} needs a WebSocket connection",
+            channel
+        )));
+    }
+    match grant {
+        Some(grant) if !grant.allows_channel(channel) => {
+            return Err(AppError::Auth(format!("Channel {} not allowed by connection token", channel)));
+        }
+        None if channel_type.is_private() => {
+            return Err(AppError::Auth(format!("Private channel {} needs a connection token", channel)));
+        }
+        _ => {}
+    }
+    if let Some(user_id) = grant.and_then(|g| g.user_id.as_deref()) {
+        if state.moderation_service().ban_of(domain_id, channel, user_id).await?.is_some() {
+            debug!(channel = %channel, user_id = %user_id, "banned user refused");
+            return Err(AppError::Auth(format!("Banned from channel {}", channel)));
+        }
+    }
+    Ok(())
+}
+
+/// Per-stream delivery state.
+struct SseStream {
+    metrics: std::sync::Arc<Metrics>,
+    positions: Positions,
+    /// Highest sequence number replayed per channel: live duplicates are skipped.
+    skip_through: HashMap<String, u64>,
+}
+
+impl SseStream {
+    fn new(metrics: std::sync::Arc<Metrics>) -> Self {
+        Self {
+            metrics,
+            positions: Positions::new(),
+            skip_through: HashMap::new(),
+        }
+    }
+
+    /// The SSE event of a published payload; `None` if expired or already sent.
+    fn event(&mut self, channel: &str, payload: &str, path: DeliveryPath) -> Option<Event> {
+        let meta = EventMeta::parse(payload);
+        if meta.is_expired() {
+            self.metrics.expired_dropped(path);
+            return None;
+        }
+        let mut event = Event::default().event(&meta.event).data(payload);
+        if meta.seq > 0 {
+            let skip = self.skip_through.entry(channel.to_string()).or_default();
+            if path == DeliveryPath::Live && meta.seq <= *skip {
+                return None;
+            }
+            if path == DeliveryPath::Replay {
+                *skip = (*skip).max(meta.seq);
+            }
+            let position = self.positions.entry(channel.to_string()).or_default();
+            *position = (*position).max(meta.seq);
+            event = event.id(serde_json::to_string(&self.positions).unwrap_or_default());
+        }
+        Some(event)
+    }
+}
+
+/// A server event without sequence number (subscription_succeeded, cache_miss, ...).
+fn envelope(value: serde_json::Value) -> Event {
+    let name = value["event"].as_str().unwrap_or_default().to_string();
+    Event::default().event(name).data(value.to_string())
+}
+
+/// Channel forwarders of one stream, stopped when the client goes away.
+struct Forwarders(Vec<JoinHandle<()>>);
+
+impl Drop for Forwarders {
+    fn drop(&mut self) {
+        for forwarder in &self.0 {
+            forwarder.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_are_validated_and_deduplicated() {
+        assert_eq!(parse_channels("news, prices,news").unwrap(), vec!["news", "prices"]);
+        assert!(parse_channels("").is_err());
+        assert!(parse_channels("news,#control").is_err());
+        let many = (0..=MAX_SSE_CHANNELS).map(|i| format!("c{}", i)).collect::<Vec<_>>().join(",");
+        assert!(parse_channels(&many).is_err());
+    }
+
+    fn payload(seq: u64, expires_at: Option<i64>) -> String {
+        json!({ "id": format!("e{}", seq), "event": "update", "seq": seq, "expires_at": expires_at }).to_string()
+    }
+
+    #[test]
+    fn resume_replays_then_skips_live_duplicates() {
+        let mut stream = SseStream::new(std::sync::Arc::new(Metrics::new()));
+        // Resumed from Last-Event-ID {"news":5}.
+        stream.positions.insert("news".into(), 5);
+        stream.skip_through.insert("news".into(), 5);
+
+        assert!(stream.event("news", &payload(6, None), DeliveryPath::Replay).is_some());
+        assert!(stream.event("news", &payload(7, None), DeliveryPath::Replay).is_some());
+        assert_eq!(stream.positions["news"], 7);
+
+        assert!(stream.event("news", &payload(6, None), DeliveryPath::Live).is_none(), "already replayed");
+        assert!(stream.event("news", &payload(7, None), DeliveryPath::Live).is_none(), "already replayed");
+        assert!(stream.event("news", &payload(8, None), DeliveryPath::Live).is_some());
+        assert_eq!(stream.positions["news"], 8);
+
+        // Other channels keep their own position; unsequenced events always pass.
+        assert!(stream.event("prices", &payload(1, None), DeliveryPath::Live).is_some());
+        assert!(stream.event("news", r#"{"event":"client-x"}"#, DeliveryPath::Live).is_some());
+        assert_eq!(stream.positions, Positions::from([("news".into(), 8), ("prices".into(), 1)]));
+    }
+
+    #[test]
+    fn expired_events_are_dropped_and_counted() {
+        let metrics = std::sync::Arc::new(Metrics::new());
+        let mut stream = SseStream::new(metrics.clone());
+        let past = chrono::Utc::now().timestamp_millis() - 1000;
+        let future = chrono::Utc::now().timestamp_millis() + 60_000;
+
+        assert!(stream.event("news", &payload(1, Some(past)), DeliveryPath::Replay).is_none());
+        assert!(stream.event("news", &payload(2, Some(past)), DeliveryPath::Live).is_none());
+        assert!(stream.positions.is_empty(), "expired events do not move the position");
+        assert_eq!(metrics.expired_dropped_count(DeliveryPath::Replay), 1);
+        assert_eq!(metrics.expired_dropped_count(DeliveryPath::Live), 1);
+        assert!(stream.event("news", &payload(3, Some(future)), DeliveryPath::Live).is_some());
+    }
+}
@@ -0,0 +1,49 @@
+//! User-targeted events: delivered to every connection signed in as the user.
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use serde_json::json;
+use tracing::info;
+
+use crate::error::AppError;
+use crate::handlers::http::{authenticate_app_key, AppState};
+use crate::models::event::UserEventRequest;
+use crate::services::channel::resolve_expiry;
+use crate::services::user::MAX_USER_ID_LEN;
+
+/// POST /api/users/:id/events — send an event to all of the user's connections in the
+/// key's domain. Requires x-app-key.
+pub async fn send_user_event(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(body): Json<UserEventRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let domain = authenticate_app_key(&state, &headers).await?;
+    if user_id.is_empty() || user_id.len() > MAX_USER_ID_LEN {
+        return Err(AppError::Validation(format!("user id must be 1-{} characters", MAX_USER_ID_LEN)));
+    }
+    let expires_at = resolve_expiry(body.expires_at, body.ttl, None, chrono::Utc::now())?;
+    let sent = state
+        .user_service()
+        .send(
+            domain.map(|d| d.id),
+            &user_id,
+            &body.event,
+            body.data,
+            expires_at.map(|at| at.timestamp_millis()),
+        )
+        .await?;
+    info!(user_id = %user_id, event = %body.event, id = %sent.id, connections = sent.connections, "user event");
+    Ok(Json(json!({
+        "ok": true,
+        "user_id": user_id,
+        "event": body.event,
+        "id": sent.id,
+        "published_at": sent.published_at,
+        "connections": sent.connections
+    })))
+}
@@ -2,35 +2,78 @@
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
         Query, State,
     },
     http::HeaderMap,
     response::Response,
 };
 use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
 use serde_json::json;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-use crate::db::domain_find_by_key;
-use crate::error::AppError;
+use crate::db::{
+    domain_find_active_by_id, domain_find_by_key, domain_origins_list, domain_public_keys_list,
+    DomainRow,
+};
+use crate::error::{AppError, AppResult};
 use crate::handlers::http::AppState;
-use crate::models::channel::ChannelType;
-use crate::models::event::ClientMessage;
-use crate::models::presence::generate_socket_id;
+use crate::models::channel::{is_cache_channel, is_reserved_channel, ChannelType};
+use crate::models::event::{
+    ClientMessage, PresenceUpdatePayload, ResumePayload, RewindOptions, SigninPayload, SubscribePayload,
+};
+use crate::models::presence::{generate_socket_id, LastSeenPolicy, PresenceMember};
+use crate::services::auth::{
+    is_jwt, parse_public_key_algorithm, ChannelJwtKeys, ChannelPublicKey,
+};
+use crate::services::authorizer::{AuthorizerDenial, AuthorizerRequest};
+use crate::services::connection_token::{
+    connection_token_domain_id, verify_connection_token, ConnectionClaims,
+};
+use crate::services::channel::RewindPage;
+use crate::services::delivery::{Recipient, MAX_DELIVERY_ATTEMPTS};
+use crate::services::control::{ControlCommand, CLOSE_TERMINATED};
+use crate::services::metrics::DeliveryPath;
+use crate::services::user::{SignedInUser, WatchlistChange};
+use crate::services::origin::OriginPolicy;
+use crate::services::presence::{last_seen_policy, merge_user_info, PresenceLimits, PRESENCE_FULL_CODE};
+use crate::services::recovery::{generate_resume_token, Recovery, ResumeSession, ResumedChannel};
 
 const HEADER_APP_KEY: &str = "x-app-key";
 const HEADER_ORIGIN: &str = "origin";
+const HEADER_CONNECTION_TOKEN: &str = "x-connection-token";
 
-/// Upgrade HTTP to WebSocket. Validates API key and origin (if API key has domains) before upgrade.
+/// How often a socket checks its reliable events for ack timeouts.
+const REDELIVERY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upgrade HTTP to WebSocket. Validates, before upgrade, either a connection token
+/// (`?token=` or `x-connection-token`, no Origin needed) or the API key and the domain's origin policy.
 pub async fn ws_handler(
     State(state): State<AppState>,
     headers: HeaderMap,
     Query(params): Query<HashMap<String, String>>,
     ws: WebSocketUpgrade,
 ) -> Result<Response, AppError> {
+    let (domain, grant) = authenticate_connection(&state, &headers, &params).await?;
+    let ctx = ConnectionContext::new(&state, domain, grant).await?;
+    Ok(ws.on_upgrade(move |socket| handle_socket(state, socket, ctx)))
+}
+
+/// Authenticate a client connection (WebSocket, SSE, long polling): a connection token, else the API key
+/// plus an Origin allowed for its domain, else no domain (legacy `APP_KEY` mode).
+pub(crate) async fn authenticate_connection(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> AppResult<(Option<DomainRow>, Option<ConnectionClaims>)> {
     let api_key = params
         .get("api_key")
         .cloned()
@@ -38,245 +81,1336 @@ pub async fn ws_handler(
     let origin = headers
         .get(HEADER_ORIGIN)
         .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_lowercase());
+        .map(String::from);
+
+    let token = params.get("token").cloned().or_else(|| {
+        headers
+            .get(HEADER_CONNECTION_TOKEN)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    });
 
-    let domain_id = if let Some(key) = &api_key {
+    let (domain, grant) = if let Some(token) = &token {
+        let domain_id = connection_token_domain_id(token)?;
+        let row = domain_find_active_by_id(state.db(), domain_id)
+            .await?
+            .ok_or_else(|| AppError::Auth("Invalid or inactive domain in connection token".to_string()))?;
+        let claims = verify_connection_token(token, &row.secret)?;
+        (Some(row), Some(claims))
+    } else if let Some(key) = &api_key {
         let row = domain_find_by_key(state.db(), key).await?;
         let row = row.ok_or_else(|| AppError::Auth("Invalid or inactive API key".to_string()))?;
-        let origin_host = origin
-            .as_ref()
-            .and_then(|o| parse_origin_host(o))
+        let origin = origin
             .ok_or_else(|| AppError::Auth("Origin required and must match domain".to_string()))?;
-        if !domain_matches(&row.domain_name, &origin_host) {
-            return Err(AppError::Auth("Origin does not match domain for this key".to_string()));
+        if !origin_policy(state, &row).await?.allows(&origin) {
+            return Err(AppError::Auth("Origin not allowed for this key".to_string()));
         }
-        Some(row.id)
+        (Some(row), None)
     } else {
-        None
+        (None, None)
     };
+    Ok((domain, grant))
+}
 
-    Ok(ws.on_upgrade(move |socket| handle_socket(state, socket, domain_id)))
+/// Origins allowed for the domain's browser clients.
+pub(crate) async fn origin_policy(state: &AppState, domain: &DomainRow) -> AppResult<OriginPolicy> {
+    let origins: Vec<String> = domain_origins_list(state.db(), domain.id)
+        .await?
+        .into_iter()
+        .map(|o| o.origin)
+        .collect();
+    Ok(OriginPolicy::new(&domain.domain_name, &origins, domain.dev_mode))
 }
 
-/// Parse host from Origin header (e.g. "https://app.example.com" -> "app.example.com").
-pub(crate) fn parse_origin_host(origin: &str) -> Option<String> {
-    let u = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://"))?;
-    let host = u.split('/').next()?.to_lowercase();
-    Some(host)
+/// What the upgrade request authenticated: domain binding and auth material for subscribes.
+pub(crate) struct ConnectionContext {
+    domain_id: Option<Uuid>,
+    /// Set for token-authenticated sockets: subscriptions are limited to its channels,
+    /// which need no per-channel auth.
+    grant: Option<ConnectionClaims>,
+    jwt_keys: ChannelJwtKeys,
+    /// Domain authorizer webhook, used for private/presence subscribes without `auth`.
+    authorizer_url: Option<String>,
+    /// Last-seen tracking of presence members; `None` when the domain turned it off.
+    last_seen: Option<LastSeenPolicy>,
+    presence_limits: PresenceLimits,
 }
 
-/// Check if origin host matches allowed domain (exact or *.example.com suffix).
-pub(crate) fn domain_matches(allowed: &str, origin_host: &str) -> bool {
-    let allowed = allowed.trim().to_lowercase();
-    if allowed.starts_with('*') {
-        origin_host.ends_with(allowed.trim_start_matches('*').trim_start_matches('.'))
-    } else {
-        allowed == origin_host
+impl ConnectionContext {
+    pub(crate) async fn new(
+        state: &AppState,
+        domain: Option<DomainRow>,
+        grant: Option<ConnectionClaims>,
+    ) -> AppResult<Self> {
+        let jwt_keys = channel_jwt_keys(state, domain.as_ref()).await?;
+        Ok(Self {
+            domain_id: domain.as_ref().map(|d| d.id),
+            last_seen: last_seen_policy(domain.as_ref()),
+            presence_limits: PresenceLimits::for_domain(domain.as_ref()),
+            authorizer_url: domain.and_then(|d| d.authorizer_url),
+            grant,
+            jwt_keys,
+        })
     }
 }
 
-async fn handle_socket(state: AppState, socket: WebSocket, domain_id: Option<Uuid>) {
-    let socket_id = generate_socket_id();
-    info!(socket_id = %socket_id, "ws connected");
+/// Keys for channel-auth JWTs: the domain secret and public keys, or the app secret without a domain.
+async fn channel_jwt_keys(state: &AppState, domain: Option<&DomainRow>) -> AppResult<ChannelJwtKeys> {
+    let Some(domain) = domain else {
+        return Ok(ChannelJwtKeys {
+            hs256_secret: state.app_secret.clone(),
+            public_keys: Vec::new(),
+        });
+    };
+    let public_keys = domain_public_keys_list(state.db(), domain.id)
+        .await?
+        .into_iter()
+        .filter_map(|k| {
+            Some(ChannelPublicKey {
+                algorithm: parse_public_key_algorithm(&k.algorithm).ok()?,
+                kid: k.kid,
+                pem: k.public_key_pem,
+            })
+        })
+        .collect();
+    Ok(ChannelJwtKeys {
+        hs256_secret: domain.secret.clone(),
+        public_keys,
+    })
+}
+
+/// Commands delivered to a socket's loop by its own timers and tasks.
+enum SocketCommand {
+    /// The channel-auth token of this channel expired: unsubscribe.
+    AuthExpired(String),
+    /// A reliable event was sent on this channel: track it until acked.
+    Delivered { channel: String, id: String, payload: String },
+}
 
-    let (mut sender, mut receiver) = socket.split();
-    let mut subscribed_channels: HashSet<String> = HashSet::new();
+/// One channel subscription of a socket. Dropping aborts its tasks.
+struct ChannelSubscription {
+    authz: ChannelAuthorization,
+    /// Presence info as last stored (`authz.user_info` plus `presence_update`s).
+    member_info: Option<serde_json::Value>,
+    /// Who acks reliable events of this channel.
+    recipient: Recipient,
+    /// Highest sequence number forwarded to the socket, saved for a resume.
+    last_seq: Arc<AtomicU64>,
+    forwarder: JoinHandle<()>,
+    expiry: Option<JoinHandle<()>>,
+}
 
-    let conn_msg = json!({
-        "event": "connection_established",
-        "data": { "socket_id": socket_id }
-    });
-    if sender.send(Message::Text(conn_msg.to_string())).await.is_err() {
-        return;
+impl Drop for ChannelSubscription {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+        if let Some(expiry) = &self.expiry {
+            expiry.abort();
+        }
+    }
+}
+
+/// Result of authorizing a subscribe: presence identity and when the authorization ends.
+#[derive(Default)]
+struct ChannelAuthorization {
+    user_id: Option<String>,
+    /// `user_id` was vouched for by the server side (token, JWT, authorizer, presence HMAC),
+    /// not just claimed by the client.
+    user_verified: bool,
+    user_info: Option<serde_json::Value>,
+    expires_at: Option<i64>,
+}
+
+/// A reliable event sent to this socket and not acked yet.
+struct Unacked {
+    channel: String,
+    recipient: Recipient,
+    payload: String,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+/// How a channel is brought up to date when it is attached.
+enum Backfill {
+    /// New subscribe, with optional history rewind.
+    Fresh(Option<RewindOptions>),
+    /// Resumed subscription: replay events after this sequence number.
+    Resume(u64),
+}
+
+/// The user a socket signed in as. Dropping stops its user events.
+struct UserSession {
+    user: SignedInUser,
+    forwarder: JoinHandle<()>,
+    /// Forwards online/offline changes of the user's watchlist.
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl Drop for UserSession {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+        if let Some(watcher) = &self.watcher {
+            watcher.abort();
+        }
+    }
+}
+
+/// State of one client connection (WebSocket or long-polling session).
+struct SocketSession {
+    state: AppState,
+    socket_id: String,
+    /// Lets a reconnect take over this session; sent in `connection_established`.
+    resume_token: String,
+    ctx: ConnectionContext,
+    tx: mpsc::UnboundedSender<String>,
+    commands: mpsc::UnboundedSender<SocketCommand>,
+    channels: HashMap<String, ChannelSubscription>,
+    /// Reliable events awaiting an ack, by event id.
+    unacked: HashMap<String, Unacked>,
+    /// Set by `pusher:signin`.
+    user: Option<UserSession>,
+}
+
+impl SocketSession {
+    fn send(&self, msg: serde_json::Value) {
+        let _ = self.tx.send(msg.to_string());
+    }
+
+    /// Send a published event, tracking it if reliable; an expired one is dropped and counted
+    /// for `path`. Returns its sequence number (0 if none).
+    fn deliver(&self, channel: &str, payload: String, path: DeliveryPath) -> u64 {
+        let meta = EventMeta::parse(&payload);
+        if meta.is_expired() {
+            self.state.metrics().expired_dropped(path);
+        } else {
+            deliver_event(&self.tx, &self.commands, channel, &meta, payload);
+        }
+        meta.seq
+    }
+
+    fn send_error(&self, message: &str, code: u16) {
+        self.send(json!({
+            "event": "pusher:error",
+            "data": { "message": message, "code": code }
+        }));
     }
 
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg)).await.is_err() {
-                break;
+    async fn handle_message(&mut self, client_msg: ClientMessage) {
+        match client_msg {
+            ClientMessage::Subscribe { data } => self.subscribe(data).await,
+            ClientMessage::Unsubscribe { data } => {
+                self.unsubscribe(&data.channel).await;
+                debug!(socket_id = %self.socket_id, channel = %data.channel, "unsubscribed");
             }
+            ClientMessage::Ping => self.send(json!({ "event": "pusher:pong", "data": {} })),
+            ClientMessage::Resume { data } => self.resume(data).await,
+            ClientMessage::Ack { data } => self.ack(&data.id).await,
+            ClientMessage::Signin { data } => self.signin(data).await,
+            ClientMessage::PresenceUpdate { data } => self.presence_update(data).await,
         }
-    });
+    }
 
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            Message::Text(text) => {
-                if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                    match client_msg {
-                        ClientMessage::Subscribe { data } => {
-                            let channel = data.channel.clone();
-                            let channel_type = ChannelType::from_name(&channel);
-
-                            let auth_ok = state
-                                .auth_service()
-                                .verify_channel_auth(
-                                    &channel,
-                                    &socket_id,
-                                    data.auth.as_deref(),
-                                    data.channel_data.as_ref().map(|v: &serde_json::Value| v.to_string()).as_deref(),
-                                )
-                                .is_ok();
-
-                            if channel_type.is_private() && !auth_ok {
-                                let err = json!({
-                                    "event": "pusher:error",
-                                    "data": { "message": "Auth failed for channel", "code": 4009 }
-                                });
-                                let _ = tx.send(err.to_string());
-                                continue;
-                            }
-
-                            match state.channel_service.subscribe(&channel).await {
-                                Ok(mut channel_rx) => {
-                                    subscribed_channels.insert(channel.clone());
-
-                                    if let Some(did) = domain_id {
-                                        if let Ok(ch_row) = crate::db::channel_ensure(state.db(), &channel, did).await {
-                                            let user_str = data
-                                                .channel_data
-                                                .as_ref()
-                                                .and_then(|v: &serde_json::Value| v.get("user_id"))
-                                                .and_then(|v: &serde_json::Value| v.as_str());
-                                            let _ = crate::db::ws_connection_insert(
-                                                state.db(),
-                                                Some(ch_row.id),
-                                                &channel,
-                                                did,
-                                                &socket_id,
-                                                user_str,
-                                            )
-                                            .await;
-                                        }
-                                    }
-
-                                    if channel_type == ChannelType::Presence {
-                                        let user_id = data
-                                            .channel_data
-                                            .as_ref()
-                                            .and_then(|v: &serde_json::Value| v.get("user_id"))
-                                            .and_then(|v: &serde_json::Value| v.as_str())
-                                            .unwrap_or("anonymous");
-                                        let user_info = data.channel_data.clone();
-                                        if state
-                                            .presence_service()
-                                            .add_member(&channel, &socket_id, user_id, user_info)
-                                            .await
-                                            .is_ok()
-                                        {
-                                            let members: Vec<crate::models::PresenceUser> = state
-                                                .presence_service()
-                                                .list_members(&channel)
-                                                .await
-                                                .unwrap_or_default();
-                                            let sub_ok = json!({
-                                                "event": "pusher_internal:subscription_succeeded",
-                                                "channel": channel,
-                                                "data": json!({
-                                                    "presence": {
-                                                        "ids": members.iter().map(|u| u.user_id.clone()).collect::<Vec<_>>(),
-                                                        "hash": {},
-                                                        "count": members.len()
-                                                    }
-                                                })
-                                            });
-                                            let _ = tx.send(sub_ok.to_string());
-                                        }
-                                    } else {
-                                        let sub_ok = json!({
-                                            "event": "pusher_internal:subscription_succeeded",
-                                            "channel": channel
-                                        });
-                                        let _ = tx.send(sub_ok.to_string());
-                                    }
-
-                                    let tx_fwd = tx.clone();
-                                    tokio::spawn(async move {
-                                        while let Ok(payload) = channel_rx.recv().await {
-                                            let _ = tx_fwd.send(payload);
-                                        }
-                                    });
-                                }
-                                Err(e) => {
-                                    warn!(channel = %channel, error = %e, "subscribe failed");
-                                    let err = json!({
-                                        "event": "pusher:error",
-                                        "data": { "message": format!("Subscribe failed: {}", e), "code": 4009 }
-                                    });
-                                    let _ = tx.send(err.to_string());
-                                }
-                            }
-                        }
-                        ClientMessage::Unsubscribe { data } => {
-                            let channel = data.channel.clone();
-                            let channel_type = ChannelType::from_name(&channel);
-                            if channel_type == ChannelType::Presence {
-                                let _ = state.presence_service().remove_member(&channel, &socket_id).await;
-                            }
-                            if domain_id.is_some() {
-                                let _ = crate::db::ws_connection_mark_disconnected_by_channel(
-                                    state.db(),
-                                    &socket_id,
-                                    &channel,
-                                )
-                                .await;
-                            }
-                            subscribed_channels.remove(&channel);
-                            debug!(socket_id = %socket_id, channel = %channel, "unsubscribed");
+    /// Frames that are not a known client message. Client events (`client-*`) are not relayed;
+    /// on encrypted channels they are rejected explicitly since the server cannot vouch for them.
+    fn handle_unknown(&self, text: &str) {
+        let Ok(frame) = serde_json::from_str::<serde_json::Value>(text) else {
+            return;
+        };
+        let is_client_event = frame["event"].as_str().is_some_and(|e| e.starts_with("client-"));
+        let channel = frame["channel"].as_str().unwrap_or_default();
+        if is_client_event && ChannelType::from_name(channel).is_encrypted() {
+            self.send_error("Client events are not allowed on encrypted channels", 4009);
+        }
+    }
+
+    async fn handle_command(&mut self, command: SocketCommand) {
+        match command {
+            SocketCommand::AuthExpired(channel) => {
+                if self.channels.contains_key(&channel) {
+                    self.unsubscribe(&channel).await;
+                    self.send(json!({
+                        "event": "pusher:subscription_expired",
+                        "channel": channel,
+                        "data": { "message": "Channel authorization expired" }
+                    }));
+                    debug!(socket_id = %self.socket_id, channel = %channel, "channel auth expired");
+                }
+            }
+            SocketCommand::Delivered { channel, id, payload } => {
+                let Some(recipient) = self.channels.get(&channel).map(|sub| sub.recipient.clone()) else {
+                    return;
+                };
+                let attempts = match self
+                    .state
+                    .delivery_service()
+                    .record_delivery(&recipient, &id, &payload)
+                    .await
+                {
+                    Ok(attempts) => attempts,
+                    Err(e) => {
+                        warn!(event_id = %id, error = %e, "recording reliable delivery failed");
+                        return;
+                    }
+                };
+                self.unacked.insert(
+                    id,
+                    Unacked {
+                        channel,
+                        recipient,
+                        payload,
+                        sent_at: Instant::now(),
+                        attempts,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Subscribe on the backend's behalf (server API): no client auth. Presence channels
+    /// need a user from the request, the signin or the connection token.
+    async fn server_subscribe(
+        &mut self,
+        channels: Vec<String>,
+        user_id: Option<String>,
+        channel_data: Option<serde_json::Value>,
+    ) {
+        let user_id = user_id
+            .or_else(|| self.user.as_ref().map(|u| u.user.id.clone()))
+            .or_else(|| self.ctx.grant.as_ref().and_then(|g| g.user_id.clone()));
+        for channel in channels {
+            if self.channels.contains_key(&channel) {
+                continue;
+            }
+            if ChannelType::from_name(&channel) == ChannelType::Presence && user_id.is_none() {
+                warn!(socket_id = %self.socket_id, channel = %channel, "server subscribe to presence without user");
+                continue;
+            }
+            let authz = ChannelAuthorization {
+                user_verified: user_id.is_some(),
+                user_id: user_id.clone(),
+                user_info: channel_data.clone(),
+                expires_at: None,
+            };
+            debug!(socket_id = %self.socket_id, channel = %channel, "server-driven subscribe");
+            self.attach(channel, authz, Backfill::Fresh(None)).await;
+        }
+    }
+
+    /// Unsubscribe on the backend's behalf and tell the client.
+    async fn server_unsubscribe(&mut self, channels: Vec<String>) {
+        for channel in channels {
+            if !self.channels.contains_key(&channel) {
+                continue;
+            }
+            self.unsubscribe(&channel).await;
+            self.send(json!({
+                "event": "pusher_internal:unsubscribed",
+                "channel": channel,
+                "data": {}
+            }));
+            debug!(socket_id = %self.socket_id, channel = %channel, "server-driven unsubscribe");
+        }
+    }
+
+    /// `pusher:signin`: verify the user data with the domain secret, then receive the
+    /// user's events on this socket. A socket signs in once.
+    async fn signin(&mut self, data: SigninPayload) {
+        if self.user.is_some() {
+            return self.send_error("Already signed in", 4009);
+        }
+        let verified = self
+            .state
+            .auth_service()
+            .verify_user_auth(&self.ctx.jwt_keys.hs256_secret, &self.socket_id, &data.user_data, &data.auth)
+            .and_then(|_| SignedInUser::parse(&data.user_data));
+        let user = match verified {
+            Ok(user) => user,
+            Err(e) => {
+                debug!(socket_id = %self.socket_id, error = %e, "signin failed");
+                return self.send_error(&format!("Signin failed: {}", e), 4009);
+            }
+        };
+        let mut user_rx = match self
+            .state
+            .user_service()
+            .sign_in(self.ctx.domain_id, &user.id, &self.socket_id)
+            .await
+        {
+            Ok(rx) => rx,
+            Err(e) => {
+                warn!(socket_id = %self.socket_id, error = %e, "signin failed");
+                return self.send_error("Signin failed", 4009);
+            }
+        };
+
+        let tx = self.tx.clone();
+        let metrics = self.state.metrics().clone();
+        let forwarder = tokio::spawn(async move {
+            while let Some(payload) = user_rx.recv().await {
+                if EventMeta::parse(&payload).is_expired() {
+                    metrics.expired_dropped(DeliveryPath::Live);
+                    continue;
+                }
+                if tx.send(payload).is_err() {
+                    break;
+                }
+            }
+        });
+        self.send(json!({
+            "event": "pusher:signin_success",
+            "data": { "user_data": data.user_data }
+        }));
+        info!(socket_id = %self.socket_id, user_id = %user.id, "signed in");
+        let watcher = self.watch(&user.watchlist).await;
+        self.user = Some(UserSession { user, forwarder, watcher });
+    }
+
+    /// Send which watched users are online, then forward their online/offline changes.
+    async fn watch(&self, watchlist: &[String]) -> Option<JoinHandle<()>> {
+        if watchlist.is_empty() {
+            return None;
+        }
+        let users = self.state.user_service();
+        let mut changes = match users.watch(self.ctx.domain_id).await {
+            Ok(rx) => rx,
+            Err(e) => {
+                warn!(socket_id = %self.socket_id, error = %e, "watchlist subscribe failed");
+                return None;
+            }
+        };
+        match users.online(self.ctx.domain_id, watchlist).await {
+            Ok(online) if !online.is_empty() => self.send(watchlist_events("online", online)),
+            Ok(_) => {}
+            Err(e) => warn!(socket_id = %self.socket_id, error = %e, "watchlist state lookup failed"),
+        }
+        let watched: HashSet<String> = watchlist.iter().cloned().collect();
+        let tx = self.tx.clone();
+        Some(tokio::spawn(async move {
+            while let Ok(payload) = changes.recv().await {
+                let Some(change) = WatchlistChange::parse_watched(&payload, &watched) else {
+                    continue;
+                };
+                let event = watchlist_events(&change.name, vec![change.user_id]);
+                if tx.send(event.to_string()).is_err() {
+                    break;
+                }
+            }
+        }))
+    }
+
+    /// Settle a reliable event. Events from an earlier connection are looked up by the
+    /// recipients of the current subscriptions.
+    async fn ack(&mut self, id: &str) {
+        let recipients: Vec<Recipient> = match self.unacked.remove(id) {
+            Some(unacked) => vec![unacked.recipient],
+            None => {
+                let mut all: Vec<Recipient> = self.channels.values().map(|sub| sub.recipient.clone()).collect();
+                all.dedup();
+                all
+            }
+        };
+        for recipient in recipients {
+            match self.state.delivery_service().ack(&recipient, id).await {
+                Ok(true) => {
+                    debug!(socket_id = %self.socket_id, event_id = %id, "reliable event acked");
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => warn!(event_id = %id, error = %e, "ack failed"),
+            }
+        }
+    }
+
+    /// Resend reliable events whose ack timed out. After [`MAX_DELIVERY_ATTEMPTS`] they
+    /// stay pending for the recipient's next subscribe instead.
+    async fn redeliver_due(&mut self) {
+        let timeout = self.state.delivery_service().config().ack_timeout;
+        let due: Vec<String> = self
+            .unacked
+            .iter()
+            .filter(|(_, u)| u.sent_at.elapsed() >= timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in due {
+            let Some(unacked) = self.unacked.remove(&id) else {
+                continue;
+            };
+            if unacked.attempts >= MAX_DELIVERY_ATTEMPTS {
+                debug!(socket_id = %self.socket_id, event_id = %id, "giving up redelivery until next subscribe");
+                continue;
+            }
+            // Another device of the same user may have acked it meanwhile.
+            if !matches!(self.state.delivery_service().is_pending(&unacked.recipient, &id).await, Ok(true)) {
+                continue;
+            }
+            if EventMeta::parse(&unacked.payload).is_expired() {
+                self.drop_expired_pending(&unacked.recipient, &id).await;
+                continue;
+            }
+            self.deliver(&unacked.channel, unacked.payload, DeliveryPath::Redelivery);
+        }
+    }
+
+    /// Resend the recipient's unacked events of `channel` (from earlier connections).
+    async fn redeliver_pending(&self, channel: &str, recipient: &Recipient) {
+        let pending = match self.state.delivery_service().pending(recipient).await {
+            Ok(p) => p,
+            Err(e) => {
+                warn!(channel = %channel, error = %e, "loading pending reliable events failed");
+                return;
+            }
+        };
+        let mut events: Vec<(EventMeta, String)> = pending
+            .into_iter()
+            .map(|payload| (EventMeta::parse(&payload), payload))
+            .filter(|(meta, _)| meta.channel == channel)
+            .collect();
+        events.sort_by_key(|(meta, _)| meta.seq);
+        for (meta, payload) in events {
+            if meta.is_expired() {
+                self.drop_expired_pending(recipient, &meta.id).await;
+                continue;
+            }
+            deliver_event(&self.tx, &self.commands, channel, &meta, payload);
+        }
+    }
+
+    /// An unacked reliable event expired: stop redelivering it.
+    async fn drop_expired_pending(&self, recipient: &Recipient, id: &str) {
+        self.state.metrics().expired_dropped(DeliveryPath::Redelivery);
+        if let Err(e) = self.state.delivery_service().discard(recipient, id).await {
+            warn!(event_id = %id, error = %e, "discarding expired reliable event failed");
+        }
+    }
+
+    /// Authorize a subscribe: connection token grant, channel-auth JWT, HMAC, or the
+    /// domain's authorizer webhook when no `auth` is given.
+    async fn authorize(&self, data: &SubscribePayload) -> AppResult<ChannelAuthorization> {
+        let channel = &data.channel;
+        let channel_data_user_id = || {
+            data.channel_data
+                .as_ref()
+                .and_then(|v| v.get("user_id"))
+                .and_then(|v| v.as_str())
+                .map(String::from)
+        };
+
+        if let Some(grant) = &self.ctx.grant {
+            if !grant.allows_channel(channel) {
+                return Err(AppError::Auth("Channel not allowed by connection token".to_string()));
+            }
+            return Ok(ChannelAuthorization {
+                user_id: grant.user_id.clone().or_else(channel_data_user_id),
+                user_verified: grant.user_id.is_some(),
+                user_info: data.channel_data.clone(),
+                expires_at: None,
+            });
+        }
+
+        if !ChannelType::from_name(channel).is_private() {
+            return Ok(ChannelAuthorization {
+                user_id: channel_data_user_id(),
+                ..Default::default()
+            });
+        }
+
+        match data.auth.as_deref() {
+            Some(token) if is_jwt(token) => {
+                let claims = self.state.auth_service().verify_channel_jwt(
+                    token,
+                    channel,
+                    &self.socket_id,
+                    &self.ctx.jwt_keys,
+                )?;
+                // Stored like the channel_data of the other paths.
+                let mut channel_data = serde_json::Map::new();
+                if let Some(user_id) = &claims.user_id {
+                    channel_data.insert("user_id".to_string(), json!(user_id));
+                }
+                if let Some(user_info) = claims.user_info {
+                    channel_data.insert("user_info".to_string(), user_info);
+                }
+                let user_info = (!channel_data.is_empty()).then_some(serde_json::Value::Object(channel_data));
+                Ok(ChannelAuthorization {
+                    user_verified: claims.user_id.is_some(),
+                    user_id: claims.user_id,
+                    user_info,
+                    expires_at: Some(claims.exp),
+                })
+            }
+            None if self.ctx.authorizer_url.is_some() => {
+                let url = self.ctx.authorizer_url.as_deref().unwrap_or_default();
+                let request = AuthorizerRequest {
+                    socket_id: &self.socket_id,
+                    channel,
+                    user_token: data.user_token.as_deref(),
+                };
+                let channel_data = self
+                    .state
+                    .authorizer_service()
+                    .authorize(url, &self.ctx.jwt_keys.hs256_secret, &request)
+                    .await
+                    .map_err(|denial| match denial {
+                        AuthorizerDenial::Denied(_) => AppError::Auth("Subscription denied by authorizer".to_string()),
+                        AuthorizerDenial::Unavailable => {
+                            AppError::Auth("Authorizer unavailable".to_string())
                         }
-                        ClientMessage::Ping => {
-                            let pong = json!({ "event": "pusher:pong", "data": {} });
-                            let _ = tx.send(pong.to_string());
+                    })?;
+                let user_id = channel_data
+                    .as_ref()
+                    .and_then(|v| v.get("user_id"))
+                    .and_then(|v| v.as_str())
+                    .map(String::from);
+                if ChannelType::from_name(channel) == ChannelType::Presence && user_id.is_none() {
+                    return Err(AppError::Auth(
+                        "Authorizer response has no channel_data.user_id for presence".to_string(),
+                    ));
+                }
+                Ok(ChannelAuthorization {
+                    user_verified: user_id.is_some(),
+                    user_id,
+                    user_info: channel_data,
+                    expires_at: None,
+                })
+            }
+            auth => {
+                let channel_data = data.channel_data.as_ref().map(|v| v.to_string());
+                self.state.auth_service().verify_channel_auth(
+                    channel,
+                    &self.socket_id,
+                    auth,
+                    channel_data.as_deref(),
+                )?;
+                // Only the presence signature covers channel_data.
+                Ok(ChannelAuthorization {
+                    user_id: channel_data_user_id(),
+                    user_verified: ChannelType::from_name(channel) == ChannelType::Presence,
+                    user_info: data.channel_data.clone(),
+                    expires_at: None,
+                })
+            }
+        }
+    }
+
+    async fn subscribe(&mut self, data: SubscribePayload) {
+        let channel = data.channel.clone();
+        if is_reserved_channel(&channel) {
+            self.send_error("Channel name is reserved", 4009);
+            return;
+        }
+        // A second subscribe would re-add the presence member under another user id.
+        if self.channels.contains_key(&channel) {
+            return self.send_error(&format!("Already subscribed to {}", channel), 4009);
+        }
+        if let Some(channel_data) = &data.channel_data {
+            if let Err(e) = self.ctx.presence_limits.check_size("channel_data", channel_data) {
+                return self.send_error(&e.to_string(), 4009);
+            }
+        }
+
+        let authz = match self.authorize(&data).await {
+            Ok(authz) => authz,
+            Err(e) => {
+                let explain = self.ctx.grant.is_some()
+                    || (data.auth.is_none() && self.ctx.authorizer_url.is_some());
+                let message = match &e {
+                    AppError::Auth(msg) if explain => msg.clone(),
+                    _ => "Auth failed for channel".to_string(),
+                };
+                debug!(channel = %channel, error = %e, "subscribe auth failed");
+                self.send_error(&message, 4009);
+                return;
+            }
+        };
+
+        self.attach(channel, authz, Backfill::Fresh(data.rewind)).await;
+    }
+
+    /// Start delivering an authorized channel: ban check, live receiver, connection tracking,
+    /// presence, backfill (rewind, cache or resume replay) and auth expiry. `false` if refused.
+    async fn attach(&mut self, channel: String, authz: ChannelAuthorization, backfill: Backfill) -> bool {
+        if self.is_banned(&channel, &authz).await {
+            debug!(socket_id = %self.socket_id, channel = %channel, "banned user refused");
+            self.send_error(&format!("Banned from channel {}", channel), 4009);
+            return false;
+        }
+        if matches!(backfill, Backfill::Fresh(_)) {
+            match self.join_presence(&channel, &authz).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    debug!(socket_id = %self.socket_id, channel = %channel, "presence channel full");
+                    self.send_error(&format!("Presence channel {} is full", channel), PRESENCE_FULL_CODE);
+                    return false;
+                }
+                Err(e) => {
+                    warn!(channel = %channel, error = %e, "presence join failed");
+                    self.send_error(&format!("Subscribe failed: {}", e), 4009);
+                    return false;
+                }
+            }
+        }
+        let mut channel_rx = match self.state.channel_service.subscribe(&channel).await {
+            Ok(rx) => rx,
+            Err(e) => {
+                warn!(channel = %channel, error = %e, "subscribe failed");
+                if matches!(backfill, Backfill::Fresh(_)) {
+                    remove_presence(&self.state, &self.socket_id, std::iter::once(channel.as_str())).await;
+                }
+                self.send_error(&format!("Subscribe failed: {}", e), 4009);
+                return false;
+            }
+        };
+
+        if let Some(did) = self.ctx.domain_id {
+            if let Ok(ch_row) = crate::db::channel_ensure(self.state.db(), &channel, did).await {
+                let _ = crate::db::ws_connection_insert(
+                    self.state.db(),
+                    Some(ch_row.id),
+                    &channel,
+                    did,
+                    &self.socket_id,
+                    authz.user_id.as_deref(),
+                )
+                .await;
+            }
+        }
+
+        // `channel_rx` is live before any backfill is read, so nothing falls in between;
+        // live events the backfill already covered are skipped by sequence number below.
+        let (skip_through, last_seq) = match backfill {
+            Backfill::Fresh(rewind) => {
+                self.send_subscription_succeeded(&channel).await;
+                let current = self.state.channel_service.current_seq(&channel).await.unwrap_or(0);
+                let skip_through = self.backfill_fresh(&channel, rewind.as_ref()).await;
+                (skip_through, current)
+            }
+            Backfill::Resume(last_seq) => {
+                let replayed = self.replay_missed(&channel, last_seq).await;
+                (replayed, replayed)
+            }
+        };
+
+        let recipient = self.recipient(&authz);
+        if let Err(e) = self.state.delivery_service().enroll(&channel, &recipient).await {
+            warn!(channel = %channel, error = %e, "enrolling reliable recipient failed");
+        }
+        self.redeliver_pending(&channel, &recipient).await;
+
+        let last_seq = Arc::new(AtomicU64::new(last_seq));
+        let delivered = last_seq.clone();
+        let tx_fwd = self.tx.clone();
+        let commands_fwd = self.commands.clone();
+        let channel_fwd = channel.clone();
+        let metrics = self.state.metrics().clone();
+        let forwarder = tokio::spawn(async move {
+            while let Ok(payload) = channel_rx.recv().await {
+                let meta = EventMeta::parse(&payload);
+                if meta.seq > 0 && meta.seq <= skip_through {
+                    continue;
+                }
+                let seq = meta.seq;
+                if meta.is_expired() {
+                    metrics.expired_dropped(DeliveryPath::Live);
+                    delivered.fetch_max(seq, Ordering::Relaxed);
+                    continue;
+                }
+                if !deliver_event(&tx_fwd, &commands_fwd, &channel_fwd, &meta, payload) {
+                    break;
+                }
+                delivered.fetch_max(seq, Ordering::Relaxed);
+            }
+        });
+        let expiry = authz.expires_at.map(|exp| {
+            let commands = self.commands.clone();
+            let channel = channel.clone();
+            let secs = (exp - chrono::Utc::now().timestamp()).max(0) as u64;
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                let _ = commands.send(SocketCommand::AuthExpired(channel));
+            })
+        });
+        self.channels.insert(
+            channel,
+            ChannelSubscription {
+                member_info: authz.user_info.clone(),
+                authz,
+                recipient,
+                last_seq,
+                forwarder,
+                expiry,
+            },
+        );
+        true
+    }
+
+    /// Whether the subscription's user or the signed-in user is banned from the channel.
+    async fn is_banned(&self, channel: &str, authz: &ChannelAuthorization) -> bool {
+        let signed_in = self.user.as_ref().map(|u| u.user.id.as_str());
+        for user_id in [authz.user_id.as_deref(), signed_in].into_iter().flatten() {
+            match self
+                .state
+                .moderation_service()
+                .ban_of(self.ctx.domain_id, channel, user_id)
+                .await
+            {
+                Ok(Some(_)) => return true,
+                Ok(None) => {}
+                Err(e) => warn!(channel = %channel, error = %e, "ban lookup failed"),
+            }
+        }
+        false
+    }
+
+    /// Presence channels: add this socket as a member, atomically with the member cap.
+    /// `false` if the channel is full; other channels always join.
+    async fn join_presence(&self, channel: &str, authz: &ChannelAuthorization) -> AppResult<bool> {
+        if ChannelType::from_name(channel) != ChannelType::Presence {
+            return Ok(true);
+        }
+        let member = PresenceMember {
+            user_id: authz.user_id.clone().unwrap_or_else(|| "anonymous".to_string()),
+            user_info: authz.user_info.clone(),
+            socket_id: self.socket_id.clone(),
+            domain_id: self.ctx.domain_id,
+            last_seen: self.ctx.last_seen.clone(),
+        };
+        self.state
+            .presence_service()
+            .add_member(channel, member, self.ctx.presence_limits.max_members)
+            .await
+    }
+
+    /// Kicked through the server API: leave the channel if this socket is the user there.
+    async fn kick(&mut self, channel: String, user_id: String) {
+        let Some(sub) = self.channels.get(&channel) else {
+            return;
+        };
+        let is_user = sub.authz.user_id.as_deref() == Some(user_id.as_str())
+            || self.user.as_ref().is_some_and(|u| u.user.id == user_id);
+        if !is_user {
+            return;
+        }
+        self.unsubscribe(&channel).await;
+        self.send(json!({
+            "event": "pusher_internal:unsubscribed",
+            "channel": channel,
+            "data": { "reason": "kicked" }
+        }));
+        info!(socket_id = %self.socket_id, channel = %channel, user_id = %user_id, "kicked");
+    }
+
+    /// Change this socket's member info on a presence channel it is subscribed to. Keys set by
+    /// the subscribe auth stay as they are; other members get `pusher_internal:member_updated`.
+    async fn presence_update(&mut self, data: PresenceUpdatePayload) {
+        if ChannelType::from_name(&data.channel) != ChannelType::Presence {
+            return self.send_error("presence_update requires a presence channel", 4009);
+        }
+        let Some(sub) = self.channels.get(&data.channel) else {
+            return self.send_error(&format!("Not subscribed to {}", data.channel), 4009);
+        };
+        let user_id = sub.authz.user_id.clone().unwrap_or_else(|| "anonymous".to_string());
+        let merged = merge_user_info(
+            sub.authz.user_info.as_ref(),
+            sub.member_info.as_ref(),
+            data.user_info,
+            &self.ctx.presence_limits,
+        );
+        let user_info = match merged {
+            Ok(info) => info,
+            Err(e) => return self.send_error(&format!("presence_update rejected: {}", e), 4009),
+        };
+        if sub.member_info.as_ref() == Some(&user_info) {
+            return;
+        }
+        let updated = self
+            .state
+            .presence_service()
+            .update_member(&data.channel, &self.socket_id, &user_id, user_info.clone())
+            .await;
+        match updated {
+            Ok(Some(member)) => {
+                if let Some(sub) = self.channels.get_mut(&data.channel) {
+                    sub.member_info = Some(user_info);
+                }
+                if let Err(e) = self
+                    .state
+                    .channel_service
+                    .publish_internal(
+                        &data.channel,
+                        "pusher_internal:member_updated",
+                        json!({ "user_id": member.user_id, "user_info": member.user_info }),
+                    )
+                    .await
+                {
+                    warn!(socket_id = %self.socket_id, channel = %data.channel, error = %e, "member_updated publish failed");
+                }
+            }
+            Ok(None) => self.send_error(&format!("Not a member of {}", data.channel), 4009),
+            Err(e) => self.send_error(&format!("presence_update failed: {}", e), 4009),
+        }
+    }
+
+    /// The subscription's verified user, else this socket.
+    fn recipient(&self, authz: &ChannelAuthorization) -> Recipient {
+        match &authz.user_id {
+            Some(user_id) if authz.user_verified => Recipient::User {
+                domain_id: self.ctx.domain_id,
+                user_id: user_id.clone(),
+            },
+            _ => Recipient::Socket(self.socket_id.clone()),
+        }
+    }
+
+    /// The socket already joined a presence channel (see [`Self::join_presence`]).
+    async fn send_subscription_succeeded(&self, channel: &str) {
+        if ChannelType::from_name(channel) == ChannelType::Presence {
+            let members: Vec<crate::models::PresenceUser> = self
+                .state
+                .presence_service()
+                .list_members(channel)
+                .await
+                .unwrap_or_default();
+            self.send(json!({
+                "event": "pusher_internal:subscription_succeeded",
+                "channel": channel,
+                "data": json!({
+                    "presence": {
+                        "ids": members.iter().map(|u| u.user_id.clone()).collect::<Vec<_>>(),
+                        "hash": {},
+                        "count": members.len()
+                    }
+                })
+            }));
+        } else {
+            self.send(json!({
+                "event": "pusher_internal:subscription_succeeded",
+                "channel": channel
+            }));
+        }
+    }
+
+    /// Rewind from the domain's history, else replay a cache channel's last event. Returns
+    /// the highest sequence number sent (0 if none).
+    async fn backfill_fresh(&self, channel: &str, rewind: Option<&RewindOptions>) -> u64 {
+        if let Some((options, domain_id)) = rewind.zip(self.ctx.domain_id) {
+            match self.state.channel_service.rewind(domain_id, channel, options).await {
+                Ok(RewindPage { mut events, truncated }) if !events.is_empty() => {
+                    if truncated {
+                        // Older events are still in the history API, before this cursor.
+                        self.send(json!({
+                            "event": "pusher:rewind_truncated",
+                            "channel": channel,
+                            "data": { "next_cursor": events[0].history_id }
+                        }));
+                    }
+                    let now = chrono::Utc::now().timestamp_millis();
+                    let before = events.len();
+                    events.retain(|e| !e.is_expired(now));
+                    for _ in events.len()..before {
+                        self.state.metrics().expired_dropped(DeliveryPath::Replay);
+                    }
+                    for event in &events {
+                        if let Ok(payload) = serde_json::to_string(event) {
+                            let _ = self.tx.send(payload);
                         }
                     }
+                    return events.iter().map(|e| e.seq).max().unwrap_or(0);
+                }
+                Ok(_) => {}
+                Err(e) => warn!(channel = %channel, error = %e, "rewind failed"),
+            }
+        }
+
+        if is_cache_channel(channel) {
+            match self.state.channel_service.cached_event(channel).await {
+                Ok(Some(payload)) if !EventMeta::parse(&payload).is_expired() => {
+                    let seq = EventMeta::parse(&payload).seq;
+                    let _ = self.tx.send(payload);
+                    return seq;
+                }
+                Ok(cached) => {
+                    if cached.is_some() {
+                        self.state.metrics().expired_dropped(DeliveryPath::Replay);
+                    }
+                    self.send(json!({
+                        "event": "pusher:cache_miss",
+                        "channel": channel,
+                        "data": {}
+                    }));
                 }
+                Err(e) => warn!(channel = %channel, error = %e, "cache lookup failed"),
+            }
+        }
+        0
+    }
+
+    /// Send events published after `last_seq` from the recovery buffer; say so when some are
+    /// gone. Returns the highest sequence number the client now has.
+    async fn replay_missed(&self, channel: &str, last_seq: u64) -> u64 {
+        let recovery = match self.state.channel_service.recover(channel, last_seq).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!(channel = %channel, error = %e, "recovery failed");
+                Recovery::default()
             }
-            Message::Close(_) => break,
-            _ => {}
+        };
+        let mut replayed = last_seq;
+        for payload in recovery.events {
+            replayed = replayed.max(self.deliver(channel, payload, DeliveryPath::Replay));
+        }
+        if !recovery.complete {
+            self.send(json!({
+                "event": "pusher:recovery_failed",
+                "channel": channel,
+                "data": {
+                    "message": "Some events published while disconnected are no longer available",
+                    "last_seq": last_seq
+                }
+            }));
         }
+        replayed
     }
 
-    for channel in &subscribed_channels {
+    /// Resume a dropped session: take over its socket id and subscriptions and replay
+    /// what was missed. Must come before any subscribe or signin.
+    async fn resume(&mut self, data: ResumePayload) {
+        if !self.channels.is_empty() || self.user.is_some() {
+            self.send_error("Resume must be sent before subscribing or signing in", 4009);
+            return;
+        }
+        let session = match self.state.channel_service.take_resume_session(&data.resume_token).await {
+            Ok(Some(session)) => session,
+            Ok(None) => return self.send_resume_failed("Unknown or expired resume token"),
+            Err(e) => {
+                warn!(error = %e, "resume lookup failed");
+                return self.send_resume_failed("Resume unavailable");
+            }
+        };
+        if session.domain_id != self.ctx.domain_id {
+            // The session is consumed: release what the dropped socket still held.
+            remove_presence(&self.state, &session.socket_id, session.channels.iter().map(|c| c.channel.as_str())).await;
+            return self.send_resume_failed("Resume token belongs to another domain");
+        }
+
+        self.state.control_service().rename(&self.socket_id, &session.socket_id).await;
+        self.socket_id = session.socket_id;
+        let mut restored = Vec::new();
+        for ch in session.channels {
+            let allowed = self.ctx.grant.as_ref().is_none_or(|g| g.allows_channel(&ch.channel));
+            if !allowed {
+                remove_presence(&self.state, &self.socket_id, std::iter::once(ch.channel.as_str())).await;
+                continue;
+            }
+            let last_seq = data.positions.get(&ch.channel).copied().unwrap_or(ch.last_seq);
+            let authz = ChannelAuthorization {
+                user_id: ch.user_id,
+                user_verified: ch.user_verified,
+                user_info: ch.user_info,
+                expires_at: ch.expires_at,
+            };
+            let channel = ch.channel.clone();
+            if self.attach(ch.channel, authz, Backfill::Resume(last_seq)).await {
+                restored.push(channel);
+            } else {
+                remove_presence(&self.state, &self.socket_id, std::iter::once(channel.as_str())).await;
+            }
+        }
+        info!(socket_id = %self.socket_id, channels = restored.len(), "ws session resumed");
+        self.send(json!({
+            "event": "pusher:resumed",
+            "data": { "socket_id": self.socket_id, "channels": restored }
+        }));
+    }
+
+    fn send_resume_failed(&self, message: &str) {
+        self.send(json!({
+            "event": "pusher:resume_failed",
+            "data": { "message": message }
+        }));
+    }
+
+    /// Stop forwarding a channel and clean up presence and connection tracking.
+    async fn unsubscribe(&mut self, channel: &str) {
+        remove_presence(&self.state, &self.socket_id, std::iter::once(channel)).await;
+        if self.ctx.domain_id.is_some() {
+            let _ = crate::db::ws_connection_mark_disconnected_by_channel(
+                self.state.db(),
+                &self.socket_id,
+                channel,
+            )
+            .await;
+        }
+        self.channels.remove(channel);
+        self.unacked.retain(|_, u| u.channel != channel);
+    }
+
+    /// Release the signed-in user; watchers see it offline after `offline_after`.
+    async fn sign_out(&mut self, offline_after: Duration) {
+        if let Some(session) = self.user.take() {
+            let _ = self
+                .state
+                .user_service()
+                .sign_out(self.ctx.domain_id, &session.user.id, &self.socket_id, offline_after)
+                .await;
+        }
+    }
+
+    /// Terminated through the server API: release presence, connection records and the
+    /// signed-in user right away. The session is not resumable.
+    async fn terminate(&mut self) {
+        self.sign_out(Duration::ZERO).await;
+        let channels: Vec<String> = self.channels.keys().cloned().collect();
+        for channel in &channels {
+            self.unsubscribe(channel).await;
+        }
+        if self.ctx.domain_id.is_some() {
+            let _ = crate::db::ws_connection_mark_disconnected(self.state.db(), &self.socket_id).await;
+        }
+    }
+
+    /// On disconnect, keep the session resumable for the grace window. Presence membership
+    /// is only dropped once the window passes without a resume.
+    async fn close(&mut self) {
+        self.sign_out(self.state.channel_service.recovery_config().grace).await;
+        let subscriptions = std::mem::take(&mut self.channels);
+        if self.ctx.domain_id.is_some() {
+            let _ = crate::db::ws_connection_mark_disconnected(self.state.db(), &self.socket_id).await;
+        }
+        if subscriptions.is_empty() {
+            return;
+        }
+
+        let session = ResumeSession {
+            socket_id: self.socket_id.clone(),
+            domain_id: self.ctx.domain_id,
+            channels: subscriptions
+                .iter()
+                .map(|(channel, sub)| ResumedChannel {
+                    channel: channel.clone(),
+                    last_seq: sub.last_seq.load(Ordering::Relaxed),
+                    user_id: sub.authz.user_id.clone(),
+                    user_verified: sub.authz.user_verified,
+                    user_info: sub.authz.user_info.clone(),
+                    expires_at: sub.authz.expires_at,
+                })
+                .collect(),
+        };
+        let channels: Vec<String> = subscriptions.into_keys().collect();
+        let grace = self.state.channel_service.recovery_config().grace;
+        let saved = !grace.is_zero()
+            && self
+                .state
+                .channel_service
+                .save_resume_session(&self.resume_token, &session)
+                .await
+                .is_ok();
+        if !saved {
+            remove_presence(&self.state, &self.socket_id, channels.iter().map(String::as_str)).await;
+            return;
+        }
+
+        let state = self.state.clone();
+        let token = self.resume_token.clone();
+        let socket_id = self.socket_id.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            if state.channel_service.discard_resume_session(&token).await.unwrap_or(true) {
+                remove_presence(&state, &socket_id, channels.iter().map(String::as_str)).await;
+            }
+        });
+    }
+}
+
+/// `pusher:watchlist_events` with one change (`online` / `offline`) for `user_ids`.
+fn watchlist_events(name: &str, user_ids: Vec<String>) -> serde_json::Value {
+    json!({
+        "event": "pusher:watchlist_events",
+        "data": { "events": [{ "name": name, "user_ids": user_ids }] }
+    })
+}
+
+/// Remove `socket_id` from the presence channels among `channels`.
+async fn remove_presence<'a>(state: &AppState, socket_id: &str, channels: impl Iterator<Item = &'a str>) {
+    for channel in channels {
         if ChannelType::from_name(channel) == ChannelType::Presence {
-            let _ = state.presence_service().remove_member(channel, &socket_id).await;
+            let _ = state.presence_service().remove_member(channel, socket_id).await;
         }
     }
-    if domain_id.is_some() {
-        let _ = crate::db::ws_connection_mark_disconnected(state.db(), &socket_id).await;
+}
+
+/// Envelope fields of a published event the socket loop acts on.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct EventMeta {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub event: String,
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(default)]
+    pub channel: String,
+    #[serde(default)]
+    pub reliable: bool,
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+impl EventMeta {
+    pub fn parse(payload: &str) -> Self {
+        serde_json::from_str(payload).unwrap_or_default()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|at| at <= chrono::Utc::now().timestamp_millis())
+    }
+}
+
+/// Send a published event to the socket; reliable ones are reported to the session loop,
+/// which tracks them until acked. `false` once the socket is gone.
+fn deliver_event(
+    tx: &mpsc::UnboundedSender<String>,
+    commands: &mpsc::UnboundedSender<SocketCommand>,
+    channel: &str,
+    meta: &EventMeta,
+    payload: String,
+) -> bool {
+    if meta.reliable && !meta.id.is_empty() {
+        let _ = commands.send(SocketCommand::Delivered {
+            channel: channel.to_string(),
+            id: meta.id.clone(),
+            payload: payload.clone(),
+        });
     }
+    tx.send(payload).is_ok()
+}
+
+async fn handle_socket(state: AppState, socket: WebSocket, ctx: ConnectionContext) {
+    let socket_id = generate_socket_id();
+    info!(socket_id = %socket_id, "ws connected");
 
+    let (mut sender, receiver) = socket.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let (close_tx, mut close_rx) = oneshot::channel::<CloseFrame<'static>>();
+    let mut send_task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(msg) => {
+                        if sender.send(Message::Text(msg)).await.is_err() {
+                            break;
+                        }
+                    }
+                    // The session is over; a terminate still hands over its close frame.
+                    None => {
+                        if let Ok(frame) = (&mut close_rx).await {
+                            let _ = sender.send(Message::Close(Some(frame))).await;
+                        }
+                        break;
+                    }
+                },
+                Ok(frame) = &mut close_rx => {
+                    let _ = sender.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+            }
+        }
+    });
+
+    // Text frames until the client closes or the connection fails.
+    let frames = receiver
+        .take_while(|msg| futures::future::ready(matches!(msg, Ok(m) if !matches!(m, Message::Close(_)))))
+        .filter_map(|msg| {
+            futures::future::ready(match msg {
+                Ok(Message::Text(text)) => Some(text),
+                _ => None,
+            })
+        });
+    let (socket_id, end) = run_session(state, ctx, socket_id, tx, frames).await;
+    if end == SessionEnd::Terminated {
+        let _ = close_tx.send(CloseFrame {
+            code: CLOSE_TERMINATED,
+            reason: "Connection terminated by server".into(),
+        });
+        let _ = tokio::time::timeout(Duration::from_secs(1), &mut send_task).await;
+        info!(socket_id = %socket_id, "ws terminated");
+    }
     send_task.abort();
     info!(socket_id = %socket_id, "ws disconnected");
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{domain_matches, parse_origin_host};
+/// How a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SessionEnd {
+    /// The client went away; the session stays resumable for the grace window.
+    Closed,
+    /// Terminated through the server API.
+    Terminated,
+}
 
-    #[test]
-    fn parse_origin_host_http_https() {
-        assert_eq!(parse_origin_host("https://app.example.com"), Some("app.example.com".to_string()));
-        assert_eq!(parse_origin_host("http://localhost:3000"), Some("localhost:3000".to_string()));
-        assert_eq!(parse_origin_host("https://sub.domain.com/path"), Some("sub.domain.com".to_string()));
-    }
+/// Run a client session over any transport: `frames` are the client's text frames (the
+/// session ends with them) and outgoing frames go to `tx`, starting with
+/// `connection_established`. Returns the final socket id (a resume takes over another).
+pub(crate) async fn run_session(
+    state: AppState,
+    ctx: ConnectionContext,
+    socket_id: String,
+    tx: mpsc::UnboundedSender<String>,
+    frames: impl futures::Stream<Item = String>,
+) -> (String, SessionEnd) {
+    let resume_token = generate_resume_token();
+    let conn_msg = json!({
+        "event": "connection_established",
+        "data": { "socket_id": socket_id, "resume_token": resume_token }
+    });
+    let _ = tx.send(conn_msg.to_string());
 
-    #[test]
-    fn parse_origin_host_invalid() {
-        assert_eq!(parse_origin_host("not-a-url"), None);
-        assert_eq!(parse_origin_host(""), None);
-    }
+    let mut control_rx = state.control_service().register(&socket_id, ctx.domain_id).await;
+
+    let (commands, mut command_rx) = mpsc::unbounded_channel::<SocketCommand>();
+    let mut session = SocketSession {
+        state,
+        socket_id,
+        resume_token,
+        ctx,
+        tx,
+        commands,
+        channels: HashMap::new(),
+        unacked: HashMap::new(),
+        user: None,
+    };
+    let mut redelivery = tokio::time::interval(REDELIVERY_CHECK_INTERVAL);
+    let mut end = SessionEnd::Closed;
+    let mut frames = std::pin::pin!(frames);
 
-    #[test]
-    fn domain_matches_exact() {
-        assert!(domain_matches("app.example.com", "app.example.com"));
-        assert!(domain_matches("localhost", "localhost"));
-        assert!(!domain_matches("other.com", "app.example.com"));
+    loop {
+        tokio::select! {
+            frame = frames.next() => match frame {
+                Some(text) => match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(client_msg) => session.handle_message(client_msg).await,
+                    Err(_) => session.handle_unknown(&text),
+                },
+                None => break,
+            },
+            Some(command) = command_rx.recv() => session.handle_command(command).await,
+            Some(command) = control_rx.recv() => match command {
+                ControlCommand::Terminate => {
+                    end = SessionEnd::Terminated;
+                    break;
+                }
+                ControlCommand::Subscribe { channels, user_id, channel_data } => {
+                    session.server_subscribe(channels, user_id, channel_data).await
+                }
+                ControlCommand::Unsubscribe { channels } => session.server_unsubscribe(channels).await,
+                ControlCommand::Kick { channel, user_id } => session.kick(channel, user_id).await,
+            },
+            _ = redelivery.tick() => session.redeliver_due().await,
+        }
     }
 
-    #[test]
-    fn domain_matches_wildcard() {
-        assert!(domain_matches("*.example.com", "app.example.com"));
-        assert!(domain_matches("*.example.com", "example.com"));
-        assert!(!domain_matches("*.example.com", "other.com"));
+    session.state.control_service().unregister(&session.socket_id).await;
+    match end {
+        SessionEnd::Terminated => session.terminate().await,
+        SessionEnd::Closed => session.close().await,
     }
+    (std::mem::take(&mut session.socket_id), end)
 }
@@ -1,7 +1,8 @@
 //! Real-time push notification system (Pusher-like) built with Rust.
 //!
 //! Provides WebSocket-based real-time channels with Redis pub/sub,
-//! support for public, private, and presence channels.
+//! support for public, private, and presence channels, plus Server-Sent Events,
+//! long-polling and MQTT transports.
 
 pub mod auth;
 pub mod config;
@@ -11,6 +12,7 @@ pub mod error;
 pub mod handlers;
 pub mod middleware;
 pub mod models;
+pub mod mqtt;
 pub mod repositories;
 pub mod services;
 
@@ -20,7 +22,7 @@ pub use handlers::http::AppState;
 pub use services::channel::ChannelService;
 pub use services::presence::PresenceService;
 
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use handlers::http;
 
 /// Build the API router (ws, broadcast, health, auth, dashboard). Used by main and by integration tests.
@@ -39,12 +41,65 @@ pub fn create_app(state: AppState) -> axum::Router {
             "/domains/:id",
             axum::routing::patch(dashboard::set_domain_active).delete(dashboard::delete_domain),
         )
+        .route(
+            "/domains/:id/regenerate-key",
+            post(dashboard::regenerate_domain_key),
+        )
+        .route(
+            "/domains/:id/regenerate-secret",
+            post(dashboard::regenerate_domain_secret),
+        )
+        .route(
+            "/domains/:id/origins",
+            get(dashboard::list_domain_origins).post(dashboard::add_domain_origin),
+        )
+        .route(
+            "/domains/:id/origins/:origin_id",
+            axum::routing::delete(dashboard::delete_domain_origin),
+        )
+        .route(
+            "/domains/:id/public-keys",
+            get(dashboard::list_domain_public_keys).post(dashboard::add_domain_public_key),
+        )
+        .route(
+            "/domains/:id/public-keys/:key_id",
+            axum::routing::delete(dashboard::delete_domain_public_key),
+        )
         .route("/channels", get(dashboard::list_channels))
         .route("/ws-status", get(dashboard::get_ws_status));
 
     axum::Router::new()
         .route("/ws", get(handlers::ws_handler))
+        .route("/sse", get(handlers::sse_handler))
+        .route("/poll", post(handlers::create_poll_session))
+        .route(
+            "/poll/:session_id",
+            get(handlers::poll_session)
+                .post(handlers::send_poll_frames)
+                .delete(handlers::close_poll_session),
+        )
         .route("/api/broadcast", post(handlers::broadcast))
+        .route("/api/channels/:name/history", get(handlers::channel_history))
+        .route("/api/channels/:name/users", get(handlers::channel_users))
+        .route("/api/events/:id/delivery", get(handlers::event_delivery))
+        .route(
+            "/api/schedules",
+            get(handlers::list_schedules).post(handlers::create_schedule),
+        )
+        .route("/api/schedules/:id", delete(handlers::cancel_schedule))
+        .route("/api/users/:id/events", post(handlers::send_user_event))
+        .route("/api/users/:id/presence", get(handlers::user_presence))
+        .route(
+            "/api/users/:id/terminate_connections",
+            post(handlers::terminate_user_connections),
+        )
+        .route("/api/sockets/:socket_id/terminate", post(handlers::terminate_socket))
+        .route("/api/sockets/:socket_id/subscribe", post(handlers::subscribe_socket))
+        .route("/api/sockets/:socket_id/unsubscribe", post(handlers::unsubscribe_socket))
+        .route("/api/channels/:name/kick", post(handlers::kick_member))
+        .route("/api/channels/:name/bans/:user_id", delete(handlers::unban_member))
+        .route("/api/bans", get(handlers::list_bans))
+        .route("/metrics", get(http::metrics))
         .route("/health", get(http::health))
         .nest("/auth", auth_routes)
         .nest("/dashboard", dashboard_routes)
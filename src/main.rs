@@ -4,9 +4,16 @@ use axum::routing::get_service;
 use notif::config::Config;
 use notif::db;
 use notif::repositories::RedisRepository;
-use notif::services::{AuthService, ChannelService, PresenceService};
+use notif::services::delivery::DeliveryConfig;
+use notif::services::idempotency::IDEMPOTENCY_CLAIM_TTL;
+use notif::services::recovery::RecoveryConfig;
+use notif::services::{
+    AuthService, AuthorizerService, ChannelService, ControlService, DeliveryService, IdempotencyService,
+    Metrics, ModerationService, PollingService, PresenceService, ScheduleService, UserService,
+};
 use notif::{create_app, AppState};
 use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
@@ -24,9 +31,38 @@ async fn main() -> anyhow::Result<()> {
 
     let db_pool = db::create_pool(&config.database_url).await?;
     let repo = Arc::new(RedisRepository::new(&config.redis_url)?);
-    let channel_service = ChannelService::new(repo.clone());
+    let channel_service = ChannelService::new(
+        repo.clone(),
+        Duration::from_secs(config.cache_channel_ttl_secs),
+        RecoveryConfig {
+            buffer_size: config.recovery_buffer_size.max(1) as usize,
+            grace: Duration::from_secs(config.resume_grace_secs),
+        },
+    );
     let auth_service = AuthService::new(config.app_secret.clone(), config.app_key.clone());
+    let delivery_service = DeliveryService::new(
+        repo.clone(),
+        DeliveryConfig {
+            ack_timeout: Duration::from_secs(config.reliable_ack_timeout_secs.max(1)),
+            retention: Duration::from_secs(config.reliable_retention_secs),
+        },
+    );
+    let idempotency_service = IdempotencyService::new(
+        repo.clone(),
+        Duration::from_secs(config.idempotency_window_secs),
+        IDEMPOTENCY_CLAIM_TTL,
+    );
+    let schedule_service = ScheduleService::new(repo.clone());
+    let user_service = UserService::new(repo.clone(), channel_service.clone());
+    let control_service = ControlService::new(repo.clone());
+    let moderation_service = ModerationService::new(repo.clone());
+    let polling_service = PollingService::new(repo.clone());
     let presence_service = PresenceService::new(repo);
+    let authorizer_service = AuthorizerService::new(
+        Duration::from_millis(config.authorizer_timeout_ms),
+        Duration::from_secs(config.authorizer_cache_secs),
+        config.authorizer_allow_private,
+    )?;
     let jwt_secret = notif::auth::JwtSecret::new(config.jwt_secret.clone());
 
     let state = AppState {
@@ -35,9 +71,27 @@ async fn main() -> anyhow::Result<()> {
         channel_service,
         auth_service,
         presence_service,
+        authorizer_service,
+        delivery_service,
+        idempotency_service,
+        schedule_service,
+        user_service,
+        control_service,
+        moderation_service,
+        polling_service,
+        metrics: Arc::new(Metrics::new()),
         db: db_pool,
         jwt_secret,
     };
+    tokio::spawn(notif::handlers::run_schedule_dispatcher(state.clone()));
+    tokio::spawn(state.control_service().clone().run());
+    tokio::spawn(state.polling_service().clone().run());
+    tokio::spawn(state.user_service().clone().run());
+    if let Some(mqtt_addr) = config.mqtt_addr {
+        tracing::info!(addr = %mqtt_addr, "mqtt listening");
+        let mqtt_listener = tokio::net::TcpListener::bind(mqtt_addr).await?;
+        tokio::spawn(notif::mqtt::serve(mqtt_listener, state.clone()));
+    }
 
     let app = create_app(state)
         // Root (/) and /docs.html: serve docs.html
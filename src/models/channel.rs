@@ -12,13 +12,17 @@ pub enum ChannelType {
     Private,
     /// Presence channel: auth + track who is online.
     Presence,
+    /// Private channel whose event data is end-to-end encrypted (NaCl secretbox).
+    PrivateEncrypted,
 }
 
 impl ChannelType {
-    /// Derive channel type from name. Pusher-style: `private-*`, `presence-*`.
+    /// Derive channel type from name. Pusher-style: `private-encrypted-*`, `private-*`, `presence-*`.
     pub fn from_name(name: &str) -> Self {
         if name.starts_with("presence-") {
             ChannelType::Presence
+        } else if name.starts_with("private-encrypted-") {
+            ChannelType::PrivateEncrypted
         } else if name.starts_with("private-") {
             ChannelType::Private
         } else {
@@ -27,8 +31,50 @@ impl ChannelType {
     }
 
     pub fn is_private(&self) -> bool {
-        matches!(self, ChannelType::Private | ChannelType::Presence)
+        matches!(
+            self,
+            ChannelType::Private | ChannelType::Presence | ChannelType::PrivateEncrypted
+        )
     }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, ChannelType::PrivateEncrypted)
+    }
+}
+
+/// Names starting with `#` are server-internal (e.g. user event channels); clients cannot
+/// subscribe to them and the publish API does not accept them.
+pub fn is_reserved_channel(name: &str) -> bool {
+    name.starts_with('#')
+}
+
+/// Cache channels (`cache-*`, `private-cache-*`, `presence-cache-*`) keep their last event
+/// and replay it to new subscribers. Auth follows the type from [`ChannelType::from_name`].
+pub fn is_cache_channel(name: &str) -> bool {
+    ["cache-", "private-cache-", "presence-cache-"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// Match a channel name against a glob pattern where `*` matches any run of characters
+/// (e.g. `presence-room-*`, `private-*-orders`). A pattern without `*` must match exactly.
+pub fn channel_matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
 }
 
 #[cfg(test)]
@@ -56,4 +102,37 @@ mod tests {
             ChannelType::Presence
         );
     }
+
+    #[test]
+    fn channel_type_from_name_private_encrypted() {
+        let t = ChannelType::from_name("private-encrypted-user-1");
+        assert_eq!(t, ChannelType::PrivateEncrypted);
+        assert!(t.is_private() && t.is_encrypted());
+        assert!(!ChannelType::from_name("private-user-1").is_encrypted());
+    }
+
+    #[test]
+    fn cache_channels() {
+        assert!(is_cache_channel("cache-price-btc"));
+        assert!(is_cache_channel("private-cache-build-7"));
+        assert!(is_cache_channel("presence-cache-room"));
+        assert!(!is_cache_channel("private-build-7"));
+        assert!(!is_cache_channel("my-cache-channel"));
+        assert_eq!(ChannelType::from_name("private-cache-build-7"), ChannelType::Private);
+        assert_eq!(ChannelType::from_name("presence-cache-room"), ChannelType::Presence);
+    }
+
+    #[test]
+    fn channel_pattern_matching() {
+        assert!(channel_matches_pattern("private-user-42", "private-user-42"));
+        assert!(!channel_matches_pattern("private-user-42", "private-user-420"));
+        assert!(channel_matches_pattern("presence-room-*", "presence-room-7"));
+        assert!(channel_matches_pattern("presence-room-*", "presence-room-"));
+        assert!(!channel_matches_pattern("presence-room-*", "presence-lobby"));
+        assert!(channel_matches_pattern("private-*-orders", "private-shop-1-orders"));
+        assert!(!channel_matches_pattern("private-*-orders", "private-shop-1-orders-x"));
+        assert!(channel_matches_pattern("*", "anything"));
+        assert!(channel_matches_pattern("a*b*c", "axxbyyc"));
+        assert!(!channel_matches_pattern("a*b*c", "axxcyyb"));
+    }
 }
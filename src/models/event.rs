@@ -2,43 +2,249 @@
 
 use serde::{Deserialize, Serialize};
 
-/// Event sent over WebSocket to clients.
+/// Event sent over WebSocket to clients. Every published event has the same envelope:
+/// `id`, `seq`, `published_at`, `event`, `channel`, `data` (+ `history_id` when stored,
+/// `expires_at` with a TTL).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WsEvent {
+    /// Globally unique event id, for de-duplication.
+    #[serde(default)]
+    pub id: String,
+    /// Per-channel sequence number, increasing by one per published event (0 = unknown).
+    #[serde(default)]
+    pub seq: u64,
+    /// Publish time, Unix milliseconds.
+    #[serde(default)]
+    pub published_at: i64,
+    /// History id (`<ms>-<seq>`), set when the channel keeps history; the history cursor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_id: Option<String>,
+    /// Client must `ack` the event `id`; redelivered until it does.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reliable: bool,
+    /// Not delivered after this time, Unix milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
     pub event: String,
     pub channel: String,
     pub data: serde_json::Value,
 }
 
+impl WsEvent {
+    pub fn is_expired(&self, now_ms: i64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_ms)
+    }
+}
+
+/// What [`ChannelService::broadcast`](crate::services::ChannelService::broadcast) assigned to
+/// a published event, returned by the broadcast API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishedEvent {
+    pub id: String,
+    pub seq: u64,
+    pub published_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_id: Option<String>,
+    /// Server nodes subscribed to the channel when it was published.
+    pub subscriber_count: u64,
+}
+
 /// Payload for HTTP API to trigger a broadcast.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BroadcastRequest {
     pub channel: String,
     pub event: String,
     pub data: serde_json::Value,
+    /// At-least-once delivery: keep the event per recipient until the client acks it.
+    #[serde(default)]
+    pub reliable: bool,
+    /// Same as the `Idempotency-Key` header (the header wins).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Publish at this time instead of now.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deliver_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Drop the event instead of delivering it after this time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Alternative to `expires_at`: seconds after publishing (or `deliver_at`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+/// Payload for HTTP API to send an event to every connection of a signed-in user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEventRequest {
+    pub event: String,
+    pub data: serde_json::Value,
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// Payload for HTTP API to subscribe a connected socket to channels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketSubscribeRequest {
+    pub channels: Vec<String>,
+    /// For presence channels: `user_id` and optional `user_info`.
+    #[serde(default)]
+    pub channel_data: Option<serde_json::Value>,
+}
+
+/// Payload for HTTP API to unsubscribe a connected socket from channels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketUnsubscribeRequest {
+    pub channels: Vec<String>,
+}
+
+/// Payload for HTTP API to kick a user from a private or presence channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KickRequest {
+    pub user_id: String,
+    /// Also ban the user from the channel.
+    #[serde(default)]
+    pub ban: Option<BanRequest>,
+}
+
+/// Ban options of a kick.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BanRequest {
+    /// Ban length; permanent when absent.
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Payload for HTTP API to create a recurring broadcast.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateScheduleRequest {
+    pub channel: String,
+    pub event: String,
+    pub data: serde_json::Value,
+    /// Five-field cron expression, evaluated in UTC (e.g. `*/5 * * * *`).
+    pub cron: String,
+    #[serde(default)]
+    pub reliable: bool,
+    /// Each run's event expires this many seconds after it is published.
+    #[serde(default)]
+    pub ttl: Option<u64>,
 }
 
-/// WebSocket client message: subscribe / unsubscribe.
+/// WebSocket client message: subscribe / unsubscribe / ping / resume / ack / signin /
+/// presence_update.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "event", rename_all = "snake_case")]
 pub enum ClientMessage {
     Subscribe { data: SubscribePayload },
     Unsubscribe { data: UnsubscribePayload },
     Ping,
+    Resume { data: ResumePayload },
+    Ack { data: AckPayload },
+    #[serde(rename = "pusher:signin", alias = "signin")]
+    Signin { data: SigninPayload },
+    PresenceUpdate { data: PresenceUpdatePayload },
+}
+
+/// `presence_update`: change this socket's member info on a presence channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceUpdatePayload {
+    pub channel: String,
+    /// Keys to set (`null` removes); keys given by the subscribe auth cannot be changed.
+    pub user_info: serde_json::Value,
+}
+
+/// `pusher:signin`: user data signed by the backend with the domain secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigninPayload {
+    /// `<key>:<hex HMAC-SHA256 of socket_id::user::user_data>`.
+    pub auth: String,
+    /// JSON object string with at least `id`.
+    pub user_data: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubscribePayload {
     pub channel: String,
-    /// For private/presence: auth signature or token (e.g. HMAC or JWT).
+    /// For private/presence: HMAC hex signature, or a channel-auth JWT (`header.payload.signature`).
     #[serde(default)]
     pub auth: Option<String>,
     /// For presence: optional channel_data (user info).
     #[serde(default)]
     pub channel_data: Option<serde_json::Value>,
+    /// Passed to the domain's authorizer webhook when `auth` is absent (e.g. the user's session token).
+    #[serde(default)]
+    pub user_token: Option<String>,
+    /// Deliver stored history before live events.
+    #[serde(default)]
+    pub rewind: Option<RewindOptions>,
+}
+
+/// Which stored events to replay on subscribe. Both set: the last `count` events since `since`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RewindOptions {
+    /// Last N events (capped by the server).
+    #[serde(default)]
+    pub count: Option<usize>,
+    /// Events published at or after this Unix timestamp (seconds).
+    #[serde(default)]
+    pub since: Option<i64>,
+}
+
+/// Take over a dropped connection's session (first message after reconnecting).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumePayload {
+    /// `resume_token` from the dropped connection's `connection_established`.
+    pub resume_token: String,
+    /// Last `seq` the client received per channel; defaults to what the server delivered.
+    #[serde(default)]
+    pub positions: std::collections::HashMap<String, u64>,
+}
+
+/// Confirms receipt of a reliable event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckPayload {
+    /// The event's `id`.
+    pub id: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnsubscribePayload {
     pub channel: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_fields_always_serialized() {
+        let event = WsEvent {
+            id: "0b6f9a54-5c8e-4f0e-9a31-2f1f6b1c7d10".to_string(),
+            seq: 7,
+            published_at: 1_735_689_600_000,
+            history_id: None,
+            reliable: false,
+            expires_at: None,
+            event: "created".to_string(),
+            channel: "orders-42".to_string(),
+            data: serde_json::json!({}),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        for key in ["id", "seq", "published_at", "event", "channel", "data"] {
+            assert!(json.get(key).is_some(), "missing {}", key);
+        }
+        assert!(json.get("history_id").is_none());
+        assert!(json.get("reliable").is_none());
+    }
+
+    #[test]
+    fn events_without_envelope_still_decode() {
+        let event: WsEvent =
+            serde_json::from_str(r#"{"event":"created","channel":"orders-42","data":{}}"#).unwrap();
+        assert_eq!(event.seq, 0);
+        assert!(event.id.is_empty());
+        assert!(!event.is_expired(i64::MAX));
+    }
+}
@@ -25,6 +25,19 @@ pub struct PresenceMember {
     pub user_id: String,
     pub user_info: Option<serde_json::Value>,
     pub socket_id: String,
+    /// Domain of the socket; moderation only touches members of its own domain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_id: Option<Uuid>,
+    /// Where to record the user's last-seen time when their last socket leaves; `None` = off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<LastSeenPolicy>,
+}
+
+/// Last-seen tracking of a domain: scope (domain id or `legacy`) and retention.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastSeenPolicy {
+    pub scope: String,
+    pub retention_secs: u64,
 }
 
 impl PresenceMember {
@@ -0,0 +1,625 @@
+//! MQTT 3.1.1 and 5 packets: the subset the listener speaks (QoS 0/1, no QoS 2, no
+//! enhanced auth). Decoding works on a growing read buffer; version 5 properties the
+//! listener does not use are skipped.
+
+use thiserror::Error;
+
+/// Protocol level of a connection, from its CONNECT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    V311,
+    V5,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CodecError {
+    #[error("malformed packet: {0}")]
+    Malformed(&'static str),
+    #[error("unsupported protocol level {0}")]
+    UnsupportedProtocol(u8),
+    #[error("packet larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+type Result<T> = std::result::Result<T, CodecError>;
+
+/// An application message, in either direction.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Publish {
+    pub dup: bool,
+    pub qos: u8,
+    pub retain: bool,
+    pub topic: String,
+    /// Set for QoS 1.
+    pub packet_id: Option<u16>,
+    pub payload: Vec<u8>,
+    /// Version 5 Message Expiry Interval, in seconds.
+    pub message_expiry: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connect {
+    pub protocol: Protocol,
+    pub client_id: String,
+    pub clean_start: bool,
+    pub keep_alive: u16,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+    pub will: Option<Publish>,
+    /// Largest packet the client accepts (version 5).
+    pub max_packet_size: Option<u32>,
+}
+
+/// One filter of a SUBSCRIBE; `options` carries the requested QoS in its low two bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscribeFilter {
+    pub filter: String,
+    pub options: u8,
+}
+
+impl SubscribeFilter {
+    pub fn qos(&self) -> u8 {
+        self.options & 0b11
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck {
+        session_present: bool,
+        /// Version 5 reason code; mapped to a 3.1.1 return code when encoding.
+        code: u8,
+        assigned_client_id: Option<String>,
+        max_packet_size: u32,
+    },
+    Publish(Publish),
+    PubAck { packet_id: u16, code: u8 },
+    Subscribe { packet_id: u16, filters: Vec<SubscribeFilter> },
+    SubAck { packet_id: u16, codes: Vec<u8> },
+    Unsubscribe { packet_id: u16, filters: Vec<String> },
+    UnsubAck { packet_id: u16, codes: Vec<u8> },
+    PingReq,
+    PingResp,
+    Disconnect { code: u8 },
+}
+
+/// Version 5 reason codes the listener uses (3.1.1 equivalents are derived from them).
+pub mod reason {
+    pub const SUCCESS: u8 = 0x00;
+    pub const GRANTED_QOS_1: u8 = 0x01;
+    pub const NO_SUBSCRIPTION_EXISTED: u8 = 0x11;
+    pub const UNSPECIFIED_ERROR: u8 = 0x80;
+    pub const MALFORMED_PACKET: u8 = 0x81;
+    pub const PROTOCOL_ERROR: u8 = 0x82;
+    pub const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+    pub const BAD_USERNAME_OR_PASSWORD: u8 = 0x86;
+    pub const NOT_AUTHORIZED: u8 = 0x87;
+    pub const KEEP_ALIVE_TIMEOUT: u8 = 0x8D;
+    pub const TOPIC_FILTER_INVALID: u8 = 0x8F;
+    pub const TOPIC_NAME_INVALID: u8 = 0x90;
+    pub const PACKET_TOO_LARGE: u8 = 0x95;
+    pub const ADMINISTRATIVE_ACTION: u8 = 0x98;
+    pub const PAYLOAD_FORMAT_INVALID: u8 = 0x99;
+    pub const QOS_NOT_SUPPORTED: u8 = 0x9B;
+}
+
+/// Take one complete packet off the front of `buf`, if there is one. `protocol` is `None`
+/// until the CONNECT was read.
+pub fn decode(buf: &mut Vec<u8>, protocol: Option<Protocol>, max_size: usize) -> Result<Option<Packet>> {
+    let Some((remaining, header_len)) = remaining_length(buf)? else {
+        return Ok(None);
+    };
+    if header_len + remaining > max_size {
+        return Err(CodecError::TooLarge(max_size));
+    }
+    if buf.len() < header_len + remaining {
+        return Ok(None);
+    }
+    let first = buf[0];
+    let body: Vec<u8> = buf.drain(..header_len + remaining).skip(header_len).collect();
+    let mut r = Reader { buf: &body, pos: 0 };
+    let v5 = protocol == Some(Protocol::V5);
+    let packet = match (first >> 4, protocol) {
+        (1, None) => Packet::Connect(read_connect(&mut r)?),
+        (1, Some(_)) => return Err(CodecError::Malformed("second CONNECT")),
+        (_, None) => return Err(CodecError::Malformed("first packet must be CONNECT")),
+        (3, _) => Packet::Publish(read_publish(&mut r, first & 0x0F, v5)?),
+        (4, _) => {
+            let packet_id = r.u16()?;
+            let code = if r.is_empty() { reason::SUCCESS } else { r.u8()? };
+            Packet::PubAck { packet_id, code }
+        }
+        (8, _) => {
+            expect_flags(first, 0b0010)?;
+            let packet_id = r.u16()?;
+            if v5 {
+                r.properties()?;
+            }
+            let mut filters = Vec::new();
+            while !r.is_empty() {
+                let filter = r.string()?;
+                let options = r.u8()?;
+                filters.push(SubscribeFilter { filter, options });
+            }
+            if filters.is_empty() {
+                return Err(CodecError::Malformed("SUBSCRIBE without filters"));
+            }
+            Packet::Subscribe { packet_id, filters }
+        }
+        (10, _) => {
+            expect_flags(first, 0b0010)?;
+            let packet_id = r.u16()?;
+            if v5 {
+                r.properties()?;
+            }
+            let mut filters = Vec::new();
+            while !r.is_empty() {
+                filters.push(r.string()?);
+            }
+            if filters.is_empty() {
+                return Err(CodecError::Malformed("UNSUBSCRIBE without filters"));
+            }
+            Packet::Unsubscribe { packet_id, filters }
+        }
+        (12, _) => Packet::PingReq,
+        (14, _) => Packet::Disconnect {
+            code: if r.is_empty() { reason::SUCCESS } else { r.u8()? },
+        },
+        _ => return Err(CodecError::Malformed("unsupported packet type")),
+    };
+    Ok(Some(packet))
+}
+
+/// Remaining length and fixed header size, once the header is complete.
+fn remaining_length(buf: &[u8]) -> Result<Option<(usize, usize)>> {
+    let mut value = 0usize;
+    for i in 0..4 {
+        let Some(&byte) = buf.get(1 + i) else {
+            return Ok(None);
+        };
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, 2 + i)));
+        }
+    }
+    Err(CodecError::Malformed("remaining length"))
+}
+
+fn expect_flags(first: u8, flags: u8) -> Result<()> {
+    if first & 0x0F != flags {
+        return Err(CodecError::Malformed("fixed header flags"));
+    }
+    Ok(())
+}
+
+fn read_connect(r: &mut Reader) -> Result<Connect> {
+    let name = r.string()?;
+    let level = r.u8()?;
+    let protocol = match (name.as_str(), level) {
+        ("MQTT", 4) => Protocol::V311,
+        ("MQTT", 5) => Protocol::V5,
+        (_, level) => return Err(CodecError::UnsupportedProtocol(level)),
+    };
+    let v5 = protocol == Protocol::V5;
+    let flags = r.u8()?;
+    if flags & 0x01 != 0 {
+        return Err(CodecError::Malformed("reserved CONNECT flag"));
+    }
+    let keep_alive = r.u16()?;
+    let max_packet_size = if v5 { r.properties()?.max_packet_size } else { None };
+    let client_id = r.string()?;
+    let will = if flags & 0x04 != 0 {
+        let message_expiry = if v5 { r.properties()?.message_expiry } else { None };
+        let topic = r.string()?;
+        let payload = r.binary()?;
+        Some(Publish {
+            qos: (flags >> 3) & 0b11,
+            retain: flags & 0x20 != 0,
+            topic,
+            payload,
+            message_expiry,
+            ..Publish::default()
+        })
+    } else {
+        None
+    };
+    let username = if flags & 0x80 != 0 { Some(r.string()?) } else { None };
+    let password = if flags & 0x40 != 0 { Some(r.binary()?) } else { None };
+    Ok(Connect {
+        protocol,
+        client_id,
+        clean_start: flags & 0x02 != 0,
+        keep_alive,
+        username,
+        password,
+        will,
+        max_packet_size,
+    })
+}
+
+fn read_publish(r: &mut Reader, flags: u8, v5: bool) -> Result<Publish> {
+    let qos = (flags >> 1) & 0b11;
+    if qos == 3 {
+        return Err(CodecError::Malformed("QoS 3"));
+    }
+    let topic = r.string()?;
+    let packet_id = if qos > 0 { Some(r.u16()?) } else { None };
+    let message_expiry = if v5 { r.properties()?.message_expiry } else { None };
+    Ok(Publish {
+        dup: flags & 0x08 != 0,
+        qos,
+        retain: flags & 0x01 != 0,
+        topic,
+        packet_id,
+        payload: r.rest().to_vec(),
+        message_expiry,
+    })
+}
+
+/// Version 5 properties the listener acts on.
+#[derive(Debug, Default)]
+struct Properties {
+    message_expiry: Option<u32>,
+    max_packet_size: Option<u32>,
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or(CodecError::Malformed("packet too short"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &[u8] {
+        let rest = &self.buf[self.pos.min(self.buf.len())..];
+        self.pos = self.buf.len();
+        rest
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        let mut value = 0usize;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(CodecError::Malformed("variable byte integer"))
+    }
+
+    fn binary(&mut self) -> Result<Vec<u8>> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.binary()?).map_err(|_| CodecError::Malformed("invalid UTF-8 string"))
+    }
+
+    fn properties(&mut self) -> Result<Properties> {
+        let len = self.varint()?;
+        let end = self.pos + len;
+        if end > self.buf.len() {
+            return Err(CodecError::Malformed("properties length"));
+        }
+        let mut props = Properties::default();
+        while self.pos < end {
+            match self.varint()? {
+                0x02 => props.message_expiry = Some(self.u32()?),
+                0x27 => props.max_packet_size = Some(self.u32()?),
+                0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => {
+                    self.u8()?;
+                }
+                0x13 | 0x21 | 0x22 | 0x23 => {
+                    self.u16()?;
+                }
+                0x11 | 0x18 => {
+                    self.u32()?;
+                }
+                0x0B => {
+                    self.varint()?;
+                }
+                0x03 | 0x08 | 0x09 | 0x12 | 0x15 | 0x16 | 0x1A | 0x1C | 0x1F => {
+                    self.binary()?;
+                }
+                0x26 => {
+                    self.binary()?;
+                    self.binary()?;
+                }
+                _ => return Err(CodecError::Malformed("unknown property")),
+            }
+        }
+        if self.pos != end {
+            return Err(CodecError::Malformed("properties length"));
+        }
+        Ok(props)
+    }
+}
+
+/// Serialize a server-to-client packet.
+pub fn encode(packet: &Packet, protocol: Protocol) -> Vec<u8> {
+    let v5 = protocol == Protocol::V5;
+    let mut body = Vec::new();
+    let first = match packet {
+        Packet::ConnAck {
+            session_present,
+            code,
+            assigned_client_id,
+            max_packet_size,
+        } => {
+            body.push(*session_present as u8);
+            if v5 {
+                body.push(*code);
+                let mut props = vec![0x24, 1, 0x25, 1, 0x2A, 0, 0x27];
+                props.extend_from_slice(&max_packet_size.to_be_bytes());
+                if let Some(id) = assigned_client_id {
+                    props.push(0x12);
+                    put_string(&mut props, id);
+                }
+                put_varint(&mut body, props.len());
+                body.extend_from_slice(&props);
+            } else {
+                body.push(connack_code_v311(*code));
+            }
+            0x20
+        }
+        Packet::Publish(publish) => {
+            put_string(&mut body, &publish.topic);
+            if let Some(id) = publish.packet_id {
+                body.extend_from_slice(&id.to_be_bytes());
+            }
+            if v5 {
+                match publish.message_expiry {
+                    Some(secs) => {
+                        body.push(5);
+                        body.push(0x02);
+                        body.extend_from_slice(&secs.to_be_bytes());
+                    }
+                    None => body.push(0),
+                }
+            }
+            body.extend_from_slice(&publish.payload);
+            0x30 | (publish.dup as u8) << 3 | publish.qos << 1 | publish.retain as u8
+        }
+        Packet::PubAck { packet_id, code } => {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+            if v5 && *code != reason::SUCCESS {
+                body.push(*code);
+            }
+            0x40
+        }
+        Packet::SubAck { packet_id, codes } => {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+            if v5 {
+                body.push(0);
+                body.extend_from_slice(codes);
+            } else {
+                // 3.1.1 only knows granted QoS and failure.
+                body.extend(codes.iter().map(|&c| if c < 0x80 { c } else { 0x80 }));
+            }
+            0x90
+        }
+        Packet::UnsubAck { packet_id, codes } => {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+            if v5 {
+                body.push(0);
+                body.extend_from_slice(codes);
+            }
+            0xB0
+        }
+        Packet::PingResp => 0xD0,
+        Packet::Disconnect { code } => {
+            if v5 {
+                body.push(*code);
+            }
+            0xE0
+        }
+        Packet::Connect(_) | Packet::Subscribe { .. } | Packet::Unsubscribe { .. } | Packet::PingReq => {
+            unreachable!("client-to-server packet")
+        }
+    };
+    let mut out = Vec::with_capacity(body.len() + 5);
+    out.push(first);
+    put_varint(&mut out, body.len());
+    out.extend_from_slice(&body);
+    out
+}
+
+/// 3.1.1 CONNACK return code for a version 5 reason code.
+fn connack_code_v311(code: u8) -> u8 {
+    match code {
+        reason::SUCCESS => 0,
+        reason::UNSUPPORTED_PROTOCOL_VERSION => 1,
+        reason::BAD_USERNAME_OR_PASSWORD => 4,
+        reason::NOT_AUTHORIZED => 5,
+        _ => 3,
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect_bytes(level: u8, flags: u8, rest: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_string(&mut body, "MQTT");
+        body.push(level);
+        body.push(flags);
+        body.extend_from_slice(&60u16.to_be_bytes());
+        body.extend_from_slice(rest);
+        let mut out = vec![0x10];
+        put_varint(&mut out, body.len());
+        out.extend_from_slice(&body);
+        out
+    }
+
+    #[test]
+    fn decodes_connect_of_both_versions() {
+        let mut rest = Vec::new();
+        put_string(&mut rest, "dev-1");
+        put_string(&mut rest, "key");
+        put_string(&mut rest, "secret");
+        let mut buf = connect_bytes(4, 0xC2, &rest);
+        let Some(Packet::Connect(c)) = decode(&mut buf, None, 1024).unwrap() else {
+            panic!("expected CONNECT");
+        };
+        assert_eq!(c.protocol, Protocol::V311);
+        assert_eq!(c.client_id, "dev-1");
+        assert_eq!(c.username.as_deref(), Some("key"));
+        assert_eq!(c.password.as_deref(), Some(&b"secret"[..]));
+        assert!(c.clean_start);
+        assert!(buf.is_empty());
+
+        // v5: properties with Maximum Packet Size and a User Property, no credentials.
+        let mut rest = vec![];
+        let mut props = vec![0x27, 0, 0, 0x10, 0];
+        props.push(0x26);
+        put_string(&mut props, "a");
+        put_string(&mut props, "b");
+        put_varint(&mut rest, props.len());
+        rest.extend_from_slice(&props);
+        put_string(&mut rest, "");
+        let mut buf = connect_bytes(5, 0x02, &rest);
+        let Some(Packet::Connect(c)) = decode(&mut buf, None, 1024).unwrap() else {
+            panic!("expected CONNECT");
+        };
+        assert_eq!(c.protocol, Protocol::V5);
+        assert_eq!(c.max_packet_size, Some(4096));
+        assert_eq!(c.username, None);
+
+        let mut buf = connect_bytes(3, 0x02, &[]);
+        assert_eq!(decode(&mut buf, None, 1024), Err(CodecError::UnsupportedProtocol(3)));
+    }
+
+    #[test]
+    fn waits_for_complete_packets() {
+        let mut buf = vec![0x30];
+        assert_eq!(decode(&mut buf, Some(Protocol::V311), 1024), Ok(None));
+        buf.extend_from_slice(&[6, 0, 3, b'a', b'/', b'b']);
+        assert_eq!(decode(&mut buf, Some(Protocol::V311), 1024), Ok(None));
+        buf.extend_from_slice(&[b'x', 0xC0]);
+        let Some(Packet::Publish(p)) = decode(&mut buf, Some(Protocol::V311), 1024).unwrap() else {
+            panic!("expected PUBLISH");
+        };
+        assert_eq!((p.topic.as_str(), p.payload.as_slice(), p.qos), ("a/b", &b"x"[..], 0));
+        assert_eq!(buf, vec![0xC0], "next packet stays buffered");
+        assert_eq!(decode(&mut buf, Some(Protocol::V311), 1024), Ok(None));
+    }
+
+    #[test]
+    fn rejects_oversized_and_out_of_order_packets() {
+        let mut buf = vec![0x30, 0xFF, 0x7F];
+        assert_eq!(decode(&mut buf, Some(Protocol::V5), 1024), Err(CodecError::TooLarge(1024)));
+        let mut buf = vec![0xC0, 0];
+        assert!(decode(&mut buf, None, 1024).is_err(), "CONNECT must come first");
+        let mut buf = vec![0x80, 3, 0, 1, 0];
+        assert!(decode(&mut buf, Some(Protocol::V311), 1024).is_err(), "SUBSCRIBE needs flags 0010");
+    }
+
+    #[test]
+    fn decodes_v5_publish_and_subscribe() {
+        let mut body = Vec::new();
+        put_string(&mut body, "news/alert");
+        body.extend_from_slice(&7u16.to_be_bytes());
+        body.extend_from_slice(&[5, 0x02, 0, 0, 0, 30]);
+        body.extend_from_slice(b"{}");
+        let mut buf = vec![0x33];
+        put_varint(&mut buf, body.len());
+        buf.extend_from_slice(&body);
+        let Some(Packet::Publish(p)) = decode(&mut buf, Some(Protocol::V5), 1024).unwrap() else {
+            panic!("expected PUBLISH");
+        };
+        assert_eq!((p.qos, p.retain, p.packet_id, p.message_expiry), (1, true, Some(7), Some(30)));
+
+        let mut body = vec![0, 9, 0];
+        put_string(&mut body, "news/#");
+        body.push(0x01);
+        let mut buf = vec![0x82];
+        put_varint(&mut buf, body.len());
+        buf.extend_from_slice(&body);
+        let packet = decode(&mut buf, Some(Protocol::V5), 1024).unwrap().unwrap();
+        let filter = SubscribeFilter {
+            filter: "news/#".to_string(),
+            options: 1,
+        };
+        assert_eq!(packet, Packet::Subscribe { packet_id: 9, filters: vec![filter] });
+    }
+
+    #[test]
+    fn encodes_per_protocol() {
+        let connack = Packet::ConnAck {
+            session_present: false,
+            code: reason::BAD_USERNAME_OR_PASSWORD,
+            assigned_client_id: None,
+            max_packet_size: 1024,
+        };
+        assert_eq!(encode(&connack, Protocol::V311), vec![0x20, 2, 0, 4]);
+        assert_eq!(
+            encode(&connack, Protocol::V5),
+            vec![0x20, 14, 0, 0x86, 11, 0x24, 1, 0x25, 1, 0x2A, 0, 0x27, 0, 0, 4, 0]
+        );
+        let suback = Packet::SubAck {
+            packet_id: 1,
+            codes: vec![reason::GRANTED_QOS_1, reason::NOT_AUTHORIZED],
+        };
+        assert_eq!(encode(&suback, Protocol::V311), vec![0x90, 4, 0, 1, 1, 0x80]);
+        assert_eq!(encode(&suback, Protocol::V5), vec![0x90, 5, 0, 1, 0, 1, 0x87]);
+        let publish = Packet::Publish(Publish {
+            qos: 1,
+            retain: true,
+            topic: "a/b".to_string(),
+            packet_id: Some(2),
+            payload: b"x".to_vec(),
+            ..Publish::default()
+        });
+        assert_eq!(encode(&publish, Protocol::V311), vec![0x33, 8, 0, 3, b'a', b'/', b'b', 0, 2, b'x']);
+        assert_eq!(encode(&Packet::PingResp, Protocol::V5), vec![0xD0, 0]);
+    }
+}
@@ -0,0 +1,736 @@
+//! Optional MQTT 3.1.1/5 listener (`MQTT_ADDR`) for devices: they publish into and subscribe
+//! from the same channels as WebSocket clients, through `ChannelService`.
+//!
+//! Topics are `<channel>/<event>`; the payload is the event data (JSON, else a string).
+//! Subscriptions name one channel: `<channel>/#` or `<channel>/+` for all its events, or
+//! `<channel>/<event>`. Cache channels hold the retained message. QoS 0 and 1 only; sessions
+//! are not persisted, so every connection starts clean.
+
+pub mod codec;
+
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::db::{domain_find_active_by_id, domain_find_by_key, DomainRow};
+use crate::error::{AppError, AppResult};
+use crate::handlers::http::{publish_broadcast, AppState};
+use crate::handlers::sse::authorize_channel;
+use crate::handlers::ws::EventMeta;
+use crate::models::channel::{is_cache_channel, is_reserved_channel, ChannelType};
+use crate::models::event::BroadcastRequest;
+use crate::models::presence::generate_socket_id;
+use crate::services::connection_token::{connection_token_domain_id, verify_connection_token, ConnectionClaims};
+use crate::services::control::ControlCommand;
+use crate::services::metrics::DeliveryPath;
+use codec::{decode, encode, reason, CodecError, Connect, Packet, Protocol, Publish, SubscribeFilter};
+
+/// Largest packet accepted from or sent to a client.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// Time a new connection has to send its CONNECT.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most topic filters one connection may hold.
+const MAX_MQTT_SUBSCRIPTIONS: usize = 100;
+
+/// Most QoS 1 messages awaiting a PUBACK; further ones are sent at QoS 0.
+const MAX_INFLIGHT: usize = 1000;
+
+/// Username of a connection-token login; the password is the token.
+const TOKEN_USERNAME: &str = "token";
+
+/// Accept MQTT connections, forever.
+pub async fn serve(listener: TcpListener, state: AppState) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle_connection(state.clone(), stream, peer));
+            }
+            Err(e) => {
+                warn!(error = %e, "mqtt accept failed");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Event part of a subscription's topic filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EventFilter {
+    /// `#`: every event.
+    All,
+    /// `+`: every event whose name is one topic level.
+    Level,
+    Exact(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TopicFilter {
+    channel: String,
+    events: EventFilter,
+}
+
+impl TopicFilter {
+    /// `<channel>/#`, `<channel>/+` or `<channel>/<event>`; wildcards in the channel level
+    /// and shared subscriptions are not supported.
+    fn parse(filter: &str) -> Option<Self> {
+        let (channel, event) = filter.split_once('/')?;
+        if !valid_level(channel) || channel.starts_with('$') || is_reserved_channel(channel) || event.is_empty() {
+            return None;
+        }
+        let events = match event {
+            "#" => EventFilter::All,
+            "+" => EventFilter::Level,
+            event if !event.contains(['+', '#']) => EventFilter::Exact(event.to_string()),
+            _ => return None,
+        };
+        Some(Self {
+            channel: channel.to_string(),
+            events,
+        })
+    }
+
+    fn matches(&self, event: &str) -> bool {
+        match &self.events {
+            EventFilter::All => true,
+            EventFilter::Level => !event.contains('/'),
+            EventFilter::Exact(name) => name == event,
+        }
+    }
+}
+
+fn valid_level(level: &str) -> bool {
+    !level.is_empty() && !level.contains(['+', '#'])
+}
+
+/// Channel and event of a PUBLISH topic.
+fn parse_topic(topic: &str) -> Option<(&str, &str)> {
+    let (channel, event) = topic.split_once('/')?;
+    if !valid_level(channel) || channel.starts_with('$') || event.is_empty() || event.contains(['+', '#']) {
+        return None;
+    }
+    Some((channel, event))
+}
+
+/// Event data of a PUBLISH payload: JSON if it parses, else the text.
+fn payload_data(payload: &[u8]) -> Option<serde_json::Value> {
+    let text = std::str::from_utf8(payload).ok()?;
+    Some(serde_json::from_str(text).unwrap_or_else(|_| json!(text)))
+}
+
+/// PUBLISH payload of event data: strings as is, anything else as JSON.
+fn data_payload(data: &serde_json::Value) -> Vec<u8> {
+    match data {
+        serde_json::Value::String(s) => s.clone().into_bytes(),
+        other => other.to_string().into_bytes(),
+    }
+}
+
+/// Who a CONNECT authenticated as.
+struct MqttIdentity {
+    domain: Option<DomainRow>,
+    /// Set for token logins: subscriptions limited to its channels, no publishing.
+    grant: Option<ConnectionClaims>,
+}
+
+/// Username `token` with a connection token as password, or an API key with its secret
+/// (the app key/secret, or a domain's key/secret). Returns a CONNACK reason code on failure.
+async fn authenticate(state: &AppState, connect: &Connect) -> Result<MqttIdentity, u8> {
+    let password = connect
+        .password
+        .as_deref()
+        .and_then(|p| std::str::from_utf8(p).ok())
+        .ok_or(reason::BAD_USERNAME_OR_PASSWORD)?;
+    let result = match connect.username.as_deref() {
+        Some(TOKEN_USERNAME) => authenticate_token(state, password).await,
+        Some(key) => authenticate_key(state, key, password).await,
+        None => return Err(reason::BAD_USERNAME_OR_PASSWORD),
+    };
+    result.map_err(|e| match e {
+        AppError::Auth(_) | AppError::Jwt(_) => reason::BAD_USERNAME_OR_PASSWORD,
+        e => {
+            warn!(error = %e, "mqtt authentication failed");
+            reason::UNSPECIFIED_ERROR
+        }
+    })
+}
+
+async fn authenticate_token(state: &AppState, token: &str) -> AppResult<MqttIdentity> {
+    let domain_id = connection_token_domain_id(token)?;
+    let domain = domain_find_active_by_id(state.db(), domain_id)
+        .await?
+        .ok_or_else(|| AppError::Auth("Invalid or inactive domain in connection token".to_string()))?;
+    let claims = verify_connection_token(token, &domain.secret)?;
+    Ok(MqttIdentity {
+        domain: Some(domain),
+        grant: Some(claims),
+    })
+}
+
+async fn authenticate_key(state: &AppState, key: &str, secret: &str) -> AppResult<MqttIdentity> {
+    if key == state.app_key {
+        if secret != state.app_secret {
+            return Err(AppError::Auth("invalid secret".to_string()));
+        }
+        return Ok(MqttIdentity { domain: None, grant: None });
+    }
+    match domain_find_by_key(state.db(), key).await? {
+        Some(domain) if domain.is_active && domain.secret == secret => Ok(MqttIdentity {
+            domain: Some(domain),
+            grant: None,
+        }),
+        _ => Err(AppError::Auth("invalid key or secret".to_string())),
+    }
+}
+
+async fn handle_connection(state: AppState, stream: TcpStream, peer: SocketAddr) {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut buf = Vec::new();
+    let connect = match tokio::time::timeout(CONNECT_TIMEOUT, read_connect(&mut reader, &mut buf)).await {
+        Ok(Ok(connect)) => connect,
+        Ok(Err(CodecError::UnsupportedProtocol(level))) => {
+            debug!(peer = %peer, level, "mqtt protocol not supported");
+            let connack = connack(reason::UNSUPPORTED_PROTOCOL_VERSION, None);
+            let _ = writer.write_all(&encode(&connack, Protocol::V311)).await;
+            return;
+        }
+        Ok(Err(e)) => {
+            debug!(peer = %peer, error = %e, "mqtt connect rejected");
+            return;
+        }
+        Err(_) => return,
+    };
+    let protocol = connect.protocol;
+    let identity = match authenticate(&state, &connect).await {
+        Ok(identity) => identity,
+        Err(code) => {
+            let _ = writer.write_all(&encode(&connack(code, None), protocol)).await;
+            return;
+        }
+    };
+    let (client_id, assigned) = match connect.client_id.as_str() {
+        "" if protocol == Protocol::V5 => (format!("notif-{}", Uuid::new_v4().simple()), true),
+        // 3.1.1 only accepts an empty id for a clean session; sessions are never kept here anyway.
+        "" => (format!("notif-{}", Uuid::new_v4().simple()), false),
+        id => (id.to_string(), false),
+    };
+    let ack = connack(reason::SUCCESS, assigned.then(|| client_id.clone()));
+    if writer.write_all(&encode(&ack, protocol)).await.is_err() {
+        return;
+    }
+    // Registered like a WebSocket so terminate, kick and ban reach it; a token's user is
+    // signed in so terminating the user's connections does too.
+    let socket_id = generate_socket_id();
+    let domain_id = identity.domain.as_ref().map(|d| d.id);
+    let mut control_rx = state.control_service().register(&socket_id, domain_id).await;
+    let user_id = identity.grant.as_ref().and_then(|g| g.user_id.clone());
+    if let Some(user_id) = &user_id {
+        // MQTT has no user events; the receiver is not needed.
+        if let Err(e) = state.user_service().sign_in(domain_id, user_id, &socket_id).await {
+            warn!(socket_id = %socket_id, error = %e, "mqtt signin failed");
+        }
+    }
+    info!(client_id = %client_id, socket_id = %socket_id, peer = %peer, "mqtt connected");
+
+    let (events, mut events_rx) = mpsc::unbounded_channel::<(String, String)>();
+    let max_packet_size = connect
+        .max_packet_size
+        .map_or(MAX_PACKET_SIZE, |size| (size as usize).min(MAX_PACKET_SIZE));
+    let keep_alive = Duration::from_secs(u64::from(connect.keep_alive) * 3 / 2);
+    let mut session = MqttSession {
+        state,
+        protocol,
+        writer,
+        max_packet_size,
+        identity,
+        channels: HashMap::new(),
+        events,
+        next_packet_id: 0,
+        inflight: HashSet::new(),
+    };
+    let mut will = connect.will;
+    let mut deadline = Instant::now() + keep_alive;
+
+    let mut clean = false;
+    'conn: loop {
+        tokio::select! {
+            read = reader.read_buf(&mut buf) => {
+                match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => deadline = Instant::now() + keep_alive,
+                }
+                loop {
+                    match decode(&mut buf, Some(protocol), MAX_PACKET_SIZE) {
+                        Ok(Some(Packet::Disconnect { code })) => {
+                            // 0x04: disconnect with will message.
+                            clean = code != 0x04;
+                            break 'conn;
+                        }
+                        Ok(Some(packet)) => {
+                            if !session.handle(packet).await {
+                                break 'conn;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            debug!(client_id = %client_id, error = %e, "mqtt protocol error");
+                            let code = match e {
+                                CodecError::TooLarge(_) => reason::PACKET_TOO_LARGE,
+                                _ => reason::MALFORMED_PACKET,
+                            };
+                            session.disconnect(code).await;
+                            break 'conn;
+                        }
+                    }
+                }
+            }
+            Some((channel, payload)) = events_rx.recv() => {
+                if !session.deliver(&channel, &payload, DeliveryPath::Live, false).await {
+                    break;
+                }
+            }
+            Some(command) = control_rx.recv() => match command {
+                ControlCommand::Terminate => {
+                    session.disconnect(reason::ADMINISTRATIVE_ACTION).await;
+                    info!(client_id = %client_id, "mqtt terminated");
+                    break;
+                }
+                ControlCommand::Subscribe { channels, .. } => session.server_subscribe(channels).await,
+                ControlCommand::Unsubscribe { channels } => session.server_unsubscribe(&channels),
+                ControlCommand::Kick { channel, user_id } => session.kick(&channel, &user_id),
+            },
+            _ = tokio::time::sleep_until(deadline), if !keep_alive.is_zero() => {
+                debug!(client_id = %client_id, "mqtt keep alive timeout");
+                session.disconnect(reason::KEEP_ALIVE_TIMEOUT).await;
+                break;
+            }
+        }
+    }
+
+    session.state.control_service().unregister(&socket_id).await;
+    if let Some(user_id) = &user_id {
+        // No resume over MQTT: the user is offline at once if this was their last socket.
+        let signed_out = session
+            .state
+            .user_service()
+            .sign_out(domain_id, user_id, &socket_id, Duration::ZERO)
+            .await;
+        if let Err(e) = signed_out {
+            warn!(socket_id = %socket_id, error = %e, "mqtt signout failed");
+        }
+    }
+    if let Some(will) = will.take().filter(|_| !clean) {
+        session.publish_will(will).await;
+    }
+    info!(client_id = %client_id, "mqtt disconnected");
+}
+
+/// Read until the first packet, which must be a CONNECT.
+async fn read_connect(reader: &mut tokio::net::tcp::OwnedReadHalf, buf: &mut Vec<u8>) -> Result<Connect, CodecError> {
+    loop {
+        if let Some(packet) = decode(buf, None, MAX_PACKET_SIZE)? {
+            let Packet::Connect(connect) = packet else {
+                return Err(CodecError::Malformed("first packet must be CONNECT"));
+            };
+            return Ok(connect);
+        }
+        match reader.read_buf(buf).await {
+            Ok(0) | Err(_) => return Err(CodecError::Malformed("connection closed before CONNECT")),
+            Ok(_) => {}
+        }
+    }
+}
+
+fn connack(code: u8, assigned_client_id: Option<String>) -> Packet {
+    Packet::ConnAck {
+        session_present: false,
+        code,
+        assigned_client_id,
+        max_packet_size: MAX_PACKET_SIZE as u32,
+    }
+}
+
+/// Topic filters on one channel. Dropping stops its forwarder.
+struct MqttChannel {
+    /// Filter text -> parsed filter and granted QoS.
+    filters: HashMap<String, (TopicFilter, u8)>,
+    forwarder: JoinHandle<()>,
+}
+
+impl Drop for MqttChannel {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+    }
+}
+
+/// State of one MQTT connection.
+struct MqttSession {
+    state: AppState,
+    protocol: Protocol,
+    writer: OwnedWriteHalf,
+    /// Largest packet the client accepts.
+    max_packet_size: usize,
+    identity: MqttIdentity,
+    channels: HashMap<String, MqttChannel>,
+    /// Channel forwarders send (channel, payload) here.
+    events: mpsc::UnboundedSender<(String, String)>,
+    next_packet_id: u16,
+    /// QoS 1 packet ids sent and not acked.
+    inflight: HashSet<u16>,
+}
+
+impl MqttSession {
+    /// Write a packet; `false` if the connection is gone.
+    async fn send(&mut self, packet: &Packet) -> bool {
+        self.writer.write_all(&encode(packet, self.protocol)).await.is_ok()
+    }
+
+    /// Tell a version 5 client why it is disconnected (3.1.1 just closes).
+    async fn disconnect(&mut self, code: u8) {
+        if self.protocol == Protocol::V5 {
+            self.send(&Packet::Disconnect { code }).await;
+        }
+    }
+
+    /// Handle a client packet; `false` closes the connection.
+    async fn handle(&mut self, packet: Packet) -> bool {
+        match packet {
+            Packet::Publish(publish) => self.publish(publish).await,
+            Packet::PubAck { packet_id, .. } => {
+                self.inflight.remove(&packet_id);
+                true
+            }
+            Packet::Subscribe { packet_id, filters } => self.subscribe(packet_id, filters).await,
+            Packet::Unsubscribe { packet_id, filters } => {
+                let codes = filters.iter().map(|f| self.unsubscribe(f)).collect();
+                self.send(&Packet::UnsubAck { packet_id, codes }).await
+            }
+            Packet::PingReq => self.send(&Packet::PingResp).await,
+            _ => {
+                self.disconnect(reason::PROTOCOL_ERROR).await;
+                false
+            }
+        }
+    }
+
+    /// Publish into the channel like `POST /api/broadcast`. A retained message with an empty
+    /// payload clears a cache channel's last event.
+    async fn publish(&mut self, publish: Publish) -> bool {
+        if publish.qos > 1 {
+            self.disconnect(reason::QOS_NOT_SUPPORTED).await;
+            return false;
+        }
+        let code = self.try_publish(&publish).await;
+        if code != reason::SUCCESS && self.protocol == Protocol::V311 {
+            // 3.1.1 has no negative acknowledgement: close instead of acking.
+            debug!(topic = %publish.topic, code, "mqtt publish refused");
+            return false;
+        }
+        match publish.packet_id {
+            Some(packet_id) if publish.qos == 1 => self.send(&Packet::PubAck { packet_id, code }).await,
+            _ => true,
+        }
+    }
+
+    async fn try_publish(&self, publish: &Publish) -> u8 {
+        if self.identity.grant.is_some() {
+            return reason::NOT_AUTHORIZED;
+        }
+        let Some((channel, event)) = parse_topic(&publish.topic) else {
+            return reason::TOPIC_NAME_INVALID;
+        };
+        if publish.retain && publish.payload.is_empty() {
+            if is_cache_channel(channel) {
+                if let Err(e) = self.state.channel_service.clear_cached_event(channel).await {
+                    warn!(error = %e, "clearing retained message failed");
+                    return reason::UNSPECIFIED_ERROR;
+                }
+            }
+            return reason::SUCCESS;
+        }
+        let Some(data) = payload_data(&publish.payload) else {
+            return reason::PAYLOAD_FORMAT_INVALID;
+        };
+        let body = BroadcastRequest {
+            channel: channel.to_string(),
+            event: event.to_string(),
+            data,
+            reliable: false,
+            idempotency_key: None,
+            deliver_at: None,
+            expires_at: None,
+            ttl: publish.message_expiry.map(u64::from),
+        };
+        match publish_broadcast(&self.state, self.identity.domain.as_ref(), body).await {
+            Ok(_) => reason::SUCCESS,
+            Err(AppError::InvalidChannel(_)) => reason::TOPIC_NAME_INVALID,
+            Err(AppError::Validation(_)) => reason::PAYLOAD_FORMAT_INVALID,
+            Err(e) => {
+                warn!(error = %e, "mqtt publish failed");
+                reason::UNSPECIFIED_ERROR
+            }
+        }
+    }
+
+    async fn subscribe(&mut self, packet_id: u16, filters: Vec<SubscribeFilter>) -> bool {
+        let mut codes = Vec::with_capacity(filters.len());
+        let mut retained = Vec::new();
+        for requested in &filters {
+            let (code, created) = self.add_filter(requested, true).await;
+            // Retain handling 2: no retained messages; 1: only for a new subscription.
+            let retain_handling = (requested.options >> 4) & 0b11;
+            if code <= reason::GRANTED_QOS_1 && (retain_handling == 0 || retain_handling == 1 && created) {
+                retained.push(requested.filter.clone());
+            }
+            codes.push(code);
+        }
+        if !self.send(&Packet::SubAck { packet_id, codes }).await {
+            return false;
+        }
+        for filter in retained {
+            if !self.send_retained(&filter).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the granted QoS or a failure reason code, and whether the filter is new.
+    /// Server-driven subscribes skip the channel authorization.
+    async fn add_filter(&mut self, requested: &SubscribeFilter, authorize: bool) -> (u8, bool) {
+        let Some(filter) = TopicFilter::parse(&requested.filter) else {
+            return (reason::TOPIC_FILTER_INVALID, false);
+        };
+        let count: usize = self.channels.values().map(|c| c.filters.len()).sum();
+        let existing = self
+            .channels
+            .get(&filter.channel)
+            .is_some_and(|c| c.filters.contains_key(&requested.filter));
+        if !existing && count >= MAX_MQTT_SUBSCRIPTIONS {
+            return (reason::UNSPECIFIED_ERROR, false);
+        }
+        if authorize && !self.may_subscribe(&filter.channel).await {
+            return (reason::NOT_AUTHORIZED, false);
+        }
+        let qos = requested.qos().min(1);
+        if !self.channels.contains_key(&filter.channel) {
+            let mut channel_rx = match self.state.channel_service.subscribe(&filter.channel).await {
+                Ok(rx) => rx,
+                Err(e) => {
+                    warn!(error = %e, "mqtt subscribe failed");
+                    return (reason::UNSPECIFIED_ERROR, false);
+                }
+            };
+            let events = self.events.clone();
+            let channel = filter.channel.clone();
+            let forwarder = tokio::spawn(async move {
+                while let Ok(payload) = channel_rx.recv().await {
+                    if events.send((channel.clone(), payload)).is_err() {
+                        break;
+                    }
+                }
+            });
+            self.channels.insert(
+                filter.channel.clone(),
+                MqttChannel {
+                    filters: HashMap::new(),
+                    forwarder,
+                },
+            );
+        }
+        if let Some(channel) = self.channels.get_mut(&filter.channel) {
+            channel.filters.insert(requested.filter.clone(), (filter, qos));
+        }
+        (qos, !existing)
+    }
+
+    /// Authorized as on `/sse`: private channels need a token login that allows them.
+    async fn may_subscribe(&self, channel: &str) -> bool {
+        let domain_id = self.identity.domain.as_ref().map(|d| d.id);
+        authorize_channel(&self.state, domain_id, self.identity.grant.as_ref(), channel)
+            .await
+            .is_ok()
+    }
+
+    /// Subscribe to every event of the channels at QoS 0, as told through the server API.
+    /// Presence channels need a WebSocket connection.
+    async fn server_subscribe(&mut self, channels: Vec<String>) {
+        for channel in channels {
+            if ChannelType::from_name(&channel) == ChannelType::Presence {
+                continue;
+            }
+            let requested = SubscribeFilter {
+                filter: format!("{}/#", channel),
+                options: 0,
+            };
+            let (code, _) = self.add_filter(&requested, false).await;
+            if code > reason::GRANTED_QOS_1 {
+                debug!(channel = %channel, code, "mqtt server-driven subscribe failed");
+            }
+        }
+    }
+
+    /// Drop every filter on the channels.
+    fn server_unsubscribe(&mut self, channels: &[String]) {
+        for channel in channels {
+            self.channels.remove(channel);
+        }
+    }
+
+    /// Drop the channel's filters if the token login is the kicked user.
+    fn kick(&mut self, channel: &str, user_id: &str) {
+        let kicked = self
+            .identity
+            .grant
+            .as_ref()
+            .is_some_and(|g| g.user_id.as_deref() == Some(user_id));
+        if kicked && self.channels.remove(channel).is_some() {
+            debug!(channel = %channel, user_id = %user_id, "mqtt subscription kicked");
+        }
+    }
+
+    fn unsubscribe(&mut self, filter: &str) -> u8 {
+        let Some(parsed) = TopicFilter::parse(filter) else {
+            return reason::NO_SUBSCRIPTION_EXISTED;
+        };
+        let Some(channel) = self.channels.get_mut(&parsed.channel) else {
+            return reason::NO_SUBSCRIPTION_EXISTED;
+        };
+        if channel.filters.remove(filter).is_none() {
+            return reason::NO_SUBSCRIPTION_EXISTED;
+        }
+        if channel.filters.is_empty() {
+            self.channels.remove(&parsed.channel);
+        }
+        reason::SUCCESS
+    }
+
+    /// The last event of a cache channel, as the retained message of a new subscription.
+    async fn send_retained(&mut self, filter: &str) -> bool {
+        let Some(channel) = TopicFilter::parse(filter).map(|f| f.channel) else {
+            return true;
+        };
+        if !is_cache_channel(&channel) {
+            return true;
+        }
+        match self.state.channel_service.cached_event(&channel).await {
+            Ok(Some(payload)) => self.deliver(&channel, &payload, DeliveryPath::Replay, true).await,
+            Ok(None) => true,
+            Err(e) => {
+                warn!(error = %e, "reading retained message failed");
+                true
+            }
+        }
+    }
+
+    /// Send a published event to the client if a filter matches; `false` if the connection is gone.
+    async fn deliver(&mut self, channel: &str, payload: &str, path: DeliveryPath, retain: bool) -> bool {
+        let meta = EventMeta::parse(payload);
+        if meta.is_expired() {
+            self.state.metrics().expired_dropped(path);
+            return true;
+        }
+        let Some(qos) = self.channels.get(channel).and_then(|c| {
+            c.filters
+                .values()
+                .filter(|(filter, _)| filter.matches(&meta.event))
+                .map(|(_, qos)| *qos)
+                .max()
+        }) else {
+            return true;
+        };
+        let data = serde_json::from_str::<serde_json::Value>(payload)
+            .map(|v| v["data"].clone())
+            .unwrap_or_default();
+        let qos = if self.inflight.len() >= MAX_INFLIGHT { 0 } else { qos };
+        let packet_id = (qos == 1).then(|| self.next_packet_id());
+        let message_expiry = meta
+            .expires_at
+            .filter(|_| self.protocol == Protocol::V5)
+            .map(|at| ((at - chrono::Utc::now().timestamp_millis()).max(1000) / 1000) as u32);
+        let packet = Packet::Publish(Publish {
+            dup: false,
+            qos,
+            retain,
+            topic: format!("{}/{}", channel, meta.event),
+            packet_id,
+            payload: data_payload(&data),
+            message_expiry,
+        });
+        let bytes = encode(&packet, self.protocol);
+        if bytes.len() > self.max_packet_size {
+            debug!(channel = %channel, "event larger than the client's maximum packet size");
+            return true;
+        }
+        if let Some(id) = packet_id {
+            self.inflight.insert(id);
+        }
+        self.writer.write_all(&bytes).await.is_ok()
+    }
+
+    /// Next free packet id (1-65535).
+    fn next_packet_id(&mut self) -> u16 {
+        loop {
+            self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+            if !self.inflight.contains(&self.next_packet_id) {
+                return self.next_packet_id;
+            }
+        }
+    }
+
+    /// Publish the will message of a connection that ended without DISCONNECT.
+    async fn publish_will(&self, will: Publish) {
+        let code = self.try_publish(&will).await;
+        if code != reason::SUCCESS {
+            debug!(topic = %will.topic, code, "mqtt will not published");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_filters_name_one_channel() {
+        let all = TopicFilter::parse("cache-prices/#").unwrap();
+        assert_eq!(all.channel, "cache-prices");
+        assert!(all.matches("update") && all.matches("a/b"));
+        let level = TopicFilter::parse("news/+").unwrap();
+        assert!(level.matches("alert") && !level.matches("a/b"));
+        let exact = TopicFilter::parse("news/alert").unwrap();
+        assert!(exact.matches("alert") && !exact.matches("other"));
+        for invalid in ["#", "+/alert", "news", "news/", "news/a+", "#control/+", "$share/g/news"] {
+            assert_eq!(TopicFilter::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn topics_map_to_channel_and_event() {
+        assert_eq!(parse_topic("news/alert"), Some(("news", "alert")));
+        assert_eq!(parse_topic("devices-7/state/power"), Some(("devices-7", "state/power")));
+        assert_eq!(parse_topic("news"), None);
+        assert_eq!(parse_topic("news/+"), None);
+        assert_eq!(parse_topic("$SYS/x"), None);
+    }
+
+    #[test]
+    fn payloads_round_trip_as_event_data() {
+        assert_eq!(payload_data(br#"{"on":true}"#), Some(json!({ "on": true })));
+        assert_eq!(payload_data(b"21.5"), Some(json!(21.5)));
+        assert_eq!(payload_data(b"hello"), Some(json!("hello")));
+        assert_eq!(payload_data(&[0xFF, 0xFE]), None);
+        assert_eq!(data_payload(&json!("hello")), b"hello");
+        assert_eq!(data_payload(&json!({ "on": true })), br#"{"on":true}"#);
+    }
+}
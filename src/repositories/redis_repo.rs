@@ -1,6 +1,7 @@
 //! Redis connection and pub/sub for channel messaging and presence storage.
 
 use crate::error::AppError;
+use redis::streams::{StreamMaxlen, StreamRangeReply};
 use redis::AsyncCommands;
 use std::sync::Arc;
 use tokio::sync::broadcast;
@@ -11,6 +12,32 @@ use futures::StreamExt;
 const CHANNEL_PREFIX: &str = "notif:channel:";
 const PRESENCE_SET_PREFIX: &str = "notif:presence:";
 const PRESENCE_HASH_PREFIX: &str = "notif:presence_hash:";
+const PRESENCE_USERS_PREFIX: &str = "notif:presence_users:";
+const PRESENCE_INDEX_PREFIX: &str = "notif:presence_index:";
+const CACHE_PREFIX: &str = "notif:cache:";
+const HISTORY_PREFIX: &str = "notif:history:";
+const SEQ_PREFIX: &str = "notif:seq:";
+const RECOVERY_PREFIX: &str = "notif:recovery:";
+const RESUME_PREFIX: &str = "notif:resume:";
+const PENDING_PREFIX: &str = "notif:pending:";
+const DELIVERY_PREFIX: &str = "notif:delivery:";
+const RELIABLE_RECIPIENTS_PREFIX: &str = "notif:reliable_recipients:";
+const IDEMPOTENCY_PREFIX: &str = "notif:idempotency:";
+const SCHEDULE_PREFIX: &str = "notif:schedule:";
+const SCHEDULE_QUEUE: &str = "notif:schedule_queue";
+const SCHEDULE_PROCESSING: &str = "notif:schedule_processing";
+const SCHEDULE_INDEX_PREFIX: &str = "notif:schedules:";
+const USER_SOCKETS_PREFIX: &str = "notif:user_heartbeats:";
+const USER_HEARTBEAT_INDEX: &str = "notif:user_heartbeat_index";
+const USER_OFFLINE_QUEUE: &str = "notif:user_offline_queue";
+const BAN_PREFIX: &str = "notif:ban:";
+const BAN_INDEX_PREFIX: &str = "notif:bans:";
+const LAST_SEEN_USER_PREFIX: &str = "notif:last_seen:";
+const LAST_SEEN_CHANNEL_PREFIX: &str = "notif:last_seen_channel:";
+const POLL_SESSION_PREFIX: &str = "notif:poll:";
+const POLL_OUTBOX_PREFIX: &str = "notif:poll_outbox:";
+const POLL_CURSOR_PREFIX: &str = "notif:poll_cursor:";
+const POLL_OWNER_PREFIX: &str = "notif:poll_owner:";
 
 fn channel_key(channel: &str) -> String {
     format!("{}{}", CHANNEL_PREFIX, channel)
@@ -75,31 +102,897 @@ impl RedisRepository {
         Ok(rx)
     }
 
+    // --- Cache channels: last event per channel, with TTL ---
+
+    /// Store the last event of a cache channel.
+    pub async fn cache_set(&self, channel: &str, message: &str, ttl_secs: u64) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", CACHE_PREFIX, channel);
+        conn.set_ex::<_, _, ()>(&key, message, ttl_secs).await?;
+        Ok(())
+    }
+
+    /// Last event of a cache channel, if not expired.
+    pub async fn cache_get(&self, channel: &str) -> Result<Option<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", CACHE_PREFIX, channel);
+        let message: Option<String> = conn.get(&key).await?;
+        Ok(message)
+    }
+
+    /// Forget the last event of a cache channel. `true` if there was one.
+    pub async fn cache_delete(&self, channel: &str) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let removed: u64 = conn.del(format!("{}{}", CACHE_PREFIX, channel)).await?;
+        Ok(removed > 0)
+    }
+
+    // --- Connection recovery: per-channel sequence numbers, replay buffer, resumable sessions ---
+
+    /// Next sequence number of a channel (starts at 1).
+    pub async fn next_seq(&self, channel: &str) -> Result<u64, AppError> {
+        let mut conn = self.connection().await?;
+        let seq: u64 = conn.incr(format!("{}{}", SEQ_PREFIX, channel), 1).await?;
+        Ok(seq)
+    }
+
+    /// Last sequence number assigned on a channel (0 if none).
+    pub async fn current_seq(&self, channel: &str) -> Result<u64, AppError> {
+        let mut conn = self.connection().await?;
+        let seq: Option<u64> = conn.get(format!("{}{}", SEQ_PREFIX, channel)).await?;
+        Ok(seq.unwrap_or(0))
+    }
+
+    /// Keep an event in the channel's replay buffer (latest `max_len`, expiring after `ttl_secs` idle).
+    pub async fn recovery_append(
+        &self,
+        channel: &str,
+        seq: u64,
+        message: &str,
+        max_len: usize,
+        ttl_secs: u64,
+    ) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", RECOVERY_PREFIX, channel);
+        redis::pipe()
+            .zadd(&key, message, seq)
+            .ignore()
+            .zremrangebyrank(&key, 0, -(max_len as isize) - 1)
+            .ignore()
+            .expire(&key, ttl_secs as i64)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Buffered `(seq, message)` entries with seq greater than `after_seq`, ascending.
+    pub async fn recovery_after(&self, channel: &str, after_seq: u64) -> Result<Vec<(u64, String)>, AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", RECOVERY_PREFIX, channel);
+        let entries: Vec<(String, u64)> = conn
+            .zrangebyscore_withscores(&key, format!("({}", after_seq), "+inf")
+            .await?;
+        Ok(entries.into_iter().map(|(message, seq)| (seq, message)).collect())
+    }
+
+    /// Store a disconnected session for `ttl_secs`.
+    pub async fn resume_save(&self, token: &str, session: &str, ttl_secs: u64) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        conn.set_ex::<_, _, ()>(format!("{}{}", RESUME_PREFIX, token), session, ttl_secs)
+            .await?;
+        Ok(())
+    }
+
+    /// Claim a stored session; only one caller gets it.
+    pub async fn resume_take(&self, token: &str) -> Result<Option<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let session: Option<String> = redis::cmd("GETDEL")
+            .arg(format!("{}{}", RESUME_PREFIX, token))
+            .query_async(&mut conn)
+            .await?;
+        Ok(session)
+    }
+
+    /// Drop a stored session. `true` if it was still there (i.e. not resumed).
+    pub async fn resume_discard(&self, token: &str) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let removed: u64 = conn.del(format!("{}{}", RESUME_PREFIX, token)).await?;
+        Ok(removed > 0)
+    }
+
+    // --- Reliable delivery: unacked events per recipient, delivery status per event ---
+
+    /// Keep an unacked event for a recipient (hash event id -> payload).
+    pub async fn pending_add(
+        &self,
+        recipient: &str,
+        event_id: &str,
+        message: &str,
+        ttl_secs: u64,
+    ) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", PENDING_PREFIX, recipient);
+        redis::pipe()
+            .hset(&key, event_id, message)
+            .ignore()
+            .expire(&key, ttl_secs as i64)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Drop an acked event. `true` if it was pending.
+    pub async fn pending_remove(&self, recipient: &str, event_id: &str) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let removed: u64 = conn.hdel(format!("{}{}", PENDING_PREFIX, recipient), event_id).await?;
+        Ok(removed > 0)
+    }
+
+    /// Whether an event is still unacked by a recipient.
+    pub async fn pending_exists(&self, recipient: &str, event_id: &str) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let exists: bool = conn.hexists(format!("{}{}", PENDING_PREFIX, recipient), event_id).await?;
+        Ok(exists)
+    }
+
+    /// All unacked events of a recipient (event id -> payload).
+    pub async fn pending_all(&self, recipient: &str) -> Result<Vec<(String, String)>, AppError> {
+        let mut conn = self.connection().await?;
+        let map: std::collections::HashMap<String, String> =
+            conn.hgetall(format!("{}{}", PENDING_PREFIX, recipient)).await?;
+        Ok(map.into_iter().collect())
+    }
+
+    /// Set one field of an event's delivery status hash.
+    pub async fn delivery_set(
+        &self,
+        event_id: &str,
+        field: &str,
+        value: &str,
+        ttl_secs: u64,
+    ) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", DELIVERY_PREFIX, event_id);
+        redis::pipe()
+            .hset(&key, field, value)
+            .ignore()
+            .expire(&key, ttl_secs as i64)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// One field of an event's delivery status hash.
+    pub async fn delivery_get(&self, event_id: &str, field: &str) -> Result<Option<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let value: Option<String> = conn.hget(format!("{}{}", DELIVERY_PREFIX, event_id), field).await?;
+        Ok(value)
+    }
+
+    /// The whole delivery status hash of an event.
+    pub async fn delivery_all(&self, event_id: &str) -> Result<Vec<(String, String)>, AppError> {
+        let mut conn = self.connection().await?;
+        let map: std::collections::HashMap<String, String> =
+            conn.hgetall(format!("{}{}", DELIVERY_PREFIX, event_id)).await?;
+        Ok(map.into_iter().collect())
+    }
+
+    /// Remember a recipient of a channel's reliable events (hash recipient -> status label),
+    /// kept `ttl_secs` after its last subscribe.
+    pub async fn reliable_recipient_add(
+        &self,
+        channel: &str,
+        recipient: &str,
+        label: &str,
+        ttl_secs: u64,
+    ) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", RELIABLE_RECIPIENTS_PREFIX, channel);
+        redis::pipe()
+            .hset(&key, recipient, label)
+            .ignore()
+            .expire(&key, ttl_secs as i64)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Recipients of a channel's reliable events (recipient -> status label).
+    pub async fn reliable_recipients(&self, channel: &str) -> Result<Vec<(String, String)>, AppError> {
+        let mut conn = self.connection().await?;
+        let map: std::collections::HashMap<String, String> =
+            conn.hgetall(format!("{}{}", RELIABLE_RECIPIENTS_PREFIX, channel)).await?;
+        Ok(map.into_iter().collect())
+    }
+
+    /// Record a reliable event before it is published, atomically: the status hash with
+    /// `meta` and one status per recipient, and the event pending for each recipient.
+    /// `recipients` are (recipient, status label, status).
+    pub async fn delivery_open(
+        &self,
+        event_id: &str,
+        meta: (&str, &str),
+        recipients: &[(String, String, String)],
+        payload: &str,
+        ttl_secs: u64,
+    ) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", DELIVERY_PREFIX, event_id);
+        let mut pipe = redis::pipe();
+        pipe.atomic().hset(&key, meta.0, meta.1).ignore();
+        for (recipient, label, status) in recipients {
+            let pending = format!("{}{}", PENDING_PREFIX, recipient);
+            pipe.hset(&key, label, status)
+                .ignore()
+                .hset(&pending, event_id, payload)
+                .ignore()
+                .expire(&pending, ttl_secs as i64)
+                .ignore();
+        }
+        pipe.expire(&key, ttl_secs as i64).ignore();
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Undo [`Self::delivery_open`] for an event that could not be published.
+    pub async fn delivery_cancel(&self, event_id: &str, recipients: &[String]) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(format!("{}{}", DELIVERY_PREFIX, event_id)).ignore();
+        for recipient in recipients {
+            pipe.hdel(format!("{}{}", PENDING_PREFIX, recipient), event_id).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    // --- Idempotency keys of the publish API ---
+
+    /// Store `record` under `key` only if absent (SET NX EX). `true` if stored.
+    pub async fn idempotency_claim(&self, key: &str, record: &str, ttl_secs: u64) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let stored: Option<String> = redis::cmd("SET")
+            .arg(format!("{}{}", IDEMPOTENCY_PREFIX, key))
+            .arg(record)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_secs)
+            .query_async(&mut conn)
+            .await?;
+        Ok(stored.is_some())
+    }
+
+    pub async fn idempotency_get(&self, key: &str) -> Result<Option<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let record: Option<String> = conn.get(format!("{}{}", IDEMPOTENCY_PREFIX, key)).await?;
+        Ok(record)
+    }
+
+    /// Overwrite the record under `key`.
+    pub async fn idempotency_store(&self, key: &str, record: &str, ttl_secs: u64) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        conn.set_ex::<_, _, ()>(format!("{}{}", IDEMPOTENCY_PREFIX, key), record, ttl_secs)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete the record under `key` if it is still `record`.
+    pub async fn idempotency_release(&self, key: &str, record: &str) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        redis::Script::new(
+            r"
+            if redis.call('GET', KEYS[1]) == ARGV[1] then
+                return redis.call('DEL', KEYS[1])
+            end
+            return 0
+            ",
+        )
+        .key(format!("{}{}", IDEMPOTENCY_PREFIX, key))
+        .arg(record)
+        .invoke_async::<_, ()>(&mut conn)
+        .await?;
+        Ok(())
+    }
+
+    // --- Scheduled broadcasts: record per schedule, queue by due time, index per domain ---
+
+    /// Store a schedule, index it and queue its first run at `due_ms`, atomically.
+    pub async fn schedule_create(&self, scope: &str, id: &str, record: &str, due_ms: i64) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        redis::pipe()
+            .atomic()
+            .set(format!("{}{}", SCHEDULE_PREFIX, id), record)
+            .ignore()
+            .sadd(format!("{}{}", SCHEDULE_INDEX_PREFIX, scope), id)
+            .ignore()
+            .zadd(SCHEDULE_QUEUE, id, due_ms)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Overwrite a schedule only if it still exists (SET XX). `true` if updated.
+    pub async fn schedule_update(&self, id: &str, record: &str) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let updated: Option<String> = redis::cmd("SET")
+            .arg(format!("{}{}", SCHEDULE_PREFIX, id))
+            .arg(record)
+            .arg("XX")
+            .query_async(&mut conn)
+            .await?;
+        Ok(updated.is_some())
+    }
+
+    /// Schedule records in the order of `ids` (`None` where missing).
+    pub async fn schedule_get_many(&self, ids: &[String]) -> Result<Vec<Option<String>>, AppError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.connection().await?;
+        let keys: Vec<String> = ids.iter().map(|id| format!("{}{}", SCHEDULE_PREFIX, id)).collect();
+        let records: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?;
+        Ok(records)
+    }
+
+    /// Drop a schedule with its index entry, queued run and lease, atomically.
+    pub async fn schedule_delete(&self, scope: &str, id: &str) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        redis::pipe()
+            .atomic()
+            .del(format!("{}{}", SCHEDULE_PREFIX, id))
+            .ignore()
+            .srem(format!("{}{}", SCHEDULE_INDEX_PREFIX, scope), id)
+            .ignore()
+            .zrem(SCHEDULE_QUEUE, id)
+            .ignore()
+            .zrem(SCHEDULE_PROCESSING, id)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Queue the next run at `due_ms` and end the lease of the current one, atomically.
+    pub async fn schedule_requeue(&self, id: &str, due_ms: i64) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        redis::pipe()
+            .atomic()
+            .zadd(SCHEDULE_QUEUE, id, due_ms)
+            .ignore()
+            .zrem(SCHEDULE_PROCESSING, id)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Move a queued run to the processing set with a lease until `lease_until_ms`.
+    /// `true` for the single caller that moved it (the claim).
+    pub async fn schedule_claim(&self, id: &str, lease_until_ms: i64) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let claimed: u64 = redis::Script::new(
+            r"
+            if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+                return 0
+            end
+            redis.call('ZADD', KEYS[2], ARGV[2], ARGV[1])
+            return 1
+            ",
+        )
+        .key(SCHEDULE_QUEUE)
+        .key(SCHEDULE_PROCESSING)
+        .arg(id)
+        .arg(lease_until_ms)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(claimed == 1)
+    }
+
+    /// End the lease of a run that is done.
+    pub async fn schedule_release(&self, id: &str) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        conn.zrem::<_, _, ()>(SCHEDULE_PROCESSING, id).await?;
+        Ok(())
+    }
+
+    /// Put up to `limit` runs whose lease ended before `now_ms` back in the queue, due now.
+    /// Returns their ids.
+    pub async fn schedule_reclaim(&self, now_ms: i64, limit: usize) -> Result<Vec<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let ids: Vec<String> = redis::Script::new(
+            r"
+            local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+            for _, id in ipairs(ids) do
+                redis.call('ZREM', KEYS[1], id)
+                redis.call('ZADD', KEYS[2], ARGV[1], id)
+            end
+            return ids
+            ",
+        )
+        .key(SCHEDULE_PROCESSING)
+        .key(SCHEDULE_QUEUE)
+        .arg(now_ms)
+        .arg(limit)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(ids)
+    }
+
+    /// Ids of runs due at or before `now_ms`, soonest first.
+    pub async fn schedule_queue_due(&self, now_ms: i64, limit: usize) -> Result<Vec<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let ids: Vec<String> = conn
+            .zrangebyscore_limit(SCHEDULE_QUEUE, "-inf", now_ms, 0, limit as isize)
+            .await?;
+        Ok(ids)
+    }
+
+    pub async fn schedule_index_remove(&self, scope: &str, id: &str) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        conn.srem::<_, _, ()>(format!("{}{}", SCHEDULE_INDEX_PREFIX, scope), id).await?;
+        Ok(())
+    }
+
+    pub async fn schedule_index_members(&self, scope: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let ids: Vec<String> = conn.smembers(format!("{}{}", SCHEDULE_INDEX_PREFIX, scope)).await?;
+        Ok(ids)
+    }
+
+    pub async fn schedule_index_count(&self, scope: &str) -> Result<usize, AppError> {
+        let mut conn = self.connection().await?;
+        let count: usize = conn.scard(format!("{}{}", SCHEDULE_INDEX_PREFIX, scope)).await?;
+        Ok(count)
+    }
+
+    // --- Channel bans: record per ban (expiring with the ban), index per domain ---
+
+    /// Store a ban and index it. Without `ttl_secs` it does not expire.
+    pub async fn ban_save(&self, scope: &str, id: &str, record: &str, ttl_secs: Option<u64>) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}:{}", BAN_PREFIX, scope, id);
+        match ttl_secs {
+            Some(ttl) => conn.set_ex::<_, _, ()>(&key, record, ttl).await?,
+            None => conn.set::<_, _, ()>(&key, record).await?,
+        }
+        conn.sadd::<_, _, ()>(format!("{}{}", BAN_INDEX_PREFIX, scope), id).await?;
+        Ok(())
+    }
+
+    /// Ban records in the order of `ids` (`None` where missing or expired).
+    pub async fn ban_get_many(&self, scope: &str, ids: &[String]) -> Result<Vec<Option<String>>, AppError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.connection().await?;
+        let keys: Vec<String> = ids.iter().map(|id| format!("{}{}:{}", BAN_PREFIX, scope, id)).collect();
+        let records: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?;
+        Ok(records)
+    }
+
+    /// Remove a ban. `true` if it was active.
+    pub async fn ban_delete(&self, scope: &str, id: &str) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let removed: u64 = conn.del(format!("{}{}:{}", BAN_PREFIX, scope, id)).await?;
+        conn.srem::<_, _, ()>(format!("{}{}", BAN_INDEX_PREFIX, scope), id).await?;
+        Ok(removed > 0)
+    }
+
+    pub async fn ban_index_members(&self, scope: &str) -> Result<Vec<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let ids: Vec<String> = conn.smembers(format!("{}{}", BAN_INDEX_PREFIX, scope)).await?;
+        Ok(ids)
+    }
+
+    pub async fn ban_index_remove(&self, scope: &str, id: &str) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        conn.srem::<_, _, ()>(format!("{}{}", BAN_INDEX_PREFIX, scope), id).await?;
+        Ok(())
+    }
+
+    // --- Signed-in users: socket ids per user, scored by last heartbeat (Unix ms) ---
+
+    /// Associate a socket with a user at `now_ms`, dropping sockets whose last heartbeat is
+    /// before `stale_ms` (their node is gone). The set expires `ttl_secs` after the last
+    /// heartbeat. Returns the user's live socket count after adding and whether an offline
+    /// queued by [`Self::user_socket_remove`] was called off, atomically.
+    pub async fn user_socket_add(
+        &self,
+        user: &str,
+        socket_id: &str,
+        now_ms: i64,
+        stale_ms: i64,
+        ttl_secs: u64,
+    ) -> Result<(usize, bool), AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", USER_SOCKETS_PREFIX, user);
+        let (held, count): (usize, usize) = redis::pipe()
+            .atomic()
+            .zrembyscore(&key, "-inf", format!("({}", stale_ms))
+            .ignore()
+            .zadd(&key, socket_id, now_ms)
+            .ignore()
+            .expire(&key, ttl_secs as i64)
+            .ignore()
+            .zadd(USER_HEARTBEAT_INDEX, user, now_ms)
+            .ignore()
+            .zrem(USER_OFFLINE_QUEUE, user)
+            .zcard(&key)
+            .query_async(&mut conn)
+            .await?;
+        Ok((count, held > 0))
+    }
+
+    /// Returns whether the socket was signed in and the user's live socket count after
+    /// removing (see [`Self::user_socket_add`] for `stale_ms`). When the last socket left,
+    /// the user is queued to go offline at `offline_at_ms`.
+    pub async fn user_socket_remove(
+        &self,
+        user: &str,
+        socket_id: &str,
+        stale_ms: i64,
+        offline_at_ms: i64,
+    ) -> Result<(bool, usize), AppError> {
+        let mut conn = self.connection().await?;
+        let (removed, count): (usize, usize) = redis::Script::new(
+            r"
+            local removed = redis.call('ZREM', KEYS[1], ARGV[1])
+            redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', '(' .. ARGV[2])
+            local count = redis.call('ZCARD', KEYS[1])
+            if count == 0 then
+                redis.call('ZREM', KEYS[2], ARGV[3])
+                if removed == 1 then
+                    redis.call('ZADD', KEYS[3], ARGV[4], ARGV[3])
+                end
+            end
+            return {removed, count}
+            ",
+        )
+        .key(format!("{}{}", USER_SOCKETS_PREFIX, user))
+        .key(USER_HEARTBEAT_INDEX)
+        .key(USER_OFFLINE_QUEUE)
+        .arg(socket_id)
+        .arg(stale_ms)
+        .arg(user)
+        .arg(offline_at_ms)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok((removed > 0, count))
+    }
+
+    /// Users queued to go offline at or before `now_ms`, at most `limit`.
+    pub async fn user_offline_due(&self, now_ms: i64, limit: usize) -> Result<Vec<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let users: Vec<String> = conn
+            .zrangebyscore_limit(USER_OFFLINE_QUEUE, "-inf", now_ms, 0, limit as isize)
+            .await?;
+        Ok(users)
+    }
+
+    /// Take a queued offline. `true` if this caller took it and the user still has no
+    /// socket with a heartbeat at or after `stale_ms`.
+    pub async fn user_offline_take(&self, user: &str, stale_ms: i64) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let offline: u64 = redis::Script::new(
+            r"
+            if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+                return 0
+            end
+            if redis.call('ZCOUNT', KEYS[2], ARGV[2], '+inf') > 0 then
+                return 0
+            end
+            return 1
+            ",
+        )
+        .key(USER_OFFLINE_QUEUE)
+        .key(format!("{}{}", USER_SOCKETS_PREFIX, user))
+        .arg(user)
+        .arg(stale_ms)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(offline == 1)
+    }
+
+    /// Users whose latest heartbeat is before `stale_ms`, at most `limit`.
+    pub async fn user_stale(&self, stale_ms: i64, limit: usize) -> Result<Vec<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let users: Vec<String> = conn
+            .zrangebyscore_limit(USER_HEARTBEAT_INDEX, "-inf", format!("({}", stale_ms), 0, limit as isize)
+            .await?;
+        Ok(users)
+    }
+
+    /// Drop the user's sockets with a heartbeat before `stale_ms`. `true` if this removed
+    /// the user's last socket (a dead node took the user offline).
+    pub async fn user_socket_reap(&self, user: &str, stale_ms: i64) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let offline: u64 = redis::Script::new(
+            r"
+            local pruned = redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', '(' .. ARGV[1])
+            local latest = redis.call('ZRANGE', KEYS[1], -1, -1, 'WITHSCORES')
+            if latest[2] then
+                redis.call('ZADD', KEYS[2], latest[2], ARGV[2])
+                return 0
+            end
+            redis.call('ZREM', KEYS[2], ARGV[2])
+            if pruned > 0 then
+                return 1
+            end
+            return 0
+            ",
+        )
+        .key(format!("{}{}", USER_SOCKETS_PREFIX, user))
+        .key(USER_HEARTBEAT_INDEX)
+        .arg(stale_ms)
+        .arg(user)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(offline == 1)
+    }
+
+    /// Refresh the heartbeat of signed-in sockets, given as (user, socket id), and of their
+    /// users in the heartbeat index. Sockets that signed out meanwhile are not added back.
+    pub async fn user_socket_heartbeat(&self, sockets: &[(String, String)], now_ms: i64, ttl_secs: u64) -> Result<(), AppError> {
+        if sockets.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.connection().await?;
+        let mut pipe = redis::pipe();
+        for (user, socket_id) in sockets {
+            let key = format!("{}{}", USER_SOCKETS_PREFIX, user);
+            pipe.cmd("ZADD")
+                .arg(&key)
+                .arg("XX")
+                .arg(now_ms)
+                .arg(socket_id)
+                .ignore()
+                .expire(&key, ttl_secs as i64)
+                .ignore()
+                .zadd(USER_HEARTBEAT_INDEX, user, now_ms)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Socket ids signed in as a user with a heartbeat at or after `stale_ms`, on any node.
+    pub async fn user_sockets(&self, user: &str, stale_ms: i64) -> Result<Vec<String>, AppError> {
+        let mut conn = self.connection().await?;
+        let members: Vec<String> = conn
+            .zrangebyscore(format!("{}{}", USER_SOCKETS_PREFIX, user), stale_ms, "+inf")
+            .await?;
+        Ok(members)
+    }
+
+    // --- Channel history: one stream per domain and channel, entry field `payload` ---
+
+    /// Append an event to a history stream (see `history_stream`). Keeps at most `max_len`
+    /// entries and, with `min_id`, none older than it; `ttl_secs` expires the whole stream
+    /// once idle. Returns the new entry id.
+    pub async fn history_append(
+        &self,
+        stream: &str,
+        message: &str,
+        max_len: usize,
+        min_id: Option<&str>,
+        ttl_secs: Option<u64>,
+    ) -> Result<String, AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", HISTORY_PREFIX, stream);
+        let id: String = conn
+            .xadd_maxlen(&key, StreamMaxlen::Equals(max_len), "*", &[("payload", message)])
+            .await?;
+        if let Some(min_id) = min_id {
+            redis::cmd("XTRIM")
+                .arg(&key)
+                .arg("MINID")
+                .arg(min_id)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+        }
+        if let Some(ttl) = ttl_secs {
+            conn.expire::<_, ()>(&key, ttl as i64).await?;
+        }
+        Ok(id)
+    }
+
+    /// History entries `(id, payload)` between `start` and `end` (stream range syntax:
+    /// `-`, `+`, `<id>`, `(<id>` for exclusive), newest first.
+    pub async fn history_rev_range(
+        &self,
+        stream: &str,
+        end: &str,
+        start: &str,
+        count: usize,
+    ) -> Result<Vec<(String, String)>, AppError> {
+        let mut conn = self.connection().await?;
+        let key = format!("{}{}", HISTORY_PREFIX, stream);
+        let reply: StreamRangeReply = conn.xrevrange_count(&key, end, start, count).await?;
+        Ok(reply
+            .ids
+            .into_iter()
+            .filter_map(|entry| {
+                let payload: String = entry.get("payload")?;
+                Some((entry.id, payload))
+            })
+            .collect())
+    }
+
     // --- Presence: store socket_id -> member in Redis SET and HASH for presence-* channels ---
+    // Per channel also: socket count per user id, and `user_id \0 socket_id` in a
+    // lexicographic ZSET for paging by user id.
 
-    /// Add a presence member to a channel.
+    /// Add a presence member to a channel and count the socket for `user_id`, unless the
+    /// user is new and the channel already has `max_users` users. `false` if full.
     pub async fn presence_add(
         &self,
         channel: &str,
         socket_id: &str,
+        user_id: &str,
         member_data: &str,
-    ) -> Result<(), AppError> {
+        max_users: usize,
+    ) -> Result<bool, AppError> {
         let mut conn = self.connection().await?;
-        let set_key = format!("{}{}", PRESENCE_SET_PREFIX, channel);
-        let hash_key = format!("{}{}", PRESENCE_HASH_PREFIX, channel);
-        conn.sadd::<_, _, ()>(&set_key, socket_id).await?;
-        conn.hset::<_, _, _, ()>(&hash_key, socket_id, member_data).await?;
-        Ok(())
+        let added: u64 = redis::Script::new(
+            r"
+            if redis.call('HEXISTS', KEYS[3], ARGV[2]) == 0
+                and redis.call('HLEN', KEYS[3]) >= tonumber(ARGV[4]) then
+                return 0
+            end
+            redis.call('SADD', KEYS[1], ARGV[1])
+            if redis.call('HSET', KEYS[2], ARGV[1], ARGV[3]) == 1 then
+                redis.call('HINCRBY', KEYS[3], ARGV[2], 1)
+                redis.call('ZADD', KEYS[4], 0, ARGV[2] .. '\0' .. ARGV[1])
+            end
+            return 1
+            ",
+        )
+        .key(format!("{}{}", PRESENCE_SET_PREFIX, channel))
+        .key(format!("{}{}", PRESENCE_HASH_PREFIX, channel))
+        .key(format!("{}{}", PRESENCE_USERS_PREFIX, channel))
+        .key(format!("{}{}", PRESENCE_INDEX_PREFIX, channel))
+        .arg(socket_id)
+        .arg(user_id)
+        .arg(member_data)
+        .arg(max_users)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(added == 1)
     }
 
-    /// Remove a presence member.
-    pub async fn presence_remove(&self, channel: &str, socket_id: &str) -> Result<(), AppError> {
+    /// Replace a member's data if the socket is still on the channel, checked in the same
+    /// script so a concurrent remove cannot leave it behind. `true` if updated.
+    pub async fn presence_update(&self, channel: &str, socket_id: &str, member_data: &str) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let updated: u64 = redis::Script::new(
+            r"
+            if redis.call('HEXISTS', KEYS[1], ARGV[1]) == 0 then
+                return 0
+            end
+            redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+            return 1
+            ",
+        )
+        .key(format!("{}{}", PRESENCE_HASH_PREFIX, channel))
+        .arg(socket_id)
+        .arg(member_data)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(updated == 1)
+    }
+
+    /// Remove a presence member of `user_id`. Returns the user's sockets left on the
+    /// channel, or `None` if the socket was not a member.
+    pub async fn presence_remove(
+        &self,
+        channel: &str,
+        socket_id: &str,
+        user_id: &str,
+    ) -> Result<Option<u64>, AppError> {
+        let mut conn = self.connection().await?;
+        let left: i64 = redis::Script::new(
+            r"
+            redis.call('SREM', KEYS[1], ARGV[1])
+            if redis.call('HDEL', KEYS[2], ARGV[1]) == 0 then
+                return -1
+            end
+            redis.call('ZREM', KEYS[4], ARGV[2] .. '\0' .. ARGV[1])
+            local left = redis.call('HINCRBY', KEYS[3], ARGV[2], -1)
+            if left <= 0 then
+                redis.call('HDEL', KEYS[3], ARGV[2])
+                return 0
+            end
+            return left
+            ",
+        )
+        .key(format!("{}{}", PRESENCE_SET_PREFIX, channel))
+        .key(format!("{}{}", PRESENCE_HASH_PREFIX, channel))
+        .key(format!("{}{}", PRESENCE_USERS_PREFIX, channel))
+        .key(format!("{}{}", PRESENCE_INDEX_PREFIX, channel))
+        .arg(socket_id)
+        .arg(user_id)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(u64::try_from(left).ok())
+    }
+
+    /// One presence member's data, if the socket is on the channel.
+    pub async fn presence_member(&self, channel: &str, socket_id: &str) -> Result<Option<String>, AppError> {
         let mut conn = self.connection().await?;
-        let set_key = format!("{}{}", PRESENCE_SET_PREFIX, channel);
         let hash_key = format!("{}{}", PRESENCE_HASH_PREFIX, channel);
-        conn.srem::<_, _, ()>(&set_key, socket_id).await?;
-        conn.hdel::<_, _, ()>(&hash_key, socket_id).await?;
-        Ok(())
+        Ok(conn.hget(&hash_key, socket_id).await?)
+    }
+
+    /// Up to `count` users after `after` (a user id) in user id order, with the data of one
+    /// member socket each (`None` if it left meanwhile).
+    pub async fn presence_page(
+        &self,
+        channel: &str,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<(String, Option<String>)>, AppError> {
+        let mut conn = self.connection().await?;
+        let start = match after {
+            Some(user_id) => format!("[{}\u{1}", user_id),
+            None => "-".to_string(),
+        };
+        let page: Vec<(String, Option<String>)> = redis::Script::new(
+            r"
+            local page = {}
+            local start = ARGV[1]
+            for _ = 1, tonumber(ARGV[2]) do
+                local entry = redis.call('ZRANGEBYLEX', KEYS[1], start, '+', 'LIMIT', 0, 1)[1]
+                if not entry then
+                    break
+                end
+                local sep = string.find(entry, '\0', 1, true)
+                local user_id = string.sub(entry, 1, sep - 1)
+                table.insert(page, user_id)
+                table.insert(page, redis.call('HGET', KEYS[2], string.sub(entry, sep + 1)))
+                start = '[' .. user_id .. '\1'
+            end
+            return page
+            ",
+        )
+        .key(format!("{}{}", PRESENCE_INDEX_PREFIX, channel))
+        .key(format!("{}{}", PRESENCE_HASH_PREFIX, channel))
+        .arg(start)
+        .arg(count)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(page)
+    }
+
+    /// Whether each of `user_ids` has a socket on the channel.
+    pub async fn presence_users_present(&self, channel: &str, user_ids: &[String]) -> Result<Vec<bool>, AppError> {
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.connection().await?;
+        let counts: Vec<Option<u64>> = redis::cmd("HMGET")
+            .arg(format!("{}{}", PRESENCE_USERS_PREFIX, channel))
+            .arg(user_ids)
+            .query_async(&mut conn)
+            .await?;
+        Ok(counts.into_iter().map(|c| c.is_some()).collect())
+    }
+
+    /// Whether `user_id` has a socket on each of the channels.
+    pub async fn presence_user_present_in(&self, channels: &[String], user_id: &str) -> Result<Vec<bool>, AppError> {
+        if channels.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.connection().await?;
+        let mut pipe = redis::pipe();
+        for channel in channels {
+            pipe.hexists(format!("{}{}", PRESENCE_USERS_PREFIX, channel), user_id);
+        }
+        Ok(pipe.query_async(&mut conn).await?)
     }
 
     /// Get all presence members for a channel (socket_id -> member_data).
@@ -109,4 +1002,215 @@ impl RedisRepository {
         let map: std::collections::HashMap<String, String> = conn.hgetall(&hash_key).await?;
         Ok(map.into_iter().collect())
     }
+
+    // --- Presence last seen: channel -> ms per user, user -> ms per channel (per scope) ---
+
+    /// Record that `user_id` left `channel` at `at_ms`. Both keys live `retention_secs`
+    /// past the latest write; older channel entries are trimmed.
+    pub async fn last_seen_set(
+        &self,
+        scope: &str,
+        channel: &str,
+        user_id: &str,
+        at_ms: i64,
+        retention_secs: u64,
+    ) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let user_key = format!("{}{}:{}", LAST_SEEN_USER_PREFIX, scope, user_id);
+        let channel_key = format!("{}{}:{}", LAST_SEEN_CHANNEL_PREFIX, scope, channel);
+        let oldest = at_ms - (retention_secs as i64) * 1000;
+        redis::pipe()
+            .hset(&user_key, channel, at_ms)
+            .ignore()
+            .expire(&user_key, retention_secs as i64)
+            .ignore()
+            .zadd(&channel_key, user_id, at_ms)
+            .ignore()
+            .zrembyscore(&channel_key, "-inf", oldest)
+            .ignore()
+            .expire(&channel_key, retention_secs as i64)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Last-seen times of a user per channel (channel -> ms).
+    pub async fn last_seen_of_user(&self, scope: &str, user_id: &str) -> Result<Vec<(String, i64)>, AppError> {
+        let mut conn = self.connection().await?;
+        let user_key = format!("{}{}:{}", LAST_SEEN_USER_PREFIX, scope, user_id);
+        let map: std::collections::HashMap<String, i64> = conn.hgetall(&user_key).await?;
+        Ok(map.into_iter().collect())
+    }
+
+    /// Users who left a channel at or after `since_ms`, most recent first (user -> ms).
+    pub async fn last_seen_of_channel(
+        &self,
+        scope: &str,
+        channel: &str,
+        since_ms: i64,
+    ) -> Result<Vec<(String, i64)>, AppError> {
+        let mut conn = self.connection().await?;
+        let channel_key = format!("{}{}:{}", LAST_SEEN_CHANNEL_PREFIX, scope, channel);
+        Ok(conn.zrevrangebyscore_withscores(&channel_key, "+inf", since_ms).await?)
+    }
+
+    // --- Long-polling sessions: liveness key, owner heartbeat and outbox list per session ---
+    // The cursor hash counts frames: `base` = frames dropped from the head of the outbox,
+    // `delivered` = end of the frames the last poll returned.
+
+    fn poll_keys(session_id: &str) -> [String; 4] {
+        [
+            format!("{}{}", POLL_SESSION_PREFIX, session_id),
+            format!("{}{}", POLL_OUTBOX_PREFIX, session_id),
+            format!("{}{}", POLL_CURSOR_PREFIX, session_id),
+            format!("{}{}", POLL_OWNER_PREFIX, session_id),
+        ]
+    }
+
+    /// Create a session that lives `ttl_secs` unless touched, owned by a node that
+    /// refreshes it within `owner_ttl_secs`.
+    pub async fn poll_session_create(&self, session_id: &str, ttl_secs: u64, owner_ttl_secs: u64) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let [session, _, _, owner] = Self::poll_keys(session_id);
+        redis::pipe()
+            .atomic()
+            .set_ex(session, 1, ttl_secs)
+            .ignore()
+            .set_ex(owner, 1, owner_ttl_secs)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Extend a session, its outbox and cursor by `ttl_secs`. `false` if the session is
+    /// gone; a session whose owner stopped refreshing is deleted.
+    pub async fn poll_session_touch(&self, session_id: &str, ttl_secs: u64) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let [session, outbox, cursor, owner] = Self::poll_keys(session_id);
+        let alive: u64 = redis::Script::new(
+            r"
+            if redis.call('EXISTS', KEYS[4]) == 0 then
+                redis.call('DEL', KEYS[1])
+                return 0
+            end
+            local alive = redis.call('EXPIRE', KEYS[1], ARGV[1])
+            redis.call('EXPIRE', KEYS[2], ARGV[1])
+            redis.call('EXPIRE', KEYS[3], ARGV[1])
+            return alive
+            ",
+        )
+        .key(session)
+        .key(outbox)
+        .key(cursor)
+        .key(owner)
+        .arg(ttl_secs)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(alive == 1)
+    }
+
+    /// The owner is still running the session: refresh its heartbeat. `false` if the
+    /// session is gone (not polled within its TTL, or deleted).
+    pub async fn poll_session_refresh_owner(&self, session_id: &str, owner_ttl_secs: u64) -> Result<bool, AppError> {
+        let mut conn = self.connection().await?;
+        let [session, _, _, owner] = Self::poll_keys(session_id);
+        let (alive,): (bool,) = redis::pipe()
+            .exists(session)
+            .set_ex(owner, 1, owner_ttl_secs)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(alive)
+    }
+
+    /// Drop a session and, with `outbox`, its queued frames (else they expire with their TTL).
+    pub async fn poll_session_delete(&self, session_id: &str, outbox: bool) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let [session, outbox_key, cursor, owner] = Self::poll_keys(session_id);
+        let mut keys = vec![session, owner];
+        if outbox {
+            keys.push(outbox_key);
+            keys.push(cursor);
+        }
+        conn.del::<_, ()>(keys).await?;
+        Ok(())
+    }
+
+    /// Queue frames for the client; the outbox keeps the newest `max_len`.
+    pub async fn poll_outbox_push(
+        &self,
+        session_id: &str,
+        frames: &[String],
+        max_len: usize,
+        ttl_secs: u64,
+    ) -> Result<(), AppError> {
+        let mut conn = self.connection().await?;
+        let [_, outbox, cursor, _] = Self::poll_keys(session_id);
+        redis::Script::new(
+            r"
+            for i = 3, #ARGV do
+                redis.call('RPUSH', KEYS[1], ARGV[i])
+            end
+            local over = redis.call('LLEN', KEYS[1]) - tonumber(ARGV[1])
+            if over > 0 then
+                redis.call('LTRIM', KEYS[1], over, -1)
+                redis.call('HINCRBY', KEYS[2], 'base', over)
+            end
+            redis.call('EXPIRE', KEYS[1], ARGV[2])
+            redis.call('EXPIRE', KEYS[2], ARGV[2])
+            return 1
+            ",
+        )
+        .key(outbox)
+        .key(cursor)
+        .arg(max_len)
+        .arg(ttl_secs)
+        .arg(frames)
+        .invoke_async::<_, ()>(&mut conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Drop the frames before `ack` (a frame count; `None` = up to what the last read
+    /// returned), then read up to `max` queued frames, oldest first. Returns the frames and
+    /// the count to ack once they arrived. The cursor lives `ttl_secs`.
+    pub async fn poll_outbox_read(
+        &self,
+        session_id: &str,
+        ack: Option<u64>,
+        max: usize,
+        ttl_secs: u64,
+    ) -> Result<(Vec<String>, u64), AppError> {
+        let mut conn = self.connection().await?;
+        let [_, outbox, cursor, _] = Self::poll_keys(session_id);
+        let (next, frames): (u64, Vec<String>) = redis::Script::new(
+            r"
+            local base = tonumber(redis.call('HGET', KEYS[2], 'base') or '0')
+            local ack = tonumber(ARGV[1])
+            if ack < 0 then
+                ack = tonumber(redis.call('HGET', KEYS[2], 'delivered') or '0')
+            end
+            if ack > base then
+                local drop = math.min(ack - base, redis.call('LLEN', KEYS[1]))
+                redis.call('LTRIM', KEYS[1], drop, -1)
+                base = base + drop
+                redis.call('HSET', KEYS[2], 'base', base)
+            end
+            local frames = redis.call('LRANGE', KEYS[1], 0, tonumber(ARGV[2]) - 1)
+            redis.call('HSET', KEYS[2], 'delivered', base + #frames)
+            redis.call('EXPIRE', KEYS[2], ARGV[3])
+            return {base + #frames, frames}
+            ",
+        )
+        .key(outbox)
+        .key(cursor)
+        .arg(ack.map_or(-1, |a| a as i64))
+        .arg(max)
+        .arg(ttl_secs)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok((frames, next))
+    }
 }
@@ -0,0 +1,70 @@
+//! Domain API keys: generation, prefix lookup, hashing at rest, and masking for display.
+//! Also generates domain secrets (server-readable, used to sign connection tokens).
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Every generated key starts with this marker.
+pub const API_KEY_MARKER: &str = "nk_";
+
+/// Number of leading characters stored in plaintext (`nk_` + 12 hex) for lookup and display.
+pub const API_KEY_PREFIX_LEN: usize = 15;
+
+/// Generate a new random API key (`nk_` + 32 hex chars).
+pub fn generate_api_key() -> String {
+    format!("{}{}", API_KEY_MARKER, Uuid::new_v4().simple())
+}
+
+/// Generate a new domain secret (`ns_` + 64 hex chars).
+pub fn generate_domain_secret() -> String {
+    format!("ns_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Lookup prefix of a key. `None` if the key is too short to have been generated by us.
+pub fn api_key_prefix(key: &str) -> Option<&str> {
+    if !key.starts_with(API_KEY_MARKER) || key.len() <= API_KEY_PREFIX_LEN {
+        return None;
+    }
+    key.get(..API_KEY_PREFIX_LEN)
+}
+
+/// SHA-256 (hex) of the full key. Keys are 128-bit random, so a fast hash is sufficient.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Masked form for listings, e.g. `nk_1a2b3c4d5e6f••••••••`.
+pub fn mask_api_key(prefix: &str) -> String {
+    format!("{}••••••••", prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_key_has_prefix_and_hash() {
+        let key = generate_api_key();
+        assert!(key.starts_with(API_KEY_MARKER));
+        let prefix = api_key_prefix(&key).unwrap();
+        assert_eq!(prefix.len(), API_KEY_PREFIX_LEN);
+        assert!(key.starts_with(prefix));
+        assert_eq!(hash_api_key(&key).len(), 64);
+        assert_ne!(hash_api_key(&key), hash_api_key(&generate_api_key()));
+    }
+
+    #[test]
+    fn prefix_rejects_foreign_or_short_keys() {
+        assert_eq!(api_key_prefix("notif_key"), None);
+        assert_eq!(api_key_prefix("nk_short"), None);
+        assert_eq!(api_key_prefix(""), None);
+    }
+
+    #[test]
+    fn mask_hides_secret_part() {
+        let key = generate_api_key();
+        let masked = mask_api_key(api_key_prefix(&key).unwrap());
+        assert!(!masked.contains(&key[API_KEY_PREFIX_LEN..]));
+        assert!(masked.starts_with("nk_"));
+    }
+}
@@ -0,0 +1,374 @@
+//! Authorizer webhook: ask the customer's backend whether a socket may subscribe to a
+//! private/presence channel, instead of the client fetching an HMAC first.
+
+use crate::error::{AppError, AppResult};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Cached positive decision: when it was made and the presence `channel_data`.
+type CachedDecision = (Instant, Option<serde_json::Value>);
+
+/// Header carrying `HMAC-SHA256(domain_secret, body)` (hex) so the authorizer can verify the caller.
+pub const HEADER_AUTHORIZER_SIGNATURE: &str = "x-notif-signature";
+
+/// Body POSTed to the authorizer URL.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthorizerRequest<'a> {
+    pub socket_id: &'a str,
+    pub channel: &'a str,
+    /// Opaque token the client passed in `subscribe` (e.g. its session token with your backend).
+    pub user_token: Option<&'a str>,
+}
+
+/// Successful authorizer response body (all fields optional).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthorizerResponse {
+    /// Presence member data (`{ "user_id": ..., "user_info": ... }`), as an object or a JSON string.
+    #[serde(default)]
+    pub channel_data: Option<serde_json::Value>,
+}
+
+impl AuthorizerResponse {
+    /// `channel_data` as a JSON object, decoding the Pusher-style string form.
+    pub fn channel_data(&self) -> Option<serde_json::Value> {
+        match &self.channel_data {
+            Some(serde_json::Value::String(s)) => serde_json::from_str(s).ok(),
+            other => other.clone(),
+        }
+    }
+}
+
+/// Why a subscribe was not authorized by the webhook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizerDenial {
+    /// The authorizer answered with a non-200 status.
+    Denied(u16),
+    /// Timeout, connection error or unreadable response.
+    Unavailable,
+}
+
+/// Calls authorizer webhooks with a timeout and caches positive decisions.
+#[derive(Clone)]
+pub struct AuthorizerService {
+    client: reqwest::Client,
+    cache_ttl: Duration,
+    /// Allow loopback, link-local and private addresses (e.g. an authorizer on the same host).
+    allow_private: bool,
+    /// sha256(url, channel, user_token or socket_id) -> (cached at, channel_data).
+    cache: Arc<RwLock<HashMap<String, CachedDecision>>>,
+}
+
+impl AuthorizerService {
+    pub fn new(timeout: Duration, cache_ttl: Duration, allow_private: bool) -> AppResult<Self> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none());
+        if !allow_private {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = builder
+            .build()
+            .map_err(|e| AppError::Config(format!("authorizer http client: {}", e)))?;
+        Ok(Self {
+            client,
+            cache_ttl,
+            allow_private,
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /// Ask `url` whether the socket may subscribe. On success returns the presence `channel_data`.
+    pub async fn authorize(
+        &self,
+        url: &str,
+        secret: &str,
+        request: &AuthorizerRequest<'_>,
+    ) -> Result<Option<serde_json::Value>, AuthorizerDenial> {
+        let cache_key = self.cache_key(url, request);
+        if let Some(channel_data) = self.cached(&cache_key).await {
+            debug!(channel = %request.channel, "authorizer cache hit");
+            return Ok(channel_data);
+        }
+
+        if validate_authorizer_url(url, self.allow_private).is_err() {
+            warn!(url = %url, "authorizer url refused");
+            return Err(AuthorizerDenial::Unavailable);
+        }
+        let body = serde_json::to_vec(request).map_err(|_| AuthorizerDenial::Unavailable)?;
+        let res = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(HEADER_AUTHORIZER_SIGNATURE, sign_body(secret, &body))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| {
+                warn!(url = %url, error = %e, "authorizer request failed");
+                AuthorizerDenial::Unavailable
+            })?;
+        let status = res.status().as_u16();
+        if status != 200 {
+            debug!(channel = %request.channel, status, "authorizer denied subscription");
+            return Err(AuthorizerDenial::Denied(status));
+        }
+        let bytes = res.bytes().await.map_err(|_| AuthorizerDenial::Unavailable)?;
+        let response: AuthorizerResponse = if bytes.iter().all(u8::is_ascii_whitespace) {
+            AuthorizerResponse::default()
+        } else {
+            serde_json::from_slice(&bytes).map_err(|_| AuthorizerDenial::Unavailable)?
+        };
+        let channel_data = response.channel_data();
+
+        let mut cache = self.cache.write().await;
+        cache.retain(|_, (at, _)| at.elapsed() < self.cache_ttl);
+        cache.insert(cache_key, (Instant::now(), channel_data.clone()));
+        Ok(channel_data)
+    }
+
+    async fn cached(&self, key: &str) -> Option<Option<serde_json::Value>> {
+        let cache = self.cache.read().await;
+        cache
+            .get(key)
+            .filter(|(at, _)| at.elapsed() < self.cache_ttl)
+            .map(|(_, data)| data.clone())
+    }
+
+    /// Decisions are cached per user token; without one, per socket.
+    /// Validate an authorizer URL against this service's address policy.
+    pub fn validate_url(&self, url: &str) -> AppResult<()> {
+        validate_authorizer_url(url, self.allow_private)
+    }
+
+    fn cache_key(&self, url: &str, request: &AuthorizerRequest<'_>) -> String {
+        let subject = request.user_token.unwrap_or(request.socket_id);
+        let mut hasher = Sha256::new();
+        for part in [url, request.channel, subject] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Hex `HMAC-SHA256(secret, body)` sent in [`HEADER_AUTHORIZER_SIGNATURE`].
+pub fn sign_body(secret: &str, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Validate an authorizer URL: http(s) with a host, and unless `allow_private`, no
+/// loopback, link-local or private address (host names are checked again when resolved).
+pub fn validate_authorizer_url(url: &str, allow_private: bool) -> AppResult<()> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|_| AppError::Validation("authorizer_url must be a valid URL".to_string()))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(AppError::Validation("authorizer_url must be http(s)".to_string()));
+    }
+    let Some(host) = parsed.host_str() else {
+        return Err(AppError::Validation("authorizer_url must be http(s)".to_string()));
+    };
+    let internal = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => !is_public_ip(ip),
+        Err(_) => host.eq_ignore_ascii_case("localhost") || host.ends_with(".localhost"),
+    };
+    if internal && !allow_private {
+        return Err(AppError::Validation(
+            "authorizer_url must not point to a loopback, link-local or private address".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether an address is reachable on the public internet: not loopback, link-local,
+/// private, unspecified or otherwise reserved.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || a == 0
+                // Carrier-grade NAT 100.64.0.0/10.
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public_ip(IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                !(v6.is_loopback()
+                    || v6.is_unspecified()
+                    // Unique local fc00::/7 and link-local fe80::/10.
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves authorizer hosts to public addresses only, so a host name cannot lead to an
+/// internal service.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, http::StatusCode, routing::post, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Local stand-in authorizer: allows `user_token == "good"`, sleeps for `"slow"`.
+    async fn spawn_authorizer(calls: Arc<AtomicUsize>) -> String {
+        let app = Router::new().route(
+            "/auth",
+            post(move |headers: HeaderMap, body: axum::body::Bytes| {
+                let calls = calls.clone();
+                async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    let sig = headers.get(HEADER_AUTHORIZER_SIGNATURE).and_then(|v| v.to_str().ok());
+                    if sig != Some(sign_body("ns_secret", &body).as_str()) {
+                        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({})));
+                    }
+                    let req: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    match req["user_token"].as_str() {
+                        Some("good") => (
+                            StatusCode::OK,
+                            Json(serde_json::json!({
+                                "channel_data": "{\"user_id\":\"u1\",\"user_info\":{\"name\":\"A\"}}"
+                            })),
+                        ),
+                        Some("slow") => {
+                            tokio::time::sleep(Duration::from_secs(2)).await;
+                            (StatusCode::OK, Json(serde_json::json!({})))
+                        }
+                        _ => (StatusCode::FORBIDDEN, Json(serde_json::json!({}))),
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/auth", addr)
+    }
+
+    fn request<'a>(channel: &'a str, token: Option<&'a str>) -> AuthorizerRequest<'a> {
+        AuthorizerRequest {
+            socket_id: "1.1",
+            channel,
+            user_token: token,
+        }
+    }
+
+    #[tokio::test]
+    async fn allows_and_caches_positive_decisions() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = spawn_authorizer(calls.clone()).await;
+        let service = AuthorizerService::new(Duration::from_millis(500), Duration::from_secs(60), true).unwrap();
+
+        let data = service
+            .authorize(&url, "ns_secret", &request("presence-room", Some("good")))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data["user_id"], "u1");
+        service
+            .authorize(&url, "ns_secret", &request("presence-room", Some("good")))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "second decision comes from cache");
+    }
+
+    #[tokio::test]
+    async fn denies_and_does_not_cache_denials() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = spawn_authorizer(calls.clone()).await;
+        let service = AuthorizerService::new(Duration::from_millis(500), Duration::from_secs(60), true).unwrap();
+
+        for _ in 0..2 {
+            let res = service.authorize(&url, "ns_secret", &request("private-a", Some("bad"))).await;
+            assert_eq!(res, Err(AuthorizerDenial::Denied(403)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let res = service.authorize(&url, "wrong_secret", &request("private-a", Some("good"))).await;
+        assert_eq!(res, Err(AuthorizerDenial::Denied(401)));
+    }
+
+    #[tokio::test]
+    async fn times_out_and_handles_unreachable() {
+        let url = spawn_authorizer(Arc::new(AtomicUsize::new(0))).await;
+        let service = AuthorizerService::new(Duration::from_millis(200), Duration::from_secs(60), true).unwrap();
+        let res = service.authorize(&url, "ns_secret", &request("private-a", Some("slow"))).await;
+        assert_eq!(res, Err(AuthorizerDenial::Unavailable));
+        let res = service
+            .authorize("http://127.0.0.1:1/auth", "ns_secret", &request("private-a", Some("good")))
+            .await;
+        assert_eq!(res, Err(AuthorizerDenial::Unavailable));
+    }
+
+    #[tokio::test]
+    async fn refuses_internal_addresses_unless_allowed() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let url = spawn_authorizer(calls.clone()).await;
+        let service = AuthorizerService::new(Duration::from_millis(500), Duration::from_secs(60), false).unwrap();
+        let res = service.authorize(&url, "ns_secret", &request("private-a", Some("good"))).await;
+        assert_eq!(res, Err(AuthorizerDenial::Unavailable));
+        let local = url.replace("127.0.0.1", "localhost");
+        let res = service.authorize(&local, "ns_secret", &request("private-a", Some("good"))).await;
+        assert_eq!(res, Err(AuthorizerDenial::Unavailable));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn validates_urls() {
+        assert!(validate_authorizer_url("https://api.example.com/notif/auth", false).is_ok());
+        assert!(validate_authorizer_url("ftp://example.com", false).is_err());
+        assert!(validate_authorizer_url("not a url", false).is_err());
+        for internal in [
+            "http://localhost:8080/auth",
+            "http://127.0.0.1/auth",
+            "http://10.0.0.5/auth",
+            "http://192.168.1.1/auth",
+            "http://172.20.0.1/auth",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/auth",
+            "http://[fe80::1]/auth",
+            "http://[fd00::1]/auth",
+            "http://[::ffff:10.0.0.1]/auth",
+        ] {
+            assert!(validate_authorizer_url(internal, false).is_err(), "{}", internal);
+            assert!(validate_authorizer_url(internal, true).is_ok(), "{}", internal);
+        }
+        assert!(is_public_ip("8.8.8.8".parse().unwrap()));
+        assert!(is_public_ip("2606:4700::1111".parse().unwrap()));
+    }
+}
@@ -1,13 +1,80 @@
 //! Channel subscription and broadcast: one Redis subscription per channel, fan-out to local receivers.
 
-use crate::error::AppResult;
-use crate::models::event::WsEvent;
+use crate::error::{AppError, AppResult};
+use crate::models::channel::{is_cache_channel, ChannelType};
+use crate::models::event::{PublishedEvent, RewindOptions, WsEvent};
+use crate::services::encryption::validate_encrypted_payload;
+use crate::services::history::{history_stream, HistoryPolicy, StreamId, MAX_HISTORY_PAGE};
+use crate::services::recovery::{Recovery, RecoveryConfig, ResumeSession, RESUME_CLEANUP_SLACK};
 use crate::repositories::RedisRepository;
+use chrono::{DateTime, Utc};
 use serde_json;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{broadcast, RwLock};
 use tracing::{debug, info};
+use uuid::Uuid;
+
+/// Per-publish behaviour beyond plain fan-out.
+#[derive(Debug, Clone, Default)]
+pub struct PublishOptions {
+    /// Append to the channel history with this retention.
+    pub history: Option<HistoryPolicy>,
+    /// Mark the event for at-least-once delivery (see [`crate::services::delivery`]).
+    pub reliable: bool,
+    /// Drop the event instead of delivering it after this time (Unix milliseconds).
+    pub expires_at: Option<i64>,
+}
+
+/// Events replayed by a rewind, oldest first.
+#[derive(Debug, Default)]
+pub struct RewindPage {
+    pub events: Vec<WsEvent>,
+    /// More events matched than the server sends in one rewind; the oldest were left out.
+    pub truncated: bool,
+}
+
+/// Newest `count` of `newest_first` (fetched with one extra), oldest first. Truncated when
+/// the extra exists and the client did not ask for fewer itself.
+fn rewind_page(mut newest_first: Vec<WsEvent>, count: usize, requested: Option<usize>) -> RewindPage {
+    let more = newest_first.len() > count;
+    newest_first.truncate(count);
+    newest_first.reverse();
+    RewindPage {
+        events: newest_first,
+        truncated: more && requested.is_none_or(|n| n > count),
+    }
+}
+
+/// Expiry of a broadcast from an absolute `expires_at` or a `ttl` in seconds counted from
+/// `deliver_at` (or `now`). It must fall after the delivery time.
+pub fn resolve_expiry(
+    expires_at: Option<DateTime<Utc>>,
+    ttl: Option<u64>,
+    deliver_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> AppResult<Option<DateTime<Utc>>> {
+    let start = deliver_at.map_or(now, |at| at.max(now));
+    let expiry = match (expires_at, ttl) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::Validation("set either expires_at or ttl, not both".to_string()))
+        }
+        (Some(at), None) => Some(at),
+        (None, Some(0)) => return Err(AppError::Validation("ttl must be at least 1 second".to_string())),
+        (None, Some(secs)) => {
+            let secs = i64::try_from(secs).unwrap_or(i64::MAX);
+            Some(start + chrono::Duration::try_seconds(secs).unwrap_or(chrono::Duration::MAX))
+        }
+        (None, None) => None,
+    };
+    if expiry.is_some_and(|at| at <= start) {
+        return Err(AppError::Validation(
+            "expires_at must be after the delivery time".to_string(),
+        ));
+    }
+    Ok(expiry)
+}
 
 /// Manages channel subscriptions: ensures one Redis subscriber per channel and distributes messages.
 #[derive(Clone)]
@@ -15,12 +82,17 @@ pub struct ChannelService {
     repo: Arc<RedisRepository>,
     /// channel_name -> (broadcast Sender, subscriber count). When count drops to 0 we could unsubscribe from Redis.
     subscribers: Arc<RwLock<HashMap<String, broadcast::Sender<String>>>>,
+    /// How long the last event of a cache channel is kept.
+    cache_ttl: Duration,
+    recovery: RecoveryConfig,
 }
 
 impl ChannelService {
-    pub fn new(repo: Arc<RedisRepository>) -> Self {
+    pub fn new(repo: Arc<RedisRepository>, cache_ttl: Duration, recovery: RecoveryConfig) -> Self {
         Self {
             repo,
+            cache_ttl,
+            recovery,
             subscribers: Arc::new(RwLock::new(HashMap::new())),
         }
     }
@@ -49,16 +121,200 @@ impl ChannelService {
     }
 
     /// Broadcast an event to a channel (publish to Redis; all subscribers receive it).
-    pub async fn broadcast(&self, channel: &str, event: &str, data: serde_json::Value) -> AppResult<u64> {
-        let ws_event = WsEvent {
+    /// Assigns the event id, the next channel sequence number and the publish time.
+    /// Encrypted channels only accept secretbox payloads.
+    pub async fn broadcast(&self, channel: &str, event: &str, data: serde_json::Value) -> AppResult<PublishedEvent> {
+        self.broadcast_with_options(channel, event, data, &PublishOptions::default()).await
+    }
+
+    /// Like [`broadcast`](Self::broadcast) with [`PublishOptions`]. With a history policy the
+    /// event is first appended to the channel history and then carries its `history_id`.
+    pub async fn broadcast_with_options(
+        &self,
+        channel: &str,
+        event: &str,
+        data: serde_json::Value,
+        options: &PublishOptions,
+    ) -> AppResult<PublishedEvent> {
+        let ws_event = self.prepare(channel, event, data, options).await?;
+        self.publish_prepared(ws_event, options).await
+    }
+
+    /// Validate an event and assign its id and sequence number without publishing it, for
+    /// callers that must record something about the event first (see
+    /// [`DeliveryService::open`](crate::services::DeliveryService::open)).
+    pub async fn prepare(
+        &self,
+        channel: &str,
+        event: &str,
+        data: serde_json::Value,
+        options: &PublishOptions,
+    ) -> AppResult<WsEvent> {
+        if ChannelType::from_name(channel).is_encrypted() {
+            validate_encrypted_payload(&data)?;
+        }
+        Ok(WsEvent {
+            id: Uuid::new_v4().to_string(),
+            seq: self.repo.next_seq(channel).await?,
+            published_at: chrono::Utc::now().timestamp_millis(),
+            history_id: None,
+            reliable: options.reliable,
+            expires_at: options.expires_at,
             event: event.to_string(),
             channel: channel.to_string(),
             data,
-        };
+        })
+    }
+
+    /// Store (history, cache, recovery buffer) and publish an event from [`Self::prepare`].
+    pub async fn publish_prepared(&self, mut ws_event: WsEvent, options: &PublishOptions) -> AppResult<PublishedEvent> {
+        let channel = ws_event.channel.clone();
+        if let Some(policy) = &options.history {
+            let min_id = policy
+                .min_id(chrono::Utc::now().timestamp_millis())
+                .map(|id| id.to_string());
+            let id = self
+                .repo
+                .history_append(
+                    &history_stream(policy.domain_id, &channel),
+                    &serde_json::to_string(&ws_event)?,
+                    policy.max_events,
+                    min_id.as_deref(),
+                    policy.max_age_secs,
+                )
+                .await?;
+            ws_event.history_id = Some(id);
+        }
         let payload = serde_json::to_string(&ws_event)?;
-        let count = self.repo.publish(channel, &payload).await?;
-        info!(channel = %channel, event = %event, count, "broadcast");
-        Ok(count)
+        if is_cache_channel(&channel) {
+            // Stored before publishing so a concurrent subscriber cannot miss it.
+            let mut ttl = self.cache_ttl.as_secs();
+            if let Some(expires_at) = ws_event.expires_at {
+                let remaining_ms = (expires_at - ws_event.published_at).max(0) as u64;
+                ttl = ttl.min(remaining_ms.div_ceil(1000));
+            }
+            self.repo.cache_set(&channel, &payload, ttl.max(1)).await?;
+        }
+        self.repo
+            .recovery_append(
+                &channel,
+                ws_event.seq,
+                &payload,
+                self.recovery.buffer_size,
+                self.recovery.grace.as_secs().max(1),
+            )
+            .await?;
+        let count = self.repo.publish(&channel, &payload).await?;
+        info!(channel = %channel, event = %ws_event.event, id = %ws_event.id, seq = ws_event.seq, count, "broadcast");
+        Ok(PublishedEvent {
+            id: ws_event.id,
+            seq: ws_event.seq,
+            published_at: ws_event.published_at,
+            history_id: ws_event.history_id,
+            subscriber_count: count,
+        })
+    }
+
+    /// Server event on a channel (e.g. `pusher_internal:member_removed`): delivered to
+    /// current subscribers only, without id, sequence number, history or cache.
+    pub async fn publish_internal(&self, channel: &str, event: &str, data: serde_json::Value) -> AppResult<()> {
+        let payload = serde_json::json!({ "event": event, "channel": channel, "data": data });
+        self.repo.publish(channel, &payload.to_string()).await?;
+        Ok(())
+    }
+
+    /// Last event of a cache channel (serialized [`WsEvent`]), if any.
+    pub async fn cached_event(&self, channel: &str) -> AppResult<Option<String>> {
+        self.repo.cache_get(channel).await
+    }
+
+    /// Drop the last event of a cache channel; new subscribers get `pusher:cache_miss`.
+    pub async fn clear_cached_event(&self, channel: &str) -> AppResult<bool> {
+        self.repo.cache_delete(channel).await
+    }
+
+    /// One page of a domain's channel history, newest first, strictly older than `cursor`
+    /// if given.
+    pub async fn history(
+        &self,
+        domain_id: Uuid,
+        channel: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> AppResult<Vec<WsEvent>> {
+        let end = match cursor {
+            Some(c) => {
+                let id = StreamId::parse(c)
+                    .ok_or_else(|| AppError::Validation("invalid history cursor".to_string()))?;
+                format!("({}", id)
+            }
+            None => "+".to_string(),
+        };
+        let entries = self
+            .repo
+            .history_rev_range(&history_stream(domain_id, channel), &end, "-", limit.clamp(1, MAX_HISTORY_PAGE))
+            .await?;
+        Ok(entries_to_events(entries))
+    }
+
+    /// Stored events of a domain's channel to deliver on subscribe, oldest first. At most
+    /// [`MAX_HISTORY_PAGE`], the newest ones, so they join up with live events; `truncated`
+    /// says older matching events were left out.
+    pub async fn rewind(&self, domain_id: Uuid, channel: &str, options: &RewindOptions) -> AppResult<RewindPage> {
+        let start = match options.since {
+            Some(since) => StreamId {
+                ms: (since.max(0) as u64).saturating_mul(1000),
+                seq: 0,
+            }
+            .to_string(),
+            None => "-".to_string(),
+        };
+        let count = options.count.unwrap_or(MAX_HISTORY_PAGE).min(MAX_HISTORY_PAGE);
+        if count == 0 {
+            return Ok(RewindPage::default());
+        }
+        // One more than needed tells whether the server cap cut anything off.
+        let entries = self
+            .repo
+            .history_rev_range(&history_stream(domain_id, channel), "+", &start, count + 1)
+            .await?;
+        Ok(rewind_page(entries_to_events(entries), count, options.count))
+    }
+
+    pub fn recovery_config(&self) -> &RecoveryConfig {
+        &self.recovery
+    }
+
+    /// Last sequence number assigned on `channel` (0 if none).
+    pub async fn current_seq(&self, channel: &str) -> AppResult<u64> {
+        self.repo.current_seq(channel).await
+    }
+
+    /// Events published on `channel` after `last_seq`, from the replay buffer.
+    pub async fn recover(&self, channel: &str, last_seq: u64) -> AppResult<Recovery> {
+        let buffered = self.repo.recovery_after(channel, last_seq).await?;
+        let current = self.repo.current_seq(channel).await?;
+        Ok(Recovery::from_buffer(last_seq, buffered, current))
+    }
+
+    /// Keep a disconnected session resumable. It outlives the grace window by
+    /// [`RESUME_CLEANUP_SLACK`] so the disconnecting server can still tell, when the window
+    /// ends, that nobody resumed it (see [`discard_resume_session`](Self::discard_resume_session)).
+    pub async fn save_resume_session(&self, token: &str, session: &ResumeSession) -> AppResult<()> {
+        let json = serde_json::to_string(session)?;
+        let ttl = self.recovery.grace + RESUME_CLEANUP_SLACK;
+        self.repo.resume_save(token, &json, ttl.as_secs().max(1)).await
+    }
+
+    /// Claim a resumable session (at most once).
+    pub async fn take_resume_session(&self, token: &str) -> AppResult<Option<ResumeSession>> {
+        let json = self.repo.resume_take(token).await?;
+        Ok(json.and_then(|j| serde_json::from_str(&j).ok()))
+    }
+
+    /// Drop a resumable session; `true` if nobody resumed it.
+    pub async fn discard_resume_session(&self, token: &str) -> AppResult<bool> {
+        self.repo.resume_discard(token).await
     }
 
     /// Remove channel from local cache when no more subscribers (optional cleanup).
@@ -68,3 +324,71 @@ impl ChannelService {
         debug!(channel = %channel, "unsubscribed from channel");
     }
 }
+
+/// Decode history entries, attaching the stream id as the history id.
+fn entries_to_events(entries: Vec<(String, String)>) -> Vec<WsEvent> {
+    entries
+        .into_iter()
+        .filter_map(|(id, payload)| {
+            let mut event: WsEvent = serde_json::from_str(&payload).ok()?;
+            event.history_id = Some(id);
+            Some(event)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_735_689_600 + secs, 0).unwrap()
+    }
+
+    fn events(seqs: &[u64]) -> Vec<WsEvent> {
+        seqs.iter()
+            .map(|&seq| WsEvent {
+                id: seq.to_string(),
+                seq,
+                published_at: 0,
+                history_id: None,
+                reliable: false,
+                expires_at: None,
+                event: "e".to_string(),
+                channel: "c".to_string(),
+                data: serde_json::Value::Null,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rewind_keeps_the_newest_events_and_flags_the_server_cap() {
+        let page = rewind_page(events(&[5, 4, 3]), 2, None);
+        assert_eq!(page.events.iter().map(|e| e.seq).collect::<Vec<_>>(), [4, 5]);
+        assert!(page.truncated, "since matched more than the cap");
+        let page = rewind_page(events(&[5, 4, 3]), 2, Some(2));
+        assert!(!page.truncated, "the client asked for two");
+        let page = rewind_page(events(&[5, 4]), 2, None);
+        assert_eq!(page.events.len(), 2);
+        assert!(!page.truncated);
+        let page = rewind_page(events(&[5, 4, 3]), 2, Some(500));
+        assert!(page.truncated, "more requested than the cap allows");
+    }
+
+    #[test]
+    fn expiry_from_ttl_counts_from_delivery() {
+        assert_eq!(resolve_expiry(None, Some(60), None, at(0)).unwrap(), Some(at(60)));
+        assert_eq!(resolve_expiry(None, Some(60), Some(at(300)), at(0)).unwrap(), Some(at(360)));
+        assert_eq!(resolve_expiry(None, None, None, at(0)).unwrap(), None);
+    }
+
+    #[test]
+    fn expiry_must_follow_delivery() {
+        assert_eq!(resolve_expiry(Some(at(10)), None, None, at(0)).unwrap(), Some(at(10)));
+        assert!(resolve_expiry(Some(at(-1)), None, None, at(0)).is_err());
+        assert!(resolve_expiry(Some(at(100)), None, Some(at(200)), at(0)).is_err());
+        assert!(resolve_expiry(Some(at(10)), Some(5), None, at(0)).is_err());
+        assert!(resolve_expiry(None, Some(0), None, at(0)).is_err());
+    }
+}
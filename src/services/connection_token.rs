@@ -0,0 +1,118 @@
+//! Connection tokens: short-lived HS256 JWTs signed with the domain secret, for clients
+//! that cannot send a matching `Origin` (mobile apps, backend workers).
+//!
+//! The JWT header `kid` carries the domain id; the claims carry the allowed channels.
+
+use crate::error::{AppError, AppResult};
+use crate::models::channel::channel_matches_pattern;
+use chrono::Utc;
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Longest accepted lifetime (`exp - now`) of a connection token, in seconds.
+pub const MAX_CONNECTION_TOKEN_TTL_SECS: i64 = 24 * 60 * 60;
+
+/// Claims of a connection token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionClaims {
+    /// Channel names or glob patterns (`*`) the socket may subscribe to. Listed private and
+    /// presence channels need no per-channel auth.
+    pub channels: Vec<String>,
+    /// Optional user id; used as the presence `user_id` for this socket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub exp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+}
+
+impl ConnectionClaims {
+    /// Whether the token allows subscribing to `channel`.
+    pub fn allows_channel(&self, channel: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|pattern| channel_matches_pattern(pattern, channel))
+    }
+}
+
+/// Read the domain id from the token's `kid` header without verifying the signature.
+pub fn connection_token_domain_id(token: &str) -> AppResult<Uuid> {
+    let header = decode_header(token).map_err(|e| AppError::Auth(format!("invalid connection token: {}", e)))?;
+    header
+        .kid
+        .as_deref()
+        .and_then(|kid| Uuid::parse_str(kid).ok())
+        .ok_or_else(|| AppError::Auth("connection token must carry the domain id as kid".to_string()))
+}
+
+/// Sign a connection token for `domain_id` (what a customer backend does with its SDK).
+pub fn sign_connection_token(domain_id: Uuid, secret: &str, claims: &ConnectionClaims) -> AppResult<String> {
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some(domain_id.to_string());
+    encode(&header, claims, &EncodingKey::from_secret(secret.as_bytes()))
+        .map_err(|e| AppError::Jwt(e.to_string()))
+}
+
+/// Verify signature and expiry with the domain secret. Tokens valid for longer than
+/// [`MAX_CONNECTION_TOKEN_TTL_SECS`] are rejected.
+pub fn verify_connection_token(token: &str, secret: &str) -> AppResult<ConnectionClaims> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.validate_exp = true;
+    validation.leeway = 0;
+    let data = decode::<ConnectionClaims>(token, &DecodingKey::from_secret(secret.as_bytes()), &validation)
+        .map_err(|e| AppError::Auth(format!("invalid connection token: {}", e)))?;
+    if data.claims.exp - Utc::now().timestamp() > MAX_CONNECTION_TOKEN_TTL_SECS {
+        return Err(AppError::Auth("connection token lifetime too long".to_string()));
+    }
+    Ok(data.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(exp_in: i64) -> ConnectionClaims {
+        ConnectionClaims {
+            channels: vec!["private-user-42".to_string(), "presence-room-*".to_string()],
+            user_id: Some("42".to_string()),
+            exp: Utc::now().timestamp() + exp_in,
+            iat: None,
+        }
+    }
+
+    #[test]
+    fn sign_and_verify_roundtrip() {
+        let domain_id = Uuid::new_v4();
+        let token = sign_connection_token(domain_id, "ns_secret", &claims(300)).unwrap();
+        assert_eq!(connection_token_domain_id(&token).unwrap(), domain_id);
+        let verified = verify_connection_token(&token, "ns_secret").unwrap();
+        assert_eq!(verified.user_id.as_deref(), Some("42"));
+        assert!(verified.allows_channel("private-user-42"));
+        assert!(verified.allows_channel("presence-room-7"));
+        assert!(!verified.allows_channel("private-user-43"));
+    }
+
+    #[test]
+    fn rejects_wrong_secret_expired_and_long_lived() {
+        let domain_id = Uuid::new_v4();
+        let token = sign_connection_token(domain_id, "ns_secret", &claims(300)).unwrap();
+        assert!(verify_connection_token(&token, "other").is_err());
+        let expired = sign_connection_token(domain_id, "ns_secret", &claims(-10)).unwrap();
+        assert!(verify_connection_token(&expired, "ns_secret").is_err());
+        let long = sign_connection_token(domain_id, "ns_secret", &claims(MAX_CONNECTION_TOKEN_TTL_SECS + 60)).unwrap();
+        assert!(verify_connection_token(&long, "ns_secret").is_err());
+    }
+
+    #[test]
+    fn rejects_token_without_kid() {
+        assert!(connection_token_domain_id("not-a-jwt").is_err());
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims(300),
+            &EncodingKey::from_secret(b"ns_secret"),
+        )
+        .unwrap();
+        assert!(connection_token_domain_id(&token).is_err());
+    }
+}
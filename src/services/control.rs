@@ -0,0 +1,246 @@
+//! Cross-node socket control: commands for sockets (e.g. terminate) are published on one
+//! internal Redis channel, and every node hands them to the sockets it holds.
+
+use crate::error::AppResult;
+use crate::repositories::RedisRepository;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// WebSocket close code for connections terminated through the server API. Clients in the
+/// 4000-4099 range should not reconnect automatically; 4009 is taken by auth and
+/// subscription errors, so termination has its own code.
+pub const CLOSE_TERMINATED: u16 = 4010;
+
+/// Internal pub/sub channel every node listens on.
+const CONTROL_CHANNEL: &str = "#control";
+
+/// Most channels per server-driven subscribe or unsubscribe.
+pub const MAX_CONTROL_CHANNELS: usize = 100;
+
+/// Wait before listening again after the control subscription dropped.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// What a socket is told to do.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Close the connection with [`CLOSE_TERMINATED`] and release its state.
+    Terminate,
+    /// Subscribe to channels without client auth. Presence channels use `user_id`, else
+    /// the socket's signed-in or connection token user; `channel_data` is the member info,
+    /// as on a client subscribe.
+    Subscribe {
+        channels: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel_data: Option<serde_json::Value>,
+    },
+    Unsubscribe { channels: Vec<String> },
+    /// Unsubscribe from `channel` if this socket is `user_id` there (or signed in as it).
+    Kick { channel: String, user_id: String },
+}
+
+/// A command for sockets of one domain, as published between nodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ControlMessage {
+    #[serde(default)]
+    domain_id: Option<Uuid>,
+    socket_ids: Vec<String>,
+    /// Every socket of the domain instead of `socket_ids`.
+    #[serde(default)]
+    all: bool,
+    command: ControlCommand,
+}
+
+/// A socket connected to this node.
+struct LocalSocket {
+    domain_id: Option<Uuid>,
+    commands: mpsc::UnboundedSender<ControlCommand>,
+}
+
+/// Routes control commands to sockets on whichever node holds them.
+#[derive(Clone)]
+pub struct ControlService {
+    repo: Arc<RedisRepository>,
+    local: Arc<RwLock<HashMap<String, LocalSocket>>>,
+}
+
+impl ControlService {
+    pub fn new(repo: Arc<RedisRepository>) -> Self {
+        Self {
+            repo,
+            local: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Make a socket of this node reachable; commands for it arrive on the receiver.
+    pub async fn register(&self, socket_id: &str, domain_id: Option<Uuid>) -> mpsc::UnboundedReceiver<ControlCommand> {
+        let (commands, rx) = mpsc::unbounded_channel();
+        self.local
+            .write()
+            .await
+            .insert(socket_id.to_string(), LocalSocket { domain_id, commands });
+        rx
+    }
+
+    /// The socket took over another socket id (resume).
+    pub async fn rename(&self, from: &str, to: &str) {
+        let mut local = self.local.write().await;
+        if let Some(socket) = local.remove(from) {
+            local.insert(to.to_string(), socket);
+        }
+    }
+
+    pub async fn unregister(&self, socket_id: &str) {
+        self.local.write().await.remove(socket_id);
+    }
+
+    /// Send a command to sockets of a domain, on any node.
+    pub async fn send(&self, domain_id: Option<Uuid>, socket_ids: Vec<String>, command: ControlCommand) -> AppResult<()> {
+        if socket_ids.is_empty() {
+            return Ok(());
+        }
+        self.publish(ControlMessage {
+            domain_id,
+            socket_ids,
+            all: false,
+            command,
+        })
+        .await
+    }
+
+    /// Send a command to every socket of a domain, on all nodes.
+    pub async fn send_to_domain(&self, domain_id: Option<Uuid>, command: ControlCommand) -> AppResult<()> {
+        self.publish(ControlMessage {
+            domain_id,
+            socket_ids: Vec::new(),
+            all: true,
+            command,
+        })
+        .await
+    }
+
+    async fn publish(&self, message: ControlMessage) -> AppResult<()> {
+        self.repo
+            .publish(CONTROL_CHANNEL, &serde_json::to_string(&message)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Hand a message to the addressed local sockets of its domain. Returns how many got it.
+    async fn dispatch(&self, message: ControlMessage) -> usize {
+        let local = self.local.read().await;
+        let mut delivered = 0;
+        if message.all {
+            for socket in local.values().filter(|s| s.domain_id == message.domain_id) {
+                if socket.commands.send(message.command.clone()).is_ok() {
+                    delivered += 1;
+                }
+            }
+            return delivered;
+        }
+        for socket_id in &message.socket_ids {
+            let Some(socket) = local.get(socket_id) else {
+                continue;
+            };
+            if socket.domain_id != message.domain_id {
+                continue;
+            }
+            if socket.commands.send(message.command.clone()).is_ok() {
+                delivered += 1;
+            }
+        }
+        delivered
+    }
+
+    /// Receive control messages from all nodes, forever.
+    pub async fn run(self) {
+        loop {
+            match self.repo.subscribe_to_channel(CONTROL_CHANNEL).await {
+                Ok(mut rx) => loop {
+                    match rx.recv().await {
+                        Ok(payload) => match serde_json::from_str::<ControlMessage>(&payload) {
+                            Ok(message) => {
+                                let delivered = self.dispatch(message).await;
+                                debug!(delivered, "control message dispatched");
+                            }
+                            Err(e) => warn!(error = %e, "invalid control message"),
+                        },
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                            warn!(skipped = n, "control messages dropped")
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    }
+                },
+                Err(e) => warn!(error = %e, "subscribing to control channel failed"),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> ControlService {
+        ControlService::new(Arc::new(RedisRepository::new("redis://127.0.0.1/").unwrap()))
+    }
+
+    fn terminate(domain_id: Option<Uuid>, socket_ids: &[&str]) -> ControlMessage {
+        ControlMessage {
+            domain_id,
+            socket_ids: socket_ids.iter().map(|s| s.to_string()).collect(),
+            all: false,
+            command: ControlCommand::Terminate,
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_to_local_sockets_of_the_domain() {
+        let control = service();
+        let domain = Uuid::new_v4();
+        let mut a = control.register("1.1", Some(domain)).await;
+        let mut b = control.register("2.2", None).await;
+        assert_eq!(control.dispatch(terminate(Some(domain), &["1.1", "2.2", "3.3"])).await, 1);
+        assert_eq!(a.try_recv().unwrap(), ControlCommand::Terminate);
+        assert!(b.try_recv().is_err(), "other domain's socket is not touched");
+        let everyone = ControlMessage { all: true, ..terminate(None, &[]) };
+        assert_eq!(control.dispatch(everyone).await, 1);
+        assert_eq!(b.try_recv().unwrap(), ControlCommand::Terminate);
+        control.unregister("1.1").await;
+        assert_eq!(control.dispatch(terminate(Some(domain), &["1.1"])).await, 0);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let subscribe = ControlCommand::Subscribe {
+            channels: vec!["presence-team-7".to_string()],
+            user_id: Some("42".to_string()),
+            channel_data: None,
+        };
+        let json = serde_json::to_string(&subscribe).unwrap();
+        assert_eq!(json, r#"{"type":"subscribe","channels":["presence-team-7"],"user_id":"42"}"#);
+        assert_eq!(serde_json::from_str::<ControlCommand>(&json).unwrap(), subscribe);
+        assert_eq!(
+            serde_json::from_str::<ControlCommand>(r#"{"type":"terminate"}"#).unwrap(),
+            ControlCommand::Terminate
+        );
+    }
+
+    #[tokio::test]
+    async fn renamed_socket_keeps_receiving() {
+        let control = service();
+        let mut rx = control.register("new.1", None).await;
+        control.rename("new.1", "old.1").await;
+        assert_eq!(control.dispatch(terminate(None, &["new.1"])).await, 0);
+        assert_eq!(control.dispatch(terminate(None, &["old.1"])).await, 1);
+        assert_eq!(rx.try_recv().unwrap(), ControlCommand::Terminate);
+    }
+}
@@ -0,0 +1,324 @@
+//! Reliable (at-least-once) delivery: events published with `reliable: true` stay pending per
+//! recipient until a client acks them, and are redelivered after the ack timeout or on the
+//! recipient's next subscribe to the channel.
+//!
+//! The recipient is the subscription's verified user (connection token, channel JWT,
+//! authorizer, presence signature), so an ack from any of the user's devices settles it;
+//! otherwise it is the socket. Users that subscribed to a channel within the retention are
+//! its known recipients: a reliable event is made pending for all of them before it is
+//! published, so those offline at the time get it on their next subscribe.
+
+use crate::error::AppResult;
+use crate::models::event::WsEvent;
+use crate::repositories::RedisRepository;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Deliveries of one event to one socket before it waits for the next subscribe.
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Field of the delivery status hash holding the [`DeliveryMeta`].
+const META_FIELD: &str = "_meta";
+
+/// Ack timeout and how long unacked events and delivery status are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct DeliveryConfig {
+    pub ack_timeout: Duration,
+    pub retention: Duration,
+}
+
+/// Who has to ack a reliable event.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Recipient {
+    User { domain_id: Option<Uuid>, user_id: String },
+    Socket(String),
+}
+
+impl Recipient {
+    /// Redis key suffix; users are scoped by domain.
+    fn key(&self) -> String {
+        match self {
+            Recipient::User { domain_id, user_id } => {
+                let scope = domain_id.map(|d| d.to_string()).unwrap_or_else(|| "legacy".to_string());
+                format!("user:{}:{}", scope, user_id)
+            }
+            Recipient::Socket(socket_id) => format!("socket:{}", socket_id),
+        }
+    }
+
+    /// Name shown in the delivery status.
+    pub fn label(&self) -> String {
+        match self {
+            Recipient::User { user_id, .. } => format!("user:{}", user_id),
+            Recipient::Socket(socket_id) => format!("socket:{}", socket_id),
+        }
+    }
+}
+
+/// What was published, recorded when a reliable event is broadcast.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryMeta {
+    pub channel: String,
+    pub event: String,
+    pub published_at: i64,
+    #[serde(default)]
+    pub domain_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Recorded for a known recipient at publish, not sent to them yet.
+    Queued,
+    /// Sent, not acked yet.
+    Delivered,
+    Acked,
+}
+
+/// Delivery of an event to one recipient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientStatus {
+    pub recipient: String,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    /// Unix milliseconds of the last delivery.
+    #[serde(default)]
+    pub delivered_at: Option<i64>,
+    #[serde(default)]
+    pub acked_at: Option<i64>,
+}
+
+/// Response of the delivery status API.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryStatus {
+    pub id: String,
+    #[serde(flatten)]
+    pub meta: DeliveryMeta,
+    pub recipients: Vec<RecipientStatus>,
+    pub pending: usize,
+    pub acked: usize,
+}
+
+impl DeliveryStatus {
+    /// Build from the raw status hash; `None` without the publish record.
+    fn from_fields(id: &str, fields: Vec<(String, String)>) -> Option<Self> {
+        let mut meta = None;
+        let mut recipients = Vec::new();
+        for (field, value) in fields {
+            if field == META_FIELD {
+                meta = serde_json::from_str::<DeliveryMeta>(&value).ok();
+            } else if let Ok(status) = serde_json::from_str::<RecipientStatus>(&value) {
+                recipients.push(status);
+            }
+        }
+        recipients.sort_by(|a, b| a.recipient.cmp(&b.recipient));
+        let acked = recipients.iter().filter(|r| r.state == DeliveryState::Acked).count();
+        Some(Self {
+            id: id.to_string(),
+            meta: meta?,
+            pending: recipients.len() - acked,
+            acked,
+            recipients,
+        })
+    }
+}
+
+/// Pending reliable events per recipient and delivery status per event, in Redis.
+#[derive(Clone)]
+pub struct DeliveryService {
+    repo: Arc<RedisRepository>,
+    config: DeliveryConfig,
+}
+
+impl DeliveryService {
+    pub fn new(repo: Arc<RedisRepository>, config: DeliveryConfig) -> Self {
+        Self { repo, config }
+    }
+
+    pub fn config(&self) -> &DeliveryConfig {
+        &self.config
+    }
+
+    fn ttl_secs(&self) -> u64 {
+        self.config.retention.as_secs().max(1)
+    }
+
+    /// Make `recipient` a known recipient of the channel's reliable events. Only users are
+    /// kept; a socket id does not outlive its connection.
+    pub async fn enroll(&self, channel: &str, recipient: &Recipient) -> AppResult<()> {
+        if matches!(recipient, Recipient::Socket(_)) {
+            return Ok(());
+        }
+        self.repo
+            .reliable_recipient_add(channel, &recipient.key(), &recipient.label(), self.ttl_secs())
+            .await
+    }
+
+    /// Start tracking a reliable event from [`ChannelService::prepare`], before it is
+    /// published: the event becomes pending for every known recipient of its channel.
+    ///
+    /// [`ChannelService::prepare`]: crate::services::ChannelService::prepare
+    pub async fn open(&self, event: &WsEvent, domain_id: Option<Uuid>) -> AppResult<()> {
+        let meta = DeliveryMeta {
+            channel: event.channel.clone(),
+            event: event.event.clone(),
+            published_at: event.published_at,
+            domain_id,
+        };
+        let queued = self
+            .repo
+            .reliable_recipients(&event.channel)
+            .await?
+            .into_iter()
+            .map(|(recipient, label)| {
+                let status = RecipientStatus {
+                    recipient: label.clone(),
+                    state: DeliveryState::Queued,
+                    attempts: 0,
+                    delivered_at: None,
+                    acked_at: None,
+                };
+                Ok((recipient, label, serde_json::to_string(&status)?))
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        self.repo
+            .delivery_open(
+                &event.id,
+                (META_FIELD, &serde_json::to_string(&meta)?),
+                &queued,
+                &serde_json::to_string(event)?,
+                self.ttl_secs(),
+            )
+            .await
+    }
+
+    /// Drop what [`Self::open`] recorded for an event whose publish failed.
+    pub async fn cancel(&self, event: &WsEvent) -> AppResult<()> {
+        let recipients: Vec<String> = self
+            .repo
+            .reliable_recipients(&event.channel)
+            .await?
+            .into_iter()
+            .map(|(recipient, _)| recipient)
+            .collect();
+        self.repo.delivery_cancel(&event.id, &recipients).await
+    }
+
+    /// The event was sent to `recipient`: keep it pending and count the attempt.
+    /// Returns the number of deliveries so far.
+    pub async fn record_delivery(&self, recipient: &Recipient, event_id: &str, payload: &str) -> AppResult<u32> {
+        let ttl = self.ttl_secs();
+        self.repo.pending_add(&recipient.key(), event_id, payload, ttl).await?;
+        let label = recipient.label();
+        let previous = self
+            .repo
+            .delivery_get(event_id, &label)
+            .await?
+            .and_then(|v| serde_json::from_str::<RecipientStatus>(&v).ok());
+        let status = RecipientStatus {
+            recipient: label.clone(),
+            state: DeliveryState::Delivered,
+            attempts: previous.as_ref().map_or(0, |p| p.attempts) + 1,
+            delivered_at: Some(chrono::Utc::now().timestamp_millis()),
+            acked_at: None,
+        };
+        self.repo
+            .delivery_set(event_id, &label, &serde_json::to_string(&status)?, ttl)
+            .await?;
+        Ok(status.attempts)
+    }
+
+    /// The recipient acked the event. `false` if it was not pending for them.
+    pub async fn ack(&self, recipient: &Recipient, event_id: &str) -> AppResult<bool> {
+        if !self.repo.pending_remove(&recipient.key(), event_id).await? {
+            return Ok(false);
+        }
+        let label = recipient.label();
+        if let Some(mut status) = self
+            .repo
+            .delivery_get(event_id, &label)
+            .await?
+            .and_then(|v| serde_json::from_str::<RecipientStatus>(&v).ok())
+        {
+            status.state = DeliveryState::Acked;
+            status.acked_at = Some(chrono::Utc::now().timestamp_millis());
+            self.repo
+                .delivery_set(event_id, &label, &serde_json::to_string(&status)?, self.ttl_secs())
+                .await?;
+        }
+        Ok(true)
+    }
+
+    /// Stop redelivering an event the recipient never acked (it expired).
+    pub async fn discard(&self, recipient: &Recipient, event_id: &str) -> AppResult<()> {
+        self.repo.pending_remove(&recipient.key(), event_id).await?;
+        Ok(())
+    }
+
+    /// Whether the recipient still has to ack the event.
+    pub async fn is_pending(&self, recipient: &Recipient, event_id: &str) -> AppResult<bool> {
+        self.repo.pending_exists(&recipient.key(), event_id).await
+    }
+
+    /// Unacked events (serialized [`WsEvent`](crate::models::event::WsEvent)) of a recipient.
+    pub async fn pending(&self, recipient: &Recipient) -> AppResult<Vec<String>> {
+        let entries = self.repo.pending_all(&recipient.key()).await?;
+        Ok(entries.into_iter().map(|(_, payload)| payload).collect())
+    }
+
+    /// Delivery status of a reliable event, if still retained.
+    pub async fn status(&self, event_id: &str) -> AppResult<Option<DeliveryStatus>> {
+        let fields = self.repo.delivery_all(event_id).await?;
+        Ok(DeliveryStatus::from_fields(event_id, fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(recipient: &str, state: DeliveryState) -> (String, String) {
+        let status = RecipientStatus {
+            recipient: recipient.to_string(),
+            state,
+            attempts: 1,
+            delivered_at: Some(1_735_689_600_000),
+            acked_at: None,
+        };
+        (recipient.to_string(), serde_json::to_string(&status).unwrap())
+    }
+
+    #[test]
+    fn recipients_are_scoped_by_domain() {
+        let domain = Uuid::new_v4();
+        let user = Recipient::User { domain_id: Some(domain), user_id: "42".to_string() };
+        let other = Recipient::User { domain_id: None, user_id: "42".to_string() };
+        assert_ne!(user.key(), other.key());
+        assert_eq!(user.label(), "user:42");
+        assert_eq!(Recipient::Socket("1.2".to_string()).key(), "socket:1.2");
+    }
+
+    #[test]
+    fn status_counts_pending_and_acked() {
+        let meta = r#"{"channel":"private-orders","event":"paid","published_at":1,"domain_id":null}"#;
+        let fields = vec![
+            (META_FIELD.to_string(), meta.to_string()),
+            status("user:2", DeliveryState::Acked),
+            status("user:1", DeliveryState::Delivered),
+            status("socket:9.9", DeliveryState::Acked),
+            status("user:3", DeliveryState::Queued),
+        ];
+        let s = DeliveryStatus::from_fields("evt", fields).unwrap();
+        assert_eq!((s.pending, s.acked), (2, 2));
+        assert_eq!(s.recipients[0].recipient, "socket:9.9");
+        assert_eq!(s.meta.channel, "private-orders");
+    }
+
+    #[test]
+    fn status_requires_publish_record() {
+        assert!(DeliveryStatus::from_fields("evt", vec![status("user:1", DeliveryState::Delivered)]).is_none());
+        assert!(DeliveryStatus::from_fields("evt", Vec::new()).is_none());
+    }
+}
@@ -0,0 +1,91 @@
+//! End-to-end encrypted channels (`private-encrypted-*`).
+//!
+//! The encryption master key lives only in the customer's backend. Per channel it derives
+//! `shared_secret = SHA-256(channel_name || master_key)`, returns it to authorized clients in
+//! its auth response and encrypts event data with NaCl secretbox. The server only checks that
+//! published data has the `{ "nonce", "ciphertext" }` shape and never accepts plaintext.
+
+use crate::error::{AppError, AppResult};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+
+/// XSalsa20 nonce length, in bytes.
+pub const NONCE_LEN: usize = 24;
+
+/// Poly1305 tag length: the shortest possible ciphertext (empty plaintext), in bytes.
+pub const MAC_LEN: usize = 16;
+
+/// Event data published to an encrypted channel (both fields standard base64).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptedPayload {
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Check that `data` is a secretbox payload, either as an object or as its JSON string form.
+pub fn validate_encrypted_payload(data: &serde_json::Value) -> AppResult<()> {
+    let invalid = || {
+        AppError::Validation(
+            "encrypted channels only accept {\"nonce\", \"ciphertext\"} secretbox payloads".to_string(),
+        )
+    };
+    let payload: EncryptedPayload = match data {
+        serde_json::Value::String(s) => serde_json::from_str(s).map_err(|_| invalid())?,
+        other => serde_json::from_value(other.clone()).map_err(|_| invalid())?,
+    };
+    let nonce = BASE64.decode(&payload.nonce).map_err(|_| invalid())?;
+    let ciphertext = BASE64.decode(&payload.ciphertext).map_err(|_| invalid())?;
+    if nonce.len() != NONCE_LEN || ciphertext.len() < MAC_LEN {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto_secretbox::aead::{Aead, KeyInit};
+    use crypto_secretbox::XSalsa20Poly1305;
+    use sha2::{Digest, Sha256};
+
+    /// What the customer's backend derives for a channel: `SHA-256(channel_name || master_key)`.
+    fn shared_secret(channel: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(channel.as_bytes());
+        hasher.update([42u8; 32]);
+        hasher.finalize().into()
+    }
+
+    fn encrypt(channel: &str, plaintext: &[u8]) -> serde_json::Value {
+        let key = shared_secret(channel);
+        let nonce = [7u8; NONCE_LEN];
+        let ciphertext = XSalsa20Poly1305::new(&key.into())
+            .encrypt(&nonce.into(), plaintext)
+            .unwrap();
+        serde_json::json!({ "nonce": BASE64.encode(nonce), "ciphertext": BASE64.encode(ciphertext) })
+    }
+
+    #[test]
+    fn accepts_secretbox_payloads() {
+        let payload = encrypt("private-encrypted-a", br#"{"ssn":"123"}"#);
+        assert!(validate_encrypted_payload(&payload).is_ok());
+        assert!(validate_encrypted_payload(&serde_json::Value::String(payload.to_string())).is_ok());
+        let empty = encrypt("private-encrypted-a", b"");
+        assert!(validate_encrypted_payload(&empty).is_ok());
+    }
+
+    #[test]
+    fn rejects_plaintext_and_malformed_payloads() {
+        assert!(validate_encrypted_payload(&serde_json::json!({ "ssn": "123" })).is_err());
+        assert!(validate_encrypted_payload(&serde_json::json!("hello")).is_err());
+        let short_nonce = serde_json::json!({ "nonce": BASE64.encode([0u8; 8]), "ciphertext": BASE64.encode([0u8; 32]) });
+        assert!(validate_encrypted_payload(&short_nonce).is_err());
+        let short_ct = serde_json::json!({ "nonce": BASE64.encode([0u8; NONCE_LEN]), "ciphertext": BASE64.encode([0u8; 4]) });
+        assert!(validate_encrypted_payload(&short_ct).is_err());
+        let mut extra = encrypt("private-encrypted-a", b"x");
+        extra["plaintext"] = serde_json::json!("x");
+        assert!(validate_encrypted_payload(&extra).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Channel history: opt-in per domain (channel patterns), kept in Redis Streams.
+//!
+//! Each domain has its own stream per channel, so tenants using the same channel name never
+//! read each other's history. Stream entry ids (`<ms>-<seq>`) double as event ids and
+//! pagination cursors.
+
+use crate::db::DomainRow;
+use crate::models::channel::channel_matches_pattern;
+use std::fmt;
+use uuid::Uuid;
+
+/// Most events returned by one history page or one rewind.
+pub const MAX_HISTORY_PAGE: usize = 200;
+
+/// Default page size of `GET /api/channels/:name/history`.
+pub const DEFAULT_HISTORY_PAGE: usize = 50;
+
+/// Largest `history_max_events` a domain may configure.
+pub const MAX_HISTORY_EVENTS: i32 = 10_000;
+
+/// History stream of a domain's channel.
+pub fn history_stream(domain_id: Uuid, channel: &str) -> String {
+    format!("{}:{}", domain_id, channel)
+}
+
+/// Retention of one channel's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryPolicy {
+    /// Domain whose stream keeps the events.
+    pub domain_id: Uuid,
+    /// Keep at most this many events.
+    pub max_events: usize,
+    /// Drop events older than this many seconds.
+    pub max_age_secs: Option<u64>,
+}
+
+impl HistoryPolicy {
+    /// Policy for `channel` if the domain enabled history for it.
+    pub fn for_channel(domain: &DomainRow, channel: &str) -> Option<Self> {
+        domain
+            .history_channels
+            .iter()
+            .any(|pattern| channel_matches_pattern(pattern, channel))
+            .then(|| Self {
+                domain_id: domain.id,
+                max_events: domain.history_max_events.max(1) as usize,
+                max_age_secs: domain.history_max_age_secs.filter(|s| *s > 0).map(|s| s as u64),
+            })
+    }
+
+    /// Oldest stream id to keep, for a given current time in milliseconds.
+    pub fn min_id(&self, now_ms: i64) -> Option<StreamId> {
+        self.max_age_secs.map(|age| StreamId {
+            ms: (now_ms.max(0) as u64).saturating_sub(age * 1000),
+            seq: 0,
+        })
+    }
+}
+
+/// Redis stream entry id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// Parse `<ms>-<seq>`; `None` for anything else.
+    pub fn parse(id: &str) -> Option<Self> {
+        let (ms, seq) = id.split_once('-')?;
+        Some(Self {
+            ms: ms.parse().ok()?,
+            seq: seq.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn domain(channels: &[&str], max_age: Option<i64>) -> DomainRow {
+        DomainRow {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            domain_name: "example.com".to_string(),
+            key_prefix: String::new(),
+            key_hash: String::new(),
+            secret: String::new(),
+            created_at: Utc::now(),
+            is_active: true,
+            dev_mode: false,
+            authorizer_url: None,
+            history_channels: channels.iter().map(|c| c.to_string()).collect(),
+            history_max_events: 100,
+            history_max_age_secs: max_age,
+            presence_last_seen_secs: 0,
+            presence_max_members: 1000,
+            presence_max_channel_data_bytes: 4096,
+        }
+    }
+
+    #[test]
+    fn policy_only_for_opted_in_channels() {
+        let d = domain(&["orders-*", "private-chat"], Some(3600));
+        let policy = HistoryPolicy::for_channel(&d, "orders-42").unwrap();
+        assert_eq!(policy.max_events, 100);
+        assert_eq!(policy.max_age_secs, Some(3600));
+        assert!(HistoryPolicy::for_channel(&d, "private-chat").is_some());
+        assert!(HistoryPolicy::for_channel(&d, "prices").is_none());
+        assert!(HistoryPolicy::for_channel(&domain(&[], None), "orders-42").is_none());
+    }
+
+    #[test]
+    fn streams_are_kept_per_domain() {
+        let (a, b) = (domain(&["orders-*"], None), domain(&["orders-*"], None));
+        let policy = HistoryPolicy::for_channel(&a, "orders-42").unwrap();
+        assert_eq!(policy.domain_id, a.id);
+        assert_ne!(history_stream(a.id, "orders-42"), history_stream(b.id, "orders-42"));
+        assert_eq!(history_stream(a.id, "orders-42"), format!("{}:orders-42", a.id));
+    }
+
+    #[test]
+    fn min_id_from_max_age() {
+        let policy = HistoryPolicy::for_channel(&domain(&["*"], Some(60)), "x").unwrap();
+        assert_eq!(policy.min_id(100_000), Some(StreamId { ms: 40_000, seq: 0 }));
+        let unbounded = HistoryPolicy::for_channel(&domain(&["*"], Some(0)), "x").unwrap();
+        assert_eq!(unbounded.min_id(100_000), None);
+    }
+
+    #[test]
+    fn stream_ids_parse_and_order() {
+        let a = StreamId::parse("1700000000000-0").unwrap();
+        let b = StreamId::parse("1700000000000-1").unwrap();
+        let c = StreamId::parse("1700000000001-0").unwrap();
+        assert!(a < b && b < c);
+        assert_eq!(b.to_string(), "1700000000000-1");
+        assert!(StreamId::parse("abc").is_none());
+        assert!(StreamId::parse("1-x").is_none());
+        assert!(StreamId::parse("").is_none());
+    }
+}
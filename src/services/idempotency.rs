@@ -0,0 +1,190 @@
+//! Idempotency keys for the publish API: a retried request with the same `Idempotency-Key`
+//! gets the original response instead of publishing again.
+//!
+//! Keys are scoped per domain and remembered in Redis for a configurable window. The first
+//! request claims the key with a short-lived in-progress record, so a request that dies
+//! mid-publish does not block retries for the whole window; the response replaces it once
+//! done and is kept for the window.
+
+use crate::error::{AppError, AppResult};
+use crate::repositories::RedisRepository;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Longest accepted idempotency key.
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// How long an in-progress claim blocks retries if its request never completes or releases it.
+pub const IDEMPOTENCY_CLAIM_TTL: Duration = Duration::from_secs(30);
+
+/// What is stored under a key: the request fingerprint, plus the response once completed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    fingerprint: String,
+    /// Random id of an in-progress claim, so only its request releases it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    claim: Option<String>,
+    #[serde(default)]
+    response: Option<serde_json::Value>,
+}
+
+impl IdempotencyRecord {
+    /// Outcome for a repeated request: the stored response, or why it cannot be replayed.
+    fn replay(self, fingerprint: &str) -> AppResult<serde_json::Value> {
+        if self.fingerprint != fingerprint {
+            return Err(AppError::Validation(
+                "Idempotency-Key was already used for a different request".to_string(),
+            ));
+        }
+        self.response.ok_or_else(|| {
+            AppError::Conflict("A request with this Idempotency-Key is still in progress".to_string())
+        })
+    }
+}
+
+/// An in-progress claim held by one request.
+#[derive(Debug)]
+pub struct ClaimToken(String);
+
+/// Result of [`IdempotencyService::begin`].
+#[derive(Debug)]
+pub enum IdempotencyClaim {
+    /// First request with this key: process it, then [`complete`](IdempotencyService::complete)
+    /// or [`release`](IdempotencyService::release).
+    New(ClaimToken),
+    /// Already processed: return this response.
+    Replay(serde_json::Value),
+}
+
+/// Check an idempotency key from a header or request body.
+pub fn validate_idempotency_key(key: &str) -> AppResult<()> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN || key.chars().any(|c| c.is_control()) {
+        return Err(AppError::Validation(format!(
+            "Idempotency-Key must be 1-{} printable characters",
+            MAX_IDEMPOTENCY_KEY_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Fingerprint of a request body, to detect a key reused for a different request.
+pub fn request_fingerprint<T: Serialize>(request: &T) -> AppResult<String> {
+    let body = serde_json::to_vec(request)?;
+    Ok(hex::encode(Sha256::digest(&body)))
+}
+
+/// Remembers publish responses by idempotency key.
+#[derive(Clone)]
+pub struct IdempotencyService {
+    repo: Arc<RedisRepository>,
+    window: Duration,
+    claim_ttl: Duration,
+}
+
+impl IdempotencyService {
+    pub fn new(repo: Arc<RedisRepository>, window: Duration, claim_ttl: Duration) -> Self {
+        Self { repo, window, claim_ttl }
+    }
+
+    fn storage_key(domain_id: Option<Uuid>, key: &str) -> String {
+        let scope = domain_id.map(|d| d.to_string()).unwrap_or_else(|| "legacy".to_string());
+        format!("{}:{}", scope, key)
+    }
+
+    /// Claim `key` for a request, or get the response of the request that claimed it.
+    pub async fn begin(&self, domain_id: Option<Uuid>, key: &str, fingerprint: &str) -> AppResult<IdempotencyClaim> {
+        let storage_key = Self::storage_key(domain_id, key);
+        let pending = serde_json::to_string(&IdempotencyRecord {
+            fingerprint: fingerprint.to_string(),
+            claim: Some(Uuid::new_v4().to_string()),
+            response: None,
+        })?;
+        let ttl = self.claim_ttl.min(self.window).as_secs().max(1);
+        loop {
+            if self.repo.idempotency_claim(&storage_key, &pending, ttl).await? {
+                return Ok(IdempotencyClaim::New(ClaimToken(pending)));
+            }
+            // `None`: expired between the two calls, claim it again.
+            if let Some(json) = self.repo.idempotency_get(&storage_key).await? {
+                return match serde_json::from_str::<IdempotencyRecord>(&json) {
+                    Ok(record) => record.replay(fingerprint).map(IdempotencyClaim::Replay),
+                    Err(_) => Ok(IdempotencyClaim::New(ClaimToken(pending))),
+                };
+            }
+        }
+    }
+
+    /// Store the response of a claimed request, kept for the whole window.
+    pub async fn complete(
+        &self,
+        domain_id: Option<Uuid>,
+        key: &str,
+        fingerprint: &str,
+        response: &serde_json::Value,
+    ) -> AppResult<()> {
+        let record = IdempotencyRecord {
+            fingerprint: fingerprint.to_string(),
+            claim: None,
+            response: Some(response.clone()),
+        };
+        self.repo
+            .idempotency_store(
+                &Self::storage_key(domain_id, key),
+                &serde_json::to_string(&record)?,
+                self.window.as_secs().max(1),
+            )
+            .await
+    }
+
+    /// Give up a claim after the request failed, so a retry can process it. A claim that
+    /// expired and was taken by a retry meanwhile is left alone.
+    pub async fn release(&self, domain_id: Option<Uuid>, key: &str, claim: &ClaimToken) -> AppResult<()> {
+        self.repo
+            .idempotency_release(&Self::storage_key(domain_id, key), &claim.0)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_must_be_short_and_printable() {
+        assert!(validate_idempotency_key("order-42-paid").is_ok());
+        assert!(validate_idempotency_key("").is_err());
+        assert!(validate_idempotency_key("a\nb").is_err());
+        assert!(validate_idempotency_key(&"k".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn replay_needs_same_request_and_finished_response() {
+        let fingerprint = request_fingerprint(&serde_json::json!({ "channel": "a" })).unwrap();
+        let done = IdempotencyRecord {
+            fingerprint: fingerprint.clone(),
+            claim: None,
+            response: Some(serde_json::json!({ "ok": true })),
+        };
+        assert_eq!(done.clone().replay(&fingerprint).unwrap()["ok"], true);
+        let other = request_fingerprint(&serde_json::json!({ "channel": "b" })).unwrap();
+        assert!(matches!(done.replay(&other), Err(AppError::Validation(_))));
+        let in_progress = IdempotencyRecord {
+            fingerprint: fingerprint.clone(),
+            claim: Some(Uuid::new_v4().to_string()),
+            response: None,
+        };
+        assert!(matches!(in_progress.replay(&fingerprint), Err(AppError::Conflict(_))));
+    }
+
+    #[test]
+    fn keys_are_scoped_by_domain() {
+        let domain = Uuid::new_v4();
+        assert_ne!(
+            IdempotencyService::storage_key(Some(domain), "k"),
+            IdempotencyService::storage_key(None, "k")
+        );
+    }
+}
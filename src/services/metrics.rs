@@ -0,0 +1,90 @@
+//! Process-wide counters, exposed at `GET /metrics` in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Where an event was about to be delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryPath {
+    /// Live fan-out to subscribed sockets.
+    Live,
+    /// Rewind, cache channel replay or resume replay.
+    Replay,
+    /// Reliable event redelivery.
+    Redelivery,
+    /// Scheduled broadcast dispatch.
+    Scheduled,
+}
+
+impl DeliveryPath {
+    const ALL: [DeliveryPath; 4] = [
+        DeliveryPath::Live,
+        DeliveryPath::Replay,
+        DeliveryPath::Redelivery,
+        DeliveryPath::Scheduled,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            DeliveryPath::Live => "live",
+            DeliveryPath::Replay => "replay",
+            DeliveryPath::Redelivery => "redelivery",
+            DeliveryPath::Scheduled => "scheduled",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Expired events dropped instead of delivered, per [`DeliveryPath`].
+    expired_dropped: [AtomicU64; 4],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn expired_dropped(&self, path: DeliveryPath) {
+        self.expired_dropped[path as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn expired_dropped_count(&self, path: DeliveryPath) -> u64 {
+        self.expired_dropped[path as usize].load(Ordering::Relaxed)
+    }
+
+    /// Prometheus text exposition.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP notif_expired_events_dropped_total Events not delivered because their TTL passed.\n");
+        out.push_str("# TYPE notif_expired_events_dropped_total counter\n");
+        for path in DeliveryPath::ALL {
+            let _ = writeln!(
+                out,
+                "notif_expired_events_dropped_total{{path=\"{}\"}} {}",
+                path.label(),
+                self.expired_dropped_count(path)
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_path() {
+        let metrics = Metrics::new();
+        metrics.expired_dropped(DeliveryPath::Live);
+        metrics.expired_dropped(DeliveryPath::Live);
+        metrics.expired_dropped(DeliveryPath::Scheduled);
+        assert_eq!(metrics.expired_dropped_count(DeliveryPath::Live), 2);
+        assert_eq!(metrics.expired_dropped_count(DeliveryPath::Replay), 0);
+        let text = metrics.render();
+        assert!(text.contains("notif_expired_events_dropped_total{path=\"live\"} 2\n"));
+        assert!(text.contains("notif_expired_events_dropped_total{path=\"scheduled\"} 1\n"));
+        assert!(text.contains("# TYPE notif_expired_events_dropped_total counter"));
+    }
+}
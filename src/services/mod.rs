@@ -1,9 +1,35 @@
-//! Business logic: channel subscription, presence, and auth.
+//! Business logic: channel subscription, presence, auth, API keys, origin policy,
+//! encrypted channel payloads, channel history, connection recovery, reliable delivery,
+//! idempotent publishing, scheduled broadcasts and long-polling sessions.
 
+pub mod api_key;
 pub mod auth;
+pub mod authorizer;
 pub mod channel;
+pub mod connection_token;
+pub mod control;
+pub mod delivery;
+pub mod encryption;
+pub mod history;
+pub mod idempotency;
+pub mod metrics;
+pub mod moderation;
+pub mod origin;
+pub mod polling;
 pub mod presence;
+pub mod recovery;
+pub mod schedule;
+pub mod user;
 
 pub use auth::AuthService;
+pub use authorizer::AuthorizerService;
 pub use channel::ChannelService;
+pub use control::ControlService;
+pub use delivery::DeliveryService;
+pub use idempotency::IdempotencyService;
+pub use metrics::Metrics;
+pub use moderation::ModerationService;
+pub use polling::PollingService;
 pub use presence::PresenceService;
+pub use schedule::ScheduleService;
+pub use user::UserService;
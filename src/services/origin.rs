@@ -0,0 +1,300 @@
+//! Origin policy per domain: allowed origins with optional scheme/port constraints,
+//! label-boundary wildcard matching, and a dev mode that allows localhost.
+
+use crate::error::{AppError, AppResult};
+
+/// Parsed `Origin` header (e.g. `https://app.example.com:8443`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestOrigin {
+    pub scheme: String,
+    pub host: String,
+    /// Explicit port, or the scheme's default (80/443) when known.
+    pub port: Option<u16>,
+}
+
+impl RequestOrigin {
+    /// Parse an `Origin` header value. Returns `None` for `null` or malformed origins.
+    pub fn parse(origin: &str) -> Option<Self> {
+        let origin = origin.trim().to_lowercase();
+        let (scheme, rest) = origin.split_once("://")?;
+        if !is_valid_scheme(scheme) {
+            return None;
+        }
+        let authority = rest.split(['/', '?', '#']).next()?;
+        let (host, port) = split_host_port(authority)?;
+        let port = match port {
+            Some(p) => Some(p.parse::<u16>().ok()?),
+            None => default_port(scheme),
+        };
+        Some(Self {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Port constraint of an allowed origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortRule {
+    /// No port given, or `:*`: any port.
+    Any,
+    Exact(u16),
+}
+
+/// One allowed origin entry: `[scheme://]host[:port]`, where host may be `*.example.com`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedOrigin {
+    /// `None` = any scheme.
+    pub scheme: Option<String>,
+    /// Lowercase host; wildcard entries keep the leading `*.`.
+    pub host: String,
+    pub port: PortRule,
+}
+
+impl AllowedOrigin {
+    /// Parse and validate an allowed-origin pattern as entered in the dashboard.
+    pub fn parse(pattern: &str) -> AppResult<Self> {
+        let invalid = || AppError::Validation(format!("invalid origin pattern: {}", pattern));
+        let pattern = pattern.trim().to_lowercase();
+        let (scheme, rest) = match pattern.split_once("://") {
+            Some((s, r)) if is_valid_scheme(s) => (Some(s.to_string()), r),
+            Some(_) => return Err(invalid()),
+            None => (None, pattern.as_str()),
+        };
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        if rest.contains(['/', '?', '#', '@']) {
+            return Err(invalid());
+        }
+        let (host, port) = split_host_port(rest).ok_or_else(invalid)?;
+        let port = match port {
+            None | Some("*") => PortRule::Any,
+            Some(p) => PortRule::Exact(p.parse::<u16>().map_err(|_| invalid())?),
+        };
+        let bare = host.strip_prefix("*.").unwrap_or(host);
+        if !is_valid_host(bare) || (host.starts_with("*.") && !bare.contains('.')) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            scheme,
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Whether this entry allows the given request origin.
+    pub fn matches(&self, origin: &RequestOrigin) -> bool {
+        if let Some(scheme) = &self.scheme {
+            if scheme != &origin.scheme {
+                return false;
+            }
+        }
+        if let PortRule::Exact(port) = self.port {
+            if origin.port != Some(port) {
+                return false;
+            }
+        }
+        host_matches(&self.host, &origin.host)
+    }
+}
+
+/// Host match: exact, or `*.example.com` matching `example.com` and any subdomain on a label boundary.
+pub fn host_matches(allowed: &str, host: &str) -> bool {
+    match allowed.strip_prefix("*.") {
+        Some(suffix) => {
+            host == suffix
+                || host
+                    .strip_suffix(suffix)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+        }
+        None => allowed == host,
+    }
+}
+
+/// Hosts allowed for every domain in dev mode.
+const DEV_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// Complete origin policy of one domain.
+#[derive(Debug, Clone, Default)]
+pub struct OriginPolicy {
+    allowed: Vec<AllowedOrigin>,
+    dev_mode: bool,
+}
+
+impl OriginPolicy {
+    /// Build the policy from the domain name (always allowed, any scheme/port) and extra entries.
+    /// Entries that no longer parse are skipped.
+    pub fn new(domain_name: &str, origins: &[String], dev_mode: bool) -> Self {
+        let allowed = std::iter::once(domain_name)
+            .chain(origins.iter().map(String::as_str))
+            .filter_map(|p| AllowedOrigin::parse(p).ok())
+            .collect();
+        Self { allowed, dev_mode }
+    }
+
+    /// Whether a raw `Origin` header value is allowed.
+    pub fn allows(&self, origin: &str) -> bool {
+        let Some(origin) = RequestOrigin::parse(origin) else {
+            return false;
+        };
+        if self.dev_mode && (DEV_HOSTS.contains(&origin.host.as_str()) || origin.host.ends_with(".localhost")) {
+            return true;
+        }
+        self.allowed.iter().any(|a| a.matches(&origin))
+    }
+}
+
+fn is_valid_scheme(scheme: &str) -> bool {
+    let mut chars = scheme.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+fn is_valid_host(host: &str) -> bool {
+    if host.starts_with('[') {
+        return host.ends_with(']') && host.len() > 2;
+    }
+    !host.is_empty()
+        && host
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+}
+
+/// Split `host[:port]`, keeping IPv6 literals (`[::1]:3000`) intact.
+fn split_host_port(authority: &str) -> Option<(&str, Option<&str>)> {
+    if authority.is_empty() || authority.contains('@') {
+        return None;
+    }
+    if authority.starts_with('[') {
+        let end = authority.find(']')?;
+        let (host, rest) = authority.split_at(end + 1);
+        return match rest {
+            "" => Some((host, None)),
+            _ => Some((host, Some(rest.strip_prefix(':')?))),
+        };
+    }
+    match authority.split_once(':') {
+        Some((host, port)) if !host.is_empty() && !port.is_empty() => Some((host, Some(port))),
+        Some(_) => None,
+        None => Some((authority, None)),
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(domain: &str, origins: &[&str], dev_mode: bool) -> OriginPolicy {
+        let origins: Vec<String> = origins.iter().map(|s| s.to_string()).collect();
+        OriginPolicy::new(domain, &origins, dev_mode)
+    }
+
+    #[test]
+    fn parse_request_origin() {
+        assert_eq!(
+            RequestOrigin::parse("https://App.Example.com"),
+            Some(RequestOrigin {
+                scheme: "https".to_string(),
+                host: "app.example.com".to_string(),
+                port: Some(443)
+            })
+        );
+        let o = RequestOrigin::parse("http://localhost:3000").unwrap();
+        assert_eq!((o.host.as_str(), o.port), ("localhost", Some(3000)));
+        let o = RequestOrigin::parse("http://[::1]:8080/path").unwrap();
+        assert_eq!((o.host.as_str(), o.port), ("[::1]", Some(8080)));
+        let o = RequestOrigin::parse("capacitor://localhost").unwrap();
+        assert_eq!((o.scheme.as_str(), o.port), ("capacitor", None));
+    }
+
+    #[test]
+    fn parse_request_origin_invalid() {
+        assert_eq!(RequestOrigin::parse("null"), None);
+        assert_eq!(RequestOrigin::parse("not-a-url"), None);
+        assert_eq!(RequestOrigin::parse(""), None);
+        assert_eq!(RequestOrigin::parse("http://host:notaport"), None);
+        assert_eq!(RequestOrigin::parse("http://host:99999"), None);
+        assert_eq!(RequestOrigin::parse("http://user@host"), None);
+    }
+
+    #[test]
+    fn parse_allowed_origin_patterns() {
+        let a = AllowedOrigin::parse("https://*.example.com:8443").unwrap();
+        assert_eq!(a.scheme.as_deref(), Some("https"));
+        assert_eq!(a.host, "*.example.com");
+        assert_eq!(a.port, PortRule::Exact(8443));
+        let a = AllowedOrigin::parse("Localhost:*").unwrap();
+        assert_eq!((a.scheme, a.host.as_str(), a.port), (None, "localhost", PortRule::Any));
+        assert!(AllowedOrigin::parse("https://example.com/").is_ok());
+    }
+
+    #[test]
+    fn parse_allowed_origin_rejects_invalid() {
+        for p in ["", "*", "*.com", "https://", "example.com/path", "ex ample.com", "host:abc", "1http://x.com", "a..b"] {
+            assert!(AllowedOrigin::parse(p).is_err(), "{} should be rejected", p);
+        }
+    }
+
+    #[test]
+    fn host_matches_label_boundary() {
+        assert!(host_matches("*.example.com", "app.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "evilexample.com"));
+        assert!(!host_matches("*.example.com", ".example.com"));
+        assert!(!host_matches("*.example.com", "example.com.evil.net"));
+        assert!(host_matches("app.example.com", "app.example.com"));
+        assert!(!host_matches("example.com", "app.example.com"));
+    }
+
+    #[test]
+    fn domain_name_allows_any_scheme_and_port() {
+        let p = policy("localhost", &[], false);
+        assert!(p.allows("http://localhost:3000"));
+        assert!(p.allows("https://localhost"));
+        assert!(!p.allows("http://127.0.0.1:3000"));
+    }
+
+    #[test]
+    fn scheme_and_port_constraints() {
+        let p = policy("example.com", &["https://app.example.net", "http://admin.example.net:8080"], false);
+        assert!(p.allows("https://app.example.net"));
+        assert!(p.allows("https://app.example.net:443"));
+        assert!(!p.allows("http://app.example.net"));
+        assert!(p.allows("http://admin.example.net:8080"));
+        assert!(!p.allows("http://admin.example.net"));
+        assert!(!p.allows("https://admin.example.net:8080"));
+    }
+
+    #[test]
+    fn multiple_origins_and_wildcards() {
+        let p = policy("*.example.com", &["partner.io", "*.cdn.example.org"], false);
+        assert!(p.allows("https://www.example.com"));
+        assert!(!p.allows("https://evilexample.com"));
+        assert!(p.allows("http://partner.io"));
+        assert!(!p.allows("http://notpartner.io"));
+        assert!(p.allows("https://eu.cdn.example.org"));
+        assert!(!p.allows("https://example.org"));
+        assert!(!p.allows("null"));
+    }
+
+    #[test]
+    fn dev_mode_allows_localhost_only() {
+        let p = policy("example.com", &[], true);
+        assert!(p.allows("http://localhost:5173"));
+        assert!(p.allows("http://127.0.0.1:8080"));
+        assert!(p.allows("http://[::1]:3000"));
+        assert!(p.allows("http://app.localhost:3000"));
+        assert!(!p.allows("http://localhost.evil.com"));
+        assert!(!p.allows("https://other.com"));
+        assert!(!policy("example.com", &[], false).allows("http://localhost:5173"));
+    }
+}
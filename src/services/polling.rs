@@ -0,0 +1,371 @@
+//! HTTP long-polling sessions: the WebSocket protocol over request/response, for clients
+//! behind proxies that break both WebSockets and streaming responses.
+//!
+//! A session runs on the node that created it, like a socket. Its outgoing frames are
+//! queued in Redis, so any node can answer a poll; frames the client sends are published on
+//! one internal channel every node listens on and reach the owning node. A session that is
+//! not polled for [`POLL_SESSION_TIMEOUT_SECS`] expires and its owner closes it; one whose
+//! owner stops refreshing it for [`POLL_OWNER_TIMEOUT_SECS`] (the node died) is gone too.
+//!
+//! Each poll returns an `ack` count; frames stay queued until a later poll acks them, so a
+//! response lost on the way is returned again.
+
+use crate::error::AppResult;
+use crate::repositories::RedisRepository;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify, RwLock};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Seconds without a poll after which a session is reclaimed.
+pub const POLL_SESSION_TIMEOUT_SECS: u64 = 60;
+
+/// Seconds without an owner heartbeat after which a session is gone.
+pub const POLL_OWNER_TIMEOUT_SECS: u64 = 15;
+
+/// Default and longest wait of a poll for new frames.
+pub const DEFAULT_POLL_WAIT_SECS: u64 = 25;
+pub const MAX_POLL_WAIT_SECS: u64 = 30;
+
+/// Most frames returned by one poll.
+pub const MAX_POLL_FRAMES: usize = 100;
+
+/// Most frames queued for a client that does not poll; older ones are dropped.
+const MAX_POLL_BACKLOG: usize = 1000;
+
+/// Most frames per send request.
+pub const MAX_POLL_SEND_FRAMES: usize = 100;
+
+/// Internal pub/sub channel every node listens on.
+const POLLING_CHANNEL: &str = "#polling";
+
+/// Wait before listening again after the polling subscription dropped.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// What reaches the node that owns a session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PollInbound {
+    /// A client frame, handled like a WebSocket text message.
+    Frame { frame: String },
+    /// The client ended the session.
+    Close,
+}
+
+/// A message between nodes about one session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PollMessage {
+    /// For the owner.
+    Inbound { session_id: String, inbound: PollInbound },
+    /// The session's outbox has new frames: wake its waiting polls.
+    Ready { session_id: String },
+}
+
+/// Random session id; it is the only credential of poll and send requests.
+pub fn generate_session_id() -> String {
+    format!("np_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Creates sessions, queues frames between client and owner, wakes waiting polls.
+#[derive(Clone)]
+pub struct PollingService {
+    repo: Arc<RedisRepository>,
+    /// Sessions owned by this node.
+    owned: Arc<RwLock<HashMap<String, mpsc::UnboundedSender<PollInbound>>>>,
+    /// Polls waiting on this node, by session.
+    waiting: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+}
+
+/// Queued frames returned by a poll, and the `ack` that confirms them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PollFrames {
+    pub frames: Vec<String>,
+    pub ack: u64,
+}
+
+/// One poll waiting on this node; leaves the waiting map when dropped, also when the
+/// request is cancelled.
+struct Waiter<'a> {
+    waiting: &'a Mutex<HashMap<String, Arc<Notify>>>,
+    session_id: &'a str,
+    notify: Arc<Notify>,
+}
+
+impl<'a> Waiter<'a> {
+    fn new(waiting: &'a Mutex<HashMap<String, Arc<Notify>>>, session_id: &'a str) -> Self {
+        let notify = waiting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(session_id.to_string())
+            .or_default()
+            .clone();
+        Self {
+            waiting,
+            session_id,
+            notify,
+        }
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        // The map and this poll hold the last references: nobody else waits.
+        if Arc::strong_count(&self.notify) == 2 {
+            waiting.remove(self.session_id);
+        }
+    }
+}
+
+impl PollingService {
+    pub fn new(repo: Arc<RedisRepository>) -> Self {
+        Self {
+            repo,
+            owned: Arc::new(RwLock::new(HashMap::new())),
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Start a session owned by this node; frames the client sends arrive on the receiver.
+    pub async fn create(&self) -> AppResult<(String, mpsc::UnboundedReceiver<PollInbound>)> {
+        let session_id = generate_session_id();
+        self.repo
+            .poll_session_create(&session_id, POLL_SESSION_TIMEOUT_SECS, POLL_OWNER_TIMEOUT_SECS)
+            .await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.owned.write().await.insert(session_id.clone(), tx);
+        Ok((session_id, rx))
+    }
+
+    /// The owner still runs the session. Returns whether it was polled within the timeout.
+    pub async fn keep_alive(&self, session_id: &str) -> AppResult<bool> {
+        self.repo
+            .poll_session_refresh_owner(session_id, POLL_OWNER_TIMEOUT_SECS)
+            .await
+    }
+
+    /// The owner ended the session: forget it and drop its queued frames.
+    pub async fn release(&self, session_id: &str) -> AppResult<()> {
+        self.owned.write().await.remove(session_id);
+        self.repo.poll_session_delete(session_id, true).await
+    }
+
+    /// Like [`release`](Self::release), but frames still queued (e.g. a termination notice)
+    /// are returned by one more poll.
+    pub async fn release_after_frames(&self, session_id: &str) -> AppResult<()> {
+        self.owned.write().await.remove(session_id);
+        self.repo.poll_session_delete(session_id, false).await
+    }
+
+    /// Queue frames for the client and wake its polls on any node.
+    pub async fn push(&self, session_id: &str, frames: &[String]) -> AppResult<()> {
+        self.repo
+            .poll_outbox_push(session_id, frames, MAX_POLL_BACKLOG, POLL_SESSION_TIMEOUT_SECS)
+            .await?;
+        self.publish(PollMessage::Ready {
+            session_id: session_id.to_string(),
+        })
+        .await
+    }
+
+    /// Queued frames after `ack` (see [`PollFrames`]; `None` acks what the previous poll
+    /// returned), waiting up to `wait` for some. `None` if the session is gone and nothing
+    /// is left to take.
+    pub async fn poll(&self, session_id: &str, ack: Option<u64>, wait: Duration) -> AppResult<Option<PollFrames>> {
+        if !self.repo.poll_session_touch(session_id, POLL_SESSION_TIMEOUT_SECS).await? {
+            let polled = self.read(session_id, ack).await?;
+            return Ok(Some(polled).filter(|p| !p.frames.is_empty()));
+        }
+        let waiter = Waiter::new(&self.waiting, session_id);
+        let polled = self.wait_for_frames(session_id, ack, &waiter.notify, wait).await?;
+        drop(waiter);
+        self.repo.poll_session_touch(session_id, POLL_SESSION_TIMEOUT_SECS).await?;
+        Ok(Some(polled))
+    }
+
+    async fn read(&self, session_id: &str, ack: Option<u64>) -> AppResult<PollFrames> {
+        let (frames, ack) = self
+            .repo
+            .poll_outbox_read(session_id, ack, MAX_POLL_FRAMES, POLL_SESSION_TIMEOUT_SECS)
+            .await?;
+        Ok(PollFrames { frames, ack })
+    }
+
+    async fn wait_for_frames(
+        &self,
+        session_id: &str,
+        mut ack: Option<u64>,
+        notify: &Notify,
+        wait: Duration,
+    ) -> AppResult<PollFrames> {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // Listen before looking, so a push in between is not missed.
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let polled = self.read(session_id, ack).await?;
+            if !polled.frames.is_empty() {
+                return Ok(polled);
+            }
+            ack = Some(polled.ack);
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Ok(polled);
+            }
+        }
+    }
+
+    /// Hand a client message to the session's owner. `false` if the session is gone.
+    pub async fn send(&self, session_id: &str, inbound: PollInbound) -> AppResult<bool> {
+        if !self.repo.poll_session_touch(session_id, POLL_SESSION_TIMEOUT_SECS).await? {
+            return Ok(false);
+        }
+        self.publish(PollMessage::Inbound {
+            session_id: session_id.to_string(),
+            inbound,
+        })
+        .await?;
+        Ok(true)
+    }
+
+    async fn publish(&self, message: PollMessage) -> AppResult<()> {
+        self.repo
+            .publish(POLLING_CHANNEL, &serde_json::to_string(&message)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Act on a message if it concerns this node. Returns whether it did.
+    async fn dispatch(&self, message: PollMessage) -> bool {
+        match message {
+            PollMessage::Inbound { session_id, inbound } => self
+                .owned
+                .read()
+                .await
+                .get(&session_id)
+                .is_some_and(|owner| owner.send(inbound).is_ok()),
+            PollMessage::Ready { session_id } => {
+                let waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+                let notify = waiting.get(&session_id);
+                if let Some(notify) = notify {
+                    notify.notify_waiters();
+                }
+                notify.is_some()
+            }
+        }
+    }
+
+    /// Receive polling messages from all nodes, forever.
+    pub async fn run(self) {
+        loop {
+            match self.repo.subscribe_to_channel(POLLING_CHANNEL).await {
+                Ok(mut rx) => loop {
+                    match rx.recv().await {
+                        Ok(payload) => match serde_json::from_str::<PollMessage>(&payload) {
+                            Ok(message) => {
+                                let handled = self.dispatch(message).await;
+                                debug!(handled, "polling message dispatched");
+                            }
+                            Err(e) => warn!(error = %e, "invalid polling message"),
+                        },
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                            warn!(skipped = n, "polling messages dropped")
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    }
+                },
+                Err(e) => warn!(error = %e, "subscribing to polling channel failed"),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> PollingService {
+        PollingService::new(Arc::new(RedisRepository::new("redis://127.0.0.1/").unwrap()))
+    }
+
+    #[tokio::test]
+    async fn inbound_reaches_the_owner_only() {
+        let polling = service();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        polling.owned.write().await.insert("s1".to_string(), tx);
+        let frame = PollInbound::Frame {
+            frame: r#"{"event":"pusher:ping"}"#.to_string(),
+        };
+        let to = |session_id: &str, inbound: PollInbound| PollMessage::Inbound {
+            session_id: session_id.to_string(),
+            inbound,
+        };
+        assert!(polling.dispatch(to("s1", frame.clone())).await);
+        assert_eq!(rx.try_recv().unwrap(), frame);
+        assert!(!polling.dispatch(to("s2", PollInbound::Close)).await);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn ready_wakes_waiting_polls() {
+        let polling = service();
+        let notify = Arc::new(Notify::new());
+        polling.waiting.lock().unwrap().insert("s1".to_string(), notify.clone());
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        let ready = PollMessage::Ready {
+            session_id: "s1".to_string(),
+        };
+        assert!(polling.dispatch(ready).await);
+        assert!(tokio::time::timeout(Duration::from_millis(50), notified).await.is_ok());
+    }
+
+    #[test]
+    fn waiters_leave_the_map_when_dropped() {
+        let polling = service();
+        let first = Waiter::new(&polling.waiting, "s1");
+        let second = Waiter::new(&polling.waiting, "s1");
+        assert!(Arc::ptr_eq(&first.notify, &second.notify), "polls of a session share a wakeup");
+        drop(first);
+        assert!(polling.waiting.lock().unwrap().contains_key("s1"), "another poll still waits");
+        drop(second);
+        assert!(polling.waiting.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_poll_leaves_the_map() {
+        let polling = service();
+        let waiting = polling.waiting.clone();
+        let cancelled = tokio::spawn(async move {
+            let waiter = Waiter::new(&polling.waiting, "s1");
+            waiter.notify.notified().await;
+        });
+        while !waiting.lock().unwrap().contains_key("s1") {
+            tokio::task::yield_now().await;
+        }
+        cancelled.abort();
+        let _ = cancelled.await;
+        assert!(waiting.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = PollMessage::Inbound {
+            session_id: "s1".to_string(),
+            inbound: PollInbound::Close,
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        assert_eq!(json, r#"{"type":"inbound","session_id":"s1","inbound":{"type":"close"}}"#);
+        assert!(matches!(
+            serde_json::from_str::<PollMessage>(&json).unwrap(),
+            PollMessage::Inbound { inbound: PollInbound::Close, .. }
+        ));
+        assert!(generate_session_id().starts_with("np_"));
+    }
+}
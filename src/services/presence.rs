@@ -1,13 +1,141 @@
 //! Presence channel: track who is online and broadcast join/leave.
 
+use crate::db::DomainRow;
 use crate::error::{AppError, AppResult};
-use crate::models::presence::{PresenceMember, PresenceUser};
+use crate::models::presence::{LastSeenPolicy, PresenceMember, PresenceUser};
 use crate::repositories::RedisRepository;
 use serde_json;
+use std::collections::HashSet;
 use std::sync::Arc;
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument};
+use uuid::Uuid;
 
-/// Presence channel operations: add/remove members, list members.
+/// `pusher:error` code of a subscribe refused because the presence channel is full.
+pub const PRESENCE_FULL_CODE: u16 = 4100;
+
+/// Default members per presence channel (distinct users).
+pub const DEFAULT_PRESENCE_MAX_MEMBERS: usize = 1000;
+
+/// Highest `presence_max_members` a domain may configure.
+pub const MAX_PRESENCE_MAX_MEMBERS: i32 = 100_000;
+
+/// Default size cap of `channel_data` and of `user_info` after a presence update (serialized).
+pub const DEFAULT_MAX_CHANNEL_DATA_BYTES: usize = 4096;
+
+/// Highest `presence_max_channel_data_bytes` a domain may configure.
+pub const MAX_CHANNEL_DATA_BYTES: i32 = 65_536;
+
+/// Default page size of presence member listings.
+pub const DEFAULT_MEMBERS_PAGE: usize = 100;
+
+/// Largest page of presence member listings.
+pub const MAX_MEMBERS_PAGE: usize = 1000;
+
+/// Presence limits of a domain (the defaults without a domain).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresenceLimits {
+    pub max_members: usize,
+    pub max_channel_data_bytes: usize,
+}
+
+impl Default for PresenceLimits {
+    fn default() -> Self {
+        Self {
+            max_members: DEFAULT_PRESENCE_MAX_MEMBERS,
+            max_channel_data_bytes: DEFAULT_MAX_CHANNEL_DATA_BYTES,
+        }
+    }
+}
+
+impl PresenceLimits {
+    pub fn for_domain(domain: Option<&DomainRow>) -> Self {
+        match domain {
+            Some(d) => Self {
+                max_members: d.presence_max_members.max(1) as usize,
+                max_channel_data_bytes: d.presence_max_channel_data_bytes.max(1) as usize,
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// Reject `channel_data` / `user_info` over the size cap.
+    pub fn check_size(&self, what: &str, value: &serde_json::Value) -> AppResult<()> {
+        if value.to_string().len() > self.max_channel_data_bytes {
+            return Err(AppError::Validation(format!(
+                "{} larger than {} bytes",
+                what, self.max_channel_data_bytes
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Apply a client's `presence_update` to the info given at subscribe time. Keys of `base`
+/// were vouched for by the auth and stay as they are; other keys are set from `update`,
+/// and `null` removes them.
+pub fn merge_user_info(
+    base: Option<&serde_json::Value>,
+    current: Option<&serde_json::Value>,
+    update: serde_json::Value,
+    limits: &PresenceLimits,
+) -> AppResult<serde_json::Value> {
+    let serde_json::Value::Object(update) = update else {
+        return Err(AppError::Validation("user_info must be a JSON object".to_string()));
+    };
+    let base = base.and_then(|b| b.as_object());
+    let mut merged = current
+        .and_then(|c| c.as_object())
+        .cloned()
+        .unwrap_or_default();
+    for (key, value) in update {
+        if base.is_some_and(|b| b.contains_key(&key)) {
+            continue;
+        }
+        if value.is_null() {
+            merged.remove(&key);
+        } else {
+            merged.insert(key, value);
+        }
+    }
+    if let Some(base) = base {
+        for (key, value) in base {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    let merged = serde_json::Value::Object(merged);
+    limits.check_size("user_info", &merged)?;
+    Ok(merged)
+}
+
+/// Default last-seen retention of a domain (and of connections without a domain).
+pub const DEFAULT_LAST_SEEN_RETENTION_SECS: u64 = 7 * 24 * 3600;
+
+/// Longest last-seen retention a domain may configure.
+pub const MAX_LAST_SEEN_RETENTION_SECS: i64 = 90 * 24 * 3600;
+
+/// Last-seen tracking for connections of `domain` (`legacy` with the default retention
+/// without a domain); `None` if the domain turned it off.
+pub fn last_seen_policy(domain: Option<&DomainRow>) -> Option<LastSeenPolicy> {
+    match domain {
+        Some(d) => (d.presence_last_seen_secs > 0).then(|| LastSeenPolicy {
+            scope: d.id.to_string(),
+            retention_secs: d.presence_last_seen_secs as u64,
+        }),
+        None => Some(LastSeenPolicy {
+            scope: "legacy".to_string(),
+            retention_secs: DEFAULT_LAST_SEEN_RETENTION_SECS,
+        }),
+    }
+}
+
+/// When a user was last seen on a channel (ms since epoch).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastSeen {
+    pub id: String,
+    pub at_ms: i64,
+}
+
+/// Presence channel operations: add/remove members, list members, last seen.
 #[derive(Clone)]
 pub struct PresenceService {
     repo: Arc<RedisRepository>,
@@ -18,44 +146,221 @@ impl PresenceService {
         Self { repo }
     }
 
+    /// Add the member's socket, unless its user is not on the channel yet and it already
+    /// has `max_members` users. `false` if full.
     #[instrument(skip(self))]
-    pub async fn add_member(
-        &self,
-        channel: &str,
-        socket_id: &str,
-        user_id: &str,
-        user_info: Option<serde_json::Value>,
-    ) -> AppResult<()> {
-        let member = PresenceMember {
-            user_id: user_id.to_string(),
-            user_info: user_info.clone(),
-            socket_id: socket_id.to_string(),
-        };
+    pub async fn add_member(&self, channel: &str, member: PresenceMember, max_members: usize) -> AppResult<bool> {
         let data = serde_json::to_string(&member).map_err(AppError::from)?;
-        self.repo.presence_add(channel, socket_id, &data).await?;
+        let (socket_id, user_id) = (&member.socket_id, &member.user_id);
+        if !self.repo.presence_add(channel, socket_id, user_id, &data, max_members).await? {
+            return Ok(false);
+        }
         info!(channel = %channel, socket_id = %socket_id, user_id = %user_id, "presence member added");
-        Ok(())
+        Ok(true)
     }
 
     #[instrument(skip(self))]
     pub async fn remove_member(&self, channel: &str, socket_id: &str) -> AppResult<()> {
-        self.repo.presence_remove(channel, socket_id).await?;
+        let Some(member) = self.member(channel, socket_id).await? else {
+            return Ok(());
+        };
+        let left = self.repo.presence_remove(channel, socket_id, &member.user_id).await?;
         info!(channel = %channel, socket_id = %socket_id, "presence member removed");
+        if left == Some(0) {
+            self.record_last_seen(channel, &member).await?;
+        }
         Ok(())
     }
 
-    /// List all members currently on the channel.
-    pub async fn list_members(&self, channel: &str) -> AppResult<Vec<PresenceUser>> {
-        let raw = self.repo.presence_members(channel).await?;
-        let mut users = Vec::new();
-        for (_socket_id, data) in raw {
-            if let Ok(member) = serde_json::from_str::<PresenceMember>(&data) {
-                users.push(PresenceUser {
-                    user_id: member.user_id,
-                    user_info: member.user_info,
-                });
+    /// Replace the info of a socket's member. `None` if the socket is not on the channel.
+    pub async fn update_member(
+        &self,
+        channel: &str,
+        socket_id: &str,
+        user_id: &str,
+        user_info: serde_json::Value,
+    ) -> AppResult<Option<PresenceUser>> {
+        let Some(mut member) = self.member(channel, socket_id).await? else {
+            return Ok(None);
+        };
+        member.user_id = user_id.to_string();
+        member.user_info = Some(user_info);
+        let data = serde_json::to_string(&member)?;
+        if !self.repo.presence_update(channel, socket_id, &data).await? {
+            return Ok(None);
+        }
+        info!(channel = %channel, socket_id = %socket_id, user_id = %user_id, "presence member updated");
+        Ok(Some(PresenceUser::new(member.user_id, member.user_info)))
+    }
+
+    /// Remove every socket of a user in `domain_id` from the channel; members of other
+    /// domains on the same channel stay. Returns the removed socket ids and whether the user
+    /// id has no member left on the channel at all.
+    pub async fn remove_user(
+        &self,
+        channel: &str,
+        domain_id: Option<Uuid>,
+        user_id: &str,
+    ) -> AppResult<(Vec<String>, bool)> {
+        let mut removed = Vec::new();
+        let mut last = None;
+        let mut left = None;
+        let mut others = 0;
+        for member in self.members(channel).await? {
+            if member.user_id != user_id {
+                continue;
+            }
+            if member.domain_id != domain_id {
+                others += 1;
+                continue;
             }
+            if let Some(n) = self.repo.presence_remove(channel, &member.socket_id, user_id).await? {
+                removed.push(member.socket_id.clone());
+                left = Some(n);
+                last = Some(member);
+            }
+        }
+        if let Some(member) = last {
+            info!(channel = %channel, user_id = %user_id, sockets = removed.len(), "presence user removed");
+            self.record_last_seen(channel, &member).await?;
         }
-        Ok(users)
+        Ok((removed, left.map_or(others == 0, |n| n == 0)))
+    }
+
+    /// List all members currently on the channel.
+    pub async fn list_members(&self, channel: &str) -> AppResult<Vec<PresenceUser>> {
+        Ok(self
+            .members(channel)
+            .await?
+            .into_iter()
+            .map(|m| PresenceUser::new(m.user_id, m.user_info))
+            .collect())
+    }
+
+    /// The ones among `user_ids` with a socket on the channel.
+    pub async fn online_users(&self, channel: &str, user_ids: &[String]) -> AppResult<HashSet<String>> {
+        let present = self.repo.presence_users_present(channel, user_ids).await?;
+        Ok(user_ids
+            .iter()
+            .zip(present)
+            .filter(|(_, present)| *present)
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
+
+    /// Whether the user has a socket on each of the channels.
+    pub async fn user_online_in(&self, channels: &[String], user_id: &str) -> AppResult<Vec<bool>> {
+        self.repo.presence_user_present_in(channels, user_id).await
+    }
+
+    /// One page of the channel's users, ordered by user id, starting after `cursor` (a user
+    /// id). Returns the next cursor if there are more.
+    pub async fn list_members_page(
+        &self,
+        channel: &str,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> AppResult<(Vec<PresenceUser>, Option<String>)> {
+        let mut page: Vec<PresenceUser> = self
+            .repo
+            .presence_page(channel, cursor, limit + 1)
+            .await?
+            .into_iter()
+            .map(|(user_id, data)| {
+                let user_info = data
+                    .and_then(|d| serde_json::from_str::<PresenceMember>(&d).ok())
+                    .and_then(|m| m.user_info);
+                PresenceUser::new(user_id, user_info)
+            })
+            .collect();
+        let next = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|u| u.user_id.clone())
+        } else {
+            None
+        };
+        Ok((page, next))
+    }
+
+    /// Channels the user left within `retention_secs`, most recent first.
+    pub async fn last_seen_of_user(&self, scope: &str, user_id: &str, retention_secs: u64) -> AppResult<Vec<LastSeen>> {
+        let oldest = chrono::Utc::now().timestamp_millis() - (retention_secs as i64) * 1000;
+        let mut seen: Vec<LastSeen> = self
+            .repo
+            .last_seen_of_user(scope, user_id)
+            .await?
+            .into_iter()
+            .filter(|(_, at_ms)| *at_ms >= oldest)
+            .map(|(id, at_ms)| LastSeen { id, at_ms })
+            .collect();
+        seen.sort_by_key(|s| std::cmp::Reverse(s.at_ms));
+        Ok(seen)
+    }
+
+    /// Users who left the channel within `retention_secs`, most recent first.
+    pub async fn last_seen_of_channel(&self, scope: &str, channel: &str, retention_secs: u64) -> AppResult<Vec<LastSeen>> {
+        let oldest = chrono::Utc::now().timestamp_millis() - (retention_secs as i64) * 1000;
+        Ok(self
+            .repo
+            .last_seen_of_channel(scope, channel, oldest)
+            .await?
+            .into_iter()
+            .map(|(id, at_ms)| LastSeen { id, at_ms })
+            .collect())
+    }
+
+    async fn member(&self, channel: &str, socket_id: &str) -> AppResult<Option<PresenceMember>> {
+        let data = self.repo.presence_member(channel, socket_id).await?;
+        Ok(data.and_then(|d| serde_json::from_str(&d).ok()))
+    }
+
+    async fn members(&self, channel: &str) -> AppResult<Vec<PresenceMember>> {
+        Ok(self
+            .repo
+            .presence_members(channel)
+            .await?
+            .into_iter()
+            .filter_map(|(_, data)| serde_json::from_str(&data).ok())
+            .collect())
+    }
+
+    /// The member's user has no socket left on the channel.
+    async fn record_last_seen(&self, channel: &str, member: &PresenceMember) -> AppResult<()> {
+        let Some(policy) = member.last_seen.as_ref().filter(|p| p.retention_secs > 0) else {
+            return Ok(());
+        };
+        let now = chrono::Utc::now().timestamp_millis();
+        self.repo
+            .last_seen_set(&policy.scope, channel, &member.user_id, now, policy.retention_secs)
+            .await?;
+        debug!(channel = %channel, user_id = %member.user_id, "presence last seen recorded");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn update_keeps_authorized_keys() {
+        let base = json!({ "name": "Alice" });
+        let limits = PresenceLimits::default();
+        let merged = merge_user_info(Some(&base), Some(&base), json!({ "name": "Mallory", "status": "away" }), &limits).unwrap();
+        assert_eq!(merged, json!({ "name": "Alice", "status": "away" }));
+        let merged = merge_user_info(Some(&base), Some(&merged), json!({ "status": null, "typing": true }), &limits).unwrap();
+        assert_eq!(merged, json!({ "name": "Alice", "typing": true }));
+    }
+
+    #[test]
+    fn update_must_be_small_object() {
+        let limits = PresenceLimits {
+            max_members: 10,
+            max_channel_data_bytes: 64,
+        };
+        assert!(merge_user_info(None, None, json!("away"), &limits).is_err());
+        assert!(merge_user_info(None, None, json!({ "doc": "x".repeat(64) }), &limits).is_err());
+        assert_eq!(merge_user_info(None, None, json!({ "status": "busy" }), &limits).unwrap(), json!({ "status": "busy" }));
     }
 }
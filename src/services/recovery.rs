@@ -0,0 +1,104 @@
+//! Connection recovery: a socket that drops can be resumed within a grace window with the
+//! resume token it got in `connection_established`, keeping its socket id, subscriptions and
+//! presence membership. Events published meanwhile are replayed from a short per-channel
+//! buffer ordered by sequence number.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Extra lifetime of a stored session past the grace window, for the cleanup check.
+pub const RESUME_CLEANUP_SLACK: Duration = Duration::from_secs(30);
+
+/// Limits of the per-channel recovery buffer and of resumable sessions.
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryConfig {
+    /// Events kept per channel for replay.
+    pub buffer_size: usize,
+    /// How long a disconnected session (and the buffer) can be resumed.
+    pub grace: Duration,
+}
+
+/// What a disconnected socket leaves behind for a resume.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeSession {
+    pub socket_id: String,
+    pub domain_id: Option<Uuid>,
+    pub channels: Vec<ResumedChannel>,
+}
+
+/// One subscription of a [`ResumeSession`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumedChannel {
+    pub channel: String,
+    /// Highest sequence number delivered to the socket.
+    pub last_seq: u64,
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub user_verified: bool,
+    #[serde(default)]
+    pub user_info: Option<serde_json::Value>,
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+/// Events to replay after `last_seq`, and whether they close the gap.
+#[derive(Debug, Clone, Default)]
+pub struct Recovery {
+    /// Serialized events in sequence order.
+    pub events: Vec<String>,
+    /// `false` when some events after `last_seq` were already evicted from the buffer.
+    pub complete: bool,
+}
+
+impl Recovery {
+    /// Build from buffered `(seq, payload)` entries after `last_seq` (ascending) and the
+    /// channel's current sequence number.
+    pub fn from_buffer(last_seq: u64, buffered: Vec<(u64, String)>, current_seq: u64) -> Self {
+        let first = buffered.first().map(|(seq, _)| *seq);
+        let complete = current_seq <= last_seq || first == Some(last_seq + 1);
+        Self {
+            events: buffered.into_iter().map(|(_, payload)| payload).collect(),
+            complete,
+        }
+    }
+}
+
+/// Random, unguessable resume token.
+pub fn generate_resume_token() -> String {
+    format!("nr_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffered(seqs: &[u64]) -> Vec<(u64, String)> {
+        seqs.iter().map(|s| (*s, format!("event-{}", s))).collect()
+    }
+
+    #[test]
+    fn recovery_complete_when_buffer_covers_gap() {
+        let r = Recovery::from_buffer(4, buffered(&[5, 6, 7]), 7);
+        assert!(r.complete);
+        assert_eq!(r.events, vec!["event-5", "event-6", "event-7"]);
+        let nothing_missed = Recovery::from_buffer(7, Vec::new(), 7);
+        assert!(nothing_missed.complete && nothing_missed.events.is_empty());
+    }
+
+    #[test]
+    fn recovery_incomplete_when_events_evicted() {
+        let r = Recovery::from_buffer(2, buffered(&[5, 6]), 6);
+        assert!(!r.complete);
+        assert_eq!(r.events.len(), 2);
+        assert!(!Recovery::from_buffer(2, Vec::new(), 6).complete);
+    }
+
+    #[test]
+    fn resume_tokens_are_unique() {
+        let a = generate_resume_token();
+        assert!(a.starts_with("nr_") && a.len() == 67);
+        assert_ne!(a, generate_resume_token());
+    }
+}
@@ -0,0 +1,253 @@
+//! Scheduled (`deliver_at`) and recurring (cron) broadcasts.
+//!
+//! Kept in Redis: one record per schedule, a sorted set of schedule ids by due time and a
+//! per-domain index for listing. Every node runs a dispatcher. Claiming a due run moves it
+//! to a processing set with a lease; the run leaves it once published. Runs whose lease ran
+//! out (the node died or could not publish) go back to the queue, so each run is published
+//! at least once, normally by exactly one node.
+
+use crate::error::{AppError, AppResult};
+use crate::repositories::RedisRepository;
+use chrono::{DateTime, Utc};
+use croner::Cron;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+/// How often each node looks for due schedules.
+pub const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most schedules claimed per dispatch round.
+pub const DISPATCH_BATCH: usize = 100;
+
+/// How long a claimed run may take before another node runs it again.
+pub const DISPATCH_LEASE: Duration = Duration::from_secs(60);
+
+/// Most pending schedules per domain.
+pub const MAX_SCHEDULES_PER_DOMAIN: usize = 1000;
+
+/// A broadcast waiting for its time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledBroadcast {
+    pub id: String,
+    #[serde(default)]
+    pub domain_id: Option<Uuid>,
+    pub channel: String,
+    pub event: String,
+    pub data: serde_json::Value,
+    #[serde(default)]
+    pub reliable: bool,
+    /// Five-field cron expression (UTC) for recurring broadcasts; `None` runs once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    pub next_run_at: DateTime<Utc>,
+    /// One-off: not published after this time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Recurring: each run's event expires this many seconds after it is published.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    pub created_at: DateTime<Utc>,
+    /// Completed runs.
+    #[serde(default)]
+    pub runs: u64,
+}
+
+impl ScheduledBroadcast {
+    pub fn new(
+        domain_id: Option<Uuid>,
+        channel: String,
+        event: String,
+        data: serde_json::Value,
+        reliable: bool,
+        cron: Option<String>,
+        next_run_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: format!("sch_{}", Uuid::new_v4().simple()),
+            domain_id,
+            channel,
+            event,
+            data,
+            reliable,
+            cron,
+            next_run_at,
+            expires_at: None,
+            ttl: None,
+            created_at: Utc::now(),
+            runs: 0,
+        }
+    }
+}
+
+fn parse_cron(expr: &str) -> AppResult<Cron> {
+    Cron::new(expr)
+        .parse()
+        .map_err(|e| AppError::Validation(format!("invalid cron expression: {}", e)))
+}
+
+/// First run of a cron expression strictly after `after`.
+pub fn next_cron_run(expr: &str, after: DateTime<Utc>) -> AppResult<DateTime<Utc>> {
+    parse_cron(expr)?
+        .find_next_occurrence(&after, false)
+        .map_err(|e| AppError::Validation(format!("cron expression never runs: {}", e)))
+}
+
+fn scope(domain_id: Option<Uuid>) -> String {
+    domain_id.map(|d| d.to_string()).unwrap_or_else(|| "legacy".to_string())
+}
+
+/// Stores schedules and hands due ones to the dispatcher.
+#[derive(Clone)]
+pub struct ScheduleService {
+    repo: Arc<RedisRepository>,
+}
+
+impl ScheduleService {
+    pub fn new(repo: Arc<RedisRepository>) -> Self {
+        Self { repo }
+    }
+
+    /// Store a new schedule and queue its first run.
+    pub async fn create(&self, schedule: &ScheduledBroadcast) -> AppResult<()> {
+        let scope = scope(schedule.domain_id);
+        if self.repo.schedule_index_count(&scope).await? >= MAX_SCHEDULES_PER_DOMAIN {
+            return Err(AppError::Validation(format!(
+                "at most {} pending schedules per domain",
+                MAX_SCHEDULES_PER_DOMAIN
+            )));
+        }
+        self.repo
+            .schedule_create(
+                &scope,
+                &schedule.id,
+                &serde_json::to_string(schedule)?,
+                schedule.next_run_at.timestamp_millis(),
+            )
+            .await
+    }
+
+    /// Pending schedules of a domain, soonest first.
+    pub async fn list(&self, domain_id: Option<Uuid>) -> AppResult<Vec<ScheduledBroadcast>> {
+        let scope = scope(domain_id);
+        let ids = self.repo.schedule_index_members(&scope).await?;
+        let records = self.repo.schedule_get_many(&ids).await?;
+        let mut schedules = Vec::new();
+        for (id, record) in ids.iter().zip(records) {
+            match record.and_then(|r| serde_json::from_str::<ScheduledBroadcast>(&r).ok()) {
+                Some(schedule) => schedules.push(schedule),
+                None => self.repo.schedule_index_remove(&scope, id).await?,
+            }
+        }
+        schedules.sort_by_key(|s| s.next_run_at);
+        Ok(schedules)
+    }
+
+    /// Cancel a domain's schedule. `false` if it does not exist (or belongs to another domain).
+    pub async fn cancel(&self, domain_id: Option<Uuid>, id: &str) -> AppResult<bool> {
+        let Some(schedule) = self.get(id).await? else {
+            return Ok(false);
+        };
+        if domain_id.is_some() && schedule.domain_id != domain_id {
+            return Ok(false);
+        }
+        self.finish(&schedule).await?;
+        Ok(true)
+    }
+
+    async fn get(&self, id: &str) -> AppResult<Option<ScheduledBroadcast>> {
+        let records = self.repo.schedule_get_many(&[id.to_string()]).await?;
+        Ok(records
+            .into_iter()
+            .next()
+            .flatten()
+            .and_then(|r| serde_json::from_str(&r).ok()))
+    }
+
+    /// Claim schedules due at `now`, after requeueing runs whose lease ended. Only one node
+    /// gets each; it must [`complete_run`](Self::complete_run) before the lease ends.
+    pub async fn claim_due(&self, now: DateTime<Utc>) -> AppResult<Vec<ScheduledBroadcast>> {
+        let now_ms = now.timestamp_millis();
+        let reclaimed = self.repo.schedule_reclaim(now_ms, DISPATCH_BATCH).await?;
+        if !reclaimed.is_empty() {
+            warn!(count = reclaimed.len(), "requeued scheduled runs whose lease expired");
+        }
+        let due = self.repo.schedule_queue_due(now_ms, DISPATCH_BATCH).await?;
+        let lease_until = now_ms + DISPATCH_LEASE.as_millis() as i64;
+        let mut claimed = Vec::new();
+        for id in due {
+            if !self.repo.schedule_claim(&id, lease_until).await? {
+                continue;
+            }
+            match self.get(&id).await? {
+                Some(schedule) => claimed.push(schedule),
+                // Cancelled after being queued: nothing to run.
+                None => self.repo.schedule_release(&id).await?,
+            }
+        }
+        Ok(claimed)
+    }
+
+    /// After a run: queue the next run of a recurring schedule, or drop a one-off.
+    /// A recurring schedule cancelled meanwhile stays cancelled.
+    pub async fn complete_run(&self, schedule: &ScheduledBroadcast, now: DateTime<Utc>) -> AppResult<()> {
+        let Some(cron) = &schedule.cron else {
+            return self.finish(schedule).await;
+        };
+        let mut next = schedule.clone();
+        next.runs += 1;
+        next.next_run_at = next_cron_run(cron, now.max(schedule.next_run_at))?;
+        if self
+            .repo
+            .schedule_update(&next.id, &serde_json::to_string(&next)?)
+            .await?
+        {
+            self.repo
+                .schedule_requeue(&next.id, next.next_run_at.timestamp_millis())
+                .await
+        } else {
+            self.repo.schedule_release(&next.id).await
+        }
+    }
+
+    async fn finish(&self, schedule: &ScheduledBroadcast) -> AppResult<()> {
+        self.repo.schedule_delete(&scope(schedule.domain_id), &schedule.id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn next_run_follows_cron() {
+        let at = Utc.with_ymd_and_hms(2025, 1, 1, 8, 59, 30).unwrap();
+        assert_eq!(
+            next_cron_run("0 9 * * *", at).unwrap(),
+            Utc.with_ymd_and_hms(2025, 1, 1, 9, 0, 0).unwrap()
+        );
+        assert_eq!(
+            next_cron_run("*/15 * * * *", Utc.with_ymd_and_hms(2025, 1, 1, 9, 0, 0).unwrap()).unwrap(),
+            Utc.with_ymd_and_hms(2025, 1, 1, 9, 15, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_cron() {
+        let now = Utc::now();
+        assert!(next_cron_run("not a cron", now).is_err());
+        assert!(next_cron_run("61 * * * *", now).is_err());
+        assert!(next_cron_run("", now).is_err());
+    }
+
+    #[test]
+    fn schedule_ids_are_unique() {
+        let a = ScheduledBroadcast::new(None, "c".into(), "e".into(), serde_json::json!({}), false, None, Utc::now());
+        let b = ScheduledBroadcast::new(None, "c".into(), "e".into(), serde_json::json!({}), false, None, Utc::now());
+        assert!(a.id.starts_with("sch_"));
+        assert_ne!(a.id, b.id);
+    }
+}
@@ -0,0 +1,495 @@
+//! Signed-in users (`pusher:signin`): a socket is associated with a user id across nodes,
+//! and events sent to the user reach every connection of that user in the domain.
+//!
+//! User events travel over one internal pub/sub channel that every node listens on, and
+//! each node hands them to its own signed-in sockets; clients cannot subscribe to it
+//! directly (see [`is_reserved_channel`](crate::models::channel::is_reserved_channel)).
+//! Nodes refresh a heartbeat of their signed-in sockets, so sockets of a node that died
+//! stop counting once the heartbeat is stale.
+//!
+//! A user's first and last signed-in socket in the domain are announced on an internal
+//! channel per domain, for sockets watching the user (`watchlist` in `user_data`). The
+//! offline is held back for the resume grace window, so a reconnect within it announces
+//! nothing; users whose sockets all died with their node are announced offline by the
+//! sweep of stale heartbeats.
+
+use crate::error::{AppError, AppResult};
+use crate::models::event::WsEvent;
+use crate::repositories::RedisRepository;
+use crate::services::ChannelService;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Longest accepted user id.
+pub const MAX_USER_ID_LEN: usize = 200;
+
+/// Internal pub/sub channel of user events, on every node.
+const USERS_CHANNEL: &str = "#users";
+
+/// How often a node refreshes the heartbeat of its signed-in sockets.
+pub const USER_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A signed-in socket without a heartbeat for this long belongs to a dead node.
+pub const USER_SOCKET_STALE: Duration = Duration::from_secs(90);
+
+/// How often a node looks for due offline changes and users of dead nodes.
+const WATCHLIST_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most due offline changes or stale users handled per sweep.
+const WATCHLIST_SWEEP_BATCH: usize = 100;
+
+/// Wait before listening again after the user event subscription dropped.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Most user ids one socket may watch.
+pub const MAX_WATCHLIST_LEN: usize = 100;
+
+/// The `user_data` of a signin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedInUser {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_info: Option<serde_json::Value>,
+    /// User ids whose online/offline changes this socket receives.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watchlist: Vec<String>,
+}
+
+impl SignedInUser {
+    /// Parse and check signed `user_data`.
+    pub fn parse(user_data: &str) -> AppResult<Self> {
+        let user: SignedInUser = serde_json::from_str(user_data)
+            .map_err(|_| AppError::Validation("user_data must be a JSON object with an id".to_string()))?;
+        if user.id.is_empty() || user.id.len() > MAX_USER_ID_LEN {
+            return Err(AppError::Validation(format!(
+                "user id must be 1-{} characters",
+                MAX_USER_ID_LEN
+            )));
+        }
+        if user.watchlist.len() > MAX_WATCHLIST_LEN {
+            return Err(AppError::Validation(format!(
+                "watchlist may have at most {} user ids",
+                MAX_WATCHLIST_LEN
+            )));
+        }
+        if user.watchlist.iter().any(|id| id.is_empty() || id.len() > MAX_USER_ID_LEN) {
+            return Err(AppError::Validation("watchlist has an invalid user id".to_string()));
+        }
+        Ok(user)
+    }
+}
+
+/// Channel name shown on user events (Pusher's `#server-to-user-<id>`).
+pub fn user_event_channel(user_id: &str) -> String {
+    format!("#server-to-user-{}", user_id)
+}
+
+/// Internal pub/sub channel of the domain's online/offline changes.
+fn watch_pubsub_channel(domain_id: Option<Uuid>) -> String {
+    let scope = domain_id.map(|d| d.to_string()).unwrap_or_else(|| "legacy".to_string());
+    format!("#watchlist:{}", scope)
+}
+
+fn user_key(domain_id: Option<Uuid>, user_id: &str) -> String {
+    let scope = domain_id.map(|d| d.to_string()).unwrap_or_else(|| "legacy".to_string());
+    format!("{}:{}", scope, user_id)
+}
+
+/// Domain and user id of a [`user_key`].
+fn parse_user_key(key: &str) -> Option<(Option<Uuid>, &str)> {
+    let (scope, user_id) = key.split_once(':')?;
+    match scope {
+        "legacy" => Some((None, user_id)),
+        _ => Some((Some(scope.parse().ok()?), user_id)),
+    }
+}
+
+/// A user's first socket signed in (`online`) or last one left (`offline`), in the domain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchlistChange {
+    pub name: String,
+    pub user_id: String,
+}
+
+impl WatchlistChange {
+    /// The change in `payload` if it is about one of the `watched` user ids.
+    pub fn parse_watched(payload: &str, watched: &HashSet<String>) -> Option<Self> {
+        serde_json::from_str::<Self>(payload)
+            .ok()
+            .filter(|change| watched.contains(&change.user_id))
+    }
+}
+
+/// A user event as published between nodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserMessage {
+    #[serde(default)]
+    domain_id: Option<Uuid>,
+    user_id: String,
+    /// Serialized [`WsEvent`].
+    event: String,
+}
+
+/// An event sent to a user.
+#[derive(Debug, Clone)]
+pub struct UserEventSent {
+    pub id: String,
+    pub published_at: i64,
+    /// Sockets signed in as the user when it was sent, on all nodes.
+    pub connections: usize,
+}
+
+/// Signed-in sockets of this node by user key, with where their user events go.
+type LocalUsers = HashMap<String, HashMap<String, mpsc::UnboundedSender<String>>>;
+
+fn stale_before(now_ms: i64) -> i64 {
+    now_ms - USER_SOCKET_STALE.as_millis() as i64
+}
+
+/// Tracks signed-in sockets and delivers user events.
+#[derive(Clone)]
+pub struct UserService {
+    repo: Arc<RedisRepository>,
+    channels: ChannelService,
+    local: Arc<RwLock<LocalUsers>>,
+}
+
+impl UserService {
+    pub fn new(repo: Arc<RedisRepository>, channels: ChannelService) -> Self {
+        Self {
+            repo,
+            channels,
+            local: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Record the socket as signed in and start receiving the user's events.
+    pub async fn sign_in(
+        &self,
+        domain_id: Option<Uuid>,
+        user_id: &str,
+        socket_id: &str,
+    ) -> AppResult<mpsc::UnboundedReceiver<String>> {
+        let key = user_key(domain_id, user_id);
+        let (events, rx) = mpsc::unbounded_channel();
+        self.local
+            .write()
+            .await
+            .entry(key.clone())
+            .or_default()
+            .insert(socket_id.to_string(), events);
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let added = self
+            .repo
+            .user_socket_add(&key, socket_id, now_ms, stale_before(now_ms), USER_SOCKET_STALE.as_secs())
+            .await;
+        let (count, held) = match added {
+            Ok(added) => added,
+            Err(e) => {
+                self.forget(&key, socket_id).await;
+                return Err(e);
+            }
+        };
+        // A held offline was never announced, so watchers still see the user online.
+        if count == 1 && !held {
+            self.announce(domain_id, "online", user_id).await?;
+        }
+        Ok(rx)
+    }
+
+    /// The socket closed or signed out. If it was the user's last socket, the offline is
+    /// announced once `offline_after` passes without another sign in.
+    pub async fn sign_out(
+        &self,
+        domain_id: Option<Uuid>,
+        user_id: &str,
+        socket_id: &str,
+        offline_after: Duration,
+    ) -> AppResult<()> {
+        let key = user_key(domain_id, user_id);
+        self.forget(&key, socket_id).await;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let (removed, count) = self
+            .repo
+            .user_socket_remove(&key, socket_id, stale_before(now_ms), now_ms + offline_after.as_millis() as i64)
+            .await?;
+        if removed && count == 0 && offline_after.is_zero() {
+            self.announce_offline(&key).await?;
+        }
+        Ok(())
+    }
+
+    /// Announce a queued offline of `key` if it is due and no other node took it.
+    async fn announce_offline(&self, key: &str) -> AppResult<()> {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        if self.repo.user_offline_take(key, stale_before(now_ms)).await? {
+            self.announce_key(key, "offline").await?;
+        }
+        Ok(())
+    }
+
+    async fn announce_key(&self, key: &str, name: &str) -> AppResult<()> {
+        match parse_user_key(key) {
+            Some((domain_id, user_id)) => self.announce(domain_id, name, user_id).await,
+            None => {
+                warn!(key = %key, "invalid signed-in user key");
+                Ok(())
+            }
+        }
+    }
+
+    /// Announce offline changes whose grace window passed, and users whose sockets all
+    /// belonged to dead nodes.
+    pub async fn sweep(&self) -> AppResult<()> {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        for key in self.repo.user_offline_due(now_ms, WATCHLIST_SWEEP_BATCH).await? {
+            self.announce_offline(&key).await?;
+        }
+        let stale_ms = stale_before(now_ms);
+        for key in self.repo.user_stale(stale_ms, WATCHLIST_SWEEP_BATCH).await? {
+            if self.repo.user_socket_reap(&key, stale_ms).await? {
+                self.announce_key(&key, "offline").await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn forget(&self, key: &str, socket_id: &str) {
+        let mut local = self.local.write().await;
+        if let Some(sockets) = local.get_mut(key) {
+            sockets.remove(socket_id);
+            if sockets.is_empty() {
+                local.remove(key);
+            }
+        }
+    }
+
+    /// Start receiving the domain's online/offline changes (JSON [`WatchlistChange`]).
+    pub async fn watch(&self, domain_id: Option<Uuid>) -> AppResult<broadcast::Receiver<String>> {
+        self.channels.subscribe(&watch_pubsub_channel(domain_id)).await
+    }
+
+    /// The ones among `user_ids` with a signed-in socket in the domain.
+    pub async fn online(&self, domain_id: Option<Uuid>, user_ids: &[String]) -> AppResult<Vec<String>> {
+        let mut online = Vec::new();
+        for user_id in user_ids {
+            if !self.sockets(domain_id, user_id).await?.is_empty() {
+                online.push(user_id.clone());
+            }
+        }
+        Ok(online)
+    }
+
+    async fn announce(&self, domain_id: Option<Uuid>, name: &str, user_id: &str) -> AppResult<()> {
+        let change = WatchlistChange {
+            name: name.to_string(),
+            user_id: user_id.to_string(),
+        };
+        self.repo
+            .publish(&watch_pubsub_channel(domain_id), &serde_json::to_string(&change)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Socket ids signed in as the user, on any live node.
+    pub async fn sockets(&self, domain_id: Option<Uuid>, user_id: &str) -> AppResult<Vec<String>> {
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        self.repo
+            .user_sockets(&user_key(domain_id, user_id), stale_before(now_ms))
+            .await
+    }
+
+    /// Send an event to every connection of the user.
+    pub async fn send(
+        &self,
+        domain_id: Option<Uuid>,
+        user_id: &str,
+        event: &str,
+        data: serde_json::Value,
+        expires_at: Option<i64>,
+    ) -> AppResult<UserEventSent> {
+        let ws_event = WsEvent {
+            id: Uuid::new_v4().to_string(),
+            seq: 0,
+            published_at: chrono::Utc::now().timestamp_millis(),
+            history_id: None,
+            reliable: false,
+            expires_at,
+            event: event.to_string(),
+            channel: user_event_channel(user_id),
+            data,
+        };
+        let message = UserMessage {
+            domain_id,
+            user_id: user_id.to_string(),
+            event: serde_json::to_string(&ws_event)?,
+        };
+        self.repo
+            .publish(USERS_CHANNEL, &serde_json::to_string(&message)?)
+            .await?;
+        let connections = self.sockets(domain_id, user_id).await?.len();
+        Ok(UserEventSent {
+            id: ws_event.id,
+            published_at: ws_event.published_at,
+            connections,
+        })
+    }
+
+    /// Hand a user event to this node's sockets of the user. Returns how many got it.
+    async fn dispatch(&self, message: UserMessage) -> usize {
+        let local = self.local.read().await;
+        let Some(sockets) = local.get(&user_key(message.domain_id, &message.user_id)) else {
+            return 0;
+        };
+        sockets
+            .values()
+            .filter(|events| events.send(message.event.clone()).is_ok())
+            .count()
+    }
+
+    /// Refresh the heartbeat of this node's signed-in sockets.
+    async fn heartbeat(&self) -> AppResult<()> {
+        let sockets: Vec<(String, String)> = self
+            .local
+            .read()
+            .await
+            .iter()
+            .flat_map(|(key, sockets)| sockets.keys().map(move |socket_id| (key.clone(), socket_id.clone())))
+            .collect();
+        self.repo
+            .user_socket_heartbeat(&sockets, chrono::Utc::now().timestamp_millis(), USER_SOCKET_STALE.as_secs())
+            .await
+    }
+
+    /// Receive user events from all nodes, keep this node's signed-in sockets alive and
+    /// announce offline changes, forever.
+    pub async fn run(self) {
+        let heartbeat = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(USER_HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = heartbeat.heartbeat().await {
+                    warn!(error = %e, "refreshing signed-in sockets failed");
+                }
+            }
+        });
+        let sweep = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCHLIST_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = sweep.sweep().await {
+                    warn!(error = %e, "watchlist sweep failed");
+                }
+            }
+        });
+        loop {
+            match self.repo.subscribe_to_channel(USERS_CHANNEL).await {
+                Ok(mut rx) => loop {
+                    match rx.recv().await {
+                        Ok(payload) => match serde_json::from_str::<UserMessage>(&payload) {
+                            Ok(message) => {
+                                let delivered = self.dispatch(message).await;
+                                debug!(delivered, "user event dispatched");
+                            }
+                            Err(e) => warn!(error = %e, "invalid user event message"),
+                        },
+                        Err(broadcast::error::RecvError::Lagged(n)) => warn!(skipped = n, "user events dropped"),
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                },
+                Err(e) => warn!(error = %e, "subscribing to user events failed"),
+            }
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_user_data() {
+        let user = SignedInUser::parse(r#"{"id":"42","user_info":{"name":"Alice"}}"#).unwrap();
+        assert_eq!(user.id, "42");
+        assert_eq!(user.user_info.unwrap()["name"], "Alice");
+        assert!(SignedInUser::parse(r#"{"id":""}"#).is_err());
+        assert!(SignedInUser::parse(r#"{"name":"no id"}"#).is_err());
+        assert!(SignedInUser::parse("not json").is_err());
+        let user = SignedInUser::parse(r#"{"id":"42","watchlist":["7","9"]}"#).unwrap();
+        assert_eq!(user.watchlist, vec!["7", "9"]);
+        let too_many: Vec<String> = (0..=MAX_WATCHLIST_LEN).map(|i| i.to_string()).collect();
+        assert!(SignedInUser::parse(&serde_json::json!({ "id": "42", "watchlist": too_many }).to_string()).is_err());
+        assert!(SignedInUser::parse(r#"{"id":"42","watchlist":[""]}"#).is_err());
+        assert!(SignedInUser::parse(&format!(r#"{{"id":"{}"}}"#, "x".repeat(MAX_USER_ID_LEN + 1))).is_err());
+    }
+
+    #[tokio::test]
+    async fn user_events_reach_only_the_users_local_sockets() {
+        let repo = Arc::new(RedisRepository::new("redis://127.0.0.1/").unwrap());
+        let recovery = crate::services::recovery::RecoveryConfig {
+            buffer_size: 1,
+            grace: Duration::from_secs(1),
+        };
+        let users = UserService::new(repo.clone(), ChannelService::new(repo, Duration::from_secs(1), recovery));
+        let domain = Uuid::new_v4();
+        let (a, mut a_rx) = mpsc::unbounded_channel();
+        let (b, mut b_rx) = mpsc::unbounded_channel();
+        {
+            let mut local = users.local.write().await;
+            local.entry(user_key(Some(domain), "42")).or_default().insert("1.1".into(), a);
+            local.entry(user_key(None, "42")).or_default().insert("2.2".into(), b);
+        }
+        let message = |domain_id| UserMessage {
+            domain_id,
+            user_id: "42".to_string(),
+            event: "{}".to_string(),
+        };
+        assert_eq!(users.dispatch(message(Some(domain))).await, 1);
+        assert_eq!(a_rx.try_recv().unwrap(), "{}");
+        assert!(b_rx.try_recv().is_err(), "same user id in another domain");
+
+        users.forget(&user_key(Some(domain), "42"), "1.1").await;
+        assert_eq!(users.dispatch(message(Some(domain))).await, 0);
+        assert!(!users.local.read().await.contains_key(&user_key(Some(domain), "42")));
+    }
+
+    #[test]
+    fn watchers_get_only_changes_of_watched_users() {
+        let watched: HashSet<String> = ["7".to_string(), "9".to_string()].into();
+        let change = |user_id: &str| {
+            serde_json::to_string(&WatchlistChange {
+                name: "online".to_string(),
+                user_id: user_id.to_string(),
+            })
+            .unwrap()
+        };
+        assert_eq!(WatchlistChange::parse_watched(&change("7"), &watched).unwrap().user_id, "7");
+        assert!(WatchlistChange::parse_watched(&change("8"), &watched).is_none());
+        assert!(WatchlistChange::parse_watched("not json", &watched).is_none());
+    }
+
+    #[test]
+    fn user_keys_round_trip() {
+        let domain = Uuid::new_v4();
+        assert_eq!(parse_user_key(&user_key(Some(domain), "a:b")), Some((Some(domain), "a:b")));
+        assert_eq!(parse_user_key(&user_key(None, "42")), Some((None, "42")));
+        assert_eq!(parse_user_key("nope:42"), None);
+    }
+
+    #[test]
+    fn user_channels_are_reserved_and_scoped() {
+        let domain = Uuid::new_v4();
+        assert!(crate::models::channel::is_reserved_channel(USERS_CHANNEL));
+        assert!(crate::models::channel::is_reserved_channel(&watch_pubsub_channel(Some(domain))));
+        assert_ne!(user_key(Some(domain), "42"), user_key(None, "42"));
+        assert_eq!(user_event_channel("42"), "#server-to-user-42");
+    }
+}
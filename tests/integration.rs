@@ -1,4 +1,4 @@
-//! Integration tests: health, auth (register/login), broadcast (legacy app_key).
+//! Integration tests: health, auth (register/login), broadcast (legacy app_key), domain keys and origins.
 //!
 //! Run with `cargo test`. For integration tests that need DB/Redis, set:
 //! - `TEST_DATABASE_URL` (Postgres, run migrations first)
@@ -7,10 +7,21 @@
 
 use axum::body::Body;
 use axum::http::{Request, StatusCode};
+use notif::models::presence::PresenceMember;
 use notif::repositories::RedisRepository;
-use notif::services::{AuthService, ChannelService, PresenceService};
+use notif::services::delivery::{DeliveryConfig, Recipient};
+use notif::services::idempotency::{IdempotencyClaim, IDEMPOTENCY_CLAIM_TTL};
+use notif::services::polling::{PollFrames, PollInbound};
+use notif::services::schedule::{ScheduledBroadcast, DISPATCH_LEASE};
+use notif::services::user::USER_SOCKET_STALE;
+use notif::services::recovery::RecoveryConfig;
+use notif::services::{
+    AuthService, AuthorizerService, ChannelService, ControlService, DeliveryService, IdempotencyService,
+    Metrics, ModerationService, PollingService, PresenceService, ScheduleService, UserService,
+};
 use notif::{create_app, auth::JwtSecret, db, AppState};
 use std::sync::Arc;
+use std::time::Duration;
 use tower::util::ServiceExt;
 
 async fn test_state(
@@ -21,9 +32,30 @@ async fn test_state(
 ) -> Result<AppState, Box<dyn std::error::Error>> {
     let db_pool = db::create_pool(database_url).await?;
     let repo = Arc::new(RedisRepository::new(redis_url)?);
-    let channel_service = ChannelService::new(repo.clone());
+    let channel_service = ChannelService::new(
+        repo.clone(),
+        Duration::from_secs(1800),
+        RecoveryConfig {
+            buffer_size: 100,
+            grace: Duration::from_secs(120),
+        },
+    );
     let auth_service = AuthService::new(app_secret.to_string(), app_key.to_string());
+    let delivery_service = DeliveryService::new(
+        repo.clone(),
+        DeliveryConfig {
+            ack_timeout: Duration::from_secs(30),
+            retention: Duration::from_secs(86400),
+        },
+    );
+    let idempotency_service = IdempotencyService::new(repo.clone(), Duration::from_secs(86400), IDEMPOTENCY_CLAIM_TTL);
+    let schedule_service = ScheduleService::new(repo.clone());
+    let user_service = UserService::new(repo.clone(), channel_service.clone());
+    let control_service = ControlService::new(repo.clone());
+    let moderation_service = ModerationService::new(repo.clone());
+    let polling_service = PollingService::new(repo.clone());
     let presence_service = PresenceService::new(repo);
+    let authorizer_service = AuthorizerService::new(Duration::from_secs(3), Duration::from_secs(60), false)?;
     let jwt_secret = JwtSecret::new("test-jwt-secret-min-32-chars!!".to_string());
     Ok(AppState {
         app_key: app_key.to_string(),
@@ -31,11 +63,98 @@ async fn test_state(
         channel_service,
         auth_service,
         presence_service,
+        authorizer_service,
+        delivery_service,
+        idempotency_service,
+        schedule_service,
+        user_service,
+        control_service,
+        moderation_service,
+        polling_service,
+        metrics: Arc::new(Metrics::new()),
         db: db_pool,
         jwt_secret,
     })
 }
 
+/// State for a test from `TEST_DATABASE_URL` / `TEST_REDIS_URL` and the app key, or `None`
+/// to skip the test.
+async fn env_state() -> Option<(AppState, String)> {
+    let database_url = std::env::var("TEST_DATABASE_URL").ok()?;
+    let redis_url = std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let app_key = std::env::var("TEST_APP_KEY").unwrap_or_else(|_| "test-key".to_string());
+    let app_secret = std::env::var("TEST_APP_SECRET").unwrap_or_else(|_| "test-secret".to_string());
+    let state = test_state(&database_url, &redis_url, &app_key, &app_secret).await.ok()?;
+    Some((state, app_key))
+}
+
+/// Register a user and create a domain; returns the dashboard `authorization` header value
+/// and the created domain (with its one-time `key` and `secret`).
+async fn create_domain(app: &axum::Router, domain_name: &str) -> (String, serde_json::Value) {
+    let email = format!("{}@example.com", uuid::Uuid::new_v4().simple());
+    let register_body = serde_json::json!({ "name": "Test", "email": email, "password": "password123" });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/auth/register")
+        .header("content-type", "application/json")
+        .body(Body::from(register_body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let auth = format!("Bearer {}", json["token"].as_str().unwrap());
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/dashboard/domains")
+        .header("content-type", "application/json")
+        .header("authorization", &auth)
+        .body(Body::from(serde_json::json!({ "domain_name": domain_name }).to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    (auth, serde_json::from_slice(&body).unwrap())
+}
+
+/// Open a long-polling session (the WebSocket protocol over HTTP); returns its URI.
+async fn poll_open(app: &axum::Router) -> String {
+    let req = Request::builder().method("POST").uri("/poll").body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let session: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    format!("/poll/{}", session["session_id"].as_str().unwrap())
+}
+
+/// Send one frame to a long-polling session.
+async fn poll_send(app: &axum::Router, uri: &str, frame: serde_json::Value) {
+    let req = Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(frame.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+/// Frames of a long-polling session until one has the event `until` (or a few polls pass).
+async fn poll_until(app: &axum::Router, uri: &str, until: &str) -> Vec<serde_json::Value> {
+    let mut messages = Vec::new();
+    for _ in 0..5 {
+        let req = Request::builder().uri(format!("{}?wait=1", uri)).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        messages.extend(body["messages"].as_array().cloned().unwrap_or_default());
+        if messages.iter().any(|m| m["event"] == until) {
+            break;
+        }
+    }
+    messages
+}
+
 #[tokio::test]
 async fn health_returns_ok() {
     let database_url = match std::env::var("TEST_DATABASE_URL") {
@@ -145,4 +264,1573 @@ async fn broadcast_requires_app_key() {
         .unwrap();
     let res = app.oneshot(req).await.unwrap();
     assert_eq!(res.status(), StatusCode::OK, "broadcast with valid app_key should succeed");
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn broadcast_stamps_unique_id_and_channel_seq() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let channel = format!("stamped-{}", uuid::Uuid::new_v4().simple());
+    let body = serde_json::json!({ "channel": channel, "event": "test", "data": {} });
+    let send = || {
+        Request::builder()
+            .method("POST")
+            .uri("/api/broadcast")
+            .header("content-type", "application/json")
+            .header("x-app-key", &app_key)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let res = app.clone().oneshot(send()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let first: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert!(first["id"].as_str().is_some_and(|id| !id.is_empty()));
+    assert!(first["published_at"].as_i64().is_some());
+    assert_eq!(first["seq"], 1, "a new channel starts at 1");
+
+    let res = app.oneshot(send()).await.unwrap();
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let second: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_ne!(first["id"], second["id"], "event ids are unique");
+    assert_eq!(second["seq"], 2, "seq increases per channel");
+}
+
+#[tokio::test]
+async fn reliable_event_is_queued_for_known_recipients_that_are_offline() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let channel = format!("private-reliable-{}", uuid::Uuid::new_v4().simple());
+    let user = Recipient::User { domain_id: None, user_id: format!("u-{}", uuid::Uuid::new_v4().simple()) };
+    state.delivery_service().enroll(&channel, &user).await.unwrap();
+    let app = create_app(state.clone());
+
+    let body = serde_json::json!({ "channel": channel, "event": "paid", "data": {}, "reliable": true });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/broadcast")
+        .header("content-type", "application/json")
+        .header("x-app-key", &app_key)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let published: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let id = published["id"].as_str().unwrap();
+
+    let pending = state.delivery_service().pending(&user).await.unwrap();
+    assert_eq!(pending.len(), 1, "pending without the user ever being connected");
+    let event: serde_json::Value = serde_json::from_str(&pending[0]).unwrap();
+    assert_eq!((event["id"].as_str(), event["reliable"].as_bool()), (Some(id), Some(true)));
+
+    let req = Request::builder()
+        .uri(format!("/api/events/{}/delivery", id))
+        .header("x-app-key", &app_key)
+        .body(Body::empty())
+        .unwrap();
+    let res = app.oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(status["recipients"][0]["state"], "queued");
+    assert_eq!(status["pending"], 1);
+
+    assert!(state.delivery_service().ack(&user, id).await.unwrap());
+    assert!(state.delivery_service().pending(&user).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn scheduled_run_is_reclaimed_when_its_lease_runs_out() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let schedules = state.schedule_service();
+    let now = chrono::Utc::now();
+    let schedule = ScheduledBroadcast::new(
+        None,
+        "lease-test".to_string(),
+        "tick".to_string(),
+        serde_json::json!({}),
+        false,
+        None,
+        now - chrono::Duration::seconds(1),
+    );
+    schedules.create(&schedule).await.unwrap();
+    let claimed = |runs: Vec<ScheduledBroadcast>| runs.iter().any(|s| s.id == schedule.id);
+
+    assert!(claimed(schedules.claim_due(now).await.unwrap()));
+    assert!(!claimed(schedules.claim_due(now).await.unwrap()), "leased to the first claimer");
+
+    // The claiming node died without completing the run: it is run again after the lease.
+    let after_lease = now + chrono::Duration::from_std(DISPATCH_LEASE).unwrap() + chrono::Duration::seconds(1);
+    let again = schedules.claim_due(after_lease).await.unwrap();
+    assert!(claimed(again));
+    schedules.complete_run(&schedule, after_lease).await.unwrap();
+
+    let later = after_lease + chrono::Duration::from_std(DISPATCH_LEASE).unwrap() * 2;
+    assert!(!claimed(schedules.claim_due(later).await.unwrap()), "completed runs are not reclaimed");
+    assert!(schedules.list(None).await.unwrap().iter().all(|s| s.id != schedule.id));
+}
+
+#[tokio::test]
+async fn broadcast_with_idempotency_key_publishes_once() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let idempotency_key = format!("retry-{}", uuid::Uuid::new_v4());
+    let send = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/broadcast")
+            .header("content-type", "application/json")
+            .header("x-app-key", &app_key)
+            .header("idempotency-key", &idempotency_key)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let body = serde_json::json!({ "channel": "test-channel", "event": "test", "data": { "n": 1 } });
+
+    let res = app.clone().oneshot(send(body.clone())).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("idempotent-replayed").is_none());
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let first: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+    let res = app.clone().oneshot(send(body)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("idempotent-replayed").unwrap(), "true");
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let second: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(first["id"], second["id"], "duplicate returns the original event");
+
+    let other = serde_json::json!({ "channel": "test-channel", "event": "test", "data": { "n": 2 } });
+    let res = app.oneshot(send(other)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "key reused for a different request");
+}
+
+#[tokio::test]
+async fn expired_idempotency_claim_lets_a_retry_through() {
+    if env_state().await.is_none() {
+        return;
+    }
+    let redis_url = std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let repo = Arc::new(RedisRepository::new(&redis_url).unwrap());
+    let idempotency = IdempotencyService::new(repo, Duration::from_secs(60), Duration::from_secs(1));
+    let key = format!("crashed-{}", uuid::Uuid::new_v4());
+
+    let Ok(IdempotencyClaim::New(stale)) = idempotency.begin(None, &key, "fp").await else {
+        panic!("expected a new claim");
+    };
+    assert!(idempotency.begin(None, &key, "fp").await.is_err(), "claimed and in progress");
+
+    // The first request never completed: its claim runs out long before the window.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(matches!(idempotency.begin(None, &key, "fp").await, Ok(IdempotencyClaim::New(_))));
+    // The slow first request failing late does not release the retry's claim.
+    idempotency.release(None, &key, &stale).await.unwrap();
+    assert!(idempotency.begin(None, &key, "fp").await.is_err(), "retry still holds the key");
+
+    let response = serde_json::json!({ "ok": true });
+    idempotency.complete(None, &key, "fp", &response).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    match idempotency.begin(None, &key, "fp").await {
+        Ok(IdempotencyClaim::Replay(replayed)) => assert_eq!(replayed, response, "kept for the window"),
+        other => panic!("expected a replay, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn user_events_require_app_key_and_reserved_channels_are_rejected() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let post = |uri: &str, body: serde_json::Value, key: Option<&str>| {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(key) = key {
+            req = req.header("x-app-key", key);
+        }
+        req.body(Body::from(body.to_string())).unwrap()
+    };
+    let event = serde_json::json!({ "event": "hello", "data": {} });
+
+    let res = app.clone().oneshot(post("/api/users/42/events", event.clone(), None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = app
+        .clone()
+        .oneshot(post("/api/users/42/events", event, Some(&app_key)))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let sent: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(sent["user_id"], "42");
+    assert!(sent["connections"].as_u64().is_some());
+
+    let internal = serde_json::json!({ "channel": "#users", "event": "x", "data": {} });
+    let res = app.oneshot(post("/api/broadcast", internal, Some(&app_key))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "internal channels cannot be published to");
+}
+
+#[tokio::test]
+async fn signed_in_sockets_of_dead_nodes_stop_counting() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let redis_url = std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let repo = RedisRepository::new(&redis_url).unwrap();
+    let users = state.user_service();
+    let user_id = format!("u-{}", uuid::Uuid::new_v4().simple());
+
+    // Signed in on a node that died two heartbeat windows ago.
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let dead_at = now_ms - 2 * USER_SOCKET_STALE.as_millis() as i64;
+    let key = format!("legacy:{}", user_id);
+    repo.user_socket_add(&key, "dead.1", dead_at, dead_at - 1, 60).await.unwrap();
+    assert!(users.sockets(None, &user_id).await.unwrap().is_empty());
+
+    let mut watch = users.watch(None).await.unwrap();
+    let _events = users.sign_in(None, &user_id, "live.1").await.unwrap();
+    assert_eq!(users.sockets(None, &user_id).await.unwrap(), vec!["live.1"]);
+    let change = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            let change: serde_json::Value = serde_json::from_str(&watch.recv().await.unwrap()).unwrap();
+            if change["user_id"] == user_id.as_str() {
+                return change;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(change["name"], "online", "the dead node's socket does not count");
+
+    users.sign_out(None, &user_id, "live.1", Duration::ZERO).await.unwrap();
+    assert!(users.sockets(None, &user_id).await.unwrap().is_empty());
+}
+
+fn member(socket_id: &str, domain_id: Option<uuid::Uuid>, user_id: &str) -> PresenceMember {
+    PresenceMember {
+        user_id: user_id.to_string(),
+        user_info: None,
+        socket_id: socket_id.to_string(),
+        domain_id,
+        last_seen: None,
+    }
+}
+
+/// The next watchlist change name of `user_id` within `wait`, if any.
+async fn next_change(watch: &mut tokio::sync::broadcast::Receiver<String>, user_id: &str, wait: Duration) -> Option<String> {
+    tokio::time::timeout(wait, async {
+        loop {
+            let change: serde_json::Value = serde_json::from_str(&watch.recv().await.unwrap()).unwrap();
+            if change["user_id"] == user_id {
+                return change["name"].as_str().unwrap().to_string();
+            }
+        }
+    })
+    .await
+    .ok()
+}
+
+#[tokio::test]
+async fn watchlist_changes_on_first_and_last_socket_with_offline_held_for_grace() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let users = state.user_service();
+    let user_id = format!("u-{}", uuid::Uuid::new_v4().simple());
+    let quiet = Duration::from_millis(300);
+    let mut watch = users.watch(None).await.unwrap();
+
+    let _a = users.sign_in(None, &user_id, "1.1").await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, Duration::from_secs(2)).await.as_deref(), Some("online"));
+    let _b = users.sign_in(None, &user_id, "1.2").await.unwrap();
+    users.sign_out(None, &user_id, "1.1", Duration::ZERO).await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, quiet).await, None, "a socket is still signed in");
+
+    users.sign_out(None, &user_id, "1.2", Duration::from_millis(200)).await.unwrap();
+    users.sweep().await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, quiet).await, None, "held for the grace window");
+    users.sweep().await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, Duration::from_secs(2)).await.as_deref(), Some("offline"));
+
+    // Back within the window: neither offline nor a second online.
+    let _c = users.sign_in(None, &user_id, "1.3").await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, Duration::from_secs(2)).await.as_deref(), Some("online"));
+    users.sign_out(None, &user_id, "1.3", Duration::from_millis(200)).await.unwrap();
+    let _d = users.sign_in(None, &user_id, "1.4").await.unwrap();
+    tokio::time::sleep(quiet).await;
+    users.sweep().await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, quiet).await, None);
+    users.sign_out(None, &user_id, "1.4", Duration::ZERO).await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, Duration::from_secs(2)).await.as_deref(), Some("offline"));
+}
+
+#[tokio::test]
+async fn users_of_dead_nodes_are_announced_offline() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let redis_url = std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let repo = RedisRepository::new(&redis_url).unwrap();
+    let users = state.user_service();
+    let user_id = format!("u-{}", uuid::Uuid::new_v4().simple());
+    let mut watch = users.watch(None).await.unwrap();
+
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let dead_at = now_ms - 2 * USER_SOCKET_STALE.as_millis() as i64;
+    repo.user_socket_add(&format!("legacy:{}", user_id), "dead.1", dead_at, dead_at - 1, 60)
+        .await
+        .unwrap();
+    users.sweep().await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, Duration::from_secs(2)).await.as_deref(), Some("offline"));
+    users.sweep().await.unwrap();
+    assert_eq!(next_change(&mut watch, &user_id, Duration::from_millis(300)).await, None, "announced once");
+}
+
+#[tokio::test]
+async fn socket_control_endpoints_require_app_key() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    for uri in ["/api/users/nobody/terminate_connections", "/api/sockets/1.1/terminate"] {
+        let req = Request::builder().method("POST").uri(uri).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{} without key", uri);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("x-app-key", &app_key)
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{} with key", uri);
+    }
+
+    let subscribe = |body: serde_json::Value, key: Option<&str>| {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/api/sockets/1.1/subscribe")
+            .header("content-type", "application/json");
+        if let Some(key) = key {
+            req = req.header("x-app-key", key);
+        }
+        req.body(Body::from(body.to_string())).unwrap()
+    };
+    let teams = serde_json::json!({ "channels": ["private-team-7"] });
+    let res = app.clone().oneshot(subscribe(teams.clone(), None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = app.clone().oneshot(subscribe(teams, Some(&app_key))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let none = serde_json::json!({ "channels": [] });
+    let res = app.clone().oneshot(subscribe(none, Some(&app_key))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let reserved = serde_json::json!({ "channels": ["#control"] });
+    let res = app.oneshot(subscribe(reserved, Some(&app_key))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+/// The app on a loopback port, for real WebSocket connections.
+async fn serve_app(state: AppState) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, create_app(state)).await });
+    addr
+}
+
+/// Open `/ws` (legacy mode, no key) with a raw HTTP upgrade.
+async fn ws_connect(addr: std::net::SocketAddr) -> tokio::net::TcpStream {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let request = "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"), "upgraded");
+    stream
+}
+
+/// Send a masked text frame.
+async fn ws_send(stream: &mut tokio::net::TcpStream, message: serde_json::Value) {
+    use tokio::io::AsyncWriteExt;
+    let payload = message.to_string().into_bytes();
+    let mut frame = vec![0x81];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).await.unwrap();
+}
+
+/// Next frame from the server as (opcode, payload); `None` if none came in time.
+async fn ws_read(stream: &mut tokio::net::TcpStream, wait: Duration) -> Option<(u8, Vec<u8>)> {
+    use tokio::io::AsyncReadExt;
+    tokio::time::timeout(wait, async {
+        let first = stream.read_u8().await.ok()?;
+        let len = match stream.read_u8().await.ok()? & 0x7F {
+            126 => stream.read_u16().await.ok()? as usize,
+            127 => stream.read_u64().await.ok()? as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.ok()?;
+        Some((first & 0x0F, payload))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Next text frame with the event `event`, skipping others.
+async fn ws_event(stream: &mut tokio::net::TcpStream, event: &str) -> serde_json::Value {
+    loop {
+        let (opcode, payload) = ws_read(stream, Duration::from_secs(5)).await.expect("frame");
+        let message: serde_json::Value = match opcode {
+            1 => serde_json::from_slice(&payload).unwrap(),
+            _ => continue,
+        };
+        if message["event"] == event {
+            return message;
+        }
+    }
+}
+
+/// `connection_established` data: (socket_id, resume_token).
+async fn ws_established(stream: &mut tokio::net::TcpStream) -> (String, String) {
+    let message = ws_event(stream, "connection_established").await;
+    let data = &message["data"];
+    (
+        data["socket_id"].as_str().unwrap().to_string(),
+        data["resume_token"].as_str().unwrap().to_string(),
+    )
+}
+
+#[tokio::test]
+async fn terminated_socket_gets_close_4010_and_cannot_resume() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    tokio::spawn(state.control_service().clone().run());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let control = state.control_service().clone();
+    let addr = serve_app(state).await;
+    let subscribe = serde_json::json!({
+        "event": "subscribe",
+        "data": { "channel": format!("news-{}", uuid::Uuid::new_v4().simple()) }
+    });
+
+    // A dropped connection stays resumable...
+    let mut dropped = ws_connect(addr).await;
+    let (_, dropped_token) = ws_established(&mut dropped).await;
+    ws_send(&mut dropped, subscribe.clone()).await;
+    ws_event(&mut dropped, "pusher_internal:subscription_succeeded").await;
+    drop(dropped);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let mut next = ws_connect(addr).await;
+    ws_established(&mut next).await;
+    ws_send(&mut next, serde_json::json!({ "event": "resume", "data": { "resume_token": dropped_token } })).await;
+    ws_event(&mut next, "pusher:resumed").await;
+
+    // ...a terminated one is closed with 4010 and is not.
+    let mut socket = ws_connect(addr).await;
+    let (socket_id, resume_token) = ws_established(&mut socket).await;
+    ws_send(&mut socket, subscribe).await;
+    ws_event(&mut socket, "pusher_internal:subscription_succeeded").await;
+    control
+        .send(None, vec![socket_id], notif::services::control::ControlCommand::Terminate)
+        .await
+        .unwrap();
+    let close = loop {
+        match ws_read(&mut socket, Duration::from_secs(5)).await.expect("close frame") {
+            (8, payload) => break payload,
+            _ => continue,
+        }
+    };
+    assert_eq!(u16::from_be_bytes([close[0], close[1]]), notif::services::control::CLOSE_TERMINATED);
+
+    let mut again = ws_connect(addr).await;
+    ws_established(&mut again).await;
+    ws_send(&mut again, serde_json::json!({ "event": "resume", "data": { "resume_token": resume_token } })).await;
+    let failed = ws_event(&mut again, "pusher:resume_failed").await;
+    assert!(failed["data"]["message"].as_str().unwrap().contains("Unknown or expired"));
+}
+
+#[tokio::test]
+async fn kick_only_removes_presence_members_of_the_callers_domain() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let presence = state.presence_service().clone();
+    let channel_service = state.channel_service.clone();
+    let app = create_app(state);
+    let channel = format!("presence-shared-{}", uuid::Uuid::new_v4().simple());
+
+    let mut domains = Vec::new();
+    for name in ["kick-a.example.com", "kick-b.example.com"] {
+        let (_, domain) = create_domain(&app, name).await;
+        let id: uuid::Uuid = domain["id"].as_str().unwrap().parse().unwrap();
+        domains.push((id, domain["key"].as_str().unwrap().to_string()));
+    }
+    let (a, b) = (domains[0].0, domains[1].0);
+    presence.add_member(&channel, member("a.1", Some(a), "42"), 10).await.unwrap();
+    presence.add_member(&channel, member("a.2", Some(a), "7"), 10).await.unwrap();
+    presence.add_member(&channel, member("b.1", Some(b), "42"), 10).await.unwrap();
+    let mut events = channel_service.subscribe(&channel).await.unwrap();
+
+    let kick = |key: String| {
+        let app = app.clone();
+        let uri = format!("/api/channels/{}/kick", channel);
+        async move {
+            let req = Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .header("x-app-key", key)
+                .body(Body::from(serde_json::json!({ "user_id": "42" }).to_string()))
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        }
+    };
+    let user_ids = || async {
+        let mut ids: Vec<String> = presence
+            .list_members(&channel)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.user_id)
+            .collect();
+        ids.sort();
+        ids
+    };
+
+    assert_eq!(kick(domains[0].1.clone()).await["presence_sockets_removed"], 1);
+    assert_eq!(user_ids().await, vec!["42", "7"], "domain b's member 42 stays");
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), events.recv()).await.is_err(),
+        "42 is still on the channel, so no member_removed"
+    );
+
+    assert_eq!(kick(domains[1].1.clone()).await["presence_sockets_removed"], 1);
+    assert_eq!(user_ids().await, vec!["7"]);
+    let removed: serde_json::Value =
+        serde_json::from_str(&tokio::time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap()).unwrap();
+    assert_eq!(removed["event"], "pusher_internal:member_removed");
+    assert_eq!(removed["data"]["user_id"], "42");
+}
+
+#[tokio::test]
+async fn last_seen_is_recorded_when_the_users_last_socket_leaves() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let presence = state.presence_service().clone();
+    let channel = format!("presence-seen-{}", uuid::Uuid::new_v4().simple());
+    let scope = uuid::Uuid::new_v4().to_string();
+    let policy = notif::models::presence::LastSeenPolicy { scope: scope.clone(), retention_secs: 60 };
+
+    for socket in ["1.1", "1.2"] {
+        let seen = PresenceMember { last_seen: Some(policy.clone()), ..member(socket, None, "42") };
+        presence.add_member(&channel, seen, 10).await.unwrap();
+    }
+    // Adding the same socket again does not count it twice.
+    let seen = PresenceMember { last_seen: Some(policy.clone()), ..member("1.2", None, "42") };
+    presence.add_member(&channel, seen, 10).await.unwrap();
+    presence.remove_member(&channel, "1.1").await.unwrap();
+    assert!(presence.last_seen_of_channel(&scope, &channel, 60).await.unwrap().is_empty());
+    let ids = vec!["42".to_string(), "7".to_string()];
+    let online = presence.online_users(&channel, &ids).await.unwrap();
+    assert_eq!(online.into_iter().collect::<Vec<_>>(), vec!["42"]);
+    let other = format!("presence-seen-{}", uuid::Uuid::new_v4().simple());
+    let channels = vec![channel.clone(), other];
+    assert_eq!(presence.user_online_in(&channels, "42").await.unwrap(), vec![true, false]);
+
+    presence.remove_member(&channel, "1.2").await.unwrap();
+    let seen = presence.last_seen_of_channel(&scope, &channel, 60).await.unwrap();
+    assert_eq!(seen.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), vec!["42"]);
+    assert!(presence.online_users(&channel, &ids).await.unwrap().is_empty());
+    assert_eq!(presence.user_online_in(&channels, "42").await.unwrap(), vec![false, false]);
+}
+
+#[tokio::test]
+async fn presence_update_only_changes_current_members() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let presence = state.presence_service().clone();
+    let channel = format!("presence-update-{}", uuid::Uuid::new_v4().simple());
+    presence.add_member(&channel, member("1.1", None, "42"), 10).await.unwrap();
+    let info = serde_json::json!({ "status": "away" });
+    let updated = presence.update_member(&channel, "1.1", "42", info.clone()).await.unwrap();
+    assert_eq!(updated.unwrap().user_info, Some(info.clone()));
+
+    presence.remove_member(&channel, "1.1").await.unwrap();
+    assert!(presence.update_member(&channel, "1.1", "42", info).await.unwrap().is_none());
+    assert!(presence.list_members(&channel).await.unwrap().is_empty(), "no member is recreated");
+}
+
+#[tokio::test]
+async fn presence_member_cap_counts_users_and_pages_by_user_id() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let presence = state.presence_service().clone();
+    let channel = format!("presence-cap-{}", uuid::Uuid::new_v4().simple());
+    let join = |socket: &'static str, user: &'static str| {
+        let presence = presence.clone();
+        let channel = channel.clone();
+        async move {
+            let info = Some(serde_json::json!({ "name": user }));
+            presence.add_member(&channel, PresenceMember { user_info: info, ..member(socket, None, user) }, 3).await.unwrap()
+        }
+    };
+
+    let joined = futures::future::join_all([join("1.1", "b"), join("1.2", "a"), join("1.3", "c"), join("1.4", "d")]).await;
+    assert_eq!(joined.iter().filter(|added| **added).count(), 3, "the cap holds under concurrent joins");
+    presence.remove_member(&channel, "1.1").await.unwrap();
+    presence.remove_member(&channel, "1.2").await.unwrap();
+    presence.remove_member(&channel, "1.3").await.unwrap();
+    presence.remove_member(&channel, "1.4").await.unwrap();
+
+    assert!(join("2.1", "b").await);
+    assert!(join("2.2", "a").await);
+    assert!(join("2.3", "c").await);
+    assert!(join("2.4", "a").await, "a second socket of a member is not a new user");
+    assert!(!join("2.5", "d").await, "full");
+
+    let (page, next) = presence.list_members_page(&channel, None, 2).await.unwrap();
+    assert_eq!(page.iter().map(|u| u.user_id.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+    assert_eq!(page[0].user_info, Some(serde_json::json!({ "name": "a" })));
+    assert_eq!(next.as_deref(), Some("b"));
+    let (page, next) = presence.list_members_page(&channel, next.as_deref(), 2).await.unwrap();
+    assert_eq!(page.iter().map(|u| u.user_id.as_str()).collect::<Vec<_>>(), ["c"]);
+    assert_eq!(next, None);
+
+    presence.remove_member(&channel, "2.2").await.unwrap();
+    let (page, _) = presence.list_members_page(&channel, None, 10).await.unwrap();
+    assert_eq!(page.iter().map(|u| u.user_id.as_str()).collect::<Vec<_>>(), ["a", "b", "c"], "a still has 2.4");
+    presence.remove_member(&channel, "2.4").await.unwrap();
+    assert!(join("2.5", "d").await, "room again once a's last socket left");
+}
+
+#[tokio::test]
+async fn kick_with_ban_lists_and_unbans() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let channel = format!("presence-room-{}", uuid::Uuid::new_v4().simple());
+    let request = |method: &str, uri: String, body: Option<serde_json::Value>| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .header("x-app-key", &app_key)
+            .body(body.map_or_else(Body::empty, |b| Body::from(b.to_string())))
+            .unwrap()
+    };
+    let json = |res: axum::response::Response| async move {
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+    };
+
+    let kick = serde_json::json!({ "user_id": "spammer", "ban": { "duration_secs": 60, "reason": "spam" } });
+    let res = app
+        .clone()
+        .oneshot(request("POST", format!("/api/channels/{}/kick", channel), Some(kick)))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(json(res).await["ban"]["reason"], "spam");
+
+    let res = app
+        .clone()
+        .oneshot(request("GET", format!("/api/bans?channel={}", channel), None))
+        .await
+        .unwrap();
+    let bans = json(res).await;
+    assert_eq!(bans["bans"].as_array().map(Vec::len), Some(1));
+    assert_eq!(bans["bans"][0]["user_id"], "spammer");
+
+    let unban = format!("/api/channels/{}/bans/spammer", channel);
+    let res = app.clone().oneshot(request("DELETE", unban.clone(), None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = app.clone().oneshot(request("DELETE", unban, None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let public = serde_json::json!({ "user_id": "spammer" });
+    let res = app
+        .oneshot(request("POST", "/api/channels/lobby/kick".to_string(), Some(public)))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "public channels have no members to kick");
+}
+
+#[tokio::test]
+async fn domain_key_is_shown_once_and_masked_in_list() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let email = format!("keys-{}@example.com", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
+    let register_body = serde_json::json!({ "name": "Keys", "email": email, "password": "password123" });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/auth/register")
+        .header("content-type", "application/json")
+        .body(Body::from(register_body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let token = json["token"].as_str().unwrap().to_string();
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/dashboard/domains")
+        .header("content-type", "application/json")
+        .header("authorization", format!("Bearer {}", token))
+        .body(Body::from(serde_json::json!({ "domain_name": "keys.example.com" }).to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let created: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let key = created["key"].as_str().expect("create returns the full key").to_string();
+    assert!(created["secret"].as_str().is_some_and(|s| s.starts_with("ns_")));
+
+    let req = Request::builder()
+        .uri("/dashboard/domains")
+        .header("authorization", format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let listed = &list[0];
+    assert!(listed.get("key").is_none(), "list must not expose the key");
+    assert!(listed.get("secret").is_none(), "list must not expose the secret");
+    assert!(!listed["key_masked"].as_str().unwrap().contains(&key[15..]));
+
+    let body = serde_json::json!({ "channel": "test-channel", "event": "test", "data": {} });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/broadcast")
+        .header("content-type", "application/json")
+        .header("x-app-key", &key)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let res = app.oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK, "hashed key still authenticates");
+}
+
+#[tokio::test]
+async fn sse_allows_cross_origin_reads_only_from_the_domains_origins() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+    let (_, domain) = create_domain(&app, "sse-cors.example.com").await;
+    let claims = notif::services::connection_token::ConnectionClaims {
+        channels: vec!["news".to_string()],
+        user_id: None,
+        exp: chrono::Utc::now().timestamp() + 60,
+        iat: None,
+    };
+    let token = notif::services::connection_token::sign_connection_token(
+        domain["id"].as_str().unwrap().parse().unwrap(),
+        domain["secret"].as_str().unwrap(),
+        &claims,
+    )
+    .unwrap();
+    let open = |origin: &'static str| {
+        let req = Request::builder()
+            .uri(format!("/sse?channels=news&token={}", token))
+            .header("origin", origin)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(req)
+    };
+
+    let res = open("https://sse-cors.example.com").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["access-control-allow-origin"], "https://sse-cors.example.com");
+    let res = open("https://evil.example.net").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK, "the token authenticates");
+    assert!(res.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn domain_origins_crud() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let email = format!("origins-{}@example.com", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
+    let register_body = serde_json::json!({ "name": "Origins", "email": email, "password": "password123" });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/auth/register")
+        .header("content-type", "application/json")
+        .body(Body::from(register_body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let auth = format!("Bearer {}", json["token"].as_str().unwrap());
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/dashboard/domains")
+        .header("content-type", "application/json")
+        .header("authorization", &auth)
+        .body(Body::from(serde_json::json!({ "domain_name": "origins.example.com" }).to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let domain: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let domain_id = domain["id"].as_str().unwrap().to_string();
+    assert_eq!(domain["dev_mode"], false);
+
+    let add = |origin: &str| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("/dashboard/domains/{}/origins", domain_id))
+            .header("content-type", "application/json")
+            .header("authorization", &auth)
+            .body(Body::from(serde_json::json!({ "origin": origin }).to_string()))
+            .unwrap()
+    };
+    let res = app.clone().oneshot(add("example.com/path")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "invalid pattern is rejected");
+    let res = app.clone().oneshot(add("https://*.partner.io:8443")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let origin: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    let req = Request::builder()
+        .uri(format!("/dashboard/domains/{}/origins", domain_id))
+        .header("authorization", &auth)
+        .body(Body::empty())
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(list.as_array().unwrap().len(), 1);
+    assert_eq!(list[0]["origin"], "https://*.partner.io:8443");
+
+    let req = Request::builder()
+        .method("PATCH")
+        .uri(format!("/dashboard/domains/{}", domain_id))
+        .header("content-type", "application/json")
+        .header("authorization", &auth)
+        .body(Body::from(serde_json::json!({ "dev_mode": true }).to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = Request::builder()
+        .method("DELETE")
+        .uri(format!("/dashboard/domains/{}/origins/{}", domain_id, origin["id"].as_str().unwrap()))
+        .header("authorization", &auth)
+        .body(Body::empty())
+        .unwrap();
+    let res = app.oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn domain_history_settings() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let email = format!("history-{}@example.com", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
+    let register_body = serde_json::json!({ "name": "History", "email": email, "password": "password123" });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/auth/register")
+        .header("content-type", "application/json")
+        .body(Body::from(register_body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let auth = format!("Bearer {}", json["token"].as_str().unwrap());
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/dashboard/domains")
+        .header("content-type", "application/json")
+        .header("authorization", &auth)
+        .body(Body::from(serde_json::json!({ "domain_name": "history.example.com" }).to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let domain: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let domain_id = domain["id"].as_str().unwrap().to_string();
+    let key = domain["key"].as_str().unwrap().to_string();
+    assert_eq!(domain["history_channels"], serde_json::json!([]));
+
+    let patch = |body: serde_json::Value| {
+        Request::builder()
+            .method("PATCH")
+            .uri(format!("/dashboard/domains/{}", domain_id))
+            .header("content-type", "application/json")
+            .header("authorization", &auth)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let res = app.clone().oneshot(patch(serde_json::json!({ "history_max_events": 0 }))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = app
+        .clone()
+        .oneshot(patch(serde_json::json!({ "history_channels": ["orders-*"], "history_max_age_secs": 3600 })))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = Request::builder()
+        .uri("/dashboard/domains")
+        .header("authorization", &auth)
+        .body(Body::empty())
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(list[0]["history_channels"], serde_json::json!(["orders-*"]));
+    assert_eq!(list[0]["history_max_events"], 100);
+    assert_eq!(list[0]["history_max_age_secs"], 3600);
+
+    let req = Request::builder()
+        .uri("/api/channels/prices/history")
+        .header("x-app-key", &key)
+        .body(Body::empty())
+        .unwrap();
+    let res = app.oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "history not enabled for this channel");
+}
+
+#[tokio::test]
+async fn domain_presence_settings() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let app = create_app(state);
+
+    let email = format!("lastseen-{}@example.com", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
+    let register_body = serde_json::json!({ "name": "Last Seen", "email": email, "password": "password123" });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/auth/register")
+        .header("content-type", "application/json")
+        .body(Body::from(register_body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let auth = format!("Bearer {}", json["token"].as_str().unwrap());
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/dashboard/domains")
+        .header("content-type", "application/json")
+        .header("authorization", &auth)
+        .body(Body::from(serde_json::json!({ "domain_name": "lastseen.example.com" }).to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let domain: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let domain_id = domain["id"].as_str().unwrap().to_string();
+    let key = domain["key"].as_str().unwrap().to_string();
+    assert_eq!(domain["presence_last_seen_secs"], 604800);
+    assert_eq!(domain["presence_max_members"], 1000);
+    assert_eq!(domain["presence_max_channel_data_bytes"], 4096);
+
+    let patch = |body: serde_json::Value| {
+        Request::builder()
+            .method("PATCH")
+            .uri(format!("/dashboard/domains/{}", domain_id))
+            .header("content-type", "application/json")
+            .header("authorization", &auth)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let res = app.clone().oneshot(patch(serde_json::json!({ "presence_last_seen_secs": -1 }))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = app.clone().oneshot(patch(serde_json::json!({ "presence_last_seen_secs": 0 }))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = app.clone().oneshot(patch(serde_json::json!({ "presence_max_members": 0 }))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = app
+        .clone()
+        .oneshot(patch(serde_json::json!({ "presence_max_members": 50, "presence_max_channel_data_bytes": 512 })))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let get = |uri: &str| {
+        Request::builder()
+            .uri(uri)
+            .header("x-app-key", &key)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let res = app.clone().oneshot(get("/api/users/42/presence")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "tracking disabled for this domain");
+    let res = app.oneshot(get("/api/channels/private-room/users")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "only presence channels have users");
+}
+
+#[tokio::test]
+async fn poll_session_round_trip() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    tokio::spawn(state.polling_service().clone().run());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let app = create_app(state);
+
+    let req = Request::builder().method("POST").uri("/poll").body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let session: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let uri = format!("/poll/{}", session["session_id"].as_str().unwrap());
+
+    let poll = |app: axum::Router| {
+        let uri = format!("{}?wait=2", uri);
+        async move {
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            body["messages"].as_array().unwrap().clone()
+        }
+    };
+    let messages = poll(app.clone()).await;
+    assert_eq!(messages[0]["event"], "connection_established");
+    assert_eq!(messages[0]["data"]["socket_id"], session["socket_id"]);
+
+    let send = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(&uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let res = app.clone().oneshot(send(serde_json::json!({ "event": "ping" }))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let messages = poll(app.clone()).await;
+    assert_eq!(messages[0]["event"], "pusher:pong");
+    let res = app.clone().oneshot(send(serde_json::json!(["ping"]))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST, "frames must be objects");
+
+    let req = Request::builder().method("DELETE").uri(&uri).body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let req = Request::builder().uri("/poll/np_unknown?wait=0").body(Body::empty()).unwrap();
+    let res = app.oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn poll_frames_stay_queued_until_acked() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let polling = state.polling_service();
+    let (session_id, _inbox) = polling.create().await.unwrap();
+    let frames = |f: &[&str]| f.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let poll = |ack: Option<u64>| polling.poll(&session_id, ack, Duration::ZERO);
+
+    polling.push(&session_id, &frames(&["a", "b"])).await.unwrap();
+    let first = poll(None).await.unwrap().unwrap();
+    assert_eq!((first.frames.clone(), first.ack), (frames(&["a", "b"]), 2));
+    // The response was lost: the client polls again with its last ack.
+    let again = poll(Some(0)).await.unwrap().unwrap();
+    assert_eq!(again, first);
+    let acked = poll(Some(2)).await.unwrap().unwrap();
+    assert_eq!((acked.frames.len(), acked.ack), (0, 2));
+
+    // Without `ack`, a poll confirms what the previous poll returned.
+    polling.push(&session_id, &frames(&["c"])).await.unwrap();
+    assert_eq!(poll(None).await.unwrap().unwrap().frames, frames(&["c"]));
+    assert_eq!(poll(None).await.unwrap().unwrap(), PollFrames { frames: Vec::new(), ack: 3 });
+    polling.release(&session_id).await.unwrap();
+}
+
+#[tokio::test]
+async fn poll_sessions_of_dead_owners_are_gone() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    let redis_url = std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let repo = RedisRepository::new(&redis_url).unwrap();
+    let polling = state.polling_service();
+    let session_id = notif::services::polling::generate_session_id();
+
+    // The owning node refreshes its heartbeat for one second, then dies.
+    repo.poll_session_create(&session_id, 60, 1).await.unwrap();
+    assert!(polling.send(&session_id, PollInbound::Close).await.unwrap());
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(!polling.send(&session_id, PollInbound::Close).await.unwrap());
+    assert!(polling.poll(&session_id, None, Duration::ZERO).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn cache_channel_replays_last_event_or_reports_a_miss() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    tokio::spawn(state.polling_service().clone().run());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let app = create_app(state);
+    let channel = format!("cache-price-{}", uuid::Uuid::new_v4().simple());
+    let subscribe = serde_json::json!({ "event": "subscribe", "data": { "channel": channel } });
+
+    let first = poll_open(&app).await;
+    poll_until(&app, &first, "connection_established").await;
+    poll_send(&app, &first, subscribe.clone()).await;
+    let messages = poll_until(&app, &first, "pusher:cache_miss").await;
+    let events: Vec<_> = messages.iter().map(|m| m["event"].as_str().unwrap_or_default()).collect();
+    assert_eq!(events, ["pusher_internal:subscription_succeeded", "pusher:cache_miss"]);
+    assert_eq!(messages[1]["channel"], channel.as_str());
+
+    let body = serde_json::json!({ "channel": channel, "event": "price", "data": { "v": 1 } });
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/broadcast")
+        .header("content-type", "application/json")
+        .header("x-app-key", &app_key)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let second = poll_open(&app).await;
+    poll_until(&app, &second, "connection_established").await;
+    poll_send(&app, &second, subscribe).await;
+    let messages = poll_until(&app, &second, "price").await;
+    let events: Vec<_> = messages.iter().map(|m| m["event"].as_str().unwrap_or_default()).collect();
+    assert_eq!(events, ["pusher_internal:subscription_succeeded", "price"], "replayed right after subscribing");
+    assert_eq!(messages[1]["data"]["v"], 1);
+    assert_eq!(messages[1]["channel"], channel.as_str());
+}
+
+#[tokio::test]
+async fn second_subscribe_of_a_socket_cannot_switch_its_presence_user() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    tokio::spawn(state.polling_service().clone().run());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let presence = state.presence_service().clone();
+    let auth = state.auth_service().clone();
+    let app = create_app(state);
+    let channel = format!("presence-room-{}", uuid::Uuid::new_v4().simple());
+
+    let session = poll_open(&app).await;
+    let messages = poll_until(&app, &session, "connection_established").await;
+    let data: serde_json::Value = match &messages[0]["data"] {
+        serde_json::Value::String(text) => serde_json::from_str(text).unwrap(),
+        data => data.clone(),
+    };
+    let socket_id = data["socket_id"].as_str().unwrap().to_string();
+    for user_id in ["1", "2"] {
+        let channel_data = serde_json::json!({ "user_id": user_id });
+        let signature = auth
+            .sign_channel(&socket_id, &channel, Some(&channel_data.to_string()))
+            .unwrap();
+        let subscribe = serde_json::json!({
+            "event": "subscribe",
+            "data": { "channel": channel, "auth": signature, "channel_data": channel_data }
+        });
+        poll_send(&app, &session, subscribe).await;
+    }
+    let messages = poll_until(&app, &session, "pusher:error").await;
+    let error = messages.iter().find(|m| m["event"] == "pusher:error").expect("second subscribe refused");
+    assert!(error["data"]["message"].as_str().unwrap().starts_with("Already subscribed"));
+
+    let ids = vec!["1".to_string(), "2".to_string()];
+    let online = presence.online_users(&channel, &ids).await.unwrap();
+    assert_eq!(online.into_iter().collect::<Vec<_>>(), vec!["1"]);
+    let unsubscribe = serde_json::json!({ "event": "unsubscribe", "data": { "channel": channel } });
+    poll_send(&app, &session, unsubscribe).await;
+    poll_send(&app, &session, serde_json::json!({ "event": "ping" })).await;
+    poll_until(&app, &session, "pusher:pong").await;
+    assert!(presence.online_users(&channel, &ids).await.unwrap().is_empty(), "no user count is left behind");
+}
+
+#[tokio::test]
+async fn server_driven_presence_subscribe_stores_the_whole_channel_data() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    tokio::spawn(state.polling_service().clone().run());
+    tokio::spawn(state.control_service().clone().run());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let presence = state.presence_service().clone();
+    let app = create_app(state);
+    let channel = format!("presence-team-{}", uuid::Uuid::new_v4().simple());
+
+    let session = poll_open(&app).await;
+    let messages = poll_until(&app, &session, "connection_established").await;
+    let data: serde_json::Value = match &messages[0]["data"] {
+        serde_json::Value::String(text) => serde_json::from_str(text).unwrap(),
+        data => data.clone(),
+    };
+    let socket_id = data["socket_id"].as_str().unwrap();
+    let channel_data = serde_json::json!({ "user_id": "42", "user_info": { "name": "Alice" } });
+    let req = Request::builder()
+        .method("POST")
+        .uri(format!("/api/sockets/{}/subscribe", socket_id))
+        .header("content-type", "application/json")
+        .header("x-app-key", &app_key)
+        .body(Body::from(serde_json::json!({ "channels": [channel], "channel_data": channel_data }).to_string()))
+        .unwrap();
+    assert_eq!(app.clone().oneshot(req).await.unwrap().status(), StatusCode::OK);
+    poll_until(&app, &session, "pusher_internal:subscription_succeeded").await;
+
+    let members = presence.list_members(&channel).await.unwrap();
+    assert_eq!(members.len(), 1);
+    assert_eq!(members[0].user_id, "42");
+    assert_eq!(members[0].user_info, Some(channel_data), "same shape as a client subscribe");
+}
+
+#[tokio::test]
+async fn plain_channels_have_no_cache() {
+    let Some((state, _)) = env_state().await else {
+        return;
+    };
+    tokio::spawn(state.polling_service().clone().run());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let channel = format!("price-{}", uuid::Uuid::new_v4().simple());
+    state
+        .channel_service
+        .broadcast(&channel, "price", serde_json::json!({ "v": 1 }))
+        .await
+        .unwrap();
+    assert_eq!(state.channel_service.cached_event(&channel).await.unwrap(), None);
+    let app = create_app(state);
+
+    let session = poll_open(&app).await;
+    poll_until(&app, &session, "connection_established").await;
+    poll_send(&app, &session, serde_json::json!({ "event": "subscribe", "data": { "channel": channel } })).await;
+    poll_send(&app, &session, serde_json::json!({ "event": "ping" })).await;
+    let messages = poll_until(&app, &session, "pusher:pong").await;
+    let events: Vec<_> = messages.iter().map(|m| m["event"].as_str().unwrap_or_default()).collect();
+    assert_eq!(events, ["pusher_internal:subscription_succeeded", "pusher:pong"], "no replay and no cache_miss");
+}
+
+#[tokio::test]
+async fn history_is_kept_per_domain_and_paged_by_the_capped_limit() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let channel_service = state.channel_service.clone();
+    let app = create_app(state);
+    let channel = format!("orders-{}", uuid::Uuid::new_v4().simple());
+
+    let mut domains = Vec::new();
+    for name in ["a.example.com", "b.example.com"] {
+        let (auth, domain) = create_domain(&app, name).await;
+        let req = Request::builder()
+            .method("PATCH")
+            .uri(format!("/dashboard/domains/{}", domain["id"].as_str().unwrap()))
+            .header("content-type", "application/json")
+            .header("authorization", &auth)
+            .body(Body::from(serde_json::json!({ "history_channels": ["orders-*"] }).to_string()))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        domains.push(domain);
+    }
+    let history = |key: String, query: String| {
+        let app = app.clone();
+        let uri = format!("/api/channels/{}/history{}", channel, query);
+        async move {
+            let req = Request::builder().uri(uri).header("x-app-key", key).body(Body::empty()).unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            let status = res.status();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+        }
+    };
+
+    let key_a = domains[0]["key"].as_str().unwrap().to_string();
+    let key_b = domains[1]["key"].as_str().unwrap().to_string();
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/broadcast")
+        .header("content-type", "application/json")
+        .header("x-app-key", &key_b)
+        .body(Body::from(serde_json::json!({ "channel": channel, "event": "b", "data": {} }).to_string()))
+        .unwrap();
+    assert_eq!(app.clone().oneshot(req).await.unwrap().status(), StatusCode::OK);
+    let policy = notif::services::history::HistoryPolicy {
+        domain_id: domains[0]["id"].as_str().unwrap().parse().unwrap(),
+        max_events: 1000,
+        max_age_secs: None,
+    };
+    let options = notif::services::channel::PublishOptions {
+        history: Some(policy),
+        ..Default::default()
+    };
+    for n in 0..201 {
+        channel_service
+            .broadcast_with_options(&channel, "a", serde_json::json!({ "n": n }), &options)
+            .await
+            .unwrap();
+    }
+
+    let (status, page) = history(key_a.clone(), "?limit=500".to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    let events = page["events"].as_array().unwrap();
+    assert_eq!(events.len(), 200, "limit is capped");
+    assert!(events.iter().all(|e| e["event"] == "a"), "only the domain's own events");
+    let cursor = page["next_cursor"].as_str().expect("a full page has a cursor").to_string();
+    let (_, page) = history(key_a, format!("?limit=500&cursor={}", cursor)).await;
+    assert_eq!(page["events"].as_array().unwrap().len(), 1);
+    assert_eq!(page["next_cursor"], serde_json::Value::Null);
+
+    let (_, page) = history(key_b, String::new()).await;
+    let events = page["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["event"], "b");
+    let (status, _) = history(app_key, String::new()).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "history belongs to domains");
+}
+
+/// An MQTT listener on a loopback port.
+async fn mqtt_listener(state: AppState) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(notif::mqtt::serve(listener, state));
+    addr
+}
+
+fn mqtt_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// A 3.1.1 packet: fixed header byte, remaining length, body.
+fn mqtt_packet(first: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![first];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        out.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend(body);
+    out
+}
+
+/// CONNECT with a clean session and an optional QoS 0 will (topic, payload).
+fn mqtt_connect(username: &str, password: &str, keep_alive: u16, will: Option<(&str, &str)>) -> Vec<u8> {
+    let mut body = Vec::new();
+    mqtt_bytes(&mut body, b"MQTT");
+    body.push(4);
+    body.push(0xC2 | if will.is_some() { 0x04 } else { 0 });
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    mqtt_bytes(&mut body, b"");
+    if let Some((topic, payload)) = will {
+        mqtt_bytes(&mut body, topic.as_bytes());
+        mqtt_bytes(&mut body, payload.as_bytes());
+    }
+    mqtt_bytes(&mut body, username.as_bytes());
+    mqtt_bytes(&mut body, password.as_bytes());
+    mqtt_packet(0x10, body)
+}
+
+fn mqtt_publish(topic: &str, packet_id: Option<u16>, retain: bool, payload: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    mqtt_bytes(&mut body, topic.as_bytes());
+    if let Some(id) = packet_id {
+        body.extend_from_slice(&id.to_be_bytes());
+    }
+    body.extend_from_slice(payload.as_bytes());
+    mqtt_packet(0x30 | (packet_id.is_some() as u8) << 1 | retain as u8, body)
+}
+
+fn mqtt_subscribe(packet_id: u16, filter: &str, qos: u8) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    mqtt_bytes(&mut body, filter.as_bytes());
+    body.push(qos);
+    mqtt_packet(0x82, body)
+}
+
+/// Next packet from the server as (fixed header byte, body); `None` if none came in time.
+async fn mqtt_read(stream: &mut tokio::net::TcpStream, wait: Duration) -> Option<(u8, Vec<u8>)> {
+    use tokio::io::AsyncReadExt;
+    tokio::time::timeout(wait, async {
+        let first = stream.read_u8().await.ok()?;
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = stream.read_u8().await.ok()?;
+            len |= ((byte & 0x7F) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.ok()?;
+        Some((first, body))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Topic and payload of a PUBLISH body.
+fn mqtt_message(first: u8, body: &[u8]) -> (String, String) {
+    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+    let start = 2 + topic_len + if first & 0x06 != 0 { 2 } else { 0 };
+    (topic, String::from_utf8(body[start..].to_vec()).unwrap())
+}
+
+/// Connect and expect the CONNACK return code.
+async fn mqtt_client(addr: std::net::SocketAddr, connect: Vec<u8>, code: u8) -> tokio::net::TcpStream {
+    use tokio::io::AsyncWriteExt;
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&connect).await.unwrap();
+    let (first, body) = mqtt_read(&mut stream, Duration::from_secs(5)).await.expect("CONNACK");
+    assert_eq!((first, body), (0x20, vec![0, code]));
+    stream
+}
+
+async fn mqtt_send(stream: &mut tokio::net::TcpStream, packet: Vec<u8>) {
+    use tokio::io::AsyncWriteExt;
+    stream.write_all(&packet).await.unwrap();
+}
+
+/// SUBSCRIBE and expect the SUBACK return code; leaves the channel subscription a moment to start.
+async fn mqtt_subscribed(stream: &mut tokio::net::TcpStream, filter: &str, qos: u8, code: u8) {
+    mqtt_send(stream, mqtt_subscribe(1, filter, qos)).await;
+    let suback = mqtt_read(stream, Duration::from_secs(5)).await.expect("SUBACK");
+    assert_eq!(suback, (0x90, vec![0, 1, code]));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+}
+
+#[tokio::test]
+async fn mqtt_connect_checks_the_key_and_secret() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let secret = state.app_secret.clone();
+    let addr = mqtt_listener(state).await;
+
+    let mut refused = mqtt_client(addr, mqtt_connect(&app_key, "wrong", 60, None), 4).await;
+    assert_eq!(mqtt_read(&mut refused, Duration::from_secs(2)).await, None, "closed after the refusal");
+    let mut client = mqtt_client(addr, mqtt_connect(&app_key, &secret, 60, None), 0).await;
+    mqtt_send(&mut client, mqtt_packet(0xC0, Vec::new())).await;
+    assert_eq!(mqtt_read(&mut client, Duration::from_secs(5)).await, Some((0xD0, Vec::new())));
+}
+
+#[tokio::test]
+async fn mqtt_subscribers_get_published_events_and_qos1_is_acked() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let secret = state.app_secret.clone();
+    let addr = mqtt_listener(state).await;
+    let channel = format!("news-{}", uuid::Uuid::new_v4().simple());
+
+    let mut subscriber = mqtt_client(addr, mqtt_connect(&app_key, &secret, 60, None), 0).await;
+    mqtt_subscribed(&mut subscriber, &format!("{}/#", channel), 1, 1).await;
+    let private = format!("private-{}/#", uuid::Uuid::new_v4().simple());
+    mqtt_subscribed(&mut subscriber, &private, 0, 0x80).await;
+
+    let mut publisher = mqtt_client(addr, mqtt_connect(&app_key, &secret, 60, None), 0).await;
+    mqtt_send(&mut publisher, mqtt_publish(&format!("{}/alert", channel), Some(7), false, r#"{"level":2}"#)).await;
+    let puback = mqtt_read(&mut publisher, Duration::from_secs(5)).await;
+    assert_eq!(puback, Some((0x40, vec![0, 7])));
+
+    let (first, body) = mqtt_read(&mut subscriber, Duration::from_secs(5)).await.expect("PUBLISH");
+    assert_eq!(first, 0x32, "QoS 1, not retained");
+    assert_eq!(mqtt_message(first, &body), (format!("{}/alert", channel), r#"{"level":2}"#.to_string()));
+}
+
+#[tokio::test]
+async fn mqtt_retained_message_is_sent_on_subscribe_until_cleared() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let secret = state.app_secret.clone();
+    let addr = mqtt_listener(state).await;
+    let channel = format!("cache-{}", uuid::Uuid::new_v4().simple());
+    let topic = format!("{}/temp", channel);
+
+    let mut publisher = mqtt_client(addr, mqtt_connect(&app_key, &secret, 60, None), 0).await;
+    mqtt_send(&mut publisher, mqtt_publish(&topic, Some(1), true, "21.5")).await;
+    assert_eq!(mqtt_read(&mut publisher, Duration::from_secs(5)).await, Some((0x40, vec![0, 1])));
+
+    let mut late = mqtt_client(addr, mqtt_connect(&app_key, &secret, 60, None), 0).await;
+    mqtt_subscribed(&mut late, &format!("{}/#", channel), 0, 0).await;
+    let (first, body) = mqtt_read(&mut late, Duration::from_secs(5)).await.expect("retained PUBLISH");
+    assert_eq!(first, 0x31, "QoS 0, retained");
+    assert_eq!(mqtt_message(first, &body), (topic.clone(), "21.5".to_string()));
+
+    mqtt_send(&mut publisher, mqtt_publish(&topic, Some(2), true, "")).await;
+    assert_eq!(mqtt_read(&mut publisher, Duration::from_secs(5)).await, Some((0x40, vec![0, 2])));
+    let mut later = mqtt_client(addr, mqtt_connect(&app_key, &secret, 60, None), 0).await;
+    mqtt_subscribed(&mut later, &format!("{}/#", channel), 0, 0).await;
+    assert_eq!(mqtt_read(&mut later, Duration::from_millis(500)).await, None, "retained message cleared");
+}
+
+#[tokio::test]
+async fn mqtt_will_is_published_on_keep_alive_timeout() {
+    let Some((state, app_key)) = env_state().await else {
+        return;
+    };
+    let secret = state.app_secret.clone();
+    let addr = mqtt_listener(state).await;
+    let channel = format!("devices-{}", uuid::Uuid::new_v4().simple());
+
+    let mut watcher = mqtt_client(addr, mqtt_connect(&app_key, &secret, 60, None), 0).await;
+    mqtt_subscribed(&mut watcher, &format!("{}/#", channel), 0, 0).await;
+    let will = (format!("{}/gone", channel), "sensor-1");
+    let mut device = mqtt_client(addr, mqtt_connect(&app_key, &secret, 1, Some((&will.0, will.1))), 0).await;
+
+    let (first, body) = mqtt_read(&mut watcher, Duration::from_secs(5)).await.expect("will PUBLISH");
+    assert_eq!(mqtt_message(first, &body), (will.0.clone(), will.1.to_string()));
+    assert_eq!(mqtt_read(&mut device, Duration::from_secs(1)).await, None, "silent device was closed");
+}